@@ -0,0 +1,170 @@
+// Benchmarks for the cart write path. Run with `cargo bench`. The MongoDB benchmarks only run when
+// BENCH_MONGODB_URI points at a disposable instance, e.g. `docker run -p 27017:27017 mongo`
+use std::{env, sync::Arc};
+
+use chrono::Utc;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use eshop_orders::{
+    cqrs::{
+        AddProductToCartCommand, AddProductToCartCommandHandler, CartConflictStrategy,
+        CommandHandler, RemoveProductFromCartCommand, RemoveProductFromCartCommandHandler,
+        DEFAULT_MAX_ADD_TO_CART_QUANTITY,
+    },
+    domain::{Cart, CartId, CartLineItem, ProductId},
+    events::{Event, LoggingMessageBroker},
+    repositories::{
+        CartRepository, InMemoryCartRepository, InMemoryOrderRepository, MongoDbCartRepository,
+        MongoDbInitializationInfo,
+    },
+    uow::{OrderUnitOfWork, TransactionSession},
+};
+use mongodb::{bson, Client};
+use tokio::runtime::Runtime;
+
+fn cart(product_count: usize) -> Cart {
+    Cart {
+        id: CartId::generate(),
+        user_id: String::from("bench-user"),
+        name: String::from("Bench cart"),
+        is_default: false,
+        products: (0..product_count)
+            .map(|i| CartLineItem {
+                product_id: ProductId::from(format!("product-{}", i)),
+                quantity: 1,
+                added_at_utc: Utc::now(),
+                unit_price_cents: None,
+            })
+            .collect(),
+        created_at_utc: Utc::now(),
+        updated_at_utc: Utc::now(),
+        version: 0,
+    }
+}
+
+fn in_memory_uow(cart_repository: Arc<InMemoryCartRepository>) -> Arc<OrderUnitOfWork> {
+    Arc::new(OrderUnitOfWork::new(
+        Arc::new(InMemoryOrderRepository::new()),
+        cart_repository,
+        Arc::new(LoggingMessageBroker {}),
+        None,
+    ))
+}
+
+// Adds and then removes the same product, so the cart stays the same size across iterations
+fn cart_handlers(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let cart_repository = Arc::new(InMemoryCartRepository::new());
+    let uow = in_memory_uow(cart_repository.clone());
+    let add_handler = AddProductToCartCommandHandler::new(
+        uow.clone(),
+        CartConflictStrategy::Merge,
+        DEFAULT_MAX_ADD_TO_CART_QUANTITY,
+    );
+    let remove_handler = RemoveProductFromCartCommandHandler::new(uow, CartConflictStrategy::Merge);
+
+    let mut group = c.benchmark_group("cart_handlers");
+    for product_count in [1, 50] {
+        let cart = cart(product_count);
+        runtime
+            .block_on(cart_repository.create(
+                cart.id.clone(),
+                cart.clone(),
+                TransactionSession::default(),
+            ))
+            .unwrap();
+        let add_command = AddProductToCartCommand {
+            user_id: cart.user_id.clone(),
+            cart_id: cart.id.clone(),
+            product_id: ProductId::from("bench-product"),
+            quantity: None,
+            client_request_id: None,
+        };
+        let remove_command = RemoveProductFromCartCommand {
+            user_id: cart.user_id.clone(),
+            cart_id: cart.id.clone(),
+            product_id: ProductId::from("bench-product"),
+        };
+
+        group.bench_function(format!("add_remove_{}_products", product_count), |b| {
+            b.to_async(&runtime).iter(|| async {
+                add_handler.handle(&add_command).await.unwrap();
+                remove_handler.handle(&remove_command).await.unwrap();
+            })
+        });
+    }
+    group.finish();
+}
+
+fn event_serialization(c: &mut Criterion) {
+    let mut group = c.benchmark_group("event_serialization");
+    for event in Event::all() {
+        group.bench_function(event.event_type(), |b| {
+            b.iter(|| serde_json::to_string(&event).unwrap())
+        });
+    }
+    group.finish();
+}
+
+// Mapping to and from BSON happens on every MongoDB read and write, so it is measured on its own
+// as well as through the repositories
+fn repository_mapping(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let cart = cart(20);
+    let document = bson::to_document(&cart).unwrap();
+
+    let mut group = c.benchmark_group("repository_mapping");
+    group.bench_function("cart_to_bson", |b| {
+        b.iter(|| bson::to_document(&cart).unwrap())
+    });
+    group.bench_function("bson_to_cart", |b| {
+        b.iter(|| bson::from_document::<Cart>(document.clone()).unwrap())
+    });
+
+    let in_memory: Arc<dyn CartRepository + Send + Sync> = Arc::new(InMemoryCartRepository::new());
+    let mut repositories = vec![("in_memory", in_memory)];
+    if let Ok(uri) = env::var("BENCH_MONGODB_URI") {
+        let info = MongoDbInitializationInfo {
+            uri,
+            database: String::from("order_service_bench"),
+            collection: String::from("carts"),
+        };
+        let mongodb: Arc<dyn CartRepository + Send + Sync> = runtime.block_on(async {
+            let client = Client::with_uri_str(&info.uri).await.unwrap();
+            client.database(&info.database).drop().await.unwrap();
+            Arc::new(MongoDbCartRepository::new(&info, &client).await)
+        });
+        repositories.push(("mongodb", mongodb));
+    }
+
+    for (name, repository) in repositories {
+        group.bench_function(format!("{}_create_read", name), |b| {
+            b.to_async(&runtime).iter_batched(
+                || {
+                    let mut cart = cart.clone();
+                    cart.id = CartId::generate();
+                    cart
+                },
+                |cart| {
+                    let repository = repository.clone();
+                    async move {
+                        let cart = repository
+                            .create(cart.id.clone(), cart, TransactionSession::default())
+                            .await
+                            .unwrap();
+                        repository.read(&cart.id).await.unwrap()
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    cart_handlers,
+    event_serialization,
+    repository_mapping
+);
+criterion_main!(benches);
@@ -0,0 +1,44 @@
+use std::{env, process::Command, time::SystemTime};
+
+// Embeds the git SHA and build time so /info can report exactly what is deployed. Docker builds
+// don't copy .git, so the SHA can also be passed in through the GIT_SHA build argument
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_SHA");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=src");
+
+    let git_sha = env::var("GIT_SHA").ok().or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+
+    let build_timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    println!(
+        "cargo:rustc-env=GIT_SHA={}",
+        git_sha.unwrap_or(String::from("unknown"))
+    );
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", build_timestamp);
+
+    #[cfg(feature = "grpc")]
+    compile_protos();
+}
+
+// The vendored protoc is used so building doesn't depend on protoc being installed
+#[cfg(feature = "grpc")]
+fn compile_protos() {
+    println!("cargo:rerun-if-changed=proto");
+
+    env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+    tonic_prost_build::configure()
+        .build_server(false)
+        .compile_protos(&["proto/inventory/v1/inventory.proto"], &["proto"])
+        .unwrap();
+}
@@ -0,0 +1,176 @@
+use std::{
+    env,
+    io::{self, Write},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::{
+    body::HttpBody,
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{SecondsFormat, Utc};
+use serde::Serialize;
+
+use crate::{auth::Claims, http_client::REQUEST_ID_HEADER};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessLogFormat {
+    Json,
+    Logfmt,
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessLogSettings {
+    pub format: AccessLogFormat,
+    // Written to stdout when not set, away from the application log in LOG_PATH
+    pub path: Option<String>,
+}
+
+impl Default for AccessLogSettings {
+    fn default() -> Self {
+        AccessLogSettings {
+            format: AccessLogFormat::Json,
+            path: None,
+        }
+    }
+}
+
+impl AccessLogSettings {
+    pub fn from_env() -> Result<AccessLogSettings, String> {
+        let defaults = AccessLogSettings::default();
+
+        Ok(AccessLogSettings {
+            format: match env::var("ACCESS_LOG_FORMAT") {
+                Ok(format) => match format.as_str() {
+                    "json" => AccessLogFormat::Json,
+                    "logfmt" => AccessLogFormat::Logfmt,
+                    other => return Err(format!("Unknown ACCESS_LOG_FORMAT {}", other)),
+                },
+                Err(_) => defaults.format,
+            },
+            path: env::var("ACCESS_LOG_PATH").ok().or(defaults.path),
+        })
+    }
+}
+
+// One record per request. The route is the template it matched rather than the requested path, so
+// ids and share tokens stay out of the access log and records group by endpoint
+#[derive(Serialize)]
+struct AccessLogRecord {
+    timestamp: String,
+    method: String,
+    route: Option<String>,
+    status: u16,
+    latency_ms: f64,
+    user: Option<String>,
+    request_id: Option<String>,
+    bytes: Option<u64>,
+}
+
+impl AccessLogRecord {
+    fn to_logfmt(&self) -> String {
+        let mut fields = vec![
+            format!("timestamp={}", self.timestamp),
+            format!("method={}", self.method),
+        ];
+        fields.extend(
+            [
+                ("route", self.route.clone()),
+                ("status", Some(self.status.to_string())),
+                ("latency_ms", Some(format!("{:.3}", self.latency_ms))),
+                ("user", self.user.clone()),
+                ("request_id", self.request_id.clone()),
+                ("bytes", self.bytes.map(|bytes| bytes.to_string())),
+            ]
+            .into_iter()
+            .filter_map(|(name, value)| {
+                value.map(|value| format!("{}={}", name, logfmt_value(&value)))
+            }),
+        );
+
+        fields.join(" ")
+    }
+}
+
+// Values with spaces, quotes or '=' have to be quoted for logfmt parsers
+fn logfmt_value(value: &str) -> String {
+    match value.contains([' ', '"', '=']) {
+        true => format!("{:?}", value),
+        false => String::from(value),
+    }
+}
+
+pub struct AccessLog {
+    format: AccessLogFormat,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    pub fn new(settings: AccessLogSettings) -> Result<AccessLog, String> {
+        let writer: Box<dyn Write + Send> = match settings.path {
+            Some(path) => Box::new(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .map_err(|e| format!("Failed to open access log {}: {}", path, e))?,
+            ),
+            None => Box::new(io::stdout()),
+        };
+
+        Ok(AccessLog {
+            format: settings.format,
+            writer: Mutex::new(writer),
+        })
+    }
+
+    fn write(&self, record: &AccessLogRecord) {
+        let line = match self.format {
+            AccessLogFormat::Json => serde_json::to_string(record).unwrap(),
+            AccessLogFormat::Logfmt => record.to_logfmt(),
+        };
+
+        // A failed write must not fail the request it describes
+        writeln!(self.writer.lock().unwrap(), "{}", line).ok();
+    }
+}
+
+// Sits outside trace_context_middleware so the request id it assigns is on the response. The caller
+// is only known inside the authentication middleware, which puts its claims on the response too
+pub async fn access_log_middleware(
+    State(access_log): State<Arc<AccessLog>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|matched_path| String::from(matched_path.as_str()));
+
+    let response = next.run(request).await;
+
+    access_log.write(&AccessLogRecord {
+        timestamp: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        method,
+        route,
+        status: response.status().as_u16(),
+        latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+        user: response
+            .extensions()
+            .get::<Claims>()
+            .map(|claims| claims.sub.clone()),
+        request_id: response
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|request_id| request_id.to_str().ok())
+            .map(String::from),
+        bytes: response.body().size_hint().exact(),
+    });
+
+    response
+}
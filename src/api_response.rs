@@ -0,0 +1,65 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+
+use crate::{
+    dtos::{ApiError, Envelope, PageInfo, ResponseMeta},
+    http_client::current_request_id,
+};
+
+// What every handler returns, so all endpoints answer in the same envelope. Throttled requests and
+// unreadable bodies are refused before a handler runs and keep their problem+json bodies
+pub struct ApiResponse<T> {
+    status: StatusCode,
+    envelope: Envelope<T>,
+}
+
+impl<T: Serialize> ApiResponse<T> {
+    pub fn new(status: StatusCode, data: T) -> ApiResponse<T> {
+        ApiResponse {
+            status,
+            envelope: Envelope {
+                data: Some(data),
+                meta: meta(),
+                errors: Vec::new(),
+            },
+        }
+    }
+
+    pub fn with_page(mut self, page: Option<PageInfo>) -> ApiResponse<T> {
+        self.envelope.meta.page = page;
+        self
+    }
+}
+
+impl ApiResponse<()> {
+    pub fn error(status: StatusCode, code: &str, message: String) -> ApiResponse<()> {
+        ApiResponse {
+            status,
+            envelope: Envelope {
+                data: None,
+                meta: meta(),
+                errors: vec![ApiError {
+                    code: String::from(code),
+                    message,
+                }],
+            },
+        }
+    }
+}
+
+fn meta() -> ResponseMeta {
+    ResponseMeta {
+        request_id: current_request_id().unwrap_or_default(),
+        page: None,
+    }
+}
+
+impl<T: Serialize> IntoResponse for ApiResponse<T> {
+    fn into_response(self) -> Response {
+        (self.status, Json(self.envelope)).into_response()
+    }
+}
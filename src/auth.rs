@@ -1,27 +1,167 @@
-use std::sync::Arc;
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, sync::Arc};
 
-use axum::{extract::{Request, State}, middleware::Next, response::Response};
-use jsonwebtoken::{decode, decode_header, Validation};
+use axum::{extract::{ConnectInfo, Request, State}, middleware::Next, response::Response};
+use jsonwebtoken::{decode, decode_header, DecodingKey, Validation};
 use jwks::Jwks;
 use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use tracing::{event, Level};
 
-use crate::state::AppState;
+use crate::{domain::CartId, rate_limiting::check_verified, signing::TokenSigner, state::AppState};
 
-#[derive(Debug, Deserialize, Serialize)]
+pub static CART_SESSION_HEADER: &str = "X-Cart-Session";
+pub static CART_SESSION_TOKEN_PREFIX: &str = "cart-session:";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Claims {
     pub sub: String,
     pub aud: Value,
     pub iss: String,
     pub exp: usize,
     pub iat: usize,
+    // Not every issuer sets these, e.g. partner tokens may carry no scopes
+    #[serde(default)]
     pub azp: String,
-    pub scope: String
+    #[serde(default)]
+    pub scope: String,
+    // Everything else the issuer put in the token, such as namespaced role claims
+    #[serde(flatten)]
+    pub other_claims: HashMap<String, Value>
+}
+
+// An identity provider whose tokens are accepted. The iss claim of a token selects the issuer that validates it
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenIssuer {
+    pub issuer: String,
+    // A token must be intended for at least one of these
+    pub audiences: Vec<String>,
+    pub jwks_url: String
+}
+
+impl TokenIssuer {
+    // Auth0 issues tokens as https://<domain>/ and publishes its keys under the same domain
+    pub fn auth0(domain: &str, audience: &str) -> TokenIssuer {
+        let domain = domain.trim_end_matches('/');
+
+        TokenIssuer {
+            issuer: format!("{}/", domain),
+            audiences: vec![String::from(audience)],
+            jwks_url: format!("{}/.well-known/jwks.json", domain)
+        }
+    }
+
+    fn accepts_audience(&self, aud: &Value) -> bool {
+        match aud {
+            Value::String(single_aud) => self.audiences.contains(single_aud),
+            Value::Array(multiple_aud) => multiple_aud.iter().all(Value::is_string) && multiple_aud.iter().any(|entry| entry.as_str().is_some_and(|aud| self.audiences.iter().any(|audience| audience == aud))),
+            _ => false
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct UnverifiedIssuer {
+    iss: String
+}
+
+// Reads the iss claim without checking the signature; only good for choosing which keys to check it with
+fn unverified_issuer(token: &str) -> Option<String> {
+    let mut validation = Validation::default();
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+    validation.required_spec_claims.clear();
+
+    decode::<UnverifiedIssuer>(token, &DecodingKey::from_secret(&[]), &validation).ok().map(|token_data| token_data.claims.iss)
+}
+
+// Identifies a guest shopper whose cart-session token authorizes mutations on a single cart
+#[derive(Debug, Clone)]
+pub struct CartSession {
+    pub cart_id: CartId
 }
 
-pub async fn authentication_middleware(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Result<Response, StatusCode>{
+// A CIDR block such as 10.0.0.0/8; a bare address is treated as a single-host network
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpNetwork {
+    pub address: IpAddr,
+    pub prefix_len: u8
+}
+
+impl IpNetwork {
+    pub fn parse(value: &str) -> Result<IpNetwork, String> {
+        let (address, prefix_len) = match value.trim().split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (value.trim(), None)
+        };
+
+        let address: IpAddr = match address.parse() {
+            Ok(address) => address,
+            Err(_) => return Err(format!("Invalid network address {}", value))
+        };
+
+        let max_prefix_len = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128
+        };
+
+        match prefix_len.map(|x| x.parse::<u8>()) {
+            None => Ok(IpNetwork{address, prefix_len: max_prefix_len}),
+            Some(Ok(prefix_len)) if prefix_len <= max_prefix_len => Ok(IpNetwork{address, prefix_len}),
+            Some(_) => Err(format!("Invalid prefix length in network {}", value))
+        }
+    }
+
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        // IPv4 clients reach dual-stack listeners as IPv4-mapped IPv6 addresses
+        match (self.address, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            },
+            _ => false
+        }
+    }
+}
+
+// Guards operational endpoints like /metrics. With neither a token nor networks configured they stay
+// open; otherwise a caller needs the bearer token or an address inside one of the allowed networks
+#[derive(Debug, Clone, Default)]
+pub struct OpsAccess {
+    pub token: Option<String>,
+    pub allowed_networks: Vec<IpNetwork>
+}
+
+impl OpsAccess {
+    fn is_open(&self) -> bool {
+        self.token.is_none() && self.allowed_networks.is_empty()
+    }
+
+    fn token_matches(&self, token: &str) -> bool {
+        match &self.token {
+            // Compare every byte so the time taken doesn't reveal how much of the token matched
+            Some(expected) => expected.len() == token.len() && expected.bytes().zip(token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0,
+            None => false
+        }
+    }
+}
+
+pub fn verify_cart_session_token(token_signer: &TokenSigner, token: &str) -> Result<CartId, String> {
+    match token_signer.verify(token) {
+        Ok(payload) => match payload.strip_prefix(CART_SESSION_TOKEN_PREFIX) {
+            Some(cart_id) => Ok(CartId::from(cart_id)),
+            None => Err(String::from("Token is not a cart session token"))
+        },
+        Err(e) => Err(e)
+    }
+}
+
+pub async fn authentication_middleware(State(state): State<Arc<AppState>>, mut request: Request, next: Next) -> Result<Response, StatusCode>{
     // Get the Authorization header
     match request.headers().get("Authorization"){
         Some(auth_header) => {
@@ -31,99 +171,143 @@ pub async fn authentication_middleware(State(state): State<Arc<AppState>>, reque
                     // Pull the token from the final part of the string 'Bearer <token>'
                     let token = auth_header_str.split_whitespace().last();
 
-                    let token = match token {
-                        Some(t) => t,
-                        None => ""
+                    let token = token.unwrap_or_default();
+
+                    // Pick the issuer that has to validate the token. Its signature is only checked below, against that issuer's keys
+                    let issuer = match unverified_issuer(token).and_then(|iss| state.token_issuers.iter().find(|issuer| issuer.issuer == iss)) {
+                        Some(issuer) => issuer,
+                        None => {
+                            event!(Level::WARN, "Token is not from a trusted issuer!");
+                            return Err(StatusCode::UNAUTHORIZED);
+                        }
                     };
 
                     // Decode the header of the JWT which contains the 'kid'
                     match decode_header(token) {
                         Ok(decoded_token) => {
-                            let kid = match decoded_token.kid{
-                                Some(k) => k,
-                                None => String::new()
-                            };
-
-                            // Retrieve the JWKS
-                            let jwks_url = format!("{}/.well-known/jwks.json", state.auth0_domain);
-                            match Jwks::from_jwks_url(jwks_url).await{
+                            let kid = decoded_token.kid.unwrap_or_default();
+
+                            // Retrieve the issuer's JWKS
+                            match Jwks::from_jwks_url(&issuer.jwks_url).await{
                                 Ok(jwks) => {
                                     // Grab the correct JWK based on the kid from the header
                                     match jwks.keys.get(&kid){
                                         Some(jwk) => {
-                                            // Configure the token validation to use RS256 decoding, valiate the expiration time, and do not validate the audience (yet)
+                                            // Configure the token validation to use RS256 decoding, validate the expiration time and issuer, and check the audience below
                                             let mut validation = Validation::new(jsonwebtoken::Algorithm::RS256);
                                             validation.validate_exp = true;
                                             validation.validate_aud = false;
+                                            validation.set_issuer(&[&issuer.issuer]);
                                             
                                             // Decode the token body
                                             match decode::<Claims>(token, &jwk.decoding_key, &validation){
                                                 Ok(token_data) => {
-                                                    match token_data.claims.aud {
-                                                        Value::String(single_aud) => {
-                                                            if state.auth0_audience != single_aud{
-                                                                event!(Level::WARN, "Invalid audience: {}!", single_aud);
-                                                                return Err(StatusCode::UNAUTHORIZED);
-                                                            }
-                                                        },
-                                                        Value::Array(multiple_aud) => {
-                                                            let mut aud_found = false;
-
-                                                            for entry in multiple_aud{
-                                                                match entry {
-                                                                    Value::String(s) => {
-                                                                        if state.auth0_audience == s{
-                                                                            aud_found = true;
-                                                                        }
-                                                                    },
-                                                                    _ => return Err(StatusCode::UNAUTHORIZED)
-                                                                }
-                                                            }
-
-                                                            if !aud_found{
-                                                                event!(Level::WARN, "Invalid audience!");
-                                                                return Err(StatusCode::UNAUTHORIZED);
-                                                            }
-                                                        },
-                                                        _ => return Err(StatusCode::UNAUTHORIZED)
+                                                    if !issuer.accepts_audience(&token_data.claims.aud) {
+                                                        event!(Level::WARN, "Invalid audience for issuer {}!", issuer.issuer);
+                                                        return Err(StatusCode::UNAUTHORIZED);
+                                                    }
+
+                                                    // Make the caller's claims available to the route handlers, and to
+                                                    // the access log once the response is on its way out
+                                                    let claims = token_data.claims;
+                                                    if let Some(mut response) = check_verified(&state.rate_limiter, &request, &claims) {
+                                                        response.extensions_mut().insert(claims);
+                                                        return Ok(response);
                                                     }
+                                                    request.extensions_mut().insert(claims.clone());
 
                                                     event!(Level::TRACE, "Auth middleware successful!");
-                                                    return Ok(next.run(request).await)
+                                                    let mut response = next.run(request).await;
+                                                    response.extensions_mut().insert(claims);
+                                                    Ok(response)
                                                 },
                                                 Err(e) => {
                                                     event!(Level::WARN, "Failed to decode token using decode key from jwk: {}!", e);
-                                                    return Err(StatusCode::UNAUTHORIZED);
+                                                    Err(StatusCode::UNAUTHORIZED)
                                                 }
                                             }
                                         },
                                         None => {
                                             event!(Level::WARN, "Failed to get JWK from JWKS!");
-                                            return Err(StatusCode::UNAUTHORIZED);
+                                            Err(StatusCode::UNAUTHORIZED)
                                         }
                                     }
                                 },
                                 Err(_) => {
-                                    event!(Level::WARN, "Failed to fetch jwks!");
-                                    return Err(StatusCode::UNAUTHORIZED);
+                                    event!(Level::WARN, "Failed to fetch jwks for issuer {}!", issuer.issuer);
+                                    Err(StatusCode::UNAUTHORIZED)
                                 }
                             }
                         },
                         Err(_) => {
                             event!(Level::WARN, "Failed to decode token header!");
-                            return Err(StatusCode::UNAUTHORIZED);
+                            Err(StatusCode::UNAUTHORIZED)
                         }
                     }
                 },
                 Err(_) => {
                     event!(Level::WARN, "Auth header not formatted correctly!");
-                    return Err(StatusCode::UNAUTHORIZED);        
+                    Err(StatusCode::UNAUTHORIZED)
                 }
             }
         },
         None => {
             event!(Level::WARN, "No auth header found!");
-            return Err(StatusCode::UNAUTHORIZED);
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+
+pub async fn guest_or_authentication_middleware(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Result<Response, StatusCode>{
+    // Guests may call the route without any credentials, but a supplied token must still be valid
+    if request.headers().contains_key("Authorization"){
+        return authentication_middleware(State(state), request, next).await;
+    }
+
+    event!(Level::TRACE, "No auth header found, continuing as guest");
+    Ok(next.run(request).await)
+}
+
+pub async fn cart_session_or_authentication_middleware(State(state): State<Arc<AppState>>, mut request: Request, next: Next) -> Result<Response, StatusCode>{
+    // Fall back to regular Auth0 authentication when no cart session token is present
+    let cart_session_header = match request.headers().get(CART_SESSION_HEADER){
+        Some(cart_session_header) => cart_session_header,
+        None => return authentication_middleware(State(state), request, next).await
+    };
+
+    match cart_session_header.to_str() {
+        Ok(token) => {
+            match verify_cart_session_token(&state.cart_session_token_signer, token){
+                Ok(cart_id) => {
+                    request.extensions_mut().insert(CartSession{cart_id});
+
+                    event!(Level::TRACE, "Cart session middleware successful!");
+                    Ok(next.run(request).await)
+                },
+                Err(e) => {
+                    event!(Level::WARN, "Failed to verify cart session token: {}!", e);
+                    Err(StatusCode::UNAUTHORIZED)
+                }
+            }
+        },
+        Err(_) => {
+            event!(Level::WARN, "Cart session header not formatted correctly!");
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+pub async fn ops_authorization_middleware(State(state): State<Arc<AppState>>, ConnectInfo(peer): ConnectInfo<SocketAddr>, request: Request, next: Next) -> Result<Response, StatusCode>{
+    let ops_access = &state.ops_access;
+    if ops_access.is_open() || ops_access.allowed_networks.iter().any(|network| network.contains(&peer.ip())) {
+        return Ok(next.run(request).await);
+    }
+
+    let token = request.headers().get("Authorization").and_then(|h| h.to_str().ok()).and_then(|h| h.strip_prefix("Bearer ")).unwrap_or_default();
+    match ops_access.token_matches(token) {
+        true => Ok(next.run(request).await),
+        false => {
+            event!(Level::WARN, "Rejected request to {} from {}!", request.uri().path(), peer.ip());
+            Err(StatusCode::UNAUTHORIZED)
         }
     }
-}
\ No newline at end of file
+}
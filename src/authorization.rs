@@ -0,0 +1,188 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{rejection::RawPathParamsRejection, MatchedPath, RawPathParams, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{event, Level};
+
+use crate::{
+    auth::{CartSession, Claims},
+    state::AppState,
+};
+
+// The policy the service runs with unless AUTHORIZATION_POLICY_PATH points at another one
+static DEFAULT_POLICY: &str = include_str!("authorization_policy.json");
+
+// Every rule about who may call which route, in one place so it can be reviewed and audited.
+// Routes behind authorization_middleware without an entry here are refused
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthorizationPolicy {
+    // Auth0 only allows custom claims such as roles under a namespace
+    pub roles_claim: String,
+    pub routes: Vec<RoutePolicy>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoutePolicy {
+    pub method: String,
+    // As registered with the router, e.g. /carts/{id}
+    pub path: String,
+    // The caller needs every one of these
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    // The caller needs at least one of these, unless there are none
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub ownership: Ownership,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Ownership {
+    #[default]
+    None,
+    // Guests may only reach the cart in the {id} path parameter their cart session was issued for
+    CartSession,
+}
+
+impl Default for AuthorizationPolicy {
+    fn default() -> Self {
+        serde_json::from_str(DEFAULT_POLICY).expect("The default authorization policy is valid")
+    }
+}
+
+impl AuthorizationPolicy {
+    pub fn from_file(path: &str) -> Result<AuthorizationPolicy, String> {
+        match std::fs::read_to_string(path) {
+            Ok(policy) => match serde_json::from_str(&policy) {
+                Ok(policy) => Ok(policy),
+                Err(e) => Err(format!("Invalid authorization policy in {}: {}", path, e)),
+            },
+            Err(e) => Err(format!(
+                "Failed to read authorization policy {}: {}",
+                path, e
+            )),
+        }
+    }
+
+    fn route(&self, method: &str, path: &str) -> Option<&RoutePolicy> {
+        self.routes
+            .iter()
+            .find(|route| route.method.eq_ignore_ascii_case(method) && route.path == path)
+    }
+
+    fn roles(&self, claims: &Claims) -> Vec<String> {
+        match claims.other_claims.get(&self.roles_claim) {
+            Some(Value::Array(roles)) => roles
+                .iter()
+                .filter_map(|role| role.as_str().map(String::from))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Unauthorized when the route needs an identity the caller didn't present, forbidden when the
+    // caller is known but not allowed
+    fn authorize(
+        &self,
+        route: &RoutePolicy,
+        claims: Option<&Claims>,
+        cart_session: Option<&CartSession>,
+        path_params: Option<&RawPathParams>,
+    ) -> Result<(), String> {
+        if !route.scopes.is_empty() || !route.roles.is_empty() {
+            let claims = match claims {
+                Some(claims) => claims,
+                None => return Err(String::from("no authenticated caller")),
+            };
+
+            if let Some(scope) = route
+                .scopes
+                .iter()
+                .find(|scope| !claims.scope.split_whitespace().any(|x| x == *scope))
+            {
+                return Err(format!("{} is missing the {} scope", claims.sub, scope));
+            }
+
+            let roles = self.roles(claims);
+            if !route.roles.is_empty() && !route.roles.iter().any(|role| roles.contains(role)) {
+                return Err(format!(
+                    "{} has none of the roles {}",
+                    claims.sub,
+                    route.roles.join(", ")
+                ));
+            }
+        }
+
+        match (route.ownership, cart_session) {
+            (Ownership::CartSession, Some(cart_session)) => {
+                let cart_id = path_params
+                    .and_then(|params| params.iter().find(|(key, _)| *key == "id"))
+                    .map(|(_, value)| value);
+
+                match cart_id == Some(cart_session.cart_id.as_str()) {
+                    true => Ok(()),
+                    false => Err(format!(
+                        "cart session for {} does not grant access to {}",
+                        cart_session.cart_id,
+                        cart_id.unwrap_or_default()
+                    )),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// Must run after the authentication middleware of the route, which provides the caller's claims or
+// cart session
+pub async fn authorization_middleware(
+    State(state): State<Arc<AppState>>,
+    matched_path: MatchedPath,
+    path_params: Result<RawPathParams, RawPathParamsRejection>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let policy = &state.authorization_policy;
+    let route = match policy.route(request.method().as_str(), matched_path.as_str()) {
+        Some(route) => route,
+        None => {
+            event!(
+                Level::ERROR,
+                "No authorization policy for {} {}!",
+                request.method(),
+                matched_path.as_str()
+            );
+            return Err(StatusCode::FORBIDDEN);
+        }
+    };
+
+    let claims = request.extensions().get::<Claims>();
+    match policy.authorize(
+        route,
+        claims,
+        request.extensions().get::<CartSession>(),
+        path_params.as_ref().ok(),
+    ) {
+        Ok(()) => Ok(next.run(request).await),
+        Err(e) => {
+            event!(
+                Level::WARN,
+                "Denied {} {}: {}!",
+                request.method(),
+                matched_path.as_str(),
+                e
+            );
+            match claims.is_some() || request.extensions().get::<CartSession>().is_some() {
+                true => Err(StatusCode::FORBIDDEN),
+                false => Err(StatusCode::UNAUTHORIZED),
+            }
+        }
+    }
+}
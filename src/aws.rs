@@ -0,0 +1,136 @@
+use std::env;
+
+use hmac::Mac;
+use reqwest::header::{HeaderValue, CONTENT_TYPE};
+use sha2::{Digest, Sha256};
+
+type HmacSha256 = hmac::Hmac<Sha256>;
+
+// The AWS integrations call the APIs directly with these credentials instead of going through the SDK
+#[derive(Debug, Clone)]
+pub struct AwsCredentials {
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl AwsCredentials {
+    pub fn from_env() -> AwsCredentials {
+        AwsCredentials {
+            region: env::var("AWS_REGION").unwrap(),
+            access_key_id: env::var("AWS_ACCESS_KEY_ID").unwrap(),
+            secret_access_key: env::var("AWS_SECRET_ACCESS_KEY").unwrap(),
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+        }
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Signs the request in place with AWS Signature Version 4. The content type and X-Amz-Target, when
+// the API needs one, must already be set since they are part of the signature
+pub fn sign(
+    request: &mut reqwest::Request,
+    credentials: &AwsCredentials,
+    service: &str,
+) -> Result<(), String> {
+    let now = chrono::Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date_stamp = now.format("%Y%m%d").to_string();
+    let host = match request.url().host_str() {
+        Some(host) => String::from(host),
+        None => return Err(format!("{} endpoint has no host", service)),
+    };
+    let header = |name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+            .map(String::from)
+    };
+    let content_type = header(CONTENT_TYPE.as_str()).unwrap_or_default();
+    let target = header("x-amz-target");
+    let body = request
+        .body()
+        .and_then(|body| body.as_bytes())
+        .unwrap_or_default();
+
+    // Canonical headers are sorted by name
+    let mut canonical_headers = format!(
+        "content-type:{}\nhost:{}\nx-amz-date:{}\n",
+        content_type, host, amz_date
+    );
+    let mut signed_headers = String::from("content-type;host;x-amz-date");
+    if let Some(session_token) = &credentials.session_token {
+        canonical_headers.push_str(&format!("x-amz-security-token:{}\n", session_token));
+        signed_headers.push_str(";x-amz-security-token");
+    }
+    if let Some(target) = &target {
+        canonical_headers.push_str(&format!("x-amz-target:{}\n", target));
+        signed_headers.push_str(";x-amz-target");
+    }
+
+    let canonical_request = format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        request.method(),
+        request.url().path(),
+        request.url().query().unwrap_or_default(),
+        canonical_headers,
+        signed_headers,
+        hex(&Sha256::digest(body))
+    );
+    let credential_scope = format!(
+        "{}/{}/{}/aws4_request",
+        date_stamp, credentials.region, service
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        credential_scope,
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let signing_key = [credentials.region.as_str(), service, "aws4_request"]
+        .iter()
+        .fold(
+            hmac_sha256(
+                format!("AWS4{}", credentials.secret_access_key).as_bytes(),
+                &date_stamp,
+            ),
+            |key, part| hmac_sha256(&key, part),
+        );
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+        credentials.access_key_id,
+        credential_scope,
+        signed_headers,
+        hex(&hmac_sha256(&signing_key, &string_to_sign))
+    );
+
+    let headers = request.headers_mut();
+    for (name, value) in [
+        ("x-amz-date", Some(amz_date)),
+        ("x-amz-security-token", credentials.session_token.clone()),
+        ("authorization", Some(authorization)),
+    ] {
+        if let Some(value) = value {
+            match HeaderValue::from_str(&value) {
+                Ok(value) => {
+                    headers.insert(name, value);
+                }
+                Err(e) => return Err(format!("Failed to set {} header: {}", name, e)),
+            }
+        }
+    }
+
+    Ok(())
+}
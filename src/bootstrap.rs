@@ -0,0 +1,1152 @@
+use std::{collections::HashMap, env, sync::Arc, time::Duration};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use mongodb::{bson::doc, Client};
+use sqlx::SqlitePool;
+
+use crate::{
+    auth::{IpNetwork, OpsAccess, TokenIssuer},
+    authorization::AuthorizationPolicy,
+    circuit_breaker::{
+        CircuitBreaker, CircuitBreakerSettings, CircuitBreakingCartRepository,
+        CircuitBreakingMessageBroker, CircuitBreakingOrderRepository,
+    },
+    consumers::{
+        RabbitMqEventConsumer, DEFAULT_CONSUMER_PREFETCH_COUNT, DEFAULT_CONSUMER_RECONNECT_DELAY,
+    },
+    cqrs::{
+        AddProductToCartCommand, AddProductToCartCommandHandler, ApplyRetentionRulesCommandHandler,
+        CancelOrderCommandHandler, CartConflictStrategy, ClaimGuestCartCommand,
+        ClaimGuestCartCommandHandler, CloneSharedCartCommand, CloneSharedCartCommandHandler,
+        CreateCartCommandHandler, DeleteCartCommand, DeleteCartCommandHandler,
+        DiscardProductFromCartsCommandHandler, EraseCustomerDataCommandHandler,
+        ExportCustomerDataQueryHandler, GetCartsContainingProductQueryHandler,
+        GetCartsQueryHandler, GetDeliverySlotsQueryHandler, GetOrderByIdQueryHandler,
+        GetOrderTimelineQueryHandler, GetOrdersQueryHandler, GetPickupCodeQueryHandler,
+        GetSharedCartQueryHandler, GetStatsQueryHandler, GetUserCartsQueryHandler,
+        ImportCartsCommandHandler, MarkOrderPaidCommandHandler, RemoveProductFromCartCommand,
+        RemoveProductFromCartCommandHandler, SetDefaultCartCommand, SetDefaultCartCommandHandler,
+        SetProductQuantityCommand, SetProductQuantityCommandHandler, ShareCartCommand,
+        ShareCartCommandHandler, VerifyPickupCodeQueryHandler, DEFAULT_MAX_ADD_TO_CART_QUANTITY,
+    },
+    decorators::{
+        require_user, AuthorizingHandler, BulkheadHandler, BulkheadSettings, DebouncingHandler,
+        LockingHandler, MaintenanceMode, RetryHandler, DEFAULT_DEBOUNCE_WINDOW,
+        DEFAULT_READ_ONLY_FAILOVER_DURATION,
+    },
+    delivery_slots::{DeliverySlotProvider, DeliverySlotSettings, ScheduledDeliverySlotProvider},
+    domain::CartId,
+    encryption::{
+        EncryptingCartRepository, EncryptingOrderRepository, FieldEncryptor, KeyProvider,
+        LocalKeyProvider,
+    },
+    events::{
+        DualPublication, DualPublishingMessageBroker, InMemoryMessageBroker, LoggingMessageBroker,
+        MessageBroker, RabbitMqInitializationInfo, RabbitMqMessageBroker,
+    },
+    fault_injection::{
+        FaultInjectingCartRepository, FaultInjectingMessageBroker, FaultInjectingOrderRepository,
+        FaultInjectionSettings, FaultInjector,
+    },
+    health::Dependency,
+    idempotency::{
+        IdempotencyKeys, IdempotencyStore, InMemoryIdempotencyStore, MongoDbIdempotencyStore,
+        DEFAULT_IDEMPOTENCY_KEY_TTL,
+    },
+    leader_election::{InMemoryLeaseStore, LeaderElector, LeaseStore, MongoDbLeaseStore},
+    locking::{DistributedLock, DistributedLockSettings},
+    log_sampling::{LogSampler, LogSamplingSettings},
+    mediator::Mediator,
+    outbox::{MongoDbOutboxStore, OutboxRelay, OutboxStore, DEFAULT_OUTBOX_RELAY_BATCH_SIZE},
+    rate_limiting::{RateLimitPolicy, RateLimiter},
+    repositories::{
+        CartRepository, InMemoryCartRepository, InMemoryOrderRepository, MongoDbCartRepository,
+        MongoDbInitializationInfo, MongoDbOrderRepository, OrderRepository, SqliteCartRepository,
+        SqliteOrderRepository,
+    },
+    retention::{RetentionEntity, RetentionRule, RetentionSettings},
+    signing::TokenSigner,
+    state::AppState,
+    uow::OrderUnitOfWork,
+};
+
+pub static DEFAULT_CART_SESSION_TTL_SECONDS: i64 = 2592000;
+pub static DEFAULT_CART_SHARE_TTL_SECONDS: i64 = 604800;
+pub static DEFAULT_PICKUP_CODE_TTL_SECONDS: i64 = 1209600;
+
+pub static SCHEDULER_LEASE_NAME: &str = "scheduler";
+pub static DEFAULT_LEADER_LEASE_DURATION: Duration = Duration::from_secs(30);
+
+// Reads are safe to repeat, so transient backend failures are retried before surfacing
+static QUERY_RETRY_ATTEMPTS: u32 = 3;
+static QUERY_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// Assembles the dependency graph behind AppState. Every backend can be swapped independently,
+// so tests and alternate deployments only replace the pieces they care about
+pub struct AppStateBuilder {
+    order_repository: Option<Arc<dyn OrderRepository + Send + Sync>>,
+    cart_repository: Option<Arc<dyn CartRepository + Send + Sync>>,
+    transaction_client: Option<Client>,
+    write_compensation: bool,
+    outbox: Option<(Arc<dyn OutboxStore + Send + Sync>, i64)>,
+    message_broker: Option<Arc<dyn MessageBroker + Send + Sync>>,
+    cart_session_token_signer: Option<TokenSigner>,
+    cart_session_ttl_seconds: i64,
+    cart_share_token_signer: Option<TokenSigner>,
+    cart_share_ttl_seconds: i64,
+    pickup_code_token_signer: Option<TokenSigner>,
+    pickup_code_ttl_seconds: i64,
+    token_issuers: Vec<TokenIssuer>,
+    repository_circuit_breaker: Option<Arc<CircuitBreaker>>,
+    message_broker_circuit_breaker: Option<Arc<CircuitBreaker>>,
+    write_bulkhead: Option<BulkheadSettings>,
+    cart_lock: Option<Arc<DistributedLock>>,
+    cart_conflict_strategy: CartConflictStrategy,
+    ops_access: OpsAccess,
+    authorization_policy: AuthorizationPolicy,
+    retention_rules: Vec<RetentionRule>,
+    field_encryptor: Option<Arc<FieldEncryptor>>,
+    fault_injection: Option<FaultInjectionSettings>,
+    log_sampling: LogSamplingSettings,
+    rate_limiter: RateLimiter,
+    idempotency_store: Arc<dyn IdempotencyStore + Send + Sync>,
+    idempotency_key_ttl: Duration,
+    read_only_failover_duration: Duration,
+    add_to_cart_debounce_window: Duration,
+    max_add_to_cart_quantity: i32,
+    delivery_slot_provider: Arc<dyn DeliverySlotProvider + Send + Sync>,
+    dual_publications: HashMap<String, Vec<DualPublication>>,
+}
+
+impl Default for AppStateBuilder {
+    fn default() -> Self {
+        AppStateBuilder {
+            order_repository: None,
+            cart_repository: None,
+            transaction_client: None,
+            write_compensation: false,
+            outbox: None,
+            message_broker: None,
+            cart_session_token_signer: None,
+            cart_session_ttl_seconds: DEFAULT_CART_SESSION_TTL_SECONDS,
+            cart_share_token_signer: None,
+            cart_share_ttl_seconds: DEFAULT_CART_SHARE_TTL_SECONDS,
+            pickup_code_token_signer: None,
+            pickup_code_ttl_seconds: DEFAULT_PICKUP_CODE_TTL_SECONDS,
+            token_issuers: Vec::new(),
+            repository_circuit_breaker: None,
+            message_broker_circuit_breaker: None,
+            write_bulkhead: None,
+            cart_lock: None,
+            cart_conflict_strategy: CartConflictStrategy::Merge,
+            ops_access: OpsAccess::default(),
+            authorization_policy: AuthorizationPolicy::default(),
+            retention_rules: Vec::new(),
+            field_encryptor: None,
+            fault_injection: None,
+            log_sampling: LogSamplingSettings::default(),
+            rate_limiter: RateLimiter::new(RateLimitPolicy::default()),
+            idempotency_store: Arc::new(InMemoryIdempotencyStore::new()),
+            idempotency_key_ttl: DEFAULT_IDEMPOTENCY_KEY_TTL,
+            read_only_failover_duration: DEFAULT_READ_ONLY_FAILOVER_DURATION,
+            add_to_cart_debounce_window: DEFAULT_DEBOUNCE_WINDOW,
+            max_add_to_cart_quantity: DEFAULT_MAX_ADD_TO_CART_QUANTITY,
+            delivery_slot_provider: Arc::new(ScheduledDeliverySlotProvider::new(
+                DeliverySlotSettings::default(),
+            )),
+            dual_publications: HashMap::new(),
+        }
+    }
+}
+
+impl AppStateBuilder {
+    pub fn new() -> AppStateBuilder {
+        AppStateBuilder::default()
+    }
+
+    pub fn with_repositories(
+        mut self,
+        order_repository: Arc<dyn OrderRepository + Send + Sync>,
+        cart_repository: Arc<dyn CartRepository + Send + Sync>,
+        transaction_client: Option<Client>,
+    ) -> AppStateBuilder {
+        self.order_repository = Some(order_repository);
+        self.cart_repository = Some(cart_repository);
+        self.transaction_client = transaction_client;
+        self
+    }
+
+    // Undoes the writes of a rolled back unit of work one by one, for backends that can't run
+    // transactions
+    pub fn with_write_compensation(mut self) -> AppStateBuilder {
+        self.write_compensation = true;
+        self
+    }
+
+    // Events go through an outbox written in the same transaction as the changes, and a relay
+    // publishes them in batches of batch_size
+    pub fn with_outbox(
+        mut self,
+        outbox_store: Arc<dyn OutboxStore + Send + Sync>,
+        batch_size: i64,
+    ) -> AppStateBuilder {
+        self.outbox = Some((outbox_store, batch_size));
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_in_memory_repositories(self) -> AppStateBuilder {
+        self.with_repositories(
+            Arc::new(InMemoryOrderRepository::new()),
+            Arc::new(InMemoryCartRepository::new()),
+            None,
+        )
+    }
+
+    pub fn with_message_broker(
+        mut self,
+        message_broker: Arc<dyn MessageBroker + Send + Sync>,
+    ) -> AppStateBuilder {
+        self.message_broker = Some(message_broker);
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn with_in_memory_message_broker(self) -> AppStateBuilder {
+        self.with_message_broker(Arc::new(InMemoryMessageBroker::new()))
+    }
+
+    pub fn with_cart_session_signing(
+        mut self,
+        token_signer: TokenSigner,
+        ttl_seconds: i64,
+    ) -> AppStateBuilder {
+        self.cart_session_token_signer = Some(token_signer);
+        self.cart_session_ttl_seconds = ttl_seconds;
+        self
+    }
+
+    pub fn with_cart_share_signing(
+        mut self,
+        token_signer: TokenSigner,
+        ttl_seconds: i64,
+    ) -> AppStateBuilder {
+        self.cart_share_token_signer = Some(token_signer);
+        self.cart_share_ttl_seconds = ttl_seconds;
+        self
+    }
+
+    pub fn with_pickup_code_signing(
+        mut self,
+        token_signer: TokenSigner,
+        ttl_seconds: i64,
+    ) -> AppStateBuilder {
+        self.pickup_code_token_signer = Some(token_signer);
+        self.pickup_code_ttl_seconds = ttl_seconds;
+        self
+    }
+
+    // Tokens are accepted from every issuer added this way
+    pub fn with_token_issuer(mut self, token_issuer: TokenIssuer) -> AppStateBuilder {
+        self.token_issuers.push(token_issuer);
+        self
+    }
+
+    // Calls to the repositories go through this breaker, which is also reported by /readyz
+    pub fn with_repository_circuit_breaker(
+        mut self,
+        circuit_breaker: Arc<CircuitBreaker>,
+    ) -> AppStateBuilder {
+        self.repository_circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    // Published events go through this breaker, which is also reported by /readyz
+    pub fn with_message_broker_circuit_breaker(
+        mut self,
+        circuit_breaker: Arc<CircuitBreaker>,
+    ) -> AppStateBuilder {
+        self.message_broker_circuit_breaker = Some(circuit_breaker);
+        self
+    }
+
+    // Each write-heavy handler gets its own concurrency limit with these settings
+    pub fn with_write_bulkhead(mut self, settings: BulkheadSettings) -> AppStateBuilder {
+        self.write_bulkhead = Some(settings);
+        self
+    }
+
+    // Commands that modify a single cart hold this lock on it while they run
+    pub fn with_cart_locking(mut self, lock: Arc<DistributedLock>) -> AppStateBuilder {
+        self.cart_lock = Some(lock);
+        self
+    }
+
+    pub fn with_cart_conflict_strategy(
+        mut self,
+        conflict_strategy: CartConflictStrategy,
+    ) -> AppStateBuilder {
+        self.cart_conflict_strategy = conflict_strategy;
+        self
+    }
+
+    pub fn with_ops_access(mut self, ops_access: OpsAccess) -> AppStateBuilder {
+        self.ops_access = ops_access;
+        self
+    }
+
+    // Replaces the built-in policy deciding who may call which route
+    pub fn with_authorization_policy(
+        mut self,
+        authorization_policy: AuthorizationPolicy,
+    ) -> AppStateBuilder {
+        self.authorization_policy = authorization_policy;
+        self
+    }
+
+    // Applied by ApplyRetentionRulesCommand, which does nothing without any rules
+    pub fn with_retention_rules(mut self, retention_rules: Vec<RetentionRule>) -> AppStateBuilder {
+        self.retention_rules = retention_rules;
+        self
+    }
+
+    // Personal data in carts and orders is encrypted with this before it reaches the repositories
+    pub fn with_field_encryption(
+        mut self,
+        field_encryptor: Arc<FieldEncryptor>,
+    ) -> AppStateBuilder {
+        self.field_encryptor = Some(field_encryptor);
+        self
+    }
+
+    // Development only. Faults are injected closest to the dependencies, so the circuit breakers,
+    // retries and rollbacks above them handle them like real failures
+    pub fn with_fault_injection(mut self, settings: FaultInjectionSettings) -> AppStateBuilder {
+        self.fault_injection = Some(settings);
+        self
+    }
+
+    // Rates start from these settings and can be changed at runtime through the admin endpoint
+    pub fn with_log_sampling(mut self, settings: LogSamplingSettings) -> AppStateBuilder {
+        self.log_sampling = settings;
+        self
+    }
+
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> AppStateBuilder {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    // Where Idempotency-Key mappings are kept and for how long retries get the first response back
+    pub fn with_idempotency_store(
+        mut self,
+        idempotency_store: Arc<dyn IdempotencyStore + Send + Sync>,
+        ttl: Duration,
+    ) -> AppStateBuilder {
+        self.idempotency_store = idempotency_store;
+        self.idempotency_key_ttl = ttl;
+        self
+    }
+
+    // How long commands are refused after one finds no writable MongoDB primary
+    pub fn with_read_only_failover_duration(mut self, duration: Duration) -> AppStateBuilder {
+        self.read_only_failover_duration = duration;
+        self
+    }
+
+    // Identical add-to-cart requests within this window are handled once. Zero turns it off
+    pub fn with_add_to_cart_debounce_window(mut self, window: Duration) -> AppStateBuilder {
+        self.add_to_cart_debounce_window = window;
+        self
+    }
+
+    // The most units of a product an add-to-cart request may ask for, and a cart item may hold
+    pub fn with_max_add_to_cart_quantity(mut self, max_quantity: i32) -> AppStateBuilder {
+        self.max_add_to_cart_quantity = max_quantity;
+        self
+    }
+
+    pub fn with_delivery_slot_provider(
+        mut self,
+        delivery_slot_provider: Arc<dyn DeliverySlotProvider + Send + Sync>,
+    ) -> AppStateBuilder {
+        self.delivery_slot_provider = delivery_slot_provider;
+        self
+    }
+
+    // Keyed by event type. Each event is also published as configured by its type's entries until
+    // their transition windows end
+    pub fn with_dual_publishing(
+        mut self,
+        dual_publications: HashMap<String, Vec<DualPublication>>,
+    ) -> AppStateBuilder {
+        self.dual_publications = dual_publications;
+        self
+    }
+
+    pub fn build(self) -> Result<AppState, String> {
+        let (order_repository, cart_repository) =
+            match (self.order_repository, self.cart_repository) {
+                (Some(order_repository), Some(cart_repository)) => {
+                    (order_repository, cart_repository)
+                }
+                _ => return Err(String::from("Repositories have not been configured")),
+            };
+        let message_broker = match self.message_broker {
+            Some(message_broker) => message_broker,
+            None => return Err(String::from("A message broker has not been configured")),
+        };
+        // Wrapped first so copies go through the same fault injection and circuit breaker
+        let message_broker: Arc<dyn MessageBroker + Send + Sync> =
+            match self.dual_publications.is_empty() {
+                true => message_broker,
+                false => Arc::new(DualPublishingMessageBroker::new(
+                    message_broker,
+                    self.dual_publications,
+                )?),
+            };
+        let cart_session_token_signer = match self.cart_session_token_signer {
+            Some(token_signer) => token_signer,
+            None => return Err(String::from("Cart session signing has not been configured")),
+        };
+        let cart_share_token_signer = match self.cart_share_token_signer {
+            Some(token_signer) => token_signer,
+            None => return Err(String::from("Cart share signing has not been configured")),
+        };
+        let pickup_code_token_signer = match self.pickup_code_token_signer {
+            Some(token_signer) => token_signer,
+            None => return Err(String::from("Pickup code signing has not been configured")),
+        };
+
+        let (order_repository, cart_repository, message_broker): (
+            Arc<dyn OrderRepository + Send + Sync>,
+            Arc<dyn CartRepository + Send + Sync>,
+            Arc<dyn MessageBroker + Send + Sync>,
+        ) = match self.fault_injection {
+            Some(settings) => {
+                let repository_faults =
+                    Arc::new(FaultInjector::new("repositories", settings.repositories));
+                let message_broker_faults = Arc::new(FaultInjector::new(
+                    "message_broker",
+                    settings.message_broker,
+                ));
+
+                (
+                    Arc::new(FaultInjectingOrderRepository::new(
+                        order_repository,
+                        repository_faults.clone(),
+                    )),
+                    Arc::new(FaultInjectingCartRepository::new(
+                        cart_repository,
+                        repository_faults,
+                    )),
+                    Arc::new(FaultInjectingMessageBroker::new(
+                        message_broker,
+                        message_broker_faults,
+                    )),
+                )
+            }
+            None => (order_repository, cart_repository, message_broker),
+        };
+
+        let (order_repository, cart_repository): (
+            Arc<dyn OrderRepository + Send + Sync>,
+            Arc<dyn CartRepository + Send + Sync>,
+        ) = match self.repository_circuit_breaker.clone() {
+            Some(circuit_breaker) => (
+                Arc::new(CircuitBreakingOrderRepository::new(
+                    order_repository,
+                    circuit_breaker.clone(),
+                )),
+                Arc::new(CircuitBreakingCartRepository::new(
+                    cart_repository,
+                    circuit_breaker,
+                )),
+            ),
+            None => (order_repository, cart_repository),
+        };
+
+        let (order_repository, cart_repository): (
+            Arc<dyn OrderRepository + Send + Sync>,
+            Arc<dyn CartRepository + Send + Sync>,
+        ) = match self.field_encryptor {
+            Some(field_encryptor) => (
+                Arc::new(EncryptingOrderRepository::new(
+                    order_repository,
+                    field_encryptor.clone(),
+                )),
+                Arc::new(EncryptingCartRepository::new(
+                    cart_repository,
+                    field_encryptor,
+                )),
+            ),
+            None => (order_repository, cart_repository),
+        };
+
+        let message_broker: Arc<dyn MessageBroker + Send + Sync> =
+            match self.message_broker_circuit_breaker.clone() {
+                Some(circuit_breaker) => Arc::new(CircuitBreakingMessageBroker::new(
+                    message_broker,
+                    circuit_breaker,
+                )),
+                None => message_broker,
+            };
+
+        let dependencies = vec![
+            Dependency::persistence(cart_repository.clone(), self.repository_circuit_breaker),
+            Dependency::message_broker(message_broker.clone(), self.message_broker_circuit_breaker),
+        ];
+
+        let outbox_relay = self.outbox.as_ref().map(|(outbox_store, batch_size)| {
+            Arc::new(OutboxRelay::new(
+                outbox_store.clone(),
+                message_broker.clone(),
+                *batch_size,
+            ))
+        });
+
+        let mut uow = OrderUnitOfWork::new(
+            order_repository,
+            cart_repository,
+            message_broker,
+            self.transaction_client,
+        );
+        if self.write_compensation {
+            uow = uow.with_compensation();
+        }
+        if let Some((outbox_store, _)) = self.outbox {
+            uow = uow.with_outbox(outbox_store);
+        }
+        let uow = Arc::new(uow);
+
+        let mut mediator =
+            Mediator::with_maintenance_mode(MaintenanceMode::new(self.read_only_failover_duration));
+        mediator.register_command_handler(CreateCartCommandHandler::new(
+            uow.clone(),
+            cart_session_token_signer.clone(),
+            self.cart_session_ttl_seconds * 1000,
+        ));
+        mediator.register_query_handler(RetryHandler::new(
+            GetCartsQueryHandler::new(uow.clone()),
+            QUERY_RETRY_ATTEMPTS,
+            QUERY_RETRY_DELAY,
+        ));
+        mediator.register_query_handler(RetryHandler::new(
+            GetUserCartsQueryHandler::new(uow.clone()),
+            QUERY_RETRY_ATTEMPTS,
+            QUERY_RETRY_DELAY,
+        ));
+        mediator.register_query_handler(RetryHandler::new(
+            GetCartsContainingProductQueryHandler::new(uow.clone()),
+            QUERY_RETRY_ATTEMPTS,
+            QUERY_RETRY_DELAY,
+        ));
+        mediator.register_command_handler(DebouncingHandler::new(
+            BulkheadHandler::new(
+                LockingHandler::new(
+                    AddProductToCartCommandHandler::new(
+                        uow.clone(),
+                        self.cart_conflict_strategy,
+                        self.max_add_to_cart_quantity,
+                    ),
+                    self.cart_lock.clone(),
+                    |command: &AddProductToCartCommand| match command.cart_id.is_empty() {
+                        true => format!("default-cart:{}", command.user_id),
+                        false => cart_lock_name(&command.cart_id),
+                    },
+                ),
+                self.write_bulkhead.as_ref(),
+            ),
+            self.add_to_cart_debounce_window,
+            |command: &AddProductToCartCommand| {
+                command.client_request_id.as_ref().map(|request_id| {
+                    format!(
+                        "{}:{}:{}:{}",
+                        command.user_id, command.cart_id, command.product_id, request_id
+                    )
+                })
+            },
+        ));
+        mediator.register_command_handler(BulkheadHandler::new(
+            LockingHandler::new(
+                RemoveProductFromCartCommandHandler::new(uow.clone(), self.cart_conflict_strategy),
+                self.cart_lock.clone(),
+                |command: &RemoveProductFromCartCommand| cart_lock_name(&command.cart_id),
+            ),
+            self.write_bulkhead.as_ref(),
+        ));
+        mediator.register_command_handler(BulkheadHandler::new(
+            LockingHandler::new(
+                DeleteCartCommandHandler::new(uow.clone()),
+                self.cart_lock.clone(),
+                |command: &DeleteCartCommand| cart_lock_name(&command.id),
+            ),
+            self.write_bulkhead.as_ref(),
+        ));
+        mediator.register_command_handler(BulkheadHandler::new(
+            LockingHandler::new(
+                SetProductQuantityCommandHandler::new(
+                    uow.clone(),
+                    self.cart_conflict_strategy,
+                    self.max_add_to_cart_quantity,
+                ),
+                self.cart_lock.clone(),
+                |command: &SetProductQuantityCommand| cart_lock_name(&command.cart_id),
+            ),
+            self.write_bulkhead.as_ref(),
+        ));
+        mediator.register_command_handler(AuthorizingHandler::new(
+            SetDefaultCartCommandHandler::new(uow.clone()),
+            |command: &SetDefaultCartCommand| require_user(&command.user_id),
+        ));
+        mediator.register_command_handler(AuthorizingHandler::new(
+            ShareCartCommandHandler::new(
+                uow.clone(),
+                cart_share_token_signer.clone(),
+                self.cart_share_ttl_seconds * 1000,
+            ),
+            |command: &ShareCartCommand| require_user(&command.user_id),
+        ));
+        mediator.register_query_handler(RetryHandler::new(
+            GetSharedCartQueryHandler::new(uow.clone(), cart_share_token_signer.clone()),
+            QUERY_RETRY_ATTEMPTS,
+            QUERY_RETRY_DELAY,
+        ));
+        mediator.register_query_handler(RetryHandler::new(
+            GetDeliverySlotsQueryHandler::new(uow.clone(), self.delivery_slot_provider),
+            QUERY_RETRY_ATTEMPTS,
+            QUERY_RETRY_DELAY,
+        ));
+        mediator.register_query_handler(RetryHandler::new(
+            GetOrderByIdQueryHandler::new(uow.clone()),
+            QUERY_RETRY_ATTEMPTS,
+            QUERY_RETRY_DELAY,
+        ));
+        mediator.register_query_handler(RetryHandler::new(
+            GetOrdersQueryHandler::new(uow.clone()),
+            QUERY_RETRY_ATTEMPTS,
+            QUERY_RETRY_DELAY,
+        ));
+        mediator.register_query_handler(RetryHandler::new(
+            GetOrderTimelineQueryHandler::new(uow.clone()),
+            QUERY_RETRY_ATTEMPTS,
+            QUERY_RETRY_DELAY,
+        ));
+        mediator.register_query_handler(RetryHandler::new(
+            GetPickupCodeQueryHandler::new(
+                uow.clone(),
+                pickup_code_token_signer.clone(),
+                self.pickup_code_ttl_seconds * 1000,
+            ),
+            QUERY_RETRY_ATTEMPTS,
+            QUERY_RETRY_DELAY,
+        ));
+        mediator.register_query_handler(RetryHandler::new(
+            VerifyPickupCodeQueryHandler::new(uow.clone(), pickup_code_token_signer),
+            QUERY_RETRY_ATTEMPTS,
+            QUERY_RETRY_DELAY,
+        ));
+        mediator.register_query_handler(RetryHandler::new(
+            ExportCustomerDataQueryHandler::new(uow.clone()),
+            QUERY_RETRY_ATTEMPTS,
+            QUERY_RETRY_DELAY,
+        ));
+        mediator.register_query_handler(RetryHandler::new(
+            GetStatsQueryHandler::new(uow.clone()),
+            QUERY_RETRY_ATTEMPTS,
+            QUERY_RETRY_DELAY,
+        ));
+        mediator.register_command_handler(EraseCustomerDataCommandHandler::new(uow.clone()));
+        mediator.register_command_handler(CancelOrderCommandHandler::new(uow.clone()));
+        mediator.register_command_handler(MarkOrderPaidCommandHandler::new(uow.clone()));
+        mediator.register_command_handler(DiscardProductFromCartsCommandHandler::new(uow.clone()));
+        mediator.register_command_handler(ImportCartsCommandHandler::new(uow.clone()));
+        mediator.register_command_handler(ApplyRetentionRulesCommandHandler::new(
+            uow.clone(),
+            self.retention_rules,
+        ));
+        mediator.register_command_handler(AuthorizingHandler::new(
+            CloneSharedCartCommandHandler::new(uow.clone(), cart_share_token_signer),
+            |command: &CloneSharedCartCommand| require_user(&command.user_id),
+        ));
+        mediator.register_command_handler(AuthorizingHandler::new(
+            LockingHandler::new(
+                ClaimGuestCartCommandHandler::new(uow.clone(), cart_session_token_signer.clone()),
+                self.cart_lock.clone(),
+                |command: &ClaimGuestCartCommand| cart_lock_name(&command.cart_id),
+            ),
+            |command: &ClaimGuestCartCommand| require_user(&command.user_id),
+        ));
+
+        Ok(AppState {
+            mediator: Arc::new(mediator),
+            cart_session_token_signer,
+            dependencies,
+            token_issuers: self.token_issuers,
+            ops_access: self.ops_access,
+            authorization_policy: self.authorization_policy,
+            log_sampler: Arc::new(LogSampler::new(self.log_sampling)),
+            rate_limiter: Arc::new(self.rate_limiter),
+            idempotency_keys: Arc::new(IdempotencyKeys::new(
+                self.idempotency_store,
+                self.idempotency_key_ttl,
+            )),
+            outbox_relay,
+        })
+    }
+}
+
+// Selects where carts and orders are persisted: "mongodb" (default), "sqlite" for local dev or
+// "memory" for throwaway instances
+pub async fn repositories_from_env() -> Result<
+    (
+        Arc<dyn OrderRepository + Send + Sync>,
+        Arc<dyn CartRepository + Send + Sync>,
+        Option<Client>,
+    ),
+    String,
+> {
+    match env::var("PERSISTENCE_BACKEND")
+        .unwrap_or(String::from("mongodb"))
+        .as_str()
+    {
+        "memory" => Ok((
+            Arc::new(InMemoryOrderRepository::new()),
+            Arc::new(InMemoryCartRepository::new()),
+            None,
+        )),
+        "sqlite" => {
+            let pool = match SqlitePool::connect(&env::var("SQLITE_URL").unwrap()).await {
+                Ok(pool) => pool,
+                Err(e) => return Err(format!("Failed to connect to SQLite: {}", e)),
+            };
+
+            Ok((
+                Arc::new(SqliteOrderRepository::new(&pool).await?),
+                Arc::new(SqliteCartRepository::new(&pool).await?),
+                None,
+            ))
+        }
+        _ => {
+            let order_db_info = MongoDbInitializationInfo {
+                uri: env::var("MONGODB_URI").unwrap(),
+                database: env::var("MONGODB_DB").unwrap(),
+                collection: env::var("MONGODB_ORDER_COLLECTION").unwrap(),
+            };
+
+            let cart_db_info = MongoDbInitializationInfo {
+                uri: env::var("MONGODB_URI").unwrap(),
+                database: env::var("MONGODB_DB").unwrap(),
+                collection: env::var("MONGODB_CARTS_COLLECTION").unwrap(),
+            };
+
+            let client = mongodb_client(&order_db_info).await?;
+            let transaction_client = match mongodb_supports_transactions(&client).await? {
+                true => Some(client.clone()),
+                false => None,
+            };
+
+            Ok((
+                Arc::new(MongoDbOrderRepository::new(&order_db_info, &client).await),
+                Arc::new(MongoDbCartRepository::new(&cart_db_info, &client).await),
+                transaction_client,
+            ))
+        }
+    }
+}
+
+// MONGODB_TRANSACTIONS is "auto" (default), "enabled" or "disabled". Replica sets and sharded
+// clusters support transactions, while a standalone server, as started by a plain `docker run
+// mongo`, doesn't
+async fn mongodb_supports_transactions(client: &Client) -> Result<bool, String> {
+    match env::var("MONGODB_TRANSACTIONS")
+        .unwrap_or(String::from("auto"))
+        .as_str()
+    {
+        "enabled" => Ok(true),
+        "disabled" => Ok(false),
+        "auto" => match client
+            .database("admin")
+            .run_command(doc! {"hello": 1})
+            .await
+        {
+            Ok(hello) => Ok(hello.contains_key("setName")
+                || hello.get_str("msg").is_ok_and(|msg| msg == "isdbgrid")),
+            Err(e) => Err(format!("Failed to detect the MongoDB deployment: {}", e)),
+        },
+        transactions => Err(format!(
+            "Unknown MONGODB_TRANSACTIONS {}, expected auto, enabled or disabled",
+            transactions
+        )),
+    }
+}
+
+// Connects with client-side field-level encryption when MONGODB_CSFLE_ENABLED is true, which needs
+// a build with the csfle feature
+async fn mongodb_client(order_db_info: &MongoDbInitializationInfo) -> Result<Client, String> {
+    #[cfg(feature = "csfle")]
+    if let Some(settings) = crate::csfle::CsfleSettings::from_env()? {
+        return crate::csfle::encrypted_client(order_db_info, &settings).await;
+    }
+    #[cfg(not(feature = "csfle"))]
+    if env::var("MONGODB_CSFLE_ENABLED").is_ok_and(|enabled| enabled == "true") {
+        return Err(String::from(
+            "MONGODB_CSFLE_ENABLED requires a build with the csfle feature",
+        ));
+    }
+
+    match Client::with_uri_str(&order_db_info.uri).await {
+        Ok(client) => Ok(client),
+        Err(e) => Err(format!("Failed to connect to MongoDB: {}", e)),
+    }
+}
+
+// Selects where events are published: "rabbitmq" (default), "nats" or "sns" when built with the
+// matching feature, "logging" to only log them or "memory" to keep them in process
+pub async fn message_broker_from_env() -> Result<Arc<dyn MessageBroker + Send + Sync>, String> {
+    match env::var("MESSAGE_BROKER")
+        .unwrap_or(String::from("rabbitmq"))
+        .as_str()
+    {
+        "logging" => Ok(Arc::new(LoggingMessageBroker {})),
+        "memory" => Ok(Arc::new(InMemoryMessageBroker::new())),
+        #[cfg(feature = "nats")]
+        "nats" => Ok(Arc::new(
+            crate::events::NatsJetStreamMessageBroker::new(&env::var("NATS_URL").unwrap()).await?,
+        )),
+        #[cfg(feature = "sns")]
+        "sns" => Ok(Arc::new(crate::events::SnsMessageBroker::new(
+            crate::events::SnsInitializationInfo {
+                credentials: crate::aws::AwsCredentials::from_env(),
+                topic_arn_prefix: env::var("SNS_TOPIC_ARN_PREFIX").unwrap(),
+            },
+            crate::http_client::HttpClientSettings::from_env(),
+        )?)),
+        _ => Ok(Arc::new(
+            RabbitMqMessageBroker::new(rabbitmq_info_from_env()?).await?,
+        )),
+    }
+}
+
+fn rabbitmq_info_from_env() -> Result<RabbitMqInitializationInfo, String> {
+    let queue_settings = match serde_json::from_str(
+        &env::var("RABBITMQ_QUEUE_SETTINGS").unwrap_or(String::from("{}")),
+    ) {
+        Ok(queue_settings) => queue_settings,
+        Err(e) => return Err(format!("Invalid RABBITMQ_QUEUE_SETTINGS: {}", e)),
+    };
+
+    Ok(RabbitMqInitializationInfo::new(
+        env::var("RABBITMQ_URI").unwrap(),
+        env::var("RABBITMQ_PORT").unwrap().parse().unwrap(),
+        env::var("RABBITMQ_USER").unwrap(),
+        env::var("RABBITMQ_PASS").unwrap(),
+        queue_settings,
+    ))
+}
+
+// Events from other services are only consumed from RabbitMQ for now. Set EVENT_CONSUMERS_ENABLED
+// to false to run without consuming, e.g. for a replica that only serves reads
+pub fn event_consumer_from_env(
+    mediator: Arc<Mediator>,
+) -> Result<Option<RabbitMqEventConsumer>, String> {
+    if env::var("EVENT_CONSUMERS_ENABLED").is_ok_and(|enabled| enabled == "false")
+        || env::var("MESSAGE_BROKER").is_ok_and(|message_broker| message_broker != "rabbitmq")
+    {
+        return Ok(None);
+    }
+
+    Ok(Some(RabbitMqEventConsumer::new(
+        rabbitmq_info_from_env()?,
+        mediator,
+        env::var("EVENT_CONSUMER_PREFETCH_COUNT")
+            .map(|prefetch_count| prefetch_count.parse().unwrap())
+            .unwrap_or(DEFAULT_CONSUMER_PREFETCH_COUNT),
+        env::var("EVENT_CONSUMER_RECONNECT_SECONDS")
+            .map(|seconds| Duration::from_secs(seconds.parse().unwrap()))
+            .unwrap_or(DEFAULT_CONSUMER_RECONNECT_DELAY),
+    )))
+}
+
+// DUAL_PUBLISH_SETTINGS maps event types to the extra publications made while consumers migrate,
+// e.g. {"ProductAddedToCartEvent": [{"destination": "cart.product.added", "format": "envelope",
+// "until_utc": "2026-12-01T00:00:00Z"}]}
+pub fn dual_publications_from_env() -> Result<HashMap<String, Vec<DualPublication>>, String> {
+    match env::var("DUAL_PUBLISH_SETTINGS") {
+        Ok(dual_publications) => serde_json::from_str(&dual_publications)
+            .map_err(|e| format!("Invalid DUAL_PUBLISH_SETTINGS: {}", e)),
+        Err(_) => Ok(HashMap::new()),
+    }
+}
+
+// METRICS_TOKEN and METRICS_ALLOWED_NETWORKS (comma separated CIDR blocks) protect /metrics and /info
+pub fn ops_access_from_env() -> Result<OpsAccess, String> {
+    let allowed_networks = match env::var("METRICS_ALLOWED_NETWORKS") {
+        Ok(networks) => networks
+            .split(',')
+            .filter(|network| !network.trim().is_empty())
+            .map(IpNetwork::parse)
+            .collect::<Result<Vec<IpNetwork>, String>>()?,
+        Err(_) => Vec::new(),
+    };
+
+    Ok(OpsAccess {
+        token: env::var("METRICS_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty()),
+        allowed_networks,
+    })
+}
+
+// AUTH0_DOMAIN and AUTH0_AUDIENCE configure the customer-facing Auth0 tenant. Further issuers, such
+// as the B2B partner identity provider, are listed in TOKEN_ISSUERS as a JSON array of
+// {"issuer", "audiences", "jwks_url"} objects
+pub fn token_issuers_from_env() -> Result<Vec<TokenIssuer>, String> {
+    let mut token_issuers: Vec<TokenIssuer> = match env::var("TOKEN_ISSUERS") {
+        Ok(token_issuers) => match serde_json::from_str(&token_issuers) {
+            Ok(token_issuers) => token_issuers,
+            Err(e) => return Err(format!("Invalid TOKEN_ISSUERS: {}", e)),
+        },
+        Err(_) => Vec::new(),
+    };
+
+    if let (Ok(domain), Ok(audience)) = (env::var("AUTH0_DOMAIN"), env::var("AUTH0_AUDIENCE")) {
+        token_issuers.insert(0, TokenIssuer::auth0(&domain, &audience));
+    }
+
+    match token_issuers.is_empty() {
+        true => Err(String::from(
+            "No token issuers configured, set AUTH0_DOMAIN and AUTH0_AUDIENCE or TOKEN_ISSUERS",
+        )),
+        false => Ok(token_issuers),
+    }
+}
+
+// FIELD_ENCRYPTION_KEY_PROVIDER selects what protects the data keys: "local" with the base64
+// encoded 32 byte FIELD_ENCRYPTION_MASTER_KEY (e.g. from openssl rand -base64 32), or "kms" with the AWS KMS key FIELD_ENCRYPTION_KMS_KEY_ID
+// when built with the kms feature. Personal data is stored in plaintext when it isn't set
+pub fn field_encryptor_from_env() -> Result<Option<Arc<FieldEncryptor>>, String> {
+    let key_provider: Arc<dyn KeyProvider + Send + Sync> =
+        match env::var("FIELD_ENCRYPTION_KEY_PROVIDER") {
+            Ok(key_provider) => match key_provider.as_str() {
+                "local" => {
+                    let master_key =
+                        match STANDARD.decode(env::var("FIELD_ENCRYPTION_MASTER_KEY").unwrap()) {
+                            Ok(master_key) => master_key,
+                            Err(e) => {
+                                return Err(format!("Invalid FIELD_ENCRYPTION_MASTER_KEY: {}", e))
+                            }
+                        };
+                    Arc::new(LocalKeyProvider::new(&master_key)?)
+                }
+                #[cfg(feature = "kms")]
+                "kms" => Arc::new(crate::encryption::KmsKeyProvider::new(
+                    &env::var("FIELD_ENCRYPTION_KMS_KEY_ID").unwrap(),
+                    crate::aws::AwsCredentials::from_env(),
+                    crate::http_client::HttpClientSettings::from_env(),
+                )?),
+                other => return Err(format!("Unknown FIELD_ENCRYPTION_KEY_PROVIDER {}", other)),
+            },
+            Err(_) => return Ok(None),
+        };
+
+    Ok(Some(Arc::new(FieldEncryptor::new(key_provider))))
+}
+
+// Rules for orders are refused with MongoDB, which can't update or delete orders yet
+pub fn retention_rules_from_env() -> Result<Vec<RetentionRule>, String> {
+    let rules = RetentionSettings::from_env()?.rules;
+    let backend = env::var("PERSISTENCE_BACKEND").unwrap_or(String::from("mongodb"));
+
+    match rules
+        .iter()
+        .any(|rule| rule.entity == RetentionEntity::Orders)
+        && !matches!(backend.as_str(), "memory" | "sqlite")
+    {
+        true => Err(String::from(
+            "Retention rules for orders are not supported with MongoDB persistence yet",
+        )),
+        false => Ok(rules),
+    }
+}
+
+fn cart_lock_name(cart_id: &CartId) -> String {
+    format!("cart:{}", cart_id)
+}
+
+// Leases are shared through MongoDB; the other backends only run as a single process, so keeping
+// them in memory is enough
+pub async fn lease_store_from_env() -> Result<Arc<dyn LeaseStore + Send + Sync>, String> {
+    match env::var("PERSISTENCE_BACKEND")
+        .unwrap_or(String::from("mongodb"))
+        .as_str()
+    {
+        "memory" | "sqlite" => Ok(Arc::new(InMemoryLeaseStore::new())),
+        _ => {
+            let client = match Client::with_uri_str(&env::var("MONGODB_URI").unwrap()).await {
+                Ok(client) => client,
+                Err(e) => return Err(format!("Failed to connect to MongoDB: {}", e)),
+            };
+
+            Ok(Arc::new(MongoDbLeaseStore::new(
+                &client,
+                &env::var("MONGODB_DB").unwrap(),
+                &env::var("MONGODB_LEASES_COLLECTION").unwrap_or(String::from("leases")),
+            )))
+        }
+    }
+}
+
+// Keys have to be shared by every replica, so only single process backends keep them in memory
+pub async fn idempotency_store_from_env() -> Result<Arc<dyn IdempotencyStore + Send + Sync>, String>
+{
+    match env::var("PERSISTENCE_BACKEND")
+        .unwrap_or(String::from("mongodb"))
+        .as_str()
+    {
+        "memory" | "sqlite" => Ok(Arc::new(InMemoryIdempotencyStore::new())),
+        _ => {
+            let client = match Client::with_uri_str(&env::var("MONGODB_URI").unwrap()).await {
+                Ok(client) => client,
+                Err(e) => return Err(format!("Failed to connect to MongoDB: {}", e)),
+            };
+
+            Ok(Arc::new(MongoDbIdempotencyStore::new(
+                &client,
+                &env::var("MONGODB_DB").unwrap(),
+                &env::var("MONGODB_IDEMPOTENCY_COLLECTION")
+                    .unwrap_or(String::from("idempotency_keys")),
+            )))
+        }
+    }
+}
+
+pub async fn leader_elector_from_env() -> Result<LeaderElector, String> {
+    Ok(LeaderElector::new(
+        lease_store_from_env().await?,
+        SCHEDULER_LEASE_NAME,
+        env::var("LEADER_LEASE_SECONDS")
+            .map(|x| Duration::from_secs(x.parse().unwrap()))
+            .unwrap_or(DEFAULT_LEADER_LEASE_DURATION),
+    ))
+}
+
+// Builds the AppState the service runs with from environment variables
+pub async fn app_state_from_env() -> Result<AppState, String> {
+    let (order_repository, cart_repository, transaction_client) = repositories_from_env().await?;
+    let circuit_breaker_settings = CircuitBreakerSettings::from_env();
+
+    let mut builder = AppStateBuilder::new();
+    // Only worth the extra round trips when several replicas write to the same carts
+    if env::var("CART_LOCKING_ENABLED").is_ok_and(|enabled| enabled == "true") {
+        builder = builder.with_cart_locking(Arc::new(DistributedLock::new(
+            lease_store_from_env().await?,
+            DistributedLockSettings::from_env(),
+        )));
+    }
+    // SQLite, in-memory stores and a MongoDB deployment without transactions, e.g. a standalone
+    // server for local development
+    if transaction_client.is_none() {
+        builder = builder.with_write_compensation();
+    }
+    // The outbox is written from the transactions' sessions, so it needs a client that runs them
+    if let Some(client) = &transaction_client {
+        builder = builder.with_outbox(
+            Arc::new(MongoDbOutboxStore::new(
+                client,
+                &env::var("MONGODB_DB").unwrap(),
+                &env::var("MONGODB_OUTBOX_COLLECTION").unwrap_or(String::from("outbox")),
+            )),
+            env::var("OUTBOX_RELAY_BATCH_SIZE")
+                .map(|batch_size| batch_size.parse().unwrap())
+                .unwrap_or(DEFAULT_OUTBOX_RELAY_BATCH_SIZE),
+        );
+    }
+    for token_issuer in token_issuers_from_env()? {
+        builder = builder.with_token_issuer(token_issuer);
+    }
+    if let Some(field_encryptor) = field_encryptor_from_env()? {
+        builder = builder.with_field_encryption(field_encryptor);
+    }
+    if let Some(fault_injection) = FaultInjectionSettings::from_env()? {
+        builder = builder.with_fault_injection(fault_injection);
+    }
+    if let Ok(path) = env::var("RATE_LIMIT_POLICY_PATH") {
+        builder = builder.with_rate_limiter(RateLimiter::from_file(&path)?);
+    }
+
+    builder
+        .with_repositories(order_repository, cart_repository, transaction_client)
+        .with_idempotency_store(
+            idempotency_store_from_env().await?,
+            env::var("IDEMPOTENCY_KEY_TTL_SECONDS")
+                .map(|seconds| Duration::from_secs(seconds.parse().unwrap()))
+                .unwrap_or(DEFAULT_IDEMPOTENCY_KEY_TTL),
+        )
+        .with_log_sampling(LogSamplingSettings::from_env()?)
+        .with_read_only_failover_duration(
+            env::var("READ_ONLY_FAILOVER_SECONDS")
+                .map(|seconds| Duration::from_secs(seconds.parse().unwrap()))
+                .unwrap_or(DEFAULT_READ_ONLY_FAILOVER_DURATION),
+        )
+        .with_add_to_cart_debounce_window(
+            env::var("ADD_TO_CART_DEBOUNCE_MILLIS")
+                .map(|millis| Duration::from_millis(millis.parse().unwrap()))
+                .unwrap_or(DEFAULT_DEBOUNCE_WINDOW),
+        )
+        .with_max_add_to_cart_quantity(
+            env::var("MAX_ADD_TO_CART_QUANTITY")
+                .map(|max_quantity| max_quantity.parse().unwrap())
+                .unwrap_or(DEFAULT_MAX_ADD_TO_CART_QUANTITY),
+        )
+        .with_repository_circuit_breaker(Arc::new(CircuitBreaker::new(
+            &env::var("PERSISTENCE_BACKEND").unwrap_or(String::from("mongodb")),
+            circuit_breaker_settings.clone(),
+        )))
+        .with_message_broker(message_broker_from_env().await?)
+        .with_dual_publishing(dual_publications_from_env()?)
+        .with_delivery_slot_provider(Arc::new(ScheduledDeliverySlotProvider::new(
+            DeliverySlotSettings::from_env()?,
+        )))
+        .with_message_broker_circuit_breaker(Arc::new(CircuitBreaker::new(
+            &env::var("MESSAGE_BROKER").unwrap_or(String::from("rabbitmq")),
+            circuit_breaker_settings,
+        )))
+        .with_cart_session_signing(
+            TokenSigner::new(env::var("CART_SESSION_SECRET").unwrap()),
+            env::var("CART_SESSION_TTL_SECONDS")
+                .map(|ttl| ttl.parse().unwrap())
+                .unwrap_or(DEFAULT_CART_SESSION_TTL_SECONDS),
+        )
+        .with_cart_share_signing(
+            TokenSigner::new(env::var("CART_SHARE_SECRET").unwrap()),
+            env::var("CART_SHARE_TTL_SECONDS")
+                .map(|ttl| ttl.parse().unwrap())
+                .unwrap_or(DEFAULT_CART_SHARE_TTL_SECONDS),
+        )
+        .with_pickup_code_signing(
+            TokenSigner::new(env::var("PICKUP_CODE_SECRET").unwrap()),
+            env::var("PICKUP_CODE_TTL_SECONDS")
+                .map(|ttl| ttl.parse().unwrap())
+                .unwrap_or(DEFAULT_PICKUP_CODE_TTL_SECONDS),
+        )
+        .with_write_bulkhead(BulkheadSettings::from_env())
+        .with_cart_conflict_strategy(
+            match env::var("CART_CONFLICT_STRATEGY")
+                .unwrap_or(String::from("merge"))
+                .as_str()
+            {
+                "fail" => CartConflictStrategy::Fail,
+                "merge" => CartConflictStrategy::Merge,
+                other => return Err(format!("Unknown CART_CONFLICT_STRATEGY {}", other)),
+            },
+        )
+        .with_ops_access(ops_access_from_env()?)
+        .with_authorization_policy(match env::var("AUTHORIZATION_POLICY_PATH") {
+            Ok(path) => AuthorizationPolicy::from_file(&path)?,
+            Err(_) => AuthorizationPolicy::default(),
+        })
+        .with_retention_rules(retention_rules_from_env()?)
+        .build()
+}
@@ -0,0 +1,457 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    env,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::{event, Level};
+
+use crate::{
+    domain::{Cart, CartId, Order, OrderId, OrderStatus, ProductId},
+    events::{Event, MessageBroker, Publication},
+    repositories::{is_primary_unavailable, CartRepository, CartStats, OrderRepository},
+    throttling::ThrottleReason,
+    uow::TransactionSession,
+};
+
+pub static CIRCUIT_BREAKER_STATE: &str = "order_service_circuit_breaker_state";
+pub static CIRCUIT_BREAKER_REJECTIONS_TOTAL: &str =
+    "order_service_circuit_breaker_rejections_total";
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerSettings {
+    // Number of most recent calls the failure rate is calculated over
+    pub window_size: usize,
+    // The breaker only trips once the window holds at least this many calls
+    pub minimum_calls: usize,
+    pub failure_rate_threshold: f64,
+    pub open_duration: Duration,
+}
+
+impl Default for CircuitBreakerSettings {
+    fn default() -> Self {
+        CircuitBreakerSettings {
+            window_size: 20,
+            minimum_calls: 10,
+            failure_rate_threshold: 0.5,
+            open_duration: Duration::from_secs(30),
+        }
+    }
+}
+
+impl CircuitBreakerSettings {
+    pub fn from_env() -> CircuitBreakerSettings {
+        let defaults = CircuitBreakerSettings::default();
+
+        CircuitBreakerSettings {
+            window_size: env::var("CIRCUIT_BREAKER_WINDOW_SIZE")
+                .map(|x| x.parse().unwrap())
+                .unwrap_or(defaults.window_size),
+            minimum_calls: env::var("CIRCUIT_BREAKER_MINIMUM_CALLS")
+                .map(|x| x.parse().unwrap())
+                .unwrap_or(defaults.minimum_calls),
+            failure_rate_threshold: env::var("CIRCUIT_BREAKER_FAILURE_RATE")
+                .map(|x| x.parse().unwrap())
+                .unwrap_or(defaults.failure_rate_threshold),
+            open_duration: env::var("CIRCUIT_BREAKER_OPEN_SECONDS")
+                .map(|x| Duration::from_secs(x.parse().unwrap()))
+                .unwrap_or(defaults.open_duration),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    HalfOpen,
+    Open,
+}
+
+impl CircuitState {
+    fn gauge_value(&self) -> f64 {
+        match self {
+            CircuitState::Closed => 0.0,
+            CircuitState::HalfOpen => 1.0,
+            CircuitState::Open => 2.0,
+        }
+    }
+}
+
+struct CircuitBreakerInner {
+    state: CircuitState,
+    opened_at: Instant,
+    outcomes: VecDeque<bool>,
+    probe_in_flight: bool,
+}
+
+// Fails calls to a dependency fast once its recent failure rate crosses the threshold. After
+// open_duration a single probe call is let through: success closes the breaker, failure reopens it
+pub struct CircuitBreaker {
+    name: String,
+    settings: CircuitBreakerSettings,
+    inner: Mutex<CircuitBreakerInner>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str, settings: CircuitBreakerSettings) -> CircuitBreaker {
+        metrics::gauge!(CIRCUIT_BREAKER_STATE, "dependency" => String::from(name))
+            .set(CircuitState::Closed.gauge_value());
+
+        CircuitBreaker {
+            name: String::from(name),
+            settings,
+            inner: Mutex::new(CircuitBreakerInner {
+                state: CircuitState::Closed,
+                opened_at: Instant::now(),
+                outcomes: VecDeque::new(),
+                probe_in_flight: false,
+            }),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.inner.lock().unwrap().state
+    }
+
+    pub async fn call<T, F>(&self, operation: F) -> Result<T, String>
+    where
+        F: Future<Output = Result<T, String>>,
+    {
+        self.call_classified(operation, |_| true).await
+    }
+
+    // Like call, but only errors for which is_failure returns true count against the dependency
+    pub async fn call_classified<T, F>(
+        &self,
+        operation: F,
+        is_failure: fn(&str) -> bool,
+    ) -> Result<T, String>
+    where
+        F: Future<Output = Result<T, String>>,
+    {
+        if !self.try_acquire() {
+            metrics::counter!(CIRCUIT_BREAKER_REJECTIONS_TOTAL, "dependency" => self.name.clone())
+                .increment(1);
+            return Err(ThrottleReason::DependencyUnavailable
+                .error(&format!("{} is unavailable", self.name)));
+        }
+
+        let result = operation.await;
+        self.record(match &result {
+            Ok(_) => true,
+            Err(e) => !is_failure(e),
+        });
+
+        result
+    }
+
+    fn try_acquire(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+
+        match inner.state {
+            CircuitState::Closed => true,
+            CircuitState::Open if inner.opened_at.elapsed() >= self.settings.open_duration => {
+                inner.probe_in_flight = true;
+                self.transition(&mut inner, CircuitState::HalfOpen);
+                true
+            }
+            CircuitState::Open => false,
+            CircuitState::HalfOpen if inner.probe_in_flight => false,
+            CircuitState::HalfOpen => {
+                inner.probe_in_flight = true;
+                true
+            }
+        }
+    }
+
+    fn record(&self, success: bool) {
+        let mut inner = self.inner.lock().unwrap();
+
+        match inner.state {
+            CircuitState::HalfOpen => {
+                inner.probe_in_flight = false;
+                match success {
+                    true => self.transition(&mut inner, CircuitState::Closed),
+                    false => self.transition(&mut inner, CircuitState::Open),
+                }
+            }
+            CircuitState::Closed => {
+                inner.outcomes.push_back(success);
+                while inner.outcomes.len() > self.settings.window_size {
+                    inner.outcomes.pop_front();
+                }
+
+                let failures = inner.outcomes.iter().filter(|x| !**x).count();
+                if inner.outcomes.len() >= self.settings.minimum_calls
+                    && failures as f64 / inner.outcomes.len() as f64
+                        >= self.settings.failure_rate_threshold
+                {
+                    self.transition(&mut inner, CircuitState::Open);
+                }
+            }
+            // Calls that started before the breaker opened don't change anything
+            CircuitState::Open => {}
+        }
+
+        // Also refreshed here because breakers are created before the metrics recorder is installed
+        metrics::gauge!(CIRCUIT_BREAKER_STATE, "dependency" => self.name.clone())
+            .set(inner.state.gauge_value());
+    }
+
+    fn transition(&self, inner: &mut CircuitBreakerInner, state: CircuitState) {
+        event!(
+            Level::WARN,
+            "Circuit breaker for {} moved from {:?} to {:?}",
+            self.name,
+            inner.state,
+            state
+        );
+
+        if state == CircuitState::Open {
+            inner.opened_at = Instant::now();
+        }
+        inner.outcomes.clear();
+        inner.state = state;
+
+        metrics::gauge!(CIRCUIT_BREAKER_STATE, "dependency" => self.name.clone())
+            .set(state.gauge_value());
+    }
+}
+
+// Lookups of missing entities are answered by a healthy dependency, so they must not trip the
+// breaker. Every repository reports them as "... with id ...". Neither must writes refused for lack
+// of a primary, as the secondaries are still answering queries; maintenance mode refuses commands
+// in the meantime
+fn is_dependency_failure(error: &str) -> bool {
+    !error.contains(" with id ") && !is_primary_unavailable(error)
+}
+
+pub struct CircuitBreakingOrderRepository {
+    inner: Arc<dyn OrderRepository + Send + Sync>,
+    circuit_breaker: Arc<CircuitBreaker>,
+}
+
+impl CircuitBreakingOrderRepository {
+    pub fn new(
+        inner: Arc<dyn OrderRepository + Send + Sync>,
+        circuit_breaker: Arc<CircuitBreaker>,
+    ) -> Self {
+        CircuitBreakingOrderRepository {
+            inner,
+            circuit_breaker,
+        }
+    }
+}
+
+#[async_trait]
+impl OrderRepository for CircuitBreakingOrderRepository {
+    async fn create(
+        &self,
+        id: OrderId,
+        order: Order,
+        session: TransactionSession,
+    ) -> Result<Order, String> {
+        self.circuit_breaker
+            .call_classified(self.inner.create(id, order, session), is_dependency_failure)
+            .await
+    }
+
+    async fn read<'a>(&self, id: &'a OrderId) -> Result<Order, String> {
+        self.circuit_breaker
+            .call_classified(self.inner.read(id), is_dependency_failure)
+            .await
+    }
+
+    async fn read_all(&self) -> Result<Vec<Order>, String> {
+        self.circuit_breaker.call(self.inner.read_all()).await
+    }
+
+    async fn read_updated_before<'a>(
+        &self,
+        cutoff_utc: DateTime<Utc>,
+        after_id: Option<&'a OrderId>,
+        limit: u32,
+    ) -> Result<Vec<Order>, String> {
+        self.circuit_breaker
+            .call(self.inner.read_updated_before(cutoff_utc, after_id, limit))
+            .await
+    }
+
+    async fn count_by_status(&self) -> Result<HashMap<OrderStatus, u64>, String> {
+        self.circuit_breaker
+            .call(self.inner.count_by_status())
+            .await
+    }
+
+    async fn update(
+        &self,
+        id: OrderId,
+        order: Order,
+        session: TransactionSession,
+    ) -> Result<Order, String> {
+        self.circuit_breaker
+            .call_classified(self.inner.update(id, order, session), is_dependency_failure)
+            .await
+    }
+
+    async fn upsert(
+        &self,
+        id: OrderId,
+        order: Order,
+        session: TransactionSession,
+    ) -> Result<Order, String> {
+        self.circuit_breaker
+            .call_classified(self.inner.upsert(id, order, session), is_dependency_failure)
+            .await
+    }
+
+    async fn delete(&self, id: &OrderId, session: TransactionSession) -> Result<(), String> {
+        self.circuit_breaker
+            .call_classified(self.inner.delete(id, session), is_dependency_failure)
+            .await
+    }
+}
+
+pub struct CircuitBreakingCartRepository {
+    inner: Arc<dyn CartRepository + Send + Sync>,
+    circuit_breaker: Arc<CircuitBreaker>,
+}
+
+impl CircuitBreakingCartRepository {
+    pub fn new(
+        inner: Arc<dyn CartRepository + Send + Sync>,
+        circuit_breaker: Arc<CircuitBreaker>,
+    ) -> Self {
+        CircuitBreakingCartRepository {
+            inner,
+            circuit_breaker,
+        }
+    }
+}
+
+#[async_trait]
+impl CartRepository for CircuitBreakingCartRepository {
+    async fn create(
+        &self,
+        id: CartId,
+        cart: Cart,
+        session: TransactionSession,
+    ) -> Result<Cart, String> {
+        self.circuit_breaker
+            .call_classified(self.inner.create(id, cart, session), is_dependency_failure)
+            .await
+    }
+
+    async fn read<'a>(&self, id: &'a CartId) -> Result<Cart, String> {
+        self.circuit_breaker
+            .call_classified(self.inner.read(id), is_dependency_failure)
+            .await
+    }
+
+    async fn read_all(&self) -> Result<Vec<Cart>, String> {
+        self.circuit_breaker.call(self.inner.read_all()).await
+    }
+
+    async fn read_updated_before<'a>(
+        &self,
+        cutoff_utc: DateTime<Utc>,
+        after_id: Option<&'a CartId>,
+        limit: u32,
+    ) -> Result<Vec<Cart>, String> {
+        self.circuit_breaker
+            .call(self.inner.read_updated_before(cutoff_utc, after_id, limit))
+            .await
+    }
+
+    async fn read_all_by_user_id<'a>(&self, user_id: &'a str) -> Result<Vec<Cart>, String> {
+        self.circuit_breaker
+            .call(self.inner.read_all_by_user_id(user_id))
+            .await
+    }
+
+    async fn read_all_containing_product<'a>(
+        &self,
+        product_id: &'a ProductId,
+    ) -> Result<Vec<Cart>, String> {
+        self.circuit_breaker
+            .call(self.inner.read_all_containing_product(product_id))
+            .await
+    }
+
+    async fn stats(&self) -> Result<CartStats, String> {
+        self.circuit_breaker.call(self.inner.stats()).await
+    }
+
+    async fn update(
+        &self,
+        id: CartId,
+        cart: Cart,
+        session: TransactionSession,
+    ) -> Result<Cart, String> {
+        self.circuit_breaker
+            .call_classified(self.inner.update(id, cart, session), is_dependency_failure)
+            .await
+    }
+
+    async fn upsert(
+        &self,
+        id: CartId,
+        cart: Cart,
+        session: TransactionSession,
+    ) -> Result<Cart, String> {
+        self.circuit_breaker
+            .call_classified(self.inner.upsert(id, cart, session), is_dependency_failure)
+            .await
+    }
+
+    // Health probes go straight to the dependency so they report on it even while the breaker is open
+    async fn ping(&self) -> Result<(), String> {
+        self.inner.ping().await
+    }
+
+    async fn delete(&self, id: &CartId, session: TransactionSession) -> Result<(), String> {
+        self.circuit_breaker
+            .call_classified(self.inner.delete(id, session), is_dependency_failure)
+            .await
+    }
+}
+
+pub struct CircuitBreakingMessageBroker {
+    inner: Arc<dyn MessageBroker + Send + Sync>,
+    circuit_breaker: Arc<CircuitBreaker>,
+}
+
+impl CircuitBreakingMessageBroker {
+    pub fn new(
+        inner: Arc<dyn MessageBroker + Send + Sync>,
+        circuit_breaker: Arc<CircuitBreaker>,
+    ) -> Self {
+        CircuitBreakingMessageBroker {
+            inner,
+            circuit_breaker,
+        }
+    }
+}
+
+#[async_trait]
+impl MessageBroker for CircuitBreakingMessageBroker {
+    async fn publish(&self, event: &Event, publication: &Publication) -> Result<(), String> {
+        self.circuit_breaker
+            .call(self.inner.publish(event, publication))
+            .await
+    }
+
+    async fn ping(&self) -> Result<(), String> {
+        self.inner.ping().await
+    }
+}
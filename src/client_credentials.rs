@@ -0,0 +1,182 @@
+// The payment and product service clients don't exist yet, so nothing asks for tokens so far
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    env,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Mutex, task::JoinHandle};
+use tracing::{event, Level};
+
+use crate::http_client::{HttpClient, HttpClientSettings};
+
+pub static M2M_TOKEN_REFRESHES_TOTAL: &str = "order_service_m2m_token_refreshes_total";
+
+#[derive(Debug, Clone)]
+pub struct ClientCredentialsSettings {
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    // Tokens are replaced this long before they expire, so callers never send one that is about to
+    pub refresh_before_expiry: Duration,
+}
+
+impl ClientCredentialsSettings {
+    // None unless M2M_CLIENT_ID and M2M_CLIENT_SECRET are set. The token URL defaults to the Auth0
+    // tenant's /oauth/token
+    pub fn from_env() -> Option<ClientCredentialsSettings> {
+        Some(ClientCredentialsSettings {
+            token_url: env::var("M2M_TOKEN_URL").unwrap_or(format!(
+                "{}/oauth/token",
+                env::var("AUTH0_DOMAIN").ok()?.trim_end_matches('/')
+            )),
+            client_id: env::var("M2M_CLIENT_ID").ok()?,
+            client_secret: env::var("M2M_CLIENT_SECRET").ok()?,
+            refresh_before_expiry: env::var("M2M_REFRESH_BEFORE_EXPIRY_SECONDS")
+                .map(|x| Duration::from_secs(x.parse().unwrap()))
+                .unwrap_or(Duration::from_secs(60)),
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct TokenRequest<'a> {
+    grant_type: &'a str,
+    client_id: &'a str,
+    client_secret: &'a str,
+    audience: &'a str,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(Clone)]
+struct CachedToken {
+    access_token: String,
+    expires_at: Instant,
+}
+
+// Fetches client-credentials tokens for calling other services and caches one per audience. A
+// background task replaces tokens before they expire, so requests normally never wait for the
+// identity provider. Each audience has its own lock, so a burst of calls triggers a single fetch
+pub struct ClientCredentialsTokenProvider {
+    settings: ClientCredentialsSettings,
+    http_client: HttpClient,
+    tokens: Mutex<HashMap<String, Arc<Mutex<Option<CachedToken>>>>>,
+}
+
+impl ClientCredentialsTokenProvider {
+    pub fn new(
+        settings: ClientCredentialsSettings,
+        http_client_settings: HttpClientSettings,
+    ) -> Result<ClientCredentialsTokenProvider, String> {
+        Ok(ClientCredentialsTokenProvider {
+            settings,
+            http_client: HttpClient::new("m2m_tokens", http_client_settings, None)?,
+            tokens: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub async fn token(&self, audience: &str) -> Result<String, String> {
+        let slot = self
+            .tokens
+            .lock()
+            .await
+            .entry(String::from(audience))
+            .or_default()
+            .clone();
+        let mut cached = slot.lock().await;
+
+        match &*cached {
+            Some(token) if token.expires_at > Instant::now() => Ok(token.access_token.clone()),
+            _ => {
+                let token = self.fetch(audience).await?;
+                *cached = Some(token.clone());
+                Ok(token.access_token)
+            }
+        }
+    }
+
+    // Replaces every cached token that is within refresh_before_expiry of expiring
+    pub fn start(self: Arc<Self>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(self.settings.refresh_before_expiry / 2).await;
+                self.refresh_expiring().await;
+            }
+        })
+    }
+
+    async fn refresh_expiring(&self) {
+        let slots: Vec<(String, Arc<Mutex<Option<CachedToken>>>)> = self
+            .tokens
+            .lock()
+            .await
+            .iter()
+            .map(|(audience, slot)| (audience.clone(), slot.clone()))
+            .collect();
+
+        for (audience, slot) in slots {
+            let mut cached = slot.lock().await;
+            let expiring = cached.as_ref().is_some_and(|token| {
+                token.expires_at <= Instant::now() + self.settings.refresh_before_expiry
+            });
+
+            if expiring {
+                match self.fetch(&audience).await {
+                    Ok(token) => *cached = Some(token),
+                    // The current token stays in use until it expires, so the next pass can retry
+                    Err(e) => event!(Level::WARN, "{}", e),
+                }
+            }
+        }
+    }
+
+    async fn fetch(&self, audience: &str) -> Result<CachedToken, String> {
+        let request = match self
+            .http_client
+            .client()
+            .post(&self.settings.token_url)
+            .json(&TokenRequest {
+                grant_type: "client_credentials",
+                client_id: &self.settings.client_id,
+                client_secret: &self.settings.client_secret,
+                audience,
+            })
+            .build()
+        {
+            Ok(request) => request,
+            Err(e) => return Err(format!("Failed to build token request: {}", e)),
+        };
+
+        let result = match self.http_client.execute(request).await {
+            Ok(response) if response.status().is_success() => {
+                match response.json::<TokenResponse>().await {
+                    Ok(token) => Ok(CachedToken {
+                        access_token: token.access_token,
+                        expires_at: Instant::now() + Duration::from_secs(token.expires_in),
+                    }),
+                    Err(e) => Err(format!("Invalid token response for {}: {}", audience, e)),
+                }
+            }
+            Ok(response) => Err(format!(
+                "Token request for {} was rejected with {}",
+                audience,
+                response.status()
+            )),
+            Err(e) => Err(format!("Failed to fetch token for {}: {}", audience, e)),
+        };
+
+        metrics::counter!(M2M_TOKEN_REFRESHES_TOTAL, "audience" => String::from(audience), "outcome" => if result.is_ok() { "success" } else { "failure" })
+            .increment(1);
+
+        result
+    }
+}
@@ -0,0 +1,305 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::sync::Mutex;
+use tracing::{event, Level};
+
+use crate::{
+    domain::{Cart, CartId, Order, OrderId, OrderStatus, ProductId},
+    repositories::{CartRepository, CartStats, OrderRepository},
+    uow::TransactionSession,
+};
+
+// Puts a document back the way it was before a write, or deletes it when the write created it
+enum Compensation {
+    Cart {
+        repository: Arc<dyn CartRepository + Send + Sync>,
+        id: CartId,
+        previous: Option<Cart>,
+    },
+    Order {
+        repository: Arc<dyn OrderRepository + Send + Sync>,
+        id: OrderId,
+        previous: Option<Order>,
+    },
+}
+
+impl Compensation {
+    async fn apply(self) -> Result<(), String> {
+        match self {
+            Compensation::Cart {
+                repository,
+                id,
+                previous: Some(previous),
+            } => repository
+                .upsert(id, previous, TransactionSession::default())
+                .await
+                .map(|_| ()),
+            Compensation::Cart {
+                repository,
+                id,
+                previous: None,
+            } => repository.delete(&id, TransactionSession::default()).await,
+            Compensation::Order {
+                repository,
+                id,
+                previous: Some(previous),
+            } => repository
+                .upsert(id, previous, TransactionSession::default())
+                .await
+                .map(|_| ()),
+            Compensation::Order {
+                repository,
+                id,
+                previous: None,
+            } => repository.delete(&id, TransactionSession::default()).await,
+        }
+    }
+}
+
+// Without transactions every write lands as soon as it is made, so rolling back means undoing the
+// writes made in the transaction one by one. Each transaction's session carries a log of its own,
+// so a rollback never touches what requests running alongside it wrote. Best effort only: other
+// writers can get in between, and an undo that fails is logged and skipped
+#[derive(Default)]
+pub struct CompensationLog {
+    compensations: Mutex<Vec<Compensation>>,
+}
+
+impl CompensationLog {
+    pub fn new() -> CompensationLog {
+        CompensationLog::default()
+    }
+
+    async fn record(&self, compensation: Compensation) {
+        self.compensations.lock().await.push(compensation);
+    }
+
+    pub async fn clear(&self) {
+        self.compensations.lock().await.clear();
+    }
+
+    // Undoes the recorded writes, latest first
+    pub async fn compensate(&self) {
+        let compensations: Vec<Compensation> =
+            self.compensations.lock().await.drain(..).rev().collect();
+
+        for compensation in compensations {
+            if let Err(e) = compensation.apply().await {
+                event!(Level::WARN, "Failed to undo a write on rollback: {}", e);
+            }
+        }
+    }
+}
+
+// Records how to undo every write that goes through it in the log of the write's transaction
+pub struct CompensatingCartRepository {
+    inner: Arc<dyn CartRepository + Send + Sync>,
+}
+
+impl CompensatingCartRepository {
+    pub fn new(inner: Arc<dyn CartRepository + Send + Sync>) -> Self {
+        CompensatingCartRepository { inner }
+    }
+
+    async fn record(&self, session: &TransactionSession, id: CartId, previous: Option<Cart>) {
+        session
+            .compensation_log()
+            .record(Compensation::Cart {
+                repository: self.inner.clone(),
+                id,
+                previous,
+            })
+            .await;
+    }
+}
+
+#[async_trait]
+impl CartRepository for CompensatingCartRepository {
+    async fn create(
+        &self,
+        id: CartId,
+        cart: Cart,
+        session: TransactionSession,
+    ) -> Result<Cart, String> {
+        let created_cart = self.inner.create(id.clone(), cart, session.clone()).await?;
+        self.record(&session, id, None).await;
+        Ok(created_cart)
+    }
+
+    async fn read<'a>(&self, id: &'a CartId) -> Result<Cart, String> {
+        self.inner.read(id).await
+    }
+
+    async fn read_all(&self) -> Result<Vec<Cart>, String> {
+        self.inner.read_all().await
+    }
+
+    async fn read_updated_before<'a>(
+        &self,
+        cutoff_utc: DateTime<Utc>,
+        after_id: Option<&'a CartId>,
+        limit: u32,
+    ) -> Result<Vec<Cart>, String> {
+        self.inner
+            .read_updated_before(cutoff_utc, after_id, limit)
+            .await
+    }
+
+    async fn read_all_by_user_id<'a>(&self, user_id: &'a str) -> Result<Vec<Cart>, String> {
+        self.inner.read_all_by_user_id(user_id).await
+    }
+
+    async fn read_all_containing_product<'a>(
+        &self,
+        product_id: &'a ProductId,
+    ) -> Result<Vec<Cart>, String> {
+        self.inner.read_all_containing_product(product_id).await
+    }
+
+    async fn stats(&self) -> Result<CartStats, String> {
+        self.inner.stats().await
+    }
+
+    async fn update(
+        &self,
+        id: CartId,
+        cart: Cart,
+        session: TransactionSession,
+    ) -> Result<Cart, String> {
+        let previous = self.inner.read(&id).await.ok();
+        let updated_cart = self.inner.update(id.clone(), cart, session.clone()).await?;
+        if previous.is_some() {
+            self.record(&session, id, previous).await;
+        }
+        Ok(updated_cart)
+    }
+
+    async fn upsert(
+        &self,
+        id: CartId,
+        cart: Cart,
+        session: TransactionSession,
+    ) -> Result<Cart, String> {
+        let previous = self.inner.read(&id).await.ok();
+        let upserted_cart = self.inner.upsert(id.clone(), cart, session.clone()).await?;
+        self.record(&session, id, previous).await;
+        Ok(upserted_cart)
+    }
+
+    async fn delete(&self, id: &CartId, session: TransactionSession) -> Result<(), String> {
+        let previous = self.inner.read(id).await.ok();
+        self.inner.delete(id, session.clone()).await?;
+        if previous.is_some() {
+            self.record(&session, id.clone(), previous).await;
+        }
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), String> {
+        self.inner.ping().await
+    }
+}
+
+pub struct CompensatingOrderRepository {
+    inner: Arc<dyn OrderRepository + Send + Sync>,
+}
+
+impl CompensatingOrderRepository {
+    pub fn new(inner: Arc<dyn OrderRepository + Send + Sync>) -> Self {
+        CompensatingOrderRepository { inner }
+    }
+
+    async fn record(&self, session: &TransactionSession, id: OrderId, previous: Option<Order>) {
+        session
+            .compensation_log()
+            .record(Compensation::Order {
+                repository: self.inner.clone(),
+                id,
+                previous,
+            })
+            .await;
+    }
+}
+
+#[async_trait]
+impl OrderRepository for CompensatingOrderRepository {
+    async fn create(
+        &self,
+        id: OrderId,
+        order: Order,
+        session: TransactionSession,
+    ) -> Result<Order, String> {
+        let created_order = self
+            .inner
+            .create(id.clone(), order, session.clone())
+            .await?;
+        self.record(&session, id, None).await;
+        Ok(created_order)
+    }
+
+    async fn read<'a>(&self, id: &'a OrderId) -> Result<Order, String> {
+        self.inner.read(id).await
+    }
+
+    async fn read_all(&self) -> Result<Vec<Order>, String> {
+        self.inner.read_all().await
+    }
+
+    async fn read_updated_before<'a>(
+        &self,
+        cutoff_utc: DateTime<Utc>,
+        after_id: Option<&'a OrderId>,
+        limit: u32,
+    ) -> Result<Vec<Order>, String> {
+        self.inner
+            .read_updated_before(cutoff_utc, after_id, limit)
+            .await
+    }
+
+    async fn count_by_status(&self) -> Result<HashMap<OrderStatus, u64>, String> {
+        self.inner.count_by_status().await
+    }
+
+    async fn update(
+        &self,
+        id: OrderId,
+        order: Order,
+        session: TransactionSession,
+    ) -> Result<Order, String> {
+        let previous = self.inner.read(&id).await.ok();
+        let updated_order = self
+            .inner
+            .update(id.clone(), order, session.clone())
+            .await?;
+        if previous.is_some() {
+            self.record(&session, id, previous).await;
+        }
+        Ok(updated_order)
+    }
+
+    async fn upsert(
+        &self,
+        id: OrderId,
+        order: Order,
+        session: TransactionSession,
+    ) -> Result<Order, String> {
+        let previous = self.inner.read(&id).await.ok();
+        let upserted_order = self
+            .inner
+            .upsert(id.clone(), order, session.clone())
+            .await?;
+        self.record(&session, id, previous).await;
+        Ok(upserted_order)
+    }
+
+    async fn delete(&self, id: &OrderId, session: TransactionSession) -> Result<(), String> {
+        let previous = self.inner.read(id).await.ok();
+        self.inner.delete(id, session.clone()).await?;
+        if previous.is_some() {
+            self.record(&session, id.clone(), previous).await;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::Request,
+    http::{HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use mongodb::{
+    bson::{self, Timestamp},
+    ClientSession, ClusterTime,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::api_response::ApiResponse;
+
+pub static CONSISTENCY_TOKEN_HEADER: &str = "x-consistency-token";
+
+// The point in MongoDB's history a client's writes reached. Clients send back the token they got
+// from a write so later reads wait for a node that has caught up to it, even on a secondary
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConsistencyToken {
+    pub operation_time: Timestamp,
+    pub cluster_time: Option<ClusterTime>,
+}
+
+impl ConsistencyToken {
+    pub fn from_session(session: &ClientSession) -> Option<ConsistencyToken> {
+        Some(ConsistencyToken {
+            operation_time: session.operation_time()?,
+            cluster_time: session.cluster_time().cloned(),
+        })
+    }
+
+    pub fn encode(&self) -> Result<String, String> {
+        bson::to_vec(self)
+            .map(|token| URL_SAFE_NO_PAD.encode(token))
+            .map_err(|e| format!("Failed to encode consistency token: {}", e))
+    }
+
+    pub fn decode(token: &str) -> Result<ConsistencyToken, String> {
+        URL_SAFE_NO_PAD
+            .decode(token.trim())
+            .ok()
+            .and_then(|token| bson::from_slice(&token).ok())
+            .ok_or_else(|| String::from("Invalid consistency token"))
+    }
+
+    // Lets a session's reads observe everything up to this token
+    pub fn apply(&self, session: &mut ClientSession) {
+        if let Some(cluster_time) = &self.cluster_time {
+            session.advance_cluster_time(cluster_time);
+        }
+        session.advance_operation_time(self.operation_time);
+    }
+
+    fn is_after(&self, other: &ConsistencyToken) -> bool {
+        (self.operation_time.time, self.operation_time.increment)
+            > (other.operation_time.time, other.operation_time.increment)
+    }
+}
+
+#[derive(Debug)]
+struct RequestConsistency {
+    // What the client has seen, from the request header
+    requested: Option<ConsistencyToken>,
+    // The latest the request's own writes reached
+    observed: Option<ConsistencyToken>,
+}
+
+tokio::task_local! {
+    static CONSISTENCY: Arc<Mutex<RequestConsistency>>;
+}
+
+// The token reads of the request being handled have to catch up to, if the client sent one
+pub fn requested() -> Option<ConsistencyToken> {
+    CONSISTENCY
+        .try_with(|consistency| consistency.lock().unwrap().requested.clone())
+        .ok()
+        .flatten()
+}
+
+// Called after a write commits, so the response can hand its token to the client
+pub fn observe(token: ConsistencyToken) {
+    let _ = CONSISTENCY.try_with(|consistency| {
+        let mut consistency = consistency.lock().unwrap();
+        if consistency
+            .observed
+            .as_ref()
+            .is_none_or(|observed| token.is_after(observed))
+        {
+            consistency.observed = Some(token);
+        }
+    });
+}
+
+// Reads X-Consistency-Token and answers with the token of the request's writes, or the one the
+// client sent when it didn't write anything, so clients can keep passing along the latest
+pub async fn consistency_middleware(request: Request, next: Next) -> Response {
+    let requested = match request
+        .headers()
+        .get(CONSISTENCY_TOKEN_HEADER)
+        .map(|token| {
+            token
+                .to_str()
+                .map_err(|_| String::from("Invalid consistency token"))
+                .and_then(ConsistencyToken::decode)
+        }) {
+        Some(Ok(token)) => Some(token),
+        Some(Err(e)) => {
+            return ApiResponse::error(StatusCode::BAD_REQUEST, "invalid_consistency_token", e)
+                .into_response()
+        }
+        None => None,
+    };
+
+    let consistency = Arc::new(Mutex::new(RequestConsistency {
+        requested,
+        observed: None,
+    }));
+    let mut response = CONSISTENCY
+        .scope(consistency.clone(), next.run(request))
+        .await;
+
+    let consistency = consistency.lock().unwrap();
+    let token = consistency
+        .observed
+        .as_ref()
+        .or(consistency.requested.as_ref())
+        .and_then(|token| token.encode().ok())
+        .and_then(|token| HeaderValue::from_str(&token).ok());
+    if let Some(token) = token {
+        response
+            .headers_mut()
+            .insert(CONSISTENCY_TOKEN_HEADER, token);
+    }
+    response
+}
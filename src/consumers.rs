@@ -0,0 +1,328 @@
+use std::{sync::Arc, time::Duration};
+
+use amqprs::{
+    callbacks::DefaultChannelCallback,
+    channel::{
+        BasicAckArguments, BasicConsumeArguments, BasicNackArguments, BasicQosArguments, Channel,
+        ConsumerMessage, ExchangeDeclareArguments, ExchangeType, QueueBindArguments,
+        QueueDeclareArguments,
+    },
+};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
+};
+use tracing::{event, Level};
+
+use crate::{
+    cqrs::{DiscardProductFromCartsCommand, MarkOrderPaidCommand},
+    domain::{OrderId, PaymentId, ProductId},
+    events::RabbitMqInitializationInfo,
+    mediator::Mediator,
+};
+
+pub static PAYMENT_COMPLETED_QUEUE_NAME: &str = "payment.completed";
+pub static PRODUCT_DELETED_QUEUE_NAME: &str = "product.deleted";
+
+pub static CONSUMED_QUEUE_NAMES: [&str; 2] =
+    [PAYMENT_COMPLETED_QUEUE_NAME, PRODUCT_DELETED_QUEUE_NAME];
+
+pub static EVENTS_CONSUMED_TOTAL: &str = "order_service_events_consumed_total";
+
+pub static DEFAULT_CONSUMER_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+pub static DEFAULT_CONSUMER_PREFETCH_COUNT: u16 = 10;
+
+// Events other services publish that this service acts on, in the tagged layout events are
+// published in, e.g. {"ProductDeletedEvent": {"product_id": "..."}}
+#[allow(clippy::enum_variant_names)]
+#[derive(Deserialize, JsonSchema)]
+pub enum InboundEvent {
+    PaymentCompletedEvent {
+        order_id: OrderId,
+        payment_id: PaymentId,
+    },
+    // The product is no longer sold, so it is taken out of every cart
+    ProductDeletedEvent {
+        product_id: ProductId,
+    },
+}
+
+impl InboundEvent {
+    // One instance of every variant, for the event catalog
+    pub fn all() -> Vec<InboundEvent> {
+        vec![
+            InboundEvent::PaymentCompletedEvent {
+                order_id: OrderId::default(),
+                payment_id: PaymentId::default(),
+            },
+            InboundEvent::ProductDeletedEvent {
+                product_id: ProductId::default(),
+            },
+        ]
+    }
+
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            InboundEvent::PaymentCompletedEvent { .. } => "PaymentCompletedEvent",
+            InboundEvent::ProductDeletedEvent { .. } => "ProductDeletedEvent",
+        }
+    }
+
+    pub fn family(&self) -> &'static str {
+        match self {
+            InboundEvent::PaymentCompletedEvent { .. } => "payment",
+            InboundEvent::ProductDeletedEvent { .. } => "product",
+        }
+    }
+
+    pub fn source_name(&self) -> &'static str {
+        match self {
+            InboundEvent::PaymentCompletedEvent { .. } => PAYMENT_COMPLETED_QUEUE_NAME,
+            InboundEvent::ProductDeletedEvent { .. } => PRODUCT_DELETED_QUEUE_NAME,
+        }
+    }
+
+    pub fn schema_version(&self) -> u32 {
+        match self {
+            InboundEvent::PaymentCompletedEvent { .. } => 1,
+            InboundEvent::ProductDeletedEvent { .. } => 1,
+        }
+    }
+
+    // The JSON schema this service expects the event type in
+    pub fn json_schema(&self) -> Value {
+        let schema = schemars::schema_for!(InboundEvent);
+
+        schema
+            .as_value()
+            .get("oneOf")
+            .and_then(Value::as_array)
+            .and_then(|variants| {
+                variants.iter().find(|variant| {
+                    variant
+                        .get("properties")
+                        .and_then(|properties| properties.get(self.event_type()))
+                        .is_some()
+                })
+            })
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // Hands the event to the command that acts on it
+    pub async fn dispatch(self, mediator: &Mediator) -> Result<(), String> {
+        match self {
+            InboundEvent::PaymentCompletedEvent {
+                order_id,
+                payment_id,
+            } => mediator
+                .send(&MarkOrderPaidCommand {
+                    id: order_id,
+                    payment_id,
+                })
+                .await
+                .map(|_| ())
+                .map_err(String::from),
+            InboundEvent::ProductDeletedEvent { product_id } => mediator
+                .send(&DiscardProductFromCartsCommand { product_id })
+                .await
+                .map(|_| ())
+                .map_err(String::from),
+        }
+    }
+}
+
+// Consumes every queue in CONSUMED_QUEUE_NAMES over one connection. Messages are acked once their
+// command succeeded; a failed one is requeued once and dropped, or dead-lettered if the queue is
+// set up for it, when it fails again
+pub struct RabbitMqEventConsumer {
+    init_info: RabbitMqInitializationInfo,
+    mediator: Arc<Mediator>,
+    prefetch_count: u16,
+    reconnect_delay: Duration,
+}
+
+impl RabbitMqEventConsumer {
+    pub fn new(
+        init_info: RabbitMqInitializationInfo,
+        mediator: Arc<Mediator>,
+        prefetch_count: u16,
+        reconnect_delay: Duration,
+    ) -> RabbitMqEventConsumer {
+        RabbitMqEventConsumer {
+            init_info,
+            mediator,
+            prefetch_count,
+            reconnect_delay,
+        }
+    }
+
+    // Runs until shutdown is set to true. The message being handled then is finished and acked;
+    // the ones prefetched but not handled yet go back to the queue when the connection closes
+    pub fn start(self, mut shutdown: watch::Receiver<bool>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                match self.consume(&mut shutdown).await {
+                    Ok(()) => return,
+                    Err(e) => event!(
+                        Level::WARN,
+                        "Event consumer disconnected, reconnecting in {:?}: {}",
+                        self.reconnect_delay,
+                        e
+                    ),
+                }
+
+                tokio::select! {
+                    _ = tokio::time::sleep(self.reconnect_delay) => {}
+                    _ = shutdown_requested(&mut shutdown) => return,
+                }
+            }
+        })
+    }
+
+    // Returns Ok once shut down, or the error that lost the connection
+    async fn consume(&self, shutdown: &mut watch::Receiver<bool>) -> Result<(), String> {
+        let connection = self.init_info.open_connection().await?;
+        let channel = match connection.open_channel(None).await {
+            Ok(channel) => channel,
+            Err(e) => return Err(format!("Failed to get channel: {}", e)),
+        };
+        if let Err(e) = channel.register_callback(DefaultChannelCallback).await {
+            return Err(format!("Failed to register channel callback: {}", e));
+        }
+        if let Err(e) = channel
+            .basic_qos(BasicQosArguments::new(0, self.prefetch_count, false))
+            .await
+        {
+            return Err(format!("Failed to set prefetch count: {}", e));
+        }
+
+        // Each queue's deliveries are forwarded into one stream, which ends once the channel closes
+        let (message_sender, mut messages) = mpsc::unbounded_channel();
+        for queue in CONSUMED_QUEUE_NAMES {
+            let mut deliveries = subscribe(&channel, queue).await?;
+            let message_sender = message_sender.clone();
+
+            tokio::spawn(async move {
+                while let Some(message) = deliveries.recv().await {
+                    if message_sender.send(message).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+        drop(message_sender);
+
+        event!(
+            Level::INFO,
+            "Consuming events from {:?}",
+            CONSUMED_QUEUE_NAMES
+        );
+
+        loop {
+            tokio::select! {
+                message = messages.recv() => match message {
+                    Some(message) => self.handle(&channel, message).await?,
+                    None => return Err(String::from("RabbitMQ closed the channel")),
+                },
+                _ = shutdown_requested(shutdown) => {
+                    let _ = channel.close().await;
+                    let _ = connection.close().await;
+                    event!(Level::INFO, "Event consumer stopped");
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    async fn handle(&self, channel: &Channel, message: ConsumerMessage) -> Result<(), String> {
+        let deliver = match message.deliver {
+            Some(deliver) => deliver,
+            None => return Ok(()),
+        };
+
+        let (event_type, result) =
+            match serde_json::from_slice::<InboundEvent>(&message.content.unwrap_or_default()) {
+                Ok(inbound_event) => (
+                    inbound_event.event_type(),
+                    inbound_event.dispatch(&self.mediator).await,
+                ),
+                Err(e) => (
+                    "unknown",
+                    Err(format!("Failed to deserialize event: {}", e)),
+                ),
+            };
+
+        let outcome = match &result {
+            Ok(()) => "success",
+            Err(_) => "failure",
+        };
+        metrics::counter!(EVENTS_CONSUMED_TOTAL, "event_type" => event_type, "outcome" => outcome)
+            .increment(1);
+
+        let acknowledgement = match result {
+            Ok(()) => {
+                channel
+                    .basic_ack(BasicAckArguments::new(deliver.delivery_tag(), false))
+                    .await
+            }
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Failed to handle {} from {}: {}",
+                    event_type,
+                    deliver.exchange(),
+                    e
+                );
+
+                channel
+                    .basic_nack(BasicNackArguments::new(
+                        deliver.delivery_tag(),
+                        false,
+                        !deliver.redelivered(),
+                    ))
+                    .await
+            }
+        };
+
+        acknowledgement.map_err(|e| format!("Failed to acknowledge message: {}", e))
+    }
+}
+
+async fn shutdown_requested(shutdown: &mut watch::Receiver<bool>) {
+    let _ = shutdown.wait_for(|shutdown| *shutdown).await;
+}
+
+// Declared the way RabbitMqMessageBroker declares the queues it publishes to, as a fanout exchange
+// with a durable queue of the same name bound to it
+async fn subscribe(
+    channel: &Channel,
+    queue: &str,
+) -> Result<mpsc::UnboundedReceiver<ConsumerMessage>, String> {
+    let declared = async {
+        channel
+            .exchange_declare(ExchangeDeclareArguments::new(
+                queue,
+                &ExchangeType::Fanout.to_string(),
+            ))
+            .await?;
+        channel
+            .queue_declare(QueueDeclareArguments::durable_client_named(queue))
+            .await?;
+        channel
+            .queue_bind(QueueBindArguments::new(queue, queue, ""))
+            .await?;
+        channel
+            .basic_consume_rx(BasicConsumeArguments::new(queue, ""))
+            .await
+    }
+    .await;
+
+    match declared {
+        Ok((_, deliveries)) => Ok(deliveries),
+        Err(e) => Err(format!("Failed to consume from {}: {}", queue, e)),
+    }
+}
@@ -1,185 +1,811 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     sync::Arc,
-    time::{SystemTime, UNIX_EPOCH},
+    time::Duration,
 };
 
+use async_trait::async_trait;
+use chrono::{DateTime, TimeDelta, Utc};
 use serde::{Deserialize, Serialize};
 use tracing::{event, Level};
 
 use crate::{
-    domain::Cart,
+    auth::{verify_cart_session_token, CART_SESSION_TOKEN_PREFIX},
+    delivery_slots::DeliverySlotProvider,
+    domain::{
+        line_items_from_quantities, Cart, CartError, CartId, CartLineItem, Order, OrderId,
+        OrderStatus, PaymentId, ProductId,
+    },
     dtos::{
-        AddProductToCartResponse, CartResponse, CreateCartResponse, EmptyResponse,
-        GetCartsResponse, Response,
+        AddProductToCartResponse, CartImportResponse, CartImportResult, CartLineItemResponse,
+        CartResponse, CreateCartResponse, CustomerDataErasedResponse, CustomerDataExportResponse,
+        DeliverySlotResponse, DeliverySlotsResponse, EmptyResponse, GetCartsResponse,
+        GetOrdersResponse, ImportedCartRecord, OrderResponse, OrderStatusTransitionResponse,
+        OrderTimelineResponse, PageInfo, PickupCodeResponse, PickupVerificationResponse, Response,
+        RetentionReportResponse, RetentionRuleReport, ShareCartResponse, SharedCartResponse,
+        StatsResponse,
     },
+    errors::HandlerError,
     events::Event,
+    repositories::{is_not_found, is_version_conflict, CartRepository},
+    retention::{RetentionAction, RetentionEntity, RetentionRule, RETENTION_DOCUMENTS_TOTAL},
+    signing::{now_utc_millis, TokenSigner},
     uow::{OrderUnitOfWork, UnitOfWork},
 };
 
 // traits
-pub trait Command {}
-pub trait Query {}
+pub trait Command {
+    type Response: Response;
+
+    // Run by the mediator before the command reaches its handler
+    fn validate(&self) -> Result<(), HandlerError> {
+        Ok(())
+    }
+}
+pub trait Query {
+    type Response: Response;
+}
+
+#[async_trait]
+pub trait CommandHandler<C: Command + Sync> {
+    async fn handle(&self, input: &C) -> Result<C::Response, HandlerError>;
+}
 
-pub trait CommandHandler<C: Command, R: Response> {
-    async fn handle(&self, input: &C) -> Result<R, String>;
+#[async_trait]
+pub trait QueryHandler<Q: Query + Send> {
+    async fn handle(&self, input: Option<Q>) -> Result<Q::Response, HandlerError>;
 }
 
-pub trait QueryHandler<Q: Query, R: Response> {
-    async fn handle(&self, input: Option<Q>) -> Result<R, String>;
+#[derive(Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CreateCartCommand {
+    #[serde(skip)]
+    pub user_id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub is_default: bool,
+}
+impl Command for CreateCartCommand {
+    type Response = CreateCartResponse;
 }
 
 #[derive(Serialize, Deserialize)]
-pub struct CreateCartCommand {}
-impl Command for CreateCartCommand {}
+pub struct DeleteCartCommand {
+    #[serde(skip)]
+    pub user_id: String,
+    #[serde(skip)]
+    pub id: CartId,
+}
+impl Command for DeleteCartCommand {
+    type Response = EmptyResponse;
+
+    fn validate(&self) -> Result<(), HandlerError> {
+        if self.id.is_empty() {
+            return Err(HandlerError::invalid("cart_id_required", &[]));
+        }
+
+        Ok(())
+    }
+}
 
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct AddProductToCartCommand {
-    pub cart_id: String,
-    pub product_id: String,
+    #[serde(skip)]
+    pub user_id: String,
+    // When left empty the product is added to the caller's default cart
+    #[serde(default)]
+    pub cart_id: CartId,
+    pub product_id: ProductId,
+    // How many units to add, one when left out
+    #[serde(default)]
+    pub quantity: Option<i32>,
+    // The X-Request-Id the client sent, which identical retries and double-clicks share
+    #[serde(skip)]
+    pub client_request_id: Option<String>,
+}
+impl Command for AddProductToCartCommand {
+    type Response = AddProductToCartResponse;
+
+    fn validate(&self) -> Result<(), HandlerError> {
+        if self.cart_id.is_empty() && self.user_id.is_empty() {
+            return Err(HandlerError::invalid("cart_id_required", &[]));
+        }
+
+        if self.product_id.is_empty() {
+            return Err(HandlerError::invalid("product_id_required", &[]));
+        }
+
+        if self.quantity.is_some_and(|quantity| quantity <= 0) {
+            return Err(HandlerError::invalid("quantity_not_positive", &[]));
+        }
+
+        Ok(())
+    }
 }
-impl Command for AddProductToCartCommand {}
 
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct RemoveProductFromCartCommand {
-    pub cart_id: String,
-    pub product_id: String,
+    #[serde(skip)]
+    pub user_id: String,
+    pub cart_id: CartId,
+    pub product_id: ProductId,
+}
+impl Command for RemoveProductFromCartCommand {
+    type Response = EmptyResponse;
+
+    fn validate(&self) -> Result<(), HandlerError> {
+        if self.cart_id.is_empty() {
+            return Err(HandlerError::invalid("cart_id_required", &[]));
+        }
+
+        if self.product_id.is_empty() {
+            return Err(HandlerError::invalid("product_id_required", &[]));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SetProductQuantityCommand {
+    #[serde(skip)]
+    pub user_id: String,
+    #[serde(skip)]
+    pub cart_id: CartId,
+    #[serde(skip)]
+    pub product_id: ProductId,
+    // Zero takes the product out of the cart
+    pub quantity: i32,
+}
+impl Command for SetProductQuantityCommand {
+    type Response = EmptyResponse;
+
+    fn validate(&self) -> Result<(), HandlerError> {
+        if self.cart_id.is_empty() {
+            return Err(HandlerError::invalid("cart_id_required", &[]));
+        }
+
+        if self.product_id.is_empty() {
+            return Err(HandlerError::invalid("product_id_required", &[]));
+        }
+
+        if self.quantity < 0 {
+            return Err(HandlerError::invalid("quantity_negative", &[]));
+        }
+
+        Ok(())
+    }
 }
-impl Command for RemoveProductFromCartCommand {}
 
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SetDefaultCartCommand {
+    #[serde(skip)]
+    pub user_id: String,
+    pub cart_id: CartId,
+}
+impl Command for SetDefaultCartCommand {
+    type Response = EmptyResponse;
+
+    fn validate(&self) -> Result<(), HandlerError> {
+        if self.cart_id.is_empty() {
+            return Err(HandlerError::invalid("cart_id_required", &[]));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct GetCartsQuery {
-    pub id: String,
+    // When left empty every cart is listed, one page at a time
+    #[serde(default)]
+    pub id: CartId,
+    // A single cart is only returned to the user it belongs to
+    #[serde(skip)]
+    pub user_id: String,
+    #[serde(default)]
+    pub page: u32,
+    #[serde(default)]
+    pub page_size: u32,
+}
+impl Query for GetCartsQuery {
+    type Response = GetCartsResponse;
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GetUserCartsQuery {
+    pub user_id: String,
+}
+impl Query for GetUserCartsQuery {
+    type Response = GetCartsResponse;
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ShareCartCommand {
+    #[serde(skip)]
+    pub user_id: String,
+    #[serde(skip)]
+    pub cart_id: CartId,
+}
+impl Command for ShareCartCommand {
+    type Response = ShareCartResponse;
+
+    fn validate(&self) -> Result<(), HandlerError> {
+        if self.cart_id.is_empty() {
+            return Err(HandlerError::invalid("cart_id_required", &[]));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CloneSharedCartCommand {
+    #[serde(skip)]
+    pub user_id: String,
+    #[serde(skip)]
+    pub token: String,
+}
+impl Command for CloneSharedCartCommand {
+    type Response = CreateCartResponse;
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ClaimGuestCartCommand {
+    #[serde(skip)]
+    pub user_id: String,
+    #[serde(skip)]
+    pub cart_id: CartId,
+    #[serde(skip)]
+    pub cart_session_token: String,
+}
+impl Command for ClaimGuestCartCommand {
+    type Response = EmptyResponse;
+
+    fn validate(&self) -> Result<(), HandlerError> {
+        if self.cart_id.is_empty() {
+            return Err(HandlerError::invalid("cart_id_required", &[]));
+        }
+
+        Ok(())
+    }
+}
+
+// Finds the carts holding a product, e.g. to see who is affected when it is recalled or mispriced
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GetCartsContainingProductQuery {
+    #[serde(rename = "containsProduct")]
+    pub product_id: ProductId,
+}
+impl Query for GetCartsContainingProductQuery {
+    type Response = GetCartsResponse;
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GetSharedCartQuery {
+    pub token: String,
+}
+impl Query for GetSharedCartQuery {
+    type Response = SharedCartResponse;
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GetDeliverySlotsQuery {
+    #[serde(skip)]
+    pub user_id: String,
+    pub cart_id: CartId,
+}
+impl Query for GetDeliverySlotsQuery {
+    type Response = DeliverySlotsResponse;
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CancelOrderCommand {
+    #[serde(skip)]
+    pub id: OrderId,
+    // Recorded as the actor on the order's timeline
+    #[serde(skip)]
+    pub cancelled_by: String,
+    #[serde(default)]
+    pub reason: String,
+}
+impl Command for CancelOrderCommand {
+    type Response = OrderResponse;
+
+    fn validate(&self) -> Result<(), HandlerError> {
+        match self.id.is_empty() {
+            true => Err(HandlerError::invalid("order_id_required", &[])),
+            false => Ok(()),
+        }
+    }
+}
+
+// Sent when the payment service reports an order's payment as completed
+#[derive(Serialize, Deserialize)]
+pub struct MarkOrderPaidCommand {
+    pub id: OrderId,
+    pub payment_id: PaymentId,
+}
+impl Command for MarkOrderPaidCommand {
+    type Response = EmptyResponse;
+
+    fn validate(&self) -> Result<(), HandlerError> {
+        match self.id.is_empty() {
+            true => Err(HandlerError::invalid("order_id_required", &[])),
+            false => Ok(()),
+        }
+    }
+}
+
+// Sent when the catalog reports a product as deleted
+#[derive(Serialize, Deserialize)]
+pub struct DiscardProductFromCartsCommand {
+    pub product_id: ProductId,
+}
+impl Command for DiscardProductFromCartsCommand {
+    type Response = EmptyResponse;
+
+    fn validate(&self) -> Result<(), HandlerError> {
+        match self.product_id.is_empty() {
+            true => Err(HandlerError::invalid("product_id_required", &[])),
+            false => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GetOrderByIdQuery {
+    pub id: OrderId,
+}
+impl Query for GetOrderByIdQuery {
+    type Response = OrderResponse;
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct GetOrdersQuery {
+    #[serde(default)]
+    pub page: u32,
+    #[serde(default)]
+    pub page_size: u32,
+}
+impl Query for GetOrdersQuery {
+    type Response = GetOrdersResponse;
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GetOrderTimelineQuery {
+    pub id: OrderId,
+}
+impl Query for GetOrderTimelineQuery {
+    type Response = OrderTimelineResponse;
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GetPickupCodeQuery {
+    pub id: OrderId,
+}
+impl Query for GetPickupCodeQuery {
+    type Response = PickupCodeResponse;
+}
+
+// Store staff check the code a customer presents before handing the order over. Nothing changes
+// when it is valid, so it is a query even though devices POST it
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VerifyPickupCodeQuery {
+    #[serde(skip)]
+    pub id: OrderId,
+    pub code: String,
+}
+impl Query for VerifyPickupCodeQuery {
+    type Response = PickupVerificationResponse;
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EraseCustomerDataCommand {
+    pub customer_id: String,
+    // The admin who requested the erasure, for the log
+    #[serde(skip)]
+    pub requested_by: String,
+}
+impl Command for EraseCustomerDataCommand {
+    type Response = CustomerDataErasedResponse;
+
+    fn validate(&self) -> Result<(), HandlerError> {
+        match self.customer_id.is_empty() {
+            true => Err(HandlerError::invalid("customer_id_required", &[])),
+            false => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ExportCustomerDataQuery {
+    pub customer_id: String,
+}
+impl Query for ExportCustomerDataQuery {
+    type Response = CustomerDataExportResponse;
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct GetStatsQuery {}
+impl Query for GetStatsQuery {
+    type Response = StatsResponse;
+}
+
+// Raw NDJSON lines, so the report can point at the line each failure came from
+#[derive(Serialize, Deserialize)]
+pub struct ImportCartsCommand {
+    pub lines: Vec<String>,
+}
+impl Command for ImportCartsCommand {
+    type Response = CartImportResponse;
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ApplyRetentionRulesCommand {
+    pub dry_run: bool,
+}
+impl Command for ApplyRetentionRulesCommand {
+    type Response = RetentionReportResponse;
+}
+
+// Basket size after every change, so a sudden drop in what customers put in their carts can be alerted on
+pub static CART_ITEMS: &str = "order_service_cart_items";
+pub static CART_ITEMS_BUCKETS: &[f64] =
+    &[0.0, 1.0, 2.0, 3.0, 5.0, 8.0, 13.0, 21.0, 34.0, 55.0, 100.0];
+pub static DEFAULT_CART_NAME: &str = "My Cart";
+pub static DEFAULT_PAGE_SIZE: u32 = 20;
+pub static MAX_PAGE_SIZE: u32 = 100;
+pub static DEFAULT_MAX_ADD_TO_CART_QUANTITY: i32 = 99;
+static SHARED_CART_TOKEN_PREFIX: &str = "shared-cart:";
+static PICKUP_CODE_TOKEN_PREFIX: &str = "pickup:";
+
+// Guests have no user ID and reach only their own cart through its cart session. Someone else's
+// cart is reported as missing rather than forbidden, so its ID can't be probed
+fn ensure_cart_owner(cart: &Cart, user_id: &str) -> Result<(), HandlerError> {
+    if cart.user_id == user_id && cart.user_id != ERASED_CART_OWNER {
+        return Ok(());
+    }
+
+    event!(
+        Level::WARN,
+        "Cart with ID {} does not belong to user {}",
+        cart.id,
+        user_id
+    );
+    Err(HandlerError::not_found(
+        "cart_not_found",
+        &[("cart_id", cart.id.as_str())],
+    ))
+}
+
+// A cart that doesn't exist is the caller's problem, a read that failed otherwise is ours
+fn cart_read_error(id: &CartId, error: String) -> HandlerError {
+    match is_not_found(&error) {
+        true => HandlerError::not_found("cart_not_found", &[("cart_id", id.as_str())]),
+        false => format!("Failed to find Cart with ID {}: {}", id, error).into(),
+    }
+}
+
+fn order_read_error(id: &OrderId, error: String) -> HandlerError {
+    match is_not_found(&error) {
+        true => HandlerError::not_found("order_not_found", &[("order_id", id.as_str())]),
+        false => format!("Failed to find Order with ID {}: {}", id, error).into(),
+    }
+}
+
+fn verify_shared_cart_token(
+    token_signer: &TokenSigner,
+    token: &str,
+) -> Result<CartId, HandlerError> {
+    match token_signer.verify(token) {
+        Ok(payload) => match payload.strip_prefix(SHARED_CART_TOKEN_PREFIX) {
+            Some(cart_id) => Ok(CartId::from(cart_id)),
+            None => Err(HandlerError::invalid("not_a_shared_cart_token", &[])),
+        },
+        Err(e) => {
+            event!(Level::INFO, "Refused shared cart token: {}", e);
+            Err(HandlerError::rejected("invalid_shared_cart_token", &[]))
+        }
+    }
 }
-impl Query for GetCartsQuery {}
 
 pub struct CreateCartCommandHandler {
     uow: Arc<OrderUnitOfWork>,
+    cart_session_token_signer: TokenSigner,
+    cart_session_ttl_millis: i64,
 }
 
 impl CreateCartCommandHandler {
-    pub fn new(uow: Arc<OrderUnitOfWork>) -> Self {
-        CreateCartCommandHandler { uow: uow }
+    pub fn new(
+        uow: Arc<OrderUnitOfWork>,
+        cart_session_token_signer: TokenSigner,
+        cart_session_ttl_millis: i64,
+    ) -> Self {
+        CreateCartCommandHandler {
+            uow,
+            cart_session_token_signer,
+            cart_session_ttl_millis,
+        }
     }
 }
 
-impl CommandHandler<CreateCartCommand, CreateCartResponse> for CreateCartCommandHandler {
-    async fn handle(&self, _: &CreateCartCommand) -> Result<CreateCartResponse, String> {
-        let since_the_epoch = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("oops")
-            .as_millis();
+#[async_trait]
+impl CommandHandler<CreateCartCommand> for CreateCartCommandHandler {
+    async fn handle(&self, input: &CreateCartCommand) -> Result<CreateCartResponse, HandlerError> {
+        let now_utc = Utc::now();
+
+        let cart_repository = self.uow.get_cart_repository().await;
+
+        // Guest carts have no owner until the shopper authenticates and claims them
+        let is_guest = input.user_id.is_empty();
+
+        let existing_carts = if is_guest {
+            Vec::new()
+        } else {
+            match cart_repository.read_all_by_user_id(&input.user_id).await {
+                Ok(carts) => carts,
+                Err(e) => {
+                    event!(
+                        Level::WARN,
+                        "Error occurred while finding user carts: {}",
+                        e
+                    );
+                    return Err(e.into());
+                }
+            }
+        };
+
+        // The first cart a user creates always becomes their default cart
+        let is_default = !is_guest && (input.is_default || existing_carts.is_empty());
+
+        let name = match input.name.trim() {
+            "" => String::from(DEFAULT_CART_NAME),
+            name => String::from(name),
+        };
 
         let domain_cart = Cart {
-            id: uuid::Uuid::new_v4().to_string(),
-            products: HashMap::new(),
-            created_at_utc: since_the_epoch as i64,
-            updated_at_utc: since_the_epoch as i64,
+            id: CartId::generate(),
+            user_id: input.user_id.clone(),
+            name,
+            is_default,
+            products: Vec::new(),
+            created_at_utc: now_utc,
+            updated_at_utc: now_utc,
             version: 0,
         };
 
-        let cart_repository = self.uow.get_cart_repository().await;
-        let session = self.uow.begin_transaction().await;
+        let session = self.uow.begin_transaction().await?;
+
+        if is_default {
+            for mut previous_default_cart in existing_carts.into_iter().filter(|c| c.is_default) {
+                previous_default_cart.is_default = false;
+
+                if let Err(e) = cart_repository
+                    .update(
+                        previous_default_cart.id.clone(),
+                        previous_default_cart,
+                        session.clone(),
+                    )
+                    .await
+                {
+                    event!(
+                        Level::WARN,
+                        "Error occurred while clearing default cart: {}",
+                        e
+                    );
+                    if let Err(rollback_error) = self.uow.rollback(session).await {
+                        event!(Level::WARN, "{}", rollback_error);
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
 
         match cart_repository
-            .create(domain_cart.id.clone(), domain_cart, session)
+            .create(domain_cart.id.clone(), domain_cart, session.clone())
             .await
         {
-            Ok(created_cart) => match self.uow.commit().await {
+            Ok(created_cart) => match self.uow.commit(session).await {
                 Ok(()) => Ok(CreateCartResponse {
                     id: created_cart.id.clone(),
+                    cart_session_token: match is_guest {
+                        true => Some(self.cart_session_token_signer.sign(
+                            &format!("{}{}", CART_SESSION_TOKEN_PREFIX, created_cart.id),
+                            now_utc_millis() + self.cart_session_ttl_millis,
+                        )),
+                        false => None,
+                    },
+                    links: BTreeMap::new(),
                 }),
                 Err(e) => {
-                    event!(Level::WARN, "Error occurred while adding product: {}", e);
-                    Err(e)
+                    event!(Level::WARN, "Error occurred while creating cart: {}", e);
+                    Err(e.into())
                 }
             },
             Err(e) => {
-                self.uow.rollback().await.unwrap();
-                event!(Level::WARN, "Error occurred while adding product: {}", e);
-                Err(e)
+                event!(Level::WARN, "Error occurred while creating cart: {}", e);
+                if let Err(rollback_error) = self.uow.rollback(session).await {
+                    event!(Level::WARN, "{}", rollback_error);
+                }
+                Err(e.into())
             }
         }
     }
 }
 
-pub struct AddProductToCartCommandHandler {
+// What add/remove product do when the cart changed between reading and writing it. Merge re-reads
+// the cart and re-applies the quantity change, so two devices editing one cart both succeed; Fail
+// surfaces the conflict to the caller as a 409
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CartConflictStrategy {
+    Fail,
+    Merge,
+}
+
+static MAX_MERGE_ATTEMPTS: u32 = 10;
+
+// Random pause before re-applying, so writers that collided don't collide again in lockstep
+async fn merge_backoff() {
+    let jitter_millis = (uuid::Uuid::new_v4().as_u128() % 20) as u64;
+    tokio::time::sleep(Duration::from_millis(jitter_millis)).await;
+}
+
+pub struct DeleteCartCommandHandler {
     uow: Arc<OrderUnitOfWork>,
 }
 
-impl AddProductToCartCommandHandler {
+impl DeleteCartCommandHandler {
     pub fn new(uow: Arc<OrderUnitOfWork>) -> Self {
-        AddProductToCartCommandHandler { uow: uow }
+        DeleteCartCommandHandler { uow }
     }
 }
 
-impl CommandHandler<AddProductToCartCommand, AddProductToCartResponse>
-    for AddProductToCartCommandHandler
-{
-    async fn handle(
-        &self,
-        input: &AddProductToCartCommand,
-    ) -> Result<AddProductToCartResponse, String> {
-        if input.cart_id.is_empty() {
-            return Err(String::from("Cart ID cannot be null or empty!!!"));
+// A deleted default cart isn't replaced; the user picks another one with setDefaultCart
+#[async_trait]
+impl CommandHandler<DeleteCartCommand> for DeleteCartCommandHandler {
+    async fn handle(&self, input: &DeleteCartCommand) -> Result<EmptyResponse, HandlerError> {
+        let cart_repository = self.uow.get_cart_repository().await;
+
+        let found_cart = match cart_repository.read(&input.id).await {
+            Ok(found_cart) => found_cart,
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Failed to find Cart with ID {}: {}",
+                    input.id,
+                    e
+                );
+                return Err(cart_read_error(&input.id, e));
+            }
+        };
+
+        ensure_cart_owner(&found_cart, &input.user_id)?;
+
+        let session = self.uow.begin_transaction().await?;
+
+        if let Err(e) = cart_repository
+            .delete(&found_cart.id, session.clone())
+            .await
+        {
+            event!(
+                Level::WARN,
+                "Failed to delete Cart with ID {}: {}",
+                found_cart.id,
+                e
+            );
+            if let Err(rollback_error) = self.uow.rollback(session).await {
+                event!(Level::WARN, "{}", rollback_error);
+            }
+            return Err(match is_not_found(&e) {
+                true => cart_read_error(&found_cart.id, e),
+                false => format!("Failed to delete Cart with ID {}: {}", found_cart.id, e).into(),
+            });
         }
 
-        if input.product_id.is_empty() {
-            return Err(String::from("Product ID cannot be null or empty!!!"));
+        session
+            .push_event(Event::CartDeletedEvent {
+                cart_id: found_cart.id,
+                deleted_at_utc: now_utc_millis(),
+            })
+            .await;
+
+        self.uow.commit(session).await?;
+
+        Ok(EmptyResponse {})
+    }
+}
+
+pub struct AddProductToCartCommandHandler {
+    uow: Arc<OrderUnitOfWork>,
+    conflict_strategy: CartConflictStrategy,
+    // The most units of a product a cart may hold, so also the most a single call may add
+    max_quantity: i32,
+}
+
+impl AddProductToCartCommandHandler {
+    pub fn new(
+        uow: Arc<OrderUnitOfWork>,
+        conflict_strategy: CartConflictStrategy,
+        max_quantity: i32,
+    ) -> Self {
+        AddProductToCartCommandHandler {
+            uow,
+            conflict_strategy,
+            max_quantity,
         }
+    }
+
+    fn should_merge(&self, error: &HandlerError, attempt: u32) -> bool {
+        self.conflict_strategy == CartConflictStrategy::Merge
+            && matches!(error, HandlerError::Internal(e) if is_version_conflict(e))
+            && attempt < MAX_MERGE_ATTEMPTS
+    }
 
+    // Reads the cart, applies the increment and writes it back against the version that was read
+    async fn add_to_cart(
+        &self,
+        cart_id: &CartId,
+        input: &AddProductToCartCommand,
+    ) -> Result<AddProductToCartResponse, HandlerError> {
         let cart_repository = self.uow.get_cart_repository().await;
 
-        match cart_repository.read(&input.cart_id).await {
+        match cart_repository.read(cart_id).await {
             Ok(mut found_cart) => {
-                match found_cart.products.get(&input.product_id) {
-                    Some(current_product_quantity) => {
-                        found_cart
-                            .products
-                            .insert(input.product_id.clone(), current_product_quantity + 1);
-                    }
-                    None => {
-                        found_cart.products.insert(input.product_id.clone(), 1);
+                ensure_cart_owner(&found_cart, &input.user_id)?;
+
+                let product_added = match found_cart.add_product(
+                    &input.product_id,
+                    input.quantity.unwrap_or(1),
+                    self.max_quantity,
+                    Utc::now(),
+                ) {
+                    Ok(product_added) => product_added,
+                    Err(CartError::QuantityTooLarge { max_quantity }) => {
+                        return Err(HandlerError::invalid(
+                            "quantity_too_large",
+                            &[("max_quantity", &max_quantity.to_string())],
+                        ))
                     }
-                }
+                };
 
-                let session = self.uow.begin_transaction().await;
+                let session = self.uow.begin_transaction().await?;
 
                 match cart_repository
-                    .update(input.cart_id.clone(), found_cart, session)
+                    .update(cart_id.clone(), found_cart, session.clone())
                     .await
                 {
                     Ok(updated_cart) => {
-                        {
-                            let events_to_publish = self.uow.get_events_to_publish().await;
-                            let mut event_lock = events_to_publish.lock().await;
-
-                            event_lock.push(Event::ProductAddedToCartEvent {
-                                product_id: input.product_id.clone(),
-                            });
-                        }
+                        session.push_event(product_added).await;
 
                         event!(Level::TRACE, "committing");
-                        self.uow.commit().await.unwrap();
+                        self.uow.commit(session).await?;
                         event!(Level::TRACE, "committed");
 
+                        metrics::histogram!(CART_ITEMS, "operation" => "add_product")
+                            .record(updated_cart.item_count() as f64);
+
                         Ok(AddProductToCartResponse {
                             cart_id: updated_cart.id,
                         })
                     }
                     Err(e) => {
-                        self.uow.rollback().await.unwrap();
-
                         event!(
                             Level::WARN,
                             "Failed to update Cart with ID {}: {}",
-                            input.cart_id,
+                            cart_id,
                             e
                         );
-                        Err(format!(
-                            "Failed to update Cart with ID {}: {}",
-                            input.cart_id, e
-                        ))
+                        if let Err(rollback_error) = self.uow.rollback(session).await {
+                            event!(Level::WARN, "{}", rollback_error);
+                        }
+                        Err(format!("Failed to update Cart with ID {}: {}", cart_id, e).into())
                     }
                 }
             }
@@ -187,94 +813,257 @@ impl CommandHandler<AddProductToCartCommand, AddProductToCartResponse>
                 event!(
                     Level::WARN,
                     "Failed to find Cart with ID {}: {}",
-                    input.cart_id,
+                    cart_id,
                     e
                 );
-                Err(format!(
-                    "Failed to find Cart with ID {}: {}",
-                    input.cart_id, e
-                ))
+                Err(cart_read_error(cart_id, e))
             }
         }
     }
 }
 
-pub struct RemoveProductFromCartCommandHandler {
-    uow: Arc<OrderUnitOfWork>,
-}
-
-impl RemoveProductFromCartCommandHandler {
-    pub fn new(uow: Arc<OrderUnitOfWork>) -> Self {
-        RemoveProductFromCartCommandHandler { uow: uow }
-    }
-}
-
-impl CommandHandler<RemoveProductFromCartCommand, EmptyResponse>
-    for RemoveProductFromCartCommandHandler
-{
-    async fn handle(&self, input: &RemoveProductFromCartCommand) -> Result<EmptyResponse, String> {
-        if input.cart_id.is_empty() {
-            return Err(String::from("Cart ID cannot be null or empty!!!"));
-        }
-
-        if input.product_id.is_empty() {
-            return Err(String::from("Product ID cannot be null or empty!!!"));
+#[async_trait]
+impl CommandHandler<AddProductToCartCommand> for AddProductToCartCommandHandler {
+    async fn handle(
+        &self,
+        input: &AddProductToCartCommand,
+    ) -> Result<AddProductToCartResponse, HandlerError> {
+        if input.quantity.unwrap_or(1) > self.max_quantity {
+            return Err(HandlerError::invalid(
+                "quantity_too_large",
+                &[("max_quantity", &self.max_quantity.to_string())],
+            ));
         }
 
         let cart_repository = self.uow.get_cart_repository().await;
 
-        match cart_repository.read(&input.cart_id).await {
-            Ok(mut found_cart) => {
-                match found_cart.products.get(&input.product_id) {
-                    Some(current_product_quantity) => {
-                        if *current_product_quantity == 1 {
-                            found_cart.products.retain(|k, _| *k != input.product_id);
-                        } else {
-                            found_cart
-                                .products
-                                .insert(input.product_id.clone(), current_product_quantity - 1);
-                        }
-                    }
+        // Quick-add flows omit the cart ID and target the caller's default cart
+        let cart_id = if input.cart_id.is_empty() {
+            match cart_repository.read_all_by_user_id(&input.user_id).await {
+                Ok(carts) => match carts.into_iter().find(|c| c.is_default) {
+                    Some(default_cart) => default_cart.id,
                     None => {
-                        return Err(format!("Cart with id {} was not found", input.cart_id));
+                        return Err(HandlerError::not_found(
+                            "default_cart_missing",
+                            &[("user_id", input.user_id.as_str())],
+                        ))
                     }
+                },
+                Err(e) => {
+                    event!(
+                        Level::WARN,
+                        "Error occurred while finding user carts: {}",
+                        e
+                    );
+                    return Err(e.into());
+                }
+            }
+        } else {
+            input.cart_id.clone()
+        };
+
+        let mut attempt = 1;
+        loop {
+            match self.add_to_cart(&cart_id, input).await {
+                Err(e) if self.should_merge(&e, attempt) => {
+                    event!(
+                        Level::DEBUG,
+                        "Cart with ID {} changed concurrently, re-applying: {}",
+                        cart_id,
+                        e
+                    );
+                    attempt += 1;
+                    merge_backoff().await;
                 }
+                result => return result,
+            }
+        }
+    }
+}
+
+pub struct RemoveProductFromCartCommandHandler {
+    uow: Arc<OrderUnitOfWork>,
+    conflict_strategy: CartConflictStrategy,
+}
+
+impl RemoveProductFromCartCommandHandler {
+    pub fn new(uow: Arc<OrderUnitOfWork>, conflict_strategy: CartConflictStrategy) -> Self {
+        RemoveProductFromCartCommandHandler {
+            uow,
+            conflict_strategy,
+        }
+    }
+
+    fn should_merge(&self, error: &HandlerError, attempt: u32) -> bool {
+        self.conflict_strategy == CartConflictStrategy::Merge
+            && matches!(error, HandlerError::Internal(e) if is_version_conflict(e))
+            && attempt < MAX_MERGE_ATTEMPTS
+    }
+
+    // Reads the cart, applies the decrement and writes it back against the version that was read
+    async fn remove_from_cart(
+        &self,
+        input: &RemoveProductFromCartCommand,
+    ) -> Result<EmptyResponse, HandlerError> {
+        let cart_repository = self.uow.get_cart_repository().await;
+
+        match cart_repository.read(&input.cart_id).await {
+            Ok(mut found_cart) => {
+                ensure_cart_owner(&found_cart, &input.user_id)?;
+
+                let product_removed = found_cart.remove_product(&input.product_id)?;
 
-                let session = self.uow.begin_transaction().await;
+                let session = self.uow.begin_transaction().await?;
 
                 match cart_repository
-                    .update(input.cart_id.clone(), found_cart, session)
+                    .update(input.cart_id.clone(), found_cart, session.clone())
                     .await
                 {
-                    Ok(_) => {
-                        {
-                            let events_to_publish = self.uow.get_events_to_publish().await;
-                            let mut event_lock = events_to_publish.lock().await;
+                    Ok(updated_cart) => {
+                        session.push_event(product_removed).await;
+
+                        event!(Level::TRACE, "committing");
+                        self.uow.commit(session).await?;
+                        event!(Level::TRACE, "committed");
+
+                        metrics::histogram!(CART_ITEMS, "operation" => "remove_product")
+                            .record(updated_cart.item_count() as f64);
 
-                            event_lock.push(Event::ProductRemovedFromCartEvent {
-                                product_id: input.product_id.clone(),
-                            });
+                        Ok(EmptyResponse {})
+                    }
+                    Err(e) => {
+                        event!(
+                            Level::WARN,
+                            "Failed to update Cart with ID {}: {}",
+                            input.cart_id,
+                            e
+                        );
+                        if let Err(rollback_error) = self.uow.rollback(session).await {
+                            event!(Level::WARN, "{}", rollback_error);
                         }
+                        Err(
+                            format!("Failed to update Cart with ID {}: {}", input.cart_id, e)
+                                .into(),
+                        )
+                    }
+                }
+            }
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Failed to find Cart with ID {}: {}",
+                    input.cart_id,
+                    e
+                );
+                Err(cart_read_error(&input.cart_id, e))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl CommandHandler<RemoveProductFromCartCommand> for RemoveProductFromCartCommandHandler {
+    async fn handle(
+        &self,
+        input: &RemoveProductFromCartCommand,
+    ) -> Result<EmptyResponse, HandlerError> {
+        let mut attempt = 1;
+        loop {
+            match self.remove_from_cart(input).await {
+                Err(e) if self.should_merge(&e, attempt) => {
+                    event!(
+                        Level::DEBUG,
+                        "Cart with ID {} changed concurrently, re-applying: {}",
+                        input.cart_id,
+                        e
+                    );
+                    attempt += 1;
+                    merge_backoff().await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+pub struct SetProductQuantityCommandHandler {
+    uow: Arc<OrderUnitOfWork>,
+    conflict_strategy: CartConflictStrategy,
+    max_quantity: i32,
+}
+
+impl SetProductQuantityCommandHandler {
+    pub fn new(
+        uow: Arc<OrderUnitOfWork>,
+        conflict_strategy: CartConflictStrategy,
+        max_quantity: i32,
+    ) -> Self {
+        SetProductQuantityCommandHandler {
+            uow,
+            conflict_strategy,
+            max_quantity,
+        }
+    }
+
+    fn should_merge(&self, error: &HandlerError, attempt: u32) -> bool {
+        self.conflict_strategy == CartConflictStrategy::Merge
+            && matches!(error, HandlerError::Internal(e) if is_version_conflict(e))
+            && attempt < MAX_MERGE_ATTEMPTS
+    }
+
+    // Reads the cart, sets the quantity and writes it back against the version that was read. Being
+    // absolute, re-applying it after a conflict gives the same cart however often it is repeated
+    async fn set_quantity(
+        &self,
+        input: &SetProductQuantityCommand,
+    ) -> Result<EmptyResponse, HandlerError> {
+        let cart_repository = self.uow.get_cart_repository().await;
+
+        match cart_repository.read(&input.cart_id).await {
+            Ok(mut found_cart) => {
+                ensure_cart_owner(&found_cart, &input.user_id)?;
+
+                let quantity_changed = match found_cart.set_product_quantity(
+                    &input.product_id,
+                    input.quantity,
+                    Utc::now(),
+                ) {
+                    Some(quantity_changed) => quantity_changed,
+                    None => return Ok(EmptyResponse {}),
+                };
+
+                let session = self.uow.begin_transaction().await?;
+
+                match cart_repository
+                    .update(input.cart_id.clone(), found_cart, session.clone())
+                    .await
+                {
+                    Ok(updated_cart) => {
+                        session.push_event(quantity_changed).await;
 
                         event!(Level::TRACE, "committing");
-                        self.uow.commit().await.unwrap();
+                        self.uow.commit(session).await?;
                         event!(Level::TRACE, "committed");
 
+                        metrics::histogram!(CART_ITEMS, "operation" => "set_quantity")
+                            .record(updated_cart.item_count() as f64);
+
                         Ok(EmptyResponse {})
                     }
                     Err(e) => {
-                        self.uow.rollback().await.unwrap();
-
                         event!(
                             Level::WARN,
                             "Failed to update Cart with ID {}: {}",
                             input.cart_id,
                             e
                         );
-                        Err(format!(
-                            "Failed to update Cart with ID {}: {}",
-                            input.cart_id, e
-                        ))
+                        if let Err(rollback_error) = self.uow.rollback(session).await {
+                            event!(Level::WARN, "{}", rollback_error);
+                        }
+                        Err(
+                            format!("Failed to update Cart with ID {}: {}", input.cart_id, e)
+                                .into(),
+                        )
                     }
                 }
             }
@@ -285,10 +1074,116 @@ impl CommandHandler<RemoveProductFromCartCommand, EmptyResponse>
                     input.cart_id,
                     e
                 );
-                Err(format!(
-                    "Failed to find Cart with ID {}: {}",
-                    input.cart_id, e
-                ))
+                Err(cart_read_error(&input.cart_id, e))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl CommandHandler<SetProductQuantityCommand> for SetProductQuantityCommandHandler {
+    async fn handle(
+        &self,
+        input: &SetProductQuantityCommand,
+    ) -> Result<EmptyResponse, HandlerError> {
+        if input.quantity > self.max_quantity {
+            return Err(HandlerError::invalid(
+                "quantity_too_large",
+                &[("max_quantity", &self.max_quantity.to_string())],
+            ));
+        }
+
+        let mut attempt = 1;
+        loop {
+            match self.set_quantity(input).await {
+                Err(e) if self.should_merge(&e, attempt) => {
+                    event!(
+                        Level::DEBUG,
+                        "Cart with ID {} changed concurrently, re-applying: {}",
+                        input.cart_id,
+                        e
+                    );
+                    attempt += 1;
+                    merge_backoff().await;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+pub struct SetDefaultCartCommandHandler {
+    uow: Arc<OrderUnitOfWork>,
+}
+
+impl SetDefaultCartCommandHandler {
+    pub fn new(uow: Arc<OrderUnitOfWork>) -> Self {
+        SetDefaultCartCommandHandler { uow }
+    }
+}
+
+#[async_trait]
+impl CommandHandler<SetDefaultCartCommand> for SetDefaultCartCommandHandler {
+    async fn handle(&self, input: &SetDefaultCartCommand) -> Result<EmptyResponse, HandlerError> {
+        let cart_repository = self.uow.get_cart_repository().await;
+
+        match cart_repository.read_all_by_user_id(&input.user_id).await {
+            Ok(user_carts) => {
+                if !user_carts.iter().any(|c| c.id == input.cart_id) {
+                    event!(
+                        Level::WARN,
+                        "Cart with ID {} does not belong to user {}",
+                        input.cart_id,
+                        input.user_id
+                    );
+                    return Err(HandlerError::not_found(
+                        "cart_not_found",
+                        &[("cart_id", input.cart_id.as_str())],
+                    ));
+                }
+
+                let session = self.uow.begin_transaction().await?;
+
+                for mut user_cart in user_carts {
+                    let is_default = user_cart.id == input.cart_id;
+                    if user_cart.is_default == is_default {
+                        continue;
+                    }
+
+                    user_cart.is_default = is_default;
+
+                    if let Err(e) = cart_repository
+                        .update(user_cart.id.clone(), user_cart, session.clone())
+                        .await
+                    {
+                        event!(
+                            Level::WARN,
+                            "Failed to set default Cart with ID {}: {}",
+                            input.cart_id,
+                            e
+                        );
+                        if let Err(rollback_error) = self.uow.rollback(session).await {
+                            event!(Level::WARN, "{}", rollback_error);
+                        }
+                        return Err(format!(
+                            "Failed to set default Cart with ID {}: {}",
+                            input.cart_id, e
+                        )
+                        .into());
+                    }
+                }
+
+                self.uow.commit(session).await?;
+
+                Ok(EmptyResponse {})
+            }
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Error occurred while finding user carts: {}",
+                    e
+                );
+                Err(e.into())
             }
         }
     }
@@ -300,38 +1195,1822 @@ pub struct GetCartsQueryHandler {
 
 impl GetCartsQueryHandler {
     pub fn new(uow: Arc<OrderUnitOfWork>) -> Self {
-        GetCartsQueryHandler { uow: uow }
+        GetCartsQueryHandler { uow }
     }
 }
 
-impl QueryHandler<GetCartsQuery, GetCartsResponse> for GetCartsQueryHandler {
+#[async_trait]
+impl QueryHandler<GetCartsQuery> for GetCartsQueryHandler {
     async fn handle(
         &self,
         input_option: Option<GetCartsQuery>,
-    ) -> Result<GetCartsResponse, String> {
+    ) -> Result<GetCartsResponse, HandlerError> {
         let cart_repository = self.uow.get_cart_repository().await;
 
         match input_option {
-            Some(input) => match cart_repository.read(input.id.as_str()).await {
+            Some(input) if !input.id.is_empty() => match cart_repository.read(&input.id).await {
                 Ok(domain_cart) => {
-                    let mut carts = Vec::new();
+                    ensure_cart_owner(&domain_cart, &input.user_id)?;
 
-                    carts.push(CartResponse {
+                    let carts = vec![CartResponse {
                         id: domain_cart.id.clone(),
-                        products: domain_cart.products.clone(),
-                    });
+                        name: domain_cart.name.clone(),
+                        is_default: domain_cart.is_default,
+                        products: domain_cart
+                            .products
+                            .iter()
+                            .cloned()
+                            .map(CartLineItemResponse::from)
+                            .collect(),
+                        links: BTreeMap::new(),
+                    }];
 
-                    Ok(GetCartsResponse { carts: carts })
+                    Ok(GetCartsResponse { carts, page: None })
                 }
                 Err(e) => {
                     event!(Level::WARN, "Error occurred while finding cart: {}", e);
-                    Err(e)
+                    Err(cart_read_error(&input.id, e))
                 }
             },
-            None => {
-                event!(Level::INFO, "NOT SUPPORTED YET");
-                Ok(GetCartsResponse { carts: Vec::new() })
+            input_option => {
+                let input = input_option.unwrap_or_default();
+                let page = input.page.max(1);
+                let page_size = match input.page_size {
+                    0 => DEFAULT_PAGE_SIZE,
+                    page_size => page_size.min(MAX_PAGE_SIZE),
+                };
+
+                match cart_repository.read_all().await {
+                    Ok(mut domain_carts) => {
+                        // Sort so pages are stable regardless of the order the backend returns
+                        domain_carts.sort_by(|a, b| {
+                            a.created_at_utc
+                                .cmp(&b.created_at_utc)
+                                .then_with(|| a.id.cmp(&b.id))
+                        });
+                        let total = domain_carts.len() as u64;
+
+                        Ok(GetCartsResponse {
+                            carts: domain_carts
+                                .into_iter()
+                                .skip((page as usize - 1).saturating_mul(page_size as usize))
+                                .take(page_size as usize)
+                                .map(|c| CartResponse {
+                                    id: c.id,
+                                    name: c.name,
+                                    is_default: c.is_default,
+                                    products: c
+                                        .products
+                                        .into_iter()
+                                        .map(CartLineItemResponse::from)
+                                        .collect(),
+                                    links: BTreeMap::new(),
+                                })
+                                .collect(),
+                            page: Some(PageInfo {
+                                page,
+                                page_size,
+                                total,
+                            }),
+                        })
+                    }
+                    Err(e) => {
+                        event!(Level::WARN, "Error occurred while listing carts: {}", e);
+                        Err(e.into())
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct GetUserCartsQueryHandler {
+    uow: Arc<OrderUnitOfWork>,
+}
+
+impl GetUserCartsQueryHandler {
+    pub fn new(uow: Arc<OrderUnitOfWork>) -> Self {
+        GetUserCartsQueryHandler { uow }
+    }
+}
+
+#[async_trait]
+impl QueryHandler<GetUserCartsQuery> for GetUserCartsQueryHandler {
+    async fn handle(
+        &self,
+        input_option: Option<GetUserCartsQuery>,
+    ) -> Result<GetCartsResponse, HandlerError> {
+        let input = match input_option {
+            Some(input) => input,
+            None => return Err(HandlerError::invalid("user_id_required", &[])),
+        };
+
+        let cart_repository = self.uow.get_cart_repository().await;
+
+        match cart_repository.read_all_by_user_id(&input.user_id).await {
+            Ok(mut domain_carts) => {
+                domain_carts.sort_by_key(|c| c.created_at_utc);
+
+                Ok(GetCartsResponse {
+                    carts: domain_carts
+                        .into_iter()
+                        .map(|c| CartResponse {
+                            id: c.id,
+                            name: c.name,
+                            is_default: c.is_default,
+                            products: c
+                                .products
+                                .into_iter()
+                                .map(CartLineItemResponse::from)
+                                .collect(),
+                            links: BTreeMap::new(),
+                        })
+                        .collect(),
+                    page: None,
+                })
+            }
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Error occurred while finding user carts: {}",
+                    e
+                );
+                Err(e.into())
+            }
+        }
+    }
+}
+
+pub struct GetCartsContainingProductQueryHandler {
+    uow: Arc<OrderUnitOfWork>,
+}
+
+impl GetCartsContainingProductQueryHandler {
+    pub fn new(uow: Arc<OrderUnitOfWork>) -> Self {
+        GetCartsContainingProductQueryHandler { uow }
+    }
+}
+
+#[async_trait]
+impl QueryHandler<GetCartsContainingProductQuery> for GetCartsContainingProductQueryHandler {
+    async fn handle(
+        &self,
+        input_option: Option<GetCartsContainingProductQuery>,
+    ) -> Result<GetCartsResponse, HandlerError> {
+        let input = match input_option {
+            Some(input) if !input.product_id.is_empty() => input,
+            _ => return Err(HandlerError::invalid("product_id_required", &[])),
+        };
+
+        let cart_repository = self.uow.get_cart_repository().await;
+
+        match cart_repository
+            .read_all_containing_product(&input.product_id)
+            .await
+        {
+            Ok(mut domain_carts) => {
+                domain_carts.sort_by(|a, b| {
+                    a.created_at_utc
+                        .cmp(&b.created_at_utc)
+                        .then_with(|| a.id.cmp(&b.id))
+                });
+
+                Ok(GetCartsResponse {
+                    carts: domain_carts
+                        .into_iter()
+                        .map(|c| CartResponse {
+                            id: c.id,
+                            name: c.name,
+                            is_default: c.is_default,
+                            products: c
+                                .products
+                                .into_iter()
+                                .map(CartLineItemResponse::from)
+                                .collect(),
+                            links: BTreeMap::new(),
+                        })
+                        .collect(),
+                    page: None,
+                })
+            }
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Error occurred while finding carts containing Product {}: {}",
+                    input.product_id,
+                    e
+                );
+                Err(e.into())
+            }
+        }
+    }
+}
+
+pub struct ShareCartCommandHandler {
+    uow: Arc<OrderUnitOfWork>,
+    token_signer: TokenSigner,
+    share_ttl_millis: i64,
+}
+
+impl ShareCartCommandHandler {
+    pub fn new(
+        uow: Arc<OrderUnitOfWork>,
+        token_signer: TokenSigner,
+        share_ttl_millis: i64,
+    ) -> Self {
+        ShareCartCommandHandler {
+            uow,
+            token_signer,
+            share_ttl_millis,
+        }
+    }
+}
+
+#[async_trait]
+impl CommandHandler<ShareCartCommand> for ShareCartCommandHandler {
+    async fn handle(&self, input: &ShareCartCommand) -> Result<ShareCartResponse, HandlerError> {
+        let cart_repository = self.uow.get_cart_repository().await;
+
+        match cart_repository.read(&input.cart_id).await {
+            Ok(found_cart) => {
+                ensure_cart_owner(&found_cart, &input.user_id)?;
+
+                let expires_at_utc = now_utc_millis() + self.share_ttl_millis;
+                let token = self.token_signer.sign(
+                    &format!("{}{}", SHARED_CART_TOKEN_PREFIX, found_cart.id),
+                    expires_at_utc,
+                );
+
+                Ok(ShareCartResponse {
+                    token,
+                    expires_at_utc: DateTime::from_timestamp_millis(expires_at_utc)
+                        .unwrap_or_default(),
+                })
+            }
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Failed to find Cart with ID {}: {}",
+                    input.cart_id,
+                    e
+                );
+                Err(cart_read_error(&input.cart_id, e))
+            }
+        }
+    }
+}
+
+pub struct GetSharedCartQueryHandler {
+    uow: Arc<OrderUnitOfWork>,
+    token_signer: TokenSigner,
+}
+
+impl GetSharedCartQueryHandler {
+    pub fn new(uow: Arc<OrderUnitOfWork>, token_signer: TokenSigner) -> Self {
+        GetSharedCartQueryHandler { uow, token_signer }
+    }
+}
+
+#[async_trait]
+impl QueryHandler<GetSharedCartQuery> for GetSharedCartQueryHandler {
+    async fn handle(
+        &self,
+        input_option: Option<GetSharedCartQuery>,
+    ) -> Result<SharedCartResponse, HandlerError> {
+        let input = match input_option {
+            Some(input) => input,
+            None => return Err(HandlerError::invalid("token_required", &[])),
+        };
+
+        let cart_id = match verify_shared_cart_token(&self.token_signer, &input.token) {
+            Ok(cart_id) => cart_id,
+            Err(e) => {
+                event!(Level::WARN, "Invalid shared cart token: {}", e);
+                return Err(e);
+            }
+        };
+
+        let cart_repository = self.uow.get_cart_repository().await;
+
+        match cart_repository.read(&cart_id).await {
+            Ok(domain_cart) => Ok(SharedCartResponse {
+                name: domain_cart.name,
+                products: domain_cart
+                    .products
+                    .into_iter()
+                    .map(CartLineItemResponse::from)
+                    .collect(),
+            }),
+            Err(e) => {
+                event!(Level::WARN, "Error occurred while finding cart: {}", e);
+                Err(cart_read_error(&cart_id, e))
             }
         }
     }
 }
+
+pub struct CloneSharedCartCommandHandler {
+    uow: Arc<OrderUnitOfWork>,
+    token_signer: TokenSigner,
+}
+
+impl CloneSharedCartCommandHandler {
+    pub fn new(uow: Arc<OrderUnitOfWork>, token_signer: TokenSigner) -> Self {
+        CloneSharedCartCommandHandler { uow, token_signer }
+    }
+}
+
+#[async_trait]
+impl CommandHandler<CloneSharedCartCommand> for CloneSharedCartCommandHandler {
+    async fn handle(
+        &self,
+        input: &CloneSharedCartCommand,
+    ) -> Result<CreateCartResponse, HandlerError> {
+        let cart_id = match verify_shared_cart_token(&self.token_signer, &input.token) {
+            Ok(cart_id) => cart_id,
+            Err(e) => {
+                event!(Level::WARN, "Invalid shared cart token: {}", e);
+                return Err(e);
+            }
+        };
+
+        let cart_repository = self.uow.get_cart_repository().await;
+
+        let shared_cart = match cart_repository.read(&cart_id).await {
+            Ok(cart) => cart,
+            Err(e) => {
+                event!(Level::WARN, "Error occurred while finding cart: {}", e);
+                return Err(cart_read_error(&cart_id, e));
+            }
+        };
+
+        let has_carts = match cart_repository.read_all_by_user_id(&input.user_id).await {
+            Ok(carts) => !carts.is_empty(),
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Error occurred while finding user carts: {}",
+                    e
+                );
+                return Err(e.into());
+            }
+        };
+
+        let now_utc = Utc::now();
+
+        let domain_cart = Cart {
+            id: CartId::generate(),
+            user_id: input.user_id.clone(),
+            name: shared_cart.name,
+            is_default: !has_carts,
+            // The copy's lines count as added now, in the order they were in the shared cart
+            products: shared_cart
+                .products
+                .into_iter()
+                .map(|line_item| CartLineItem {
+                    added_at_utc: now_utc,
+                    ..line_item
+                })
+                .collect(),
+            created_at_utc: now_utc,
+            updated_at_utc: now_utc,
+            version: 0,
+        };
+
+        let session = self.uow.begin_transaction().await?;
+
+        match cart_repository
+            .create(domain_cart.id.clone(), domain_cart, session.clone())
+            .await
+        {
+            Ok(created_cart) => match self.uow.commit(session).await {
+                Ok(()) => Ok(CreateCartResponse {
+                    id: created_cart.id.clone(),
+                    cart_session_token: None,
+                    links: BTreeMap::new(),
+                }),
+                Err(e) => {
+                    event!(Level::WARN, "Error occurred while cloning cart: {}", e);
+                    Err(e.into())
+                }
+            },
+            Err(e) => {
+                event!(Level::WARN, "Error occurred while cloning cart: {}", e);
+                if let Err(rollback_error) = self.uow.rollback(session).await {
+                    event!(Level::WARN, "{}", rollback_error);
+                }
+                Err(e.into())
+            }
+        }
+    }
+}
+
+pub struct ClaimGuestCartCommandHandler {
+    uow: Arc<OrderUnitOfWork>,
+    cart_session_token_signer: TokenSigner,
+}
+
+impl ClaimGuestCartCommandHandler {
+    pub fn new(uow: Arc<OrderUnitOfWork>, cart_session_token_signer: TokenSigner) -> Self {
+        ClaimGuestCartCommandHandler {
+            uow,
+            cart_session_token_signer,
+        }
+    }
+}
+
+#[async_trait]
+impl CommandHandler<ClaimGuestCartCommand> for ClaimGuestCartCommandHandler {
+    async fn handle(&self, input: &ClaimGuestCartCommand) -> Result<EmptyResponse, HandlerError> {
+        match verify_cart_session_token(&self.cart_session_token_signer, &input.cart_session_token)
+        {
+            Ok(session_cart_id) if session_cart_id == input.cart_id => {}
+            Ok(_) => {
+                event!(
+                    Level::WARN,
+                    "Cart session token does not grant access to Cart with ID {}",
+                    input.cart_id
+                );
+                return Err(HandlerError::rejected(
+                    "cart_session_token_mismatch",
+                    &[("cart_id", input.cart_id.as_str())],
+                ));
+            }
+            Err(e) => {
+                event!(Level::WARN, "Invalid cart session token: {}", e);
+                return Err(HandlerError::unauthenticated(
+                    "invalid_cart_session_token",
+                    &[],
+                ));
+            }
+        }
+
+        let cart_repository = self.uow.get_cart_repository().await;
+
+        let has_default_cart = match cart_repository.read_all_by_user_id(&input.user_id).await {
+            Ok(carts) => carts.iter().any(|c| c.is_default),
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Error occurred while finding user carts: {}",
+                    e
+                );
+                return Err(e.into());
+            }
+        };
+
+        match cart_repository.read(&input.cart_id).await {
+            Ok(mut found_cart) => {
+                if !found_cart.user_id.is_empty() {
+                    event!(
+                        Level::WARN,
+                        "Cart with ID {} has already been claimed",
+                        input.cart_id
+                    );
+                    return Err(HandlerError::conflict(
+                        "cart_already_claimed",
+                        &[("cart_id", input.cart_id.as_str())],
+                    ));
+                }
+
+                found_cart.user_id = input.user_id.clone();
+                found_cart.is_default = !has_default_cart;
+
+                let session = self.uow.begin_transaction().await?;
+
+                match cart_repository
+                    .update(input.cart_id.clone(), found_cart, session.clone())
+                    .await
+                {
+                    Ok(_) => {
+                        self.uow.commit(session).await?;
+
+                        Ok(EmptyResponse {})
+                    }
+                    Err(e) => {
+                        event!(
+                            Level::WARN,
+                            "Failed to claim Cart with ID {}: {}",
+                            input.cart_id,
+                            e
+                        );
+                        if let Err(rollback_error) = self.uow.rollback(session).await {
+                            event!(Level::WARN, "{}", rollback_error);
+                        }
+                        Err(format!("Failed to claim Cart with ID {}: {}", input.cart_id, e).into())
+                    }
+                }
+            }
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Failed to find Cart with ID {}: {}",
+                    input.cart_id,
+                    e
+                );
+                Err(cart_read_error(&input.cart_id, e))
+            }
+        }
+    }
+}
+
+pub struct GetDeliverySlotsQueryHandler {
+    uow: Arc<OrderUnitOfWork>,
+    delivery_slot_provider: Arc<dyn DeliverySlotProvider + Send + Sync>,
+}
+
+impl GetDeliverySlotsQueryHandler {
+    pub fn new(
+        uow: Arc<OrderUnitOfWork>,
+        delivery_slot_provider: Arc<dyn DeliverySlotProvider + Send + Sync>,
+    ) -> Self {
+        GetDeliverySlotsQueryHandler {
+            uow,
+            delivery_slot_provider,
+        }
+    }
+}
+
+#[async_trait]
+impl QueryHandler<GetDeliverySlotsQuery> for GetDeliverySlotsQueryHandler {
+    async fn handle(
+        &self,
+        input_option: Option<GetDeliverySlotsQuery>,
+    ) -> Result<DeliverySlotsResponse, HandlerError> {
+        let input = match input_option {
+            Some(input) if !input.cart_id.is_empty() => input,
+            _ => return Err(HandlerError::invalid("cart_id_required", &[])),
+        };
+
+        let cart_repository = self.uow.get_cart_repository().await;
+
+        let cart = match cart_repository.read(&input.cart_id).await {
+            Ok(cart) => {
+                ensure_cart_owner(&cart, &input.user_id)?;
+                cart
+            }
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Failed to find Cart with ID {}: {}",
+                    input.cart_id,
+                    e
+                );
+                return Err(cart_read_error(&input.cart_id, e));
+            }
+        };
+
+        match self.delivery_slot_provider.slots(&cart, Utc::now()).await {
+            Ok(slots) => Ok(DeliverySlotsResponse {
+                cart_id: cart.id,
+                slots: slots
+                    .into_iter()
+                    .map(|slot| DeliverySlotResponse {
+                        available: slot.remaining_capacity() > 0,
+                        remaining_capacity: slot.remaining_capacity(),
+                        id: slot.id,
+                        starts_at_utc: slot.starts_at_utc,
+                        ends_at_utc: slot.ends_at_utc,
+                    })
+                    .collect(),
+            }),
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Failed to get delivery slots for Cart with ID {}: {}",
+                    input.cart_id,
+                    e
+                );
+                Err(format!("Failed to get delivery slots: {}", e).into())
+            }
+        }
+    }
+}
+
+pub struct CancelOrderCommandHandler {
+    uow: Arc<OrderUnitOfWork>,
+}
+
+impl CancelOrderCommandHandler {
+    pub fn new(uow: Arc<OrderUnitOfWork>) -> Self {
+        CancelOrderCommandHandler { uow }
+    }
+}
+
+#[async_trait]
+impl CommandHandler<CancelOrderCommand> for CancelOrderCommandHandler {
+    async fn handle(&self, input: &CancelOrderCommand) -> Result<OrderResponse, HandlerError> {
+        let order_repository = self.uow.get_order_repository().await;
+
+        let mut order = match order_repository.read(&input.id).await {
+            Ok(order) => order,
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Failed to find Order with ID {}: {}",
+                    input.id,
+                    e
+                );
+                return Err(order_read_error(&input.id, e));
+            }
+        };
+
+        // Only orders that haven't shipped yet can be cancelled
+        if let Err(e) = order.transition_to(
+            OrderStatus::Cancelled,
+            &input.cancelled_by,
+            &input.reason,
+            Utc::now(),
+        ) {
+            event!(
+                Level::WARN,
+                "Order with ID {} can no longer be cancelled: {}",
+                input.id,
+                e
+            );
+            return Err(HandlerError::conflict(
+                "order_not_cancellable",
+                &[("order_id", input.id.as_str())],
+            ));
+        }
+
+        let session = self.uow.begin_transaction().await?;
+
+        let cancelled_order = match order_repository
+            .update(input.id.clone(), order, session.clone())
+            .await
+        {
+            Ok(cancelled_order) => cancelled_order,
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Failed to cancel Order with ID {}: {}",
+                    input.id,
+                    e
+                );
+                if let Err(rollback_error) = self.uow.rollback(session).await {
+                    event!(Level::WARN, "{}", rollback_error);
+                }
+                return Err(format!("Failed to cancel Order with ID {}: {}", input.id, e).into());
+            }
+        };
+
+        session
+            .push_event(Event::OrderCancelledEvent {
+                order_id: cancelled_order.id.clone(),
+                payment_id: cancelled_order.payment_id.clone(),
+                products: cancelled_order.products.clone(),
+                reason: input.reason.clone(),
+                cancelled_at_utc: cancelled_order.updated_at_utc.timestamp_millis(),
+            })
+            .await;
+
+        match self.uow.commit(session).await {
+            Ok(()) => Ok(OrderResponse {
+                id: cancelled_order.id,
+                status: cancelled_order.status,
+                products: cancelled_order.products,
+                created_at_utc: cancelled_order.created_at_utc,
+                updated_at_utc: cancelled_order.updated_at_utc,
+                links: BTreeMap::new(),
+            }),
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Failed to cancel Order with ID {}: {}",
+                    input.id,
+                    e
+                );
+                Err(e.into())
+            }
+        }
+    }
+}
+
+pub struct MarkOrderPaidCommandHandler {
+    uow: Arc<OrderUnitOfWork>,
+}
+
+impl MarkOrderPaidCommandHandler {
+    pub fn new(uow: Arc<OrderUnitOfWork>) -> Self {
+        MarkOrderPaidCommandHandler { uow }
+    }
+}
+
+#[async_trait]
+impl CommandHandler<MarkOrderPaidCommand> for MarkOrderPaidCommandHandler {
+    async fn handle(&self, input: &MarkOrderPaidCommand) -> Result<EmptyResponse, HandlerError> {
+        let order_repository = self.uow.get_order_repository().await;
+
+        let mut order = match order_repository.read(&input.id).await {
+            Ok(order) => order,
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Failed to find Order with ID {}: {}",
+                    input.id,
+                    e
+                );
+                return Err(order_read_error(&input.id, e));
+            }
+        };
+
+        // Payment events can be delivered more than once
+        if matches!(
+            order.status,
+            OrderStatus::Paid | OrderStatus::Shipped | OrderStatus::Delivered
+        ) {
+            event!(
+                Level::DEBUG,
+                "Order with ID {} is already paid, ignoring payment {}",
+                input.id,
+                input.payment_id
+            );
+            return Ok(EmptyResponse {});
+        }
+
+        if let Err(e) = order.transition_to(
+            OrderStatus::Paid,
+            "payments",
+            &format!("Payment {} completed", input.payment_id),
+            Utc::now(),
+        ) {
+            return Err(format!("Failed to mark Order with ID {} as paid: {}", input.id, e).into());
+        }
+        order.payment_id = input.payment_id.clone();
+
+        let session = self.uow.begin_transaction().await?;
+
+        if let Err(e) = order_repository
+            .update(input.id.clone(), order, session.clone())
+            .await
+        {
+            event!(
+                Level::WARN,
+                "Failed to mark Order with ID {} as paid: {}",
+                input.id,
+                e
+            );
+            if let Err(rollback_error) = self.uow.rollback(session).await {
+                event!(Level::WARN, "{}", rollback_error);
+            }
+            return Err(format!("Failed to mark Order with ID {} as paid: {}", input.id, e).into());
+        }
+
+        self.uow.commit(session).await?;
+
+        Ok(EmptyResponse {})
+    }
+}
+
+pub struct DiscardProductFromCartsCommandHandler {
+    uow: Arc<OrderUnitOfWork>,
+}
+
+impl DiscardProductFromCartsCommandHandler {
+    pub fn new(uow: Arc<OrderUnitOfWork>) -> Self {
+        DiscardProductFromCartsCommandHandler { uow }
+    }
+}
+
+#[async_trait]
+impl CommandHandler<DiscardProductFromCartsCommand> for DiscardProductFromCartsCommandHandler {
+    async fn handle(
+        &self,
+        input: &DiscardProductFromCartsCommand,
+    ) -> Result<EmptyResponse, HandlerError> {
+        let cart_repository = self.uow.get_cart_repository().await;
+
+        let carts = match cart_repository
+            .read_all_containing_product(&input.product_id)
+            .await
+        {
+            Ok(carts) => carts,
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Error occurred while finding carts containing product {}: {}",
+                    input.product_id,
+                    e
+                );
+                return Err(e.into());
+            }
+        };
+
+        let session = self.uow.begin_transaction().await?;
+
+        for mut cart in carts {
+            if !cart.discard_product(&input.product_id) {
+                continue;
+            }
+
+            if let Err(e) = cart_repository
+                .update(cart.id.clone(), cart, session.clone())
+                .await
+            {
+                event!(
+                    Level::WARN,
+                    "Failed to discard product {} from carts: {}",
+                    input.product_id,
+                    e
+                );
+                if let Err(rollback_error) = self.uow.rollback(session).await {
+                    event!(Level::WARN, "{}", rollback_error);
+                }
+                return Err(format!(
+                    "Failed to discard product {} from carts: {}",
+                    input.product_id, e
+                )
+                .into());
+            }
+        }
+
+        self.uow.commit(session).await?;
+
+        Ok(EmptyResponse {})
+    }
+}
+
+pub struct GetOrderByIdQueryHandler {
+    uow: Arc<OrderUnitOfWork>,
+}
+
+impl GetOrderByIdQueryHandler {
+    pub fn new(uow: Arc<OrderUnitOfWork>) -> Self {
+        GetOrderByIdQueryHandler { uow }
+    }
+}
+
+#[async_trait]
+impl QueryHandler<GetOrderByIdQuery> for GetOrderByIdQueryHandler {
+    async fn handle(
+        &self,
+        input_option: Option<GetOrderByIdQuery>,
+    ) -> Result<OrderResponse, HandlerError> {
+        let input = match input_option {
+            Some(input) if !input.id.is_empty() => input,
+            _ => return Err(HandlerError::invalid("order_id_required", &[])),
+        };
+
+        let order_repository = self.uow.get_order_repository().await;
+
+        match order_repository.read(&input.id).await {
+            Ok(order) => Ok(OrderResponse {
+                id: order.id,
+                status: order.status,
+                products: order.products,
+                created_at_utc: order.created_at_utc,
+                updated_at_utc: order.updated_at_utc,
+                links: BTreeMap::new(),
+            }),
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Failed to find Order with ID {}: {}",
+                    input.id,
+                    e
+                );
+                Err(order_read_error(&input.id, e))
+            }
+        }
+    }
+}
+
+pub struct GetOrdersQueryHandler {
+    uow: Arc<OrderUnitOfWork>,
+}
+
+impl GetOrdersQueryHandler {
+    pub fn new(uow: Arc<OrderUnitOfWork>) -> Self {
+        GetOrdersQueryHandler { uow }
+    }
+}
+
+#[async_trait]
+impl QueryHandler<GetOrdersQuery> for GetOrdersQueryHandler {
+    async fn handle(
+        &self,
+        input_option: Option<GetOrdersQuery>,
+    ) -> Result<GetOrdersResponse, HandlerError> {
+        let input = input_option.unwrap_or_default();
+        let page = input.page.max(1);
+        let page_size = match input.page_size {
+            0 => DEFAULT_PAGE_SIZE,
+            page_size => page_size.min(MAX_PAGE_SIZE),
+        };
+
+        let order_repository = self.uow.get_order_repository().await;
+
+        match order_repository.read_all().await {
+            Ok(mut orders) => {
+                // Newest first, as orders are mostly looked up soon after they are placed
+                orders.sort_by(|a, b| {
+                    b.created_at_utc
+                        .cmp(&a.created_at_utc)
+                        .then_with(|| a.id.cmp(&b.id))
+                });
+                let total = orders.len() as u64;
+
+                Ok(GetOrdersResponse {
+                    orders: orders
+                        .into_iter()
+                        .skip((page as usize - 1).saturating_mul(page_size as usize))
+                        .take(page_size as usize)
+                        .map(|order| OrderResponse {
+                            id: order.id,
+                            status: order.status,
+                            products: order.products,
+                            created_at_utc: order.created_at_utc,
+                            updated_at_utc: order.updated_at_utc,
+                            links: BTreeMap::new(),
+                        })
+                        .collect(),
+                    page: Some(PageInfo {
+                        page,
+                        page_size,
+                        total,
+                    }),
+                })
+            }
+            Err(e) => {
+                event!(Level::WARN, "Error occurred while listing orders: {}", e);
+                Err(e.into())
+            }
+        }
+    }
+}
+
+pub struct GetOrderTimelineQueryHandler {
+    uow: Arc<OrderUnitOfWork>,
+}
+
+impl GetOrderTimelineQueryHandler {
+    pub fn new(uow: Arc<OrderUnitOfWork>) -> Self {
+        GetOrderTimelineQueryHandler { uow }
+    }
+}
+
+#[async_trait]
+impl QueryHandler<GetOrderTimelineQuery> for GetOrderTimelineQueryHandler {
+    async fn handle(
+        &self,
+        input_option: Option<GetOrderTimelineQuery>,
+    ) -> Result<OrderTimelineResponse, HandlerError> {
+        let input = match input_option {
+            Some(input) if !input.id.is_empty() => input,
+            _ => return Err(HandlerError::invalid("order_id_required", &[])),
+        };
+
+        let order_repository = self.uow.get_order_repository().await;
+
+        match order_repository.read(&input.id).await {
+            Ok(order) => Ok(OrderTimelineResponse {
+                order_id: order.id,
+                status: order.status,
+                timeline: order
+                    .status_history
+                    .into_iter()
+                    .map(|transition| OrderStatusTransitionResponse {
+                        from: transition.from,
+                        to: transition.to,
+                        at_utc: transition.at_utc,
+                        actor: transition.actor,
+                        reason: transition.reason,
+                    })
+                    .collect(),
+                links: BTreeMap::new(),
+            }),
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Failed to find Order with ID {}: {}",
+                    input.id,
+                    e
+                );
+                Err(order_read_error(&input.id, e))
+            }
+        }
+    }
+}
+
+// Only paid orders wait in store; anything else has been handed over, shipped or cancelled
+fn ensure_ready_for_pickup(order: &Order) -> Result<(), HandlerError> {
+    match order.status {
+        OrderStatus::Paid => Ok(()),
+        status => {
+            event!(
+                Level::WARN,
+                "Order with ID {} is not ready for pickup: it is {:?}",
+                order.id,
+                status
+            );
+            Err(HandlerError::conflict(
+                "order_not_ready_for_pickup",
+                &[("order_id", order.id.as_str())],
+            ))
+        }
+    }
+}
+
+pub struct GetPickupCodeQueryHandler {
+    uow: Arc<OrderUnitOfWork>,
+    token_signer: TokenSigner,
+    pickup_code_ttl_millis: i64,
+}
+
+impl GetPickupCodeQueryHandler {
+    pub fn new(
+        uow: Arc<OrderUnitOfWork>,
+        token_signer: TokenSigner,
+        pickup_code_ttl_millis: i64,
+    ) -> Self {
+        GetPickupCodeQueryHandler {
+            uow,
+            token_signer,
+            pickup_code_ttl_millis,
+        }
+    }
+}
+
+#[async_trait]
+impl QueryHandler<GetPickupCodeQuery> for GetPickupCodeQueryHandler {
+    async fn handle(
+        &self,
+        input_option: Option<GetPickupCodeQuery>,
+    ) -> Result<PickupCodeResponse, HandlerError> {
+        let input = match input_option {
+            Some(input) if !input.id.is_empty() => input,
+            _ => return Err(HandlerError::invalid("order_id_required", &[])),
+        };
+
+        let order_repository = self.uow.get_order_repository().await;
+
+        match order_repository.read(&input.id).await {
+            Ok(order) => {
+                ensure_ready_for_pickup(&order)?;
+
+                let expires_at_utc = now_utc_millis() + self.pickup_code_ttl_millis;
+                let code = self.token_signer.sign(
+                    &format!("{}{}", PICKUP_CODE_TOKEN_PREFIX, order.id),
+                    expires_at_utc,
+                );
+
+                Ok(PickupCodeResponse {
+                    order_id: order.id,
+                    code,
+                    expires_at_utc: DateTime::from_timestamp_millis(expires_at_utc)
+                        .unwrap_or_default(),
+                })
+            }
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Failed to find Order with ID {}: {}",
+                    input.id,
+                    e
+                );
+                Err(order_read_error(&input.id, e))
+            }
+        }
+    }
+}
+
+pub struct VerifyPickupCodeQueryHandler {
+    uow: Arc<OrderUnitOfWork>,
+    token_signer: TokenSigner,
+}
+
+impl VerifyPickupCodeQueryHandler {
+    pub fn new(uow: Arc<OrderUnitOfWork>, token_signer: TokenSigner) -> Self {
+        VerifyPickupCodeQueryHandler { uow, token_signer }
+    }
+}
+
+#[async_trait]
+impl QueryHandler<VerifyPickupCodeQuery> for VerifyPickupCodeQueryHandler {
+    async fn handle(
+        &self,
+        input_option: Option<VerifyPickupCodeQuery>,
+    ) -> Result<PickupVerificationResponse, HandlerError> {
+        let input = match input_option {
+            Some(input) if !input.id.is_empty() => input,
+            _ => return Err(HandlerError::invalid("order_id_required", &[])),
+        };
+
+        let order_id = match self.token_signer.verify(&input.code) {
+            Ok(payload) => payload
+                .strip_prefix(PICKUP_CODE_TOKEN_PREFIX)
+                .map(String::from),
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Invalid pickup code presented for Order with ID {}: {}",
+                    input.id,
+                    e
+                );
+                return Err(HandlerError::rejected("invalid_pickup_code", &[]));
+            }
+        };
+
+        // A code is only good for the order it was issued for
+        if order_id.as_deref() != Some(input.id.as_str()) {
+            event!(
+                Level::WARN,
+                "Pickup code presented for Order with ID {} was not issued for it",
+                input.id
+            );
+            return Err(HandlerError::rejected("invalid_pickup_code", &[]));
+        }
+
+        let order_repository = self.uow.get_order_repository().await;
+
+        match order_repository.read(&input.id).await {
+            Ok(order) => {
+                ensure_ready_for_pickup(&order)?;
+
+                Ok(PickupVerificationResponse {
+                    order_id: order.id,
+                    status: order.status,
+                    products: order.products,
+                })
+            }
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Failed to find Order with ID {}: {}",
+                    input.id,
+                    e
+                );
+                Err(order_read_error(&input.id, e))
+            }
+        }
+    }
+}
+
+// Who anonymized carts belong to. Carts without an owner are guest carts, so erased ones are given
+// an owner no caller is let through as instead
+static ERASED_CART_OWNER: &str = "erased";
+
+// Detaches the cart from its customer and strips the name they gave it, so only the products remain
+fn anonymize_cart(cart: &mut Cart) {
+    cart.user_id = String::from(ERASED_CART_OWNER);
+    cart.name = String::from(DEFAULT_CART_NAME);
+    cart.is_default = false;
+}
+
+// Carts are anonymized rather than deleted
+pub struct EraseCustomerDataCommandHandler {
+    uow: Arc<OrderUnitOfWork>,
+}
+
+impl EraseCustomerDataCommandHandler {
+    pub fn new(uow: Arc<OrderUnitOfWork>) -> Self {
+        EraseCustomerDataCommandHandler { uow }
+    }
+}
+
+#[async_trait]
+impl CommandHandler<EraseCustomerDataCommand> for EraseCustomerDataCommandHandler {
+    async fn handle(
+        &self,
+        input: &EraseCustomerDataCommand,
+    ) -> Result<CustomerDataErasedResponse, HandlerError> {
+        let cart_repository = self.uow.get_cart_repository().await;
+
+        let customer_carts = match cart_repository
+            .read_all_by_user_id(&input.customer_id)
+            .await
+        {
+            Ok(customer_carts) => customer_carts,
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Error occurred while finding customer carts: {}",
+                    e
+                );
+                return Err(e.into());
+            }
+        };
+        let carts_anonymized = customer_carts.len();
+
+        let session = self.uow.begin_transaction().await?;
+
+        for mut customer_cart in customer_carts {
+            anonymize_cart(&mut customer_cart);
+
+            if let Err(e) = cart_repository
+                .update(customer_cart.id.clone(), customer_cart, session.clone())
+                .await
+            {
+                event!(
+                    Level::WARN,
+                    "Failed to erase data of customer {}: {}",
+                    input.customer_id,
+                    e
+                );
+                if let Err(rollback_error) = self.uow.rollback(session).await {
+                    event!(Level::WARN, "{}", rollback_error);
+                }
+                return Err(format!(
+                    "Failed to erase data of customer {}: {}",
+                    input.customer_id, e
+                )
+                .into());
+            }
+        }
+
+        session
+            .push_event(Event::CustomerDataErasedEvent {
+                customer_id: input.customer_id.clone(),
+                erased_at_utc: now_utc_millis(),
+            })
+            .await;
+
+        match self.uow.commit(session).await {
+            Ok(()) => {
+                event!(
+                    Level::WARN,
+                    "Data of customer {} erased by {}, {} carts anonymized",
+                    input.customer_id,
+                    input.requested_by,
+                    carts_anonymized
+                );
+
+                Ok(CustomerDataErasedResponse {
+                    customer_id: input.customer_id.clone(),
+                    carts_anonymized,
+                })
+            }
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Failed to erase data of customer {}: {}",
+                    input.customer_id,
+                    e
+                );
+                Err(e.into())
+            }
+        }
+    }
+}
+
+pub struct GetStatsQueryHandler {
+    uow: Arc<OrderUnitOfWork>,
+}
+
+impl GetStatsQueryHandler {
+    pub fn new(uow: Arc<OrderUnitOfWork>) -> Self {
+        GetStatsQueryHandler { uow }
+    }
+}
+
+#[async_trait]
+impl QueryHandler<GetStatsQuery> for GetStatsQueryHandler {
+    async fn handle(&self, _: Option<GetStatsQuery>) -> Result<StatsResponse, HandlerError> {
+        let cart_repository = self.uow.get_cart_repository().await;
+        let order_repository = self.uow.get_order_repository().await;
+
+        let (cart_stats, orders_by_status) =
+            match tokio::join!(cart_repository.stats(), order_repository.count_by_status()) {
+                (Ok(cart_stats), Ok(orders_by_status)) => (cart_stats, orders_by_status),
+                (Err(e), _) | (_, Err(e)) => {
+                    event!(Level::WARN, "Error occurred while gathering stats: {}", e);
+                    return Err(e.into());
+                }
+            };
+
+        Ok(StatsResponse {
+            carts: cart_stats.carts,
+            active_carts: cart_stats.active_carts,
+            average_items_per_active_cart: match cart_stats.active_carts {
+                0 => 0.0,
+                active_carts => cart_stats.items as f64 / active_carts as f64,
+            },
+            orders_by_status,
+        })
+    }
+}
+
+pub struct ExportCustomerDataQueryHandler {
+    uow: Arc<OrderUnitOfWork>,
+}
+
+impl ExportCustomerDataQueryHandler {
+    pub fn new(uow: Arc<OrderUnitOfWork>) -> Self {
+        ExportCustomerDataQueryHandler { uow }
+    }
+}
+
+#[async_trait]
+impl QueryHandler<ExportCustomerDataQuery> for ExportCustomerDataQueryHandler {
+    async fn handle(
+        &self,
+        input_option: Option<ExportCustomerDataQuery>,
+    ) -> Result<CustomerDataExportResponse, HandlerError> {
+        let input = match input_option {
+            Some(input) if !input.customer_id.is_empty() => input,
+            _ => return Err(HandlerError::invalid("customer_id_required", &[])),
+        };
+
+        let cart_repository = self.uow.get_cart_repository().await;
+
+        match cart_repository
+            .read_all_by_user_id(&input.customer_id)
+            .await
+        {
+            Ok(mut customer_carts) => {
+                customer_carts.sort_by_key(|c| c.created_at_utc);
+
+                Ok(CustomerDataExportResponse {
+                    customer_id: input.customer_id,
+                    exported_at_utc: Utc::now(),
+                    carts: customer_carts
+                        .into_iter()
+                        .map(|c| CartResponse {
+                            id: c.id,
+                            name: c.name,
+                            is_default: c.is_default,
+                            products: c
+                                .products
+                                .into_iter()
+                                .map(CartLineItemResponse::from)
+                                .collect(),
+                            links: BTreeMap::new(),
+                        })
+                        .collect(),
+                })
+            }
+            Err(e) => {
+                event!(
+                    Level::WARN,
+                    "Error occurred while exporting customer data: {}",
+                    e
+                );
+                Err(e.into())
+            }
+        }
+    }
+}
+
+static ANONYMIZED_ACTOR: &str = "anonymized";
+
+// Orders only identify customers through who changed their status
+fn anonymize_order(order: &mut Order) {
+    for transition in order.status_history.iter_mut() {
+        transition.actor = String::from(ANONYMIZED_ACTOR);
+    }
+}
+
+// Documents are gone through this many at a time, each batch in a transaction of its own
+static RETENTION_BATCH_SIZE: u32 = 500;
+
+// Applies every retention rule in turn. Documents are only matched when the rule would still
+// change them, so anonymized documents aren't counted again on every run
+pub struct ApplyRetentionRulesCommandHandler {
+    uow: Arc<OrderUnitOfWork>,
+    rules: Vec<RetentionRule>,
+}
+
+impl ApplyRetentionRulesCommandHandler {
+    pub fn new(uow: Arc<OrderUnitOfWork>, rules: Vec<RetentionRule>) -> Self {
+        ApplyRetentionRulesCommandHandler { uow, rules }
+    }
+
+    async fn apply_to_carts(
+        &self,
+        rule: &RetentionRule,
+        cutoff_utc: DateTime<Utc>,
+        dry_run: bool,
+    ) -> Result<usize, String> {
+        let cart_repository = self.uow.get_cart_repository().await;
+
+        let mut matched = 0;
+        let mut after_id = None;
+        loop {
+            let batch = cart_repository
+                .read_updated_before(cutoff_utc, after_id.as_ref(), RETENTION_BATCH_SIZE)
+                .await?;
+            let is_last_batch = batch.len() < RETENTION_BATCH_SIZE as usize;
+            after_id = match batch.last() {
+                Some(cart) => Some(cart.id.clone()),
+                None => return Ok(matched),
+            };
+
+            let expired_carts: Vec<Cart> = batch
+                .into_iter()
+                .filter(|c| match rule.action {
+                    RetentionAction::Delete => true,
+                    RetentionAction::Anonymize => {
+                        !c.user_id.is_empty() && c.user_id != ERASED_CART_OWNER
+                    }
+                })
+                .collect();
+            matched += expired_carts.len();
+
+            if !dry_run && !expired_carts.is_empty() {
+                self.apply_to_cart_batch(rule, expired_carts).await?;
+            }
+            if is_last_batch {
+                return Ok(matched);
+            }
+        }
+    }
+
+    // Each batch is committed on its own, so a failing batch leaves the ones before it applied
+    async fn apply_to_cart_batch(
+        &self,
+        rule: &RetentionRule,
+        expired_carts: Vec<Cart>,
+    ) -> Result<(), String> {
+        let cart_repository = self.uow.get_cart_repository().await;
+        let session = self.uow.begin_transaction().await?;
+
+        for mut expired_cart in expired_carts {
+            let result = match rule.action {
+                RetentionAction::Delete => {
+                    cart_repository
+                        .delete(&expired_cart.id, session.clone())
+                        .await
+                }
+                RetentionAction::Anonymize => {
+                    anonymize_cart(&mut expired_cart);
+
+                    cart_repository
+                        .update(expired_cart.id.clone(), expired_cart, session.clone())
+                        .await
+                        .map(|_| ())
+                }
+            };
+
+            if let Err(e) = result {
+                if let Err(rollback_error) = self.uow.rollback(session).await {
+                    event!(Level::WARN, "{}", rollback_error);
+                }
+                return Err(e);
+            }
+        }
+
+        self.uow.commit(session).await
+    }
+
+    async fn apply_to_orders(
+        &self,
+        rule: &RetentionRule,
+        cutoff_utc: DateTime<Utc>,
+        dry_run: bool,
+    ) -> Result<usize, String> {
+        let order_repository = self.uow.get_order_repository().await;
+
+        let mut matched = 0;
+        let mut after_id = None;
+        loop {
+            let batch = order_repository
+                .read_updated_before(cutoff_utc, after_id.as_ref(), RETENTION_BATCH_SIZE)
+                .await?;
+            let is_last_batch = batch.len() < RETENTION_BATCH_SIZE as usize;
+            after_id = match batch.last() {
+                Some(order) => Some(order.id.clone()),
+                None => return Ok(matched),
+            };
+
+            let expired_orders: Vec<Order> = batch
+                .into_iter()
+                .filter(|o| rule.statuses.is_empty() || rule.statuses.contains(&o.status))
+                .filter(|o| match rule.action {
+                    RetentionAction::Delete => true,
+                    RetentionAction::Anonymize => {
+                        o.status_history.iter().any(|t| t.actor != ANONYMIZED_ACTOR)
+                    }
+                })
+                .collect();
+            matched += expired_orders.len();
+
+            if !dry_run && !expired_orders.is_empty() {
+                self.apply_to_order_batch(rule, expired_orders).await?;
+            }
+            if is_last_batch {
+                return Ok(matched);
+            }
+        }
+    }
+
+    async fn apply_to_order_batch(
+        &self,
+        rule: &RetentionRule,
+        expired_orders: Vec<Order>,
+    ) -> Result<(), String> {
+        let order_repository = self.uow.get_order_repository().await;
+        let session = self.uow.begin_transaction().await?;
+
+        for mut expired_order in expired_orders {
+            let result = match rule.action {
+                RetentionAction::Delete => {
+                    order_repository
+                        .delete(&expired_order.id, session.clone())
+                        .await
+                }
+                RetentionAction::Anonymize => {
+                    anonymize_order(&mut expired_order);
+
+                    order_repository
+                        .update(expired_order.id.clone(), expired_order, session.clone())
+                        .await
+                        .map(|_| ())
+                }
+            };
+
+            if let Err(e) = result {
+                if let Err(rollback_error) = self.uow.rollback(session).await {
+                    event!(Level::WARN, "{}", rollback_error);
+                }
+                return Err(e);
+            }
+        }
+
+        self.uow.commit(session).await
+    }
+}
+
+#[async_trait]
+impl CommandHandler<ApplyRetentionRulesCommand> for ApplyRetentionRulesCommandHandler {
+    async fn handle(
+        &self,
+        input: &ApplyRetentionRulesCommand,
+    ) -> Result<RetentionReportResponse, HandlerError> {
+        let mut reports = Vec::new();
+
+        for rule in &self.rules {
+            let cutoff_utc = Utc::now() - TimeDelta::days(i64::from(rule.max_age_days));
+
+            let result = match rule.entity {
+                RetentionEntity::Carts => {
+                    self.apply_to_carts(rule, cutoff_utc, input.dry_run).await
+                }
+                RetentionEntity::Orders => {
+                    self.apply_to_orders(rule, cutoff_utc, input.dry_run).await
+                }
+            };
+
+            let matched = match result {
+                Ok(matched) => matched,
+                Err(e) => {
+                    event!(
+                        Level::WARN,
+                        "Failed to {} {} older than {} days: {}",
+                        rule.action.name(),
+                        rule.entity.name(),
+                        rule.max_age_days,
+                        e
+                    );
+                    return Err(format!(
+                        "Failed to {} {} older than {} days: {}",
+                        rule.action.name(),
+                        rule.entity.name(),
+                        rule.max_age_days,
+                        e
+                    )
+                    .into());
+                }
+            };
+
+            metrics::counter!(RETENTION_DOCUMENTS_TOTAL, "entity" => rule.entity.name(), "action" => rule.action.name(), "dry_run" => if input.dry_run { "true" } else { "false" })
+                .increment(matched as u64);
+            event!(
+                Level::WARN,
+                "Retention rule to {} {} older than {} days matched {} documents{}",
+                rule.action.name(),
+                rule.entity.name(),
+                rule.max_age_days,
+                matched,
+                if input.dry_run { " (dry run)" } else { "" }
+            );
+
+            reports.push(RetentionRuleReport {
+                rule: rule.clone(),
+                matched,
+            });
+        }
+
+        Ok(RetentionReportResponse {
+            dry_run: input.dry_run,
+            rules: reports,
+        })
+    }
+}
+
+static CART_IMPORT_BATCH_SIZE: usize = 100;
+
+// Validates and writes carts exported from the legacy platform. Valid records are written in
+// batches, each in one transaction. When a batch fails its records are retried one by one, so the
+// report names exactly the records that couldn't be written
+pub struct ImportCartsCommandHandler {
+    uow: Arc<OrderUnitOfWork>,
+}
+
+impl ImportCartsCommandHandler {
+    pub fn new(uow: Arc<OrderUnitOfWork>) -> Self {
+        ImportCartsCommandHandler { uow }
+    }
+
+    fn to_cart(record: ImportedCartRecord) -> Result<Cart, String> {
+        if let Some((product_id, quantity)) = record
+            .products
+            .iter()
+            .find(|(product_id, quantity)| product_id.is_empty() || **quantity <= 0)
+        {
+            return Err(format!(
+                "Invalid quantity {} of product '{}'",
+                quantity, product_id
+            ));
+        }
+        if record.is_default && record.user_id.is_empty() {
+            return Err(String::from("Guest carts can't be default carts"));
+        }
+
+        let created_at_utc = record.created_at_utc.unwrap_or(Utc::now());
+        Ok(Cart {
+            id: record
+                .id
+                .filter(|id| !id.is_empty())
+                .unwrap_or_else(CartId::generate),
+            user_id: record.user_id,
+            name: match record.name.trim() {
+                "" => String::from(DEFAULT_CART_NAME),
+                name => String::from(name),
+            },
+            is_default: record.is_default,
+            products: line_items_from_quantities(record.products, created_at_utc),
+            created_at_utc,
+            updated_at_utc: record.updated_at_utc.unwrap_or(created_at_utc),
+            version: 0,
+        })
+    }
+
+    // Writes the whole batch in one transaction, so a record that fails leaves none of it behind
+    async fn write_all(
+        &self,
+        cart_repository: &Arc<dyn CartRepository + Send + Sync>,
+        batch: &[(usize, Cart)],
+    ) -> Result<(), String> {
+        let session = self.uow.begin_transaction().await?;
+
+        for (_, cart) in batch {
+            if let Err(e) = cart_repository
+                .create(cart.id.clone(), cart.clone(), session.clone())
+                .await
+            {
+                if let Err(rollback_error) = self.uow.rollback(session).await {
+                    event!(
+                        Level::WARN,
+                        "Failed to roll back cart import batch: {}",
+                        rollback_error
+                    );
+                }
+                return Err(e);
+            }
+        }
+
+        self.uow.commit(session).await
+    }
+
+    async fn write_one(
+        &self,
+        cart_repository: &Arc<dyn CartRepository + Send + Sync>,
+        cart: Cart,
+    ) -> Result<(), String> {
+        let session = self.uow.begin_transaction().await?;
+
+        match cart_repository
+            .create(cart.id.clone(), cart, session.clone())
+            .await
+        {
+            Ok(_) => self.uow.commit(session).await,
+            Err(e) => {
+                if let Err(rollback_error) = self.uow.rollback(session).await {
+                    event!(Level::WARN, "{}", rollback_error);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn write_batch(&self, batch: Vec<(usize, Cart)>) -> Vec<CartImportResult> {
+        let cart_repository = self.uow.get_cart_repository().await;
+
+        // The records are retried one by one below when the batch fails
+        if self.write_all(&cart_repository, &batch).await.is_ok() {
+            return batch
+                .into_iter()
+                .map(|(line, cart)| CartImportResult {
+                    line,
+                    cart_id: Some(cart.id),
+                    error: None,
+                })
+                .collect();
+        }
+
+        let mut results = Vec::new();
+        for (line, cart) in batch {
+            let id = cart.id.clone();
+            let result = self.write_one(&cart_repository, cart).await;
+            results.push(CartImportResult {
+                line,
+                cart_id: Some(id),
+                error: result.err(),
+            });
+        }
+
+        results
+    }
+}
+
+#[async_trait]
+impl CommandHandler<ImportCartsCommand> for ImportCartsCommandHandler {
+    async fn handle(&self, input: &ImportCartsCommand) -> Result<CartImportResponse, HandlerError> {
+        let cart_repository = self.uow.get_cart_repository().await;
+
+        let mut results = Vec::new();
+        let mut valid_carts = Vec::new();
+        let mut seen_ids = HashSet::new();
+        // Users known to have a default cart, whether it already exists or comes earlier in this import
+        let mut users_with_default: HashMap<String, bool> = HashMap::new();
+
+        for (index, line) in input.lines.iter().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let cart = match serde_json::from_str::<ImportedCartRecord>(line) {
+                Ok(record) => Self::to_cart(record),
+                Err(e) => Err(format!("Invalid record: {}", e)),
+            };
+            let cart = match cart {
+                Ok(cart) if !seen_ids.insert(cart.id.clone()) => {
+                    Err(format!("Cart {} appears more than once", cart.id))
+                }
+                Ok(cart) if cart.is_default => {
+                    // A user whose carts can't be read fails only this record, and is looked up
+                    // again for the next one
+                    let has_default = match users_with_default.get(&cart.user_id) {
+                        Some(has_default) => Ok(*has_default),
+                        None => cart_repository
+                            .read_all_by_user_id(&cart.user_id)
+                            .await
+                            .map(|carts| carts.iter().any(|c| c.is_default)),
+                    };
+
+                    match has_default {
+                        Ok(has_default) => {
+                            users_with_default.insert(cart.user_id.clone(), true);
+
+                            match has_default {
+                                true => {
+                                    Err(format!("User {} already has a default cart", cart.user_id))
+                                }
+                                false => Ok(cart),
+                            }
+                        }
+                        Err(e) => Err(format!(
+                            "Failed to find Carts for user {}: {}",
+                            cart.user_id, e
+                        )),
+                    }
+                }
+                cart => cart,
+            };
+
+            match cart {
+                Ok(cart) => valid_carts.push((index + 1, cart)),
+                Err(e) => results.push(CartImportResult {
+                    line: index + 1,
+                    cart_id: None,
+                    error: Some(e),
+                }),
+            }
+        }
+
+        let mut valid_carts = valid_carts.into_iter().peekable();
+        while valid_carts.peek().is_some() {
+            let batch = valid_carts.by_ref().take(CART_IMPORT_BATCH_SIZE).collect();
+            results.extend(self.write_batch(batch).await);
+        }
+        results.sort_by_key(|result| result.line);
+
+        let failed = results
+            .iter()
+            .filter(|result| result.error.is_some())
+            .count();
+        event!(
+            Level::WARN,
+            "Imported {} carts, {} records failed",
+            results.len() - failed,
+            failed
+        );
+
+        Ok(CartImportResponse {
+            imported: results.len() - failed,
+            failed,
+            results,
+        })
+    }
+}
@@ -0,0 +1,182 @@
+use std::{env, str::FromStr};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use mongodb::{
+    bson::{doc, spec::BinarySubtype, Binary, Bson, Document, Uuid},
+    mongocrypt::ctx::KmsProvider,
+    options::ClientOptions,
+    Client, Namespace,
+};
+
+use crate::{aws::AwsCredentials, repositories::MongoDbInitializationInfo};
+
+static LOCAL_MASTER_KEY_LENGTH: usize = 96;
+
+enum CsfleKmsProvider {
+    Local(Vec<u8>),
+    Aws(AwsCredentials),
+}
+
+// MongoDB client-side field-level encryption, for deployments whose compliance rules don't allow
+// the database to ever see personal data. Needs libmongocrypt where the service is built and run,
+// and the crypt_shared library or mongocryptd where it runs
+pub struct CsfleSettings {
+    key_vault_namespace: String,
+    kms_provider: CsfleKmsProvider,
+    // The data key in the key vault that encrypts order fields
+    data_key_id: String,
+    schema_map_path: Option<String>,
+    crypt_shared_lib_path: Option<String>,
+}
+
+impl CsfleSettings {
+    // None unless MONGODB_CSFLE_ENABLED is true. MONGODB_CSFLE_KMS_PROVIDER is "local", with the
+    // base64 encoded 96 byte MONGODB_CSFLE_LOCAL_MASTER_KEY, or "aws" with the AWS credentials
+    pub fn from_env() -> Result<Option<CsfleSettings>, String> {
+        if !env::var("MONGODB_CSFLE_ENABLED").is_ok_and(|enabled| enabled == "true") {
+            return Ok(None);
+        }
+
+        let kms_provider = match env::var("MONGODB_CSFLE_KMS_PROVIDER")
+            .unwrap_or(String::from("local"))
+            .as_str()
+        {
+            "local" => match STANDARD.decode(env::var("MONGODB_CSFLE_LOCAL_MASTER_KEY").unwrap()) {
+                Ok(master_key) if master_key.len() == LOCAL_MASTER_KEY_LENGTH => {
+                    CsfleKmsProvider::Local(master_key)
+                }
+                Ok(_) => {
+                    return Err(format!(
+                        "MONGODB_CSFLE_LOCAL_MASTER_KEY must be {} bytes",
+                        LOCAL_MASTER_KEY_LENGTH
+                    ))
+                }
+                Err(e) => return Err(format!("Invalid MONGODB_CSFLE_LOCAL_MASTER_KEY: {}", e)),
+            },
+            "aws" => CsfleKmsProvider::Aws(AwsCredentials::from_env()),
+            other => return Err(format!("Unknown MONGODB_CSFLE_KMS_PROVIDER {}", other)),
+        };
+
+        Ok(Some(CsfleSettings {
+            key_vault_namespace: env::var("MONGODB_CSFLE_KEY_VAULT_NAMESPACE")
+                .unwrap_or(String::from("encryption.__keyVault")),
+            kms_provider,
+            data_key_id: env::var("MONGODB_CSFLE_DATA_KEY_ID").unwrap(),
+            schema_map_path: env::var("MONGODB_CSFLE_SCHEMA_MAP_PATH").ok(),
+            crypt_shared_lib_path: env::var("MONGODB_CSFLE_CRYPT_SHARED_LIB_PATH").ok(),
+        }))
+    }
+
+    fn kms_providers(&self) -> Vec<(KmsProvider, Document, Option<mongodb::options::TlsOptions>)> {
+        match &self.kms_provider {
+            CsfleKmsProvider::Local(master_key) => vec![(
+                KmsProvider::Local,
+                doc! {"key": Binary {subtype: BinarySubtype::Generic, bytes: master_key.clone()}},
+                None,
+            )],
+            CsfleKmsProvider::Aws(credentials) => {
+                let mut aws = doc! {
+                    "accessKeyId": &credentials.access_key_id,
+                    "secretAccessKey": &credentials.secret_access_key,
+                };
+                if let Some(session_token) = &credentials.session_token {
+                    aws.insert("sessionToken", session_token);
+                }
+
+                vec![(KmsProvider::Aws, aws, None)]
+            }
+        }
+    }
+
+    // Encrypts the status history of orders, which records who changed them. Fields inside arrays
+    // can't be encrypted on their own, so the whole history is. MONGODB_CSFLE_SCHEMA_MAP_PATH
+    // replaces this with a schema map in extended JSON, keyed by namespace
+    fn schema_map(&self, order_db_info: &MongoDbInitializationInfo) -> Result<Document, String> {
+        if let Some(path) = &self.schema_map_path {
+            let schema_map = match std::fs::read_to_string(path) {
+                Ok(schema_map) => schema_map,
+                Err(e) => return Err(format!("Failed to read CSFLE schema map {}: {}", path, e)),
+            };
+
+            return match serde_json::from_str::<serde_json::Value>(&schema_map)
+                .map_err(|e| e.to_string())
+                .and_then(|schema_map| Bson::try_from(schema_map).map_err(|e| e.to_string()))
+            {
+                Ok(Bson::Document(schema_map)) => Ok(schema_map),
+                Ok(_) => Err(format!("CSFLE schema map {} is not an object", path)),
+                Err(e) => Err(format!("Invalid CSFLE schema map {}: {}", path, e)),
+            };
+        }
+
+        let data_key_id = match Uuid::parse_str(&self.data_key_id) {
+            Ok(data_key_id) => Binary::from_uuid(data_key_id),
+            Err(e) => return Err(format!("Invalid MONGODB_CSFLE_DATA_KEY_ID: {}", e)),
+        };
+
+        let mut schema_map = Document::new();
+        schema_map.insert(
+            format!("{}.{}", order_db_info.database, order_db_info.collection),
+            doc! {
+                "bsonType": "object",
+                "encryptMetadata": {"keyId": [data_key_id]},
+                "properties": {
+                    "status_history": {
+                        "encrypt": {
+                            "bsonType": "array",
+                            "algorithm": "AEAD_AES_256_CBC_HMAC_SHA_512-Random",
+                        },
+                    },
+                },
+            },
+        );
+
+        Ok(schema_map)
+    }
+}
+
+// A client that encrypts and decrypts the fields in the schema map automatically. Carts and every
+// other collection go through it unchanged
+pub async fn encrypted_client(
+    order_db_info: &MongoDbInitializationInfo,
+    settings: &CsfleSettings,
+) -> Result<Client, String> {
+    let client_options = match ClientOptions::parse(&order_db_info.uri).await {
+        Ok(client_options) => client_options,
+        Err(e) => return Err(format!("Invalid MONGODB_URI: {}", e)),
+    };
+    let key_vault_namespace = match Namespace::from_str(&settings.key_vault_namespace) {
+        Ok(key_vault_namespace) => key_vault_namespace,
+        Err(e) => return Err(format!("Invalid MONGODB_CSFLE_KEY_VAULT_NAMESPACE: {}", e)),
+    };
+    let schema_map =
+        settings
+            .schema_map(order_db_info)?
+            .into_iter()
+            .filter_map(|(namespace, schema)| match schema {
+                Bson::Document(schema) => Some((namespace, schema)),
+                _ => None,
+            });
+
+    let builder = match Client::encrypted_builder(
+        client_options,
+        key_vault_namespace,
+        settings.kms_providers(),
+    ) {
+        Ok(builder) => builder.schema_map(schema_map),
+        Err(e) => return Err(format!("Invalid CSFLE configuration: {}", e)),
+    };
+    let builder = match &settings.crypt_shared_lib_path {
+        Some(path) => {
+            builder.extra_options(doc! {"cryptSharedLibPath": path, "cryptSharedLibRequired": true})
+        }
+        None => builder,
+    };
+
+    match builder.build().await {
+        Ok(client) => Ok(client),
+        Err(e) => Err(format!(
+            "Failed to connect to MongoDB with field-level encryption: {}",
+            e
+        )),
+    }
+}
@@ -0,0 +1,291 @@
+use std::{collections::HashMap, sync::Arc};
+
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File,
+    io::{self, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+};
+
+use crate::{
+    bootstrap::{field_encryptor_from_env, repositories_from_env},
+    cqrs::DEFAULT_CART_NAME,
+    domain::{Cart, CartId, Order, OrderId},
+    encryption::{EncryptingCartRepository, EncryptingOrderRepository},
+    repositories::{CartRepository, OrderRepository},
+    uow::TransactionSession,
+};
+
+static SCRUBBED_ACTOR: &str = "scrubbed";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DataSet {
+    Carts,
+    Orders,
+}
+
+// `export <carts|orders> [--scrub-pii] [--file <path>]` and
+// `import <carts|orders> [--scrub-pii] [--remap-ids] [--file <path>]`. Without --file records are
+// written to stdout and read from stdin
+struct TransferOptions {
+    data_set: DataSet,
+    file: Option<String>,
+    scrub_pii: bool,
+    remap_ids: bool,
+}
+
+impl TransferOptions {
+    fn parse(args: &[String]) -> Result<TransferOptions, String> {
+        let data_set = match args.first().map(String::as_str) {
+            Some("carts") => DataSet::Carts,
+            Some("orders") => DataSet::Orders,
+            _ => return Err(String::from("Specify what to transfer: carts or orders")),
+        };
+
+        let mut options = TransferOptions {
+            data_set,
+            file: None,
+            scrub_pii: false,
+            remap_ids: false,
+        };
+        let mut args = args[1..].iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--scrub-pii" => options.scrub_pii = true,
+                "--remap-ids" => options.remap_ids = true,
+                "--file" => match args.next() {
+                    Some(file) => options.file = Some(file.clone()),
+                    None => return Err(String::from("--file needs a path")),
+                },
+                other => return Err(format!("Unknown option {}", other)),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+// The same user always gets the same pseudonym, so carts stay grouped by owner after scrubbing
+fn pseudonymize_user_id(user_id: &str) -> String {
+    match user_id.is_empty() {
+        true => String::new(),
+        false => {
+            let digest: String = Sha256::digest(user_id.as_bytes())
+                .iter()
+                .take(8)
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            format!("user-{}", digest)
+        }
+    }
+}
+
+fn scrub_cart(cart: &mut Cart) {
+    cart.user_id = pseudonymize_user_id(&cart.user_id);
+    cart.name = String::from(DEFAULT_CART_NAME);
+}
+
+fn scrub_order(order: &mut Order) {
+    for transition in order.status_history.iter_mut() {
+        transition.actor = String::from(SCRUBBED_ACTOR);
+    }
+}
+
+// Both go through field encryption when it is configured, so dumps hold plaintext that the
+// importing environment encrypts with its own keys
+async fn repositories() -> Result<
+    (
+        Arc<dyn OrderRepository + Send + Sync>,
+        Arc<dyn CartRepository + Send + Sync>,
+    ),
+    String,
+> {
+    let (order_repository, cart_repository, _) = repositories_from_env().await?;
+
+    Ok(match field_encryptor_from_env()? {
+        Some(field_encryptor) => (
+            Arc::new(EncryptingOrderRepository::new(
+                order_repository,
+                field_encryptor.clone(),
+            )),
+            Arc::new(EncryptingCartRepository::new(
+                cart_repository,
+                field_encryptor,
+            )),
+        ),
+        None => (order_repository, cart_repository),
+    })
+}
+
+async fn write_ndjson<T: Serialize>(
+    output: &mut (dyn AsyncWrite + Unpin + Send),
+    records: &[T],
+) -> Result<(), String> {
+    for record in records {
+        let line = match serde_json::to_string(record) {
+            Ok(line) => line,
+            Err(e) => return Err(format!("Failed to serialize record: {}", e)),
+        };
+
+        if let Err(e) = output.write_all(format!("{}\n", line).as_bytes()).await {
+            return Err(format!("Failed to write record: {}", e));
+        }
+    }
+
+    match output.flush().await {
+        Ok(()) => Ok(()),
+        Err(e) => Err(format!("Failed to write records: {}", e)),
+    }
+}
+
+// Skips blank lines and reports each line that isn't a valid record by its line number
+async fn read_ndjson<T: DeserializeOwned>(
+    input: Box<dyn AsyncRead + Unpin + Send>,
+) -> Result<Vec<(usize, Result<T, String>)>, String> {
+    let mut lines = BufReader::new(input).lines();
+    let mut records = Vec::new();
+    let mut line_number = 0;
+
+    loop {
+        line_number += 1;
+        match lines.next_line().await {
+            Ok(Some(line)) if line.trim().is_empty() => continue,
+            Ok(Some(line)) => records.push((
+                line_number,
+                serde_json::from_str(&line).map_err(|e| e.to_string()),
+            )),
+            Ok(None) => return Ok(records),
+            Err(e) => return Err(format!("Failed to read line {}: {}", line_number, e)),
+        }
+    }
+}
+
+pub async fn export(args: &[String]) -> Result<(), String> {
+    let options = TransferOptions::parse(args)?;
+    if options.remap_ids {
+        return Err(String::from(
+            "--remap-ids only applies to import, since ids are only reassigned when records are written",
+        ));
+    }
+    let (order_repository, cart_repository) = repositories().await?;
+
+    let mut output: Box<dyn AsyncWrite + Unpin + Send> = match &options.file {
+        Some(path) => match File::create(path).await {
+            Ok(file) => Box::new(file),
+            Err(e) => return Err(format!("Failed to create {}: {}", path, e)),
+        },
+        None => Box::new(io::stdout()),
+    };
+
+    let exported = match options.data_set {
+        DataSet::Carts => {
+            let mut carts = cart_repository.read_all().await?;
+            if options.scrub_pii {
+                carts.iter_mut().for_each(scrub_cart);
+            }
+            write_ndjson(&mut output, &carts).await?;
+            carts.len()
+        }
+        DataSet::Orders => {
+            let mut orders = order_repository.read_all().await?;
+            if options.scrub_pii {
+                orders.iter_mut().for_each(scrub_order);
+            }
+            write_ndjson(&mut output, &orders).await?;
+            orders.len()
+        }
+    };
+
+    eprintln!("Exported {} records", exported);
+    Ok(())
+}
+
+// Every record is written on its own, so one that fails, e.g. because its id already exists,
+// doesn't stop the rest. Fails when any record couldn't be imported
+pub async fn import(args: &[String]) -> Result<(), String> {
+    let options = TransferOptions::parse(args)?;
+    let (order_repository, cart_repository) = repositories().await?;
+
+    let input: Box<dyn AsyncRead + Unpin + Send> = match &options.file {
+        Some(path) => match File::open(path).await {
+            Ok(file) => Box::new(file),
+            Err(e) => return Err(format!("Failed to open {}: {}", path, e)),
+        },
+        None => Box::new(io::stdin()),
+    };
+
+    let mut imported = 0;
+    let mut failed = 0;
+    let mut report = |line_number: usize, result: Result<(), String>| match result {
+        Ok(()) => imported += 1,
+        Err(e) => {
+            failed += 1;
+            eprintln!("Line {}: {}", line_number, e);
+        }
+    };
+
+    match options.data_set {
+        DataSet::Carts => {
+            // The same old id always maps to the same new one
+            let mut new_ids: HashMap<CartId, CartId> = HashMap::new();
+
+            for (line_number, record) in read_ndjson::<Cart>(input).await? {
+                let result = match record {
+                    Ok(mut cart) => {
+                        if options.remap_ids {
+                            cart.id = new_ids
+                                .entry(cart.id.clone())
+                                .or_insert_with(CartId::generate)
+                                .clone();
+                        }
+                        if options.scrub_pii {
+                            scrub_cart(&mut cart);
+                        }
+
+                        cart_repository
+                            .create(cart.id.clone(), cart, TransactionSession::default())
+                            .await
+                            .map(|_| ())
+                    }
+                    Err(e) => Err(format!("Invalid cart: {}", e)),
+                };
+                report(line_number, result);
+            }
+        }
+        DataSet::Orders => {
+            let mut new_ids: HashMap<OrderId, OrderId> = HashMap::new();
+
+            for (line_number, record) in read_ndjson::<Order>(input).await? {
+                let result = match record {
+                    Ok(mut order) => {
+                        if options.remap_ids {
+                            order.id = new_ids
+                                .entry(order.id.clone())
+                                .or_insert_with(OrderId::generate)
+                                .clone();
+                        }
+                        if options.scrub_pii {
+                            scrub_order(&mut order);
+                        }
+
+                        match order.validate() {
+                            Ok(()) => order_repository
+                                .create(order.id.clone(), order, TransactionSession::default())
+                                .await
+                                .map(|_| ()),
+                            Err(e) => Err(format!("Invalid order: {}", e)),
+                        }
+                    }
+                    Err(e) => Err(format!("Invalid order: {}", e)),
+                };
+                report(line_number, result);
+            }
+        }
+    }
+
+    eprintln!("Imported {} records, {} failed", imported, failed);
+    match failed {
+        0 => Ok(()),
+        _ => Err(format!("{} records could not be imported", failed)),
+    }
+}
@@ -0,0 +1,561 @@
+use std::{
+    collections::HashMap,
+    env,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use tokio::sync::{OnceCell, Semaphore};
+use tracing::{event, Level};
+
+use crate::{
+    cqrs::{Command, CommandHandler, Query, QueryHandler},
+    errors::HandlerError,
+    locking::DistributedLock,
+    repositories::{is_not_found, is_primary_unavailable, is_version_conflict},
+    throttling::ThrottleReason,
+};
+
+pub static HANDLER_CALLS_TOTAL: &str = "order_service_handler_calls_total";
+pub static HANDLER_DURATION_SECONDS: &str = "order_service_handler_duration_seconds";
+pub static BULKHEAD_REJECTIONS_TOTAL: &str = "order_service_bulkhead_rejections_total";
+pub static COMMANDS_COALESCED_TOTAL: &str = "order_service_commands_coalesced_total";
+
+// Decorators wrap a handler and implement the same handler trait, so cross-cutting behavior is
+// composed around handlers when they are registered instead of being repeated in every handle body
+
+// e.g. "eshop_orders::cqrs::CreateCartCommand" -> "CreateCartCommand"
+fn input_name<T>() -> &'static str {
+    let type_name = std::any::type_name::<T>();
+    type_name.rsplit("::").next().unwrap_or(type_name)
+}
+
+fn outcome<R>(result: &Result<R, HandlerError>) -> &'static str {
+    match result {
+        Ok(_) => "success",
+        Err(_) => "failure",
+    }
+}
+
+pub struct LoggingHandler<H> {
+    inner: H,
+}
+
+impl<H> LoggingHandler<H> {
+    pub fn new(inner: H) -> Self {
+        LoggingHandler { inner }
+    }
+}
+
+#[async_trait]
+impl<C, H> CommandHandler<C> for LoggingHandler<H>
+where
+    C: Command + Sync + 'static,
+    H: CommandHandler<C> + Send + Sync,
+{
+    async fn handle(&self, input: &C) -> Result<C::Response, HandlerError> {
+        event!(Level::DEBUG, "Handling {}", input_name::<C>());
+
+        let result = self.inner.handle(input).await;
+        if let Err(e) = &result {
+            event!(Level::WARN, "{} failed: {}", input_name::<C>(), e);
+        }
+
+        result
+    }
+}
+
+#[async_trait]
+impl<Q, H> QueryHandler<Q> for LoggingHandler<H>
+where
+    Q: Query + Send + 'static,
+    H: QueryHandler<Q> + Send + Sync,
+{
+    async fn handle(&self, input: Option<Q>) -> Result<Q::Response, HandlerError> {
+        event!(Level::DEBUG, "Handling {}", input_name::<Q>());
+
+        let result = self.inner.handle(input).await;
+        if let Err(e) = &result {
+            event!(Level::WARN, "{} failed: {}", input_name::<Q>(), e);
+        }
+
+        result
+    }
+}
+
+pub struct MetricsHandler<H> {
+    inner: H,
+}
+
+impl<H> MetricsHandler<H> {
+    pub fn new(inner: H) -> Self {
+        MetricsHandler { inner }
+    }
+}
+
+#[async_trait]
+impl<C, H> CommandHandler<C> for MetricsHandler<H>
+where
+    C: Command + Sync + 'static,
+    H: CommandHandler<C> + Send + Sync,
+{
+    async fn handle(&self, input: &C) -> Result<C::Response, HandlerError> {
+        let started = Instant::now();
+        let result = self.inner.handle(input).await;
+
+        metrics::counter!(HANDLER_CALLS_TOTAL, "handler" => input_name::<C>(), "outcome" => outcome(&result))
+            .increment(1);
+        metrics::histogram!(HANDLER_DURATION_SECONDS, "handler" => input_name::<C>())
+            .record(started.elapsed().as_secs_f64());
+
+        result
+    }
+}
+
+#[async_trait]
+impl<Q, H> QueryHandler<Q> for MetricsHandler<H>
+where
+    Q: Query + Send + 'static,
+    H: QueryHandler<Q> + Send + Sync,
+{
+    async fn handle(&self, input: Option<Q>) -> Result<Q::Response, HandlerError> {
+        let started = Instant::now();
+        let result = self.inner.handle(input).await;
+
+        metrics::counter!(HANDLER_CALLS_TOTAL, "handler" => input_name::<Q>(), "outcome" => outcome(&result))
+            .increment(1);
+        metrics::histogram!(HANDLER_DURATION_SECONDS, "handler" => input_name::<Q>())
+            .record(started.elapsed().as_secs_f64());
+
+        result
+    }
+}
+
+// Rejects commands that fail Command::validate before they reach the handler
+pub struct ValidatingHandler<H> {
+    inner: H,
+}
+
+impl<H> ValidatingHandler<H> {
+    pub fn new(inner: H) -> Self {
+        ValidatingHandler { inner }
+    }
+}
+
+#[async_trait]
+impl<C, H> CommandHandler<C> for ValidatingHandler<H>
+where
+    C: Command + Sync + 'static,
+    H: CommandHandler<C> + Send + Sync,
+{
+    async fn handle(&self, input: &C) -> Result<C::Response, HandlerError> {
+        input.validate()?;
+        self.inner.handle(input).await
+    }
+}
+
+// Only failures that may clear up on their own are worth another attempt. Coded errors are answers
+// about the request itself, and throttling errors, e.g. from an open circuit breaker, must fail fast
+fn is_retryable(error: &HandlerError) -> bool {
+    match error {
+        HandlerError::Internal(e) => {
+            ThrottleReason::from_error(e).is_none() && !is_not_found(e) && !is_version_conflict(e)
+        }
+        HandlerError::Coded { .. } => false,
+    }
+}
+
+// Retries failed calls with a doubling delay; only wrap handlers that are safe to repeat
+pub struct RetryHandler<H> {
+    inner: H,
+    max_attempts: u32,
+    initial_delay: Duration,
+}
+
+impl<H> RetryHandler<H> {
+    pub fn new(inner: H, max_attempts: u32, initial_delay: Duration) -> Self {
+        RetryHandler {
+            inner,
+            max_attempts: max_attempts.max(1),
+            initial_delay,
+        }
+    }
+}
+
+#[async_trait]
+impl<Q, H> QueryHandler<Q> for RetryHandler<H>
+where
+    Q: Query + Clone + Send + Sync + 'static,
+    Q::Response: Send,
+    H: QueryHandler<Q> + Send + Sync,
+{
+    async fn handle(&self, input: Option<Q>) -> Result<Q::Response, HandlerError> {
+        let mut delay = self.initial_delay;
+        let mut attempt = 1;
+
+        loop {
+            match self.inner.handle(input.clone()).await {
+                Err(e) if attempt < self.max_attempts && is_retryable(&e) => {
+                    event!(
+                        Level::WARN,
+                        "{} failed on attempt {}, retrying: {}",
+                        input_name::<Q>(),
+                        attempt,
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BulkheadSettings {
+    pub max_concurrent: usize,
+    // How long a call may queue for a permit before it is rejected
+    pub max_wait: Duration,
+}
+
+impl Default for BulkheadSettings {
+    fn default() -> Self {
+        BulkheadSettings {
+            max_concurrent: 32,
+            max_wait: Duration::from_millis(500),
+        }
+    }
+}
+
+impl BulkheadSettings {
+    pub fn from_env() -> BulkheadSettings {
+        let defaults = BulkheadSettings::default();
+
+        BulkheadSettings {
+            max_concurrent: env::var("WRITE_HANDLER_MAX_CONCURRENCY")
+                .map(|x| x.parse().unwrap())
+                .unwrap_or(defaults.max_concurrent),
+            max_wait: env::var("WRITE_HANDLER_MAX_WAIT_MILLIS")
+                .map(|x| Duration::from_millis(x.parse().unwrap()))
+                .unwrap_or(defaults.max_wait),
+        }
+    }
+}
+
+// Caps how many calls run through the wrapped handler at once, so a burst against one endpoint
+// can't take every database connection the rest of the API needs. Without settings calls pass through
+pub struct BulkheadHandler<H> {
+    inner: H,
+    permits: Option<Semaphore>,
+    max_wait: Duration,
+}
+
+impl<H> BulkheadHandler<H> {
+    pub fn new(inner: H, settings: Option<&BulkheadSettings>) -> Self {
+        BulkheadHandler {
+            inner,
+            permits: settings.map(|settings| Semaphore::new(settings.max_concurrent.max(1))),
+            max_wait: settings
+                .map(|settings| settings.max_wait)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[async_trait]
+impl<C, H> CommandHandler<C> for BulkheadHandler<H>
+where
+    C: Command + Sync + 'static,
+    H: CommandHandler<C> + Send + Sync,
+{
+    async fn handle(&self, input: &C) -> Result<C::Response, HandlerError> {
+        let permits = match &self.permits {
+            Some(permits) => permits,
+            None => return self.inner.handle(input).await,
+        };
+
+        let _permit = match tokio::time::timeout(self.max_wait, permits.acquire()).await {
+            Ok(Ok(permit)) => permit,
+            _ => {
+                metrics::counter!(BULKHEAD_REJECTIONS_TOTAL, "handler" => input_name::<C>())
+                    .increment(1);
+                return Err(ThrottleReason::ConcurrencyLimit
+                    .error(&format!(
+                        "Too many concurrent {} requests",
+                        input_name::<C>()
+                    ))
+                    .into());
+            }
+        };
+
+        self.inner.handle(input).await
+    }
+}
+
+static DEFAULT_MAINTENANCE_MESSAGE: &str =
+    "The service is undergoing maintenance, please retry later";
+static FAILOVER_MAINTENANCE_MESSAGE: &str =
+    "The database is failing over and changes are paused, please retry shortly";
+
+pub static DEFAULT_READ_ONLY_FAILOVER_DURATION: Duration = Duration::from_secs(30);
+
+// Switch shared between the admin endpoint and every command handler. While enabled, commands are
+// refused so nothing changes state, but queries keep working. Besides the admin switch, a command
+// that finds no writable MongoDB primary makes the service read-only for failover_duration, so
+// commands fail fast instead of each waiting out server selection. The first command after that
+// finds out whether the primary is back
+pub struct MaintenanceMode {
+    enabled: AtomicBool,
+    message: Mutex<String>,
+    failover_until: Mutex<Option<Instant>>,
+    failover_duration: Duration,
+}
+
+impl Default for MaintenanceMode {
+    fn default() -> Self {
+        MaintenanceMode::new(DEFAULT_READ_ONLY_FAILOVER_DURATION)
+    }
+}
+
+impl MaintenanceMode {
+    pub fn new(failover_duration: Duration) -> MaintenanceMode {
+        MaintenanceMode {
+            enabled: AtomicBool::new(false),
+            message: Mutex::new(String::new()),
+            failover_until: Mutex::new(None),
+            failover_duration,
+        }
+    }
+
+    pub fn enable(&self, message: Option<String>) {
+        *self.message.lock().unwrap() =
+            message.unwrap_or(String::from(DEFAULT_MAINTENANCE_MESSAGE));
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn disable(&self) {
+        self.enabled.store(false, Ordering::SeqCst);
+    }
+
+    // Whether an admin turned maintenance on
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    pub fn is_failing_over(&self) -> bool {
+        self.failover_until
+            .lock()
+            .unwrap()
+            .is_some_and(|failover_until| failover_until > Instant::now())
+    }
+
+    // Whether commands are refused, for either reason
+    pub fn is_read_only(&self) -> bool {
+        self.is_enabled() || self.is_failing_over()
+    }
+
+    pub fn start_failover(&self) {
+        let mut failover_until = self.failover_until.lock().unwrap();
+        let now = Instant::now();
+        if !failover_until.is_some_and(|failover_until| failover_until > now) {
+            event!(
+                Level::WARN,
+                "No writable MongoDB primary, refusing commands for {:?}",
+                self.failover_duration
+            );
+        }
+
+        *failover_until = Some(now + self.failover_duration);
+    }
+
+    pub fn message(&self) -> String {
+        match self.is_enabled() {
+            true => self.message.lock().unwrap().clone(),
+            false => String::from(FAILOVER_MAINTENANCE_MESSAGE),
+        }
+    }
+}
+
+pub struct MaintenanceHandler<H> {
+    inner: H,
+    maintenance_mode: Arc<MaintenanceMode>,
+}
+
+impl<H> MaintenanceHandler<H> {
+    pub fn new(inner: H, maintenance_mode: Arc<MaintenanceMode>) -> Self {
+        MaintenanceHandler {
+            inner,
+            maintenance_mode,
+        }
+    }
+}
+
+#[async_trait]
+impl<C, H> CommandHandler<C> for MaintenanceHandler<H>
+where
+    C: Command + Sync + 'static,
+    H: CommandHandler<C> + Send + Sync,
+{
+    async fn handle(&self, input: &C) -> Result<C::Response, HandlerError> {
+        if self.maintenance_mode.is_read_only() {
+            return Err(ThrottleReason::Maintenance
+                .error(&self.maintenance_mode.message())
+                .into());
+        }
+
+        let result = self.inner.handle(input).await;
+        if matches!(&result, Err(HandlerError::Internal(e)) if is_primary_unavailable(e)) {
+            self.maintenance_mode.start_failover();
+        }
+
+        result
+    }
+}
+
+// Serializes commands on the same resource across replicas, using the lock name lock_name derives
+// from the command. Without a lock calls pass through
+pub struct LockingHandler<C, H> {
+    inner: H,
+    lock: Option<Arc<DistributedLock>>,
+    lock_name: fn(&C) -> String,
+}
+
+impl<C, H> LockingHandler<C, H> {
+    pub fn new(inner: H, lock: Option<Arc<DistributedLock>>, lock_name: fn(&C) -> String) -> Self {
+        LockingHandler {
+            inner,
+            lock,
+            lock_name,
+        }
+    }
+}
+
+#[async_trait]
+impl<C, H> CommandHandler<C> for LockingHandler<C, H>
+where
+    C: Command + Sync + 'static,
+    C::Response: Send,
+    H: CommandHandler<C> + Send + Sync,
+{
+    async fn handle(&self, input: &C) -> Result<C::Response, HandlerError> {
+        match &self.lock {
+            Some(lock) => {
+                lock.run(&(self.lock_name)(input), self.inner.handle(input))
+                    .await
+            }
+            None => self.inner.handle(input).await,
+        }
+    }
+}
+
+pub static DEFAULT_DEBOUNCE_WINDOW: Duration = Duration::from_secs(2);
+
+type DebouncedResult<C> = Arc<OnceCell<Result<<C as Command>::Response, HandlerError>>>;
+
+// Coalesces identical commands arriving within the window, such as the requests of an impatient
+// double-click: the first is handled and the others wait for and share its result. Commands
+// without a key, and all commands when the window is zero, pass straight through
+pub struct DebouncingHandler<C: Command, H> {
+    inner: H,
+    window: Duration,
+    key: fn(&C) -> Option<String>,
+    recent: Mutex<HashMap<String, (Instant, DebouncedResult<C>)>>,
+}
+
+impl<C: Command, H> DebouncingHandler<C, H> {
+    pub fn new(inner: H, window: Duration, key: fn(&C) -> Option<String>) -> Self {
+        DebouncingHandler {
+            inner,
+            window,
+            key,
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl<C, H> CommandHandler<C> for DebouncingHandler<C, H>
+where
+    C: Command + Sync + 'static,
+    C::Response: Clone + Send + Sync,
+    H: CommandHandler<C> + Send + Sync,
+{
+    async fn handle(&self, input: &C) -> Result<C::Response, HandlerError> {
+        let key = match (self.key)(input) {
+            Some(key) if !self.window.is_zero() => key,
+            _ => return self.inner.handle(input).await,
+        };
+
+        let now = Instant::now();
+        let result = {
+            let mut recent = self.recent.lock().unwrap();
+            recent.retain(|_, (received_at, _)| now.duration_since(*received_at) < self.window);
+            recent
+                .entry(key)
+                .or_insert_with(|| (now, Arc::new(OnceCell::new())))
+                .1
+                .clone()
+        };
+
+        let mut coalesced = true;
+        let result = result
+            .get_or_init(|| {
+                coalesced = false;
+                self.inner.handle(input)
+            })
+            .await
+            .clone();
+
+        if coalesced {
+            event!(
+                Level::INFO,
+                "Coalesced a duplicate {} into the one received before it",
+                input_name::<C>()
+            );
+            metrics::counter!(COMMANDS_COALESCED_TOTAL, "input" => input_name::<C>()).increment(1);
+        }
+
+        result
+    }
+}
+
+// Runs an authorization policy against the command before the handler sees it
+pub struct AuthorizingHandler<C, H> {
+    inner: H,
+    policy: fn(&C) -> Result<(), HandlerError>,
+}
+
+impl<C, H> AuthorizingHandler<C, H> {
+    pub fn new(inner: H, policy: fn(&C) -> Result<(), HandlerError>) -> Self {
+        AuthorizingHandler { inner, policy }
+    }
+}
+
+#[async_trait]
+impl<C, H> CommandHandler<C> for AuthorizingHandler<C, H>
+where
+    C: Command + Sync + 'static,
+    H: CommandHandler<C> + Send + Sync,
+{
+    async fn handle(&self, input: &C) -> Result<C::Response, HandlerError> {
+        (self.policy)(input)?;
+        self.inner.handle(input).await
+    }
+}
+
+// Policy for commands that may only be sent on behalf of an authenticated user
+pub fn require_user(user_id: &str) -> Result<(), HandlerError> {
+    match user_id.is_empty() {
+        true => Err(HandlerError::unauthenticated(
+            "authentication_required",
+            &[],
+        )),
+        false => Ok(()),
+    }
+}
@@ -0,0 +1,216 @@
+use std::{collections::HashMap, env, sync::Mutex};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, DurationRound, TimeDelta, Timelike, Utc};
+
+use crate::domain::Cart;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliverySlot {
+    pub id: String,
+    pub starts_at_utc: DateTime<Utc>,
+    pub ends_at_utc: DateTime<Utc>,
+    pub capacity: u32,
+    pub reserved: u32,
+}
+
+impl DeliverySlot {
+    pub fn remaining_capacity(&self) -> u32 {
+        self.capacity.saturating_sub(self.reserved)
+    }
+}
+
+// Where delivery slots and their capacity come from, e.g. the logistics service. Checkout doesn't
+// exist yet, so slots are only offered so far and nothing reserves them
+#[async_trait]
+pub trait DeliverySlotProvider {
+    // Slots starting after from_utc, full ones included so customers can see when deliveries open up
+    async fn slots(
+        &self,
+        cart: &Cart,
+        from_utc: DateTime<Utc>,
+    ) -> Result<Vec<DeliverySlot>, String>;
+    // False when the slot is full. Reserving again with the same id returns the original outcome
+    async fn reserve_slot(&self, reservation_id: &str, slot_id: &str) -> Result<bool, String>;
+    async fn release_slot(&self, reservation_id: &str) -> Result<(), String>;
+}
+
+#[derive(Debug, Clone)]
+pub struct DeliverySlotSettings {
+    pub days_ahead: u32,
+    // Hours of the day in UTC. The last slot ends at last_hour
+    pub first_hour: u32,
+    pub last_hour: u32,
+    pub slot_hours: u32,
+    pub capacity_per_slot: u32,
+    // Slots starting sooner than this can't be picked any more
+    pub lead_time: Duration,
+}
+
+impl Default for DeliverySlotSettings {
+    fn default() -> Self {
+        DeliverySlotSettings {
+            days_ahead: 7,
+            first_hour: 8,
+            last_hour: 20,
+            slot_hours: 2,
+            capacity_per_slot: 20,
+            lead_time: Duration::hours(4),
+        }
+    }
+}
+
+impl DeliverySlotSettings {
+    pub fn from_env() -> Result<DeliverySlotSettings, String> {
+        let defaults = DeliverySlotSettings::default();
+
+        let settings = DeliverySlotSettings {
+            days_ahead: env::var("DELIVERY_SLOT_DAYS_AHEAD")
+                .map(|x| x.parse().unwrap())
+                .unwrap_or(defaults.days_ahead),
+            first_hour: env::var("DELIVERY_SLOT_FIRST_HOUR")
+                .map(|x| x.parse().unwrap())
+                .unwrap_or(defaults.first_hour),
+            last_hour: env::var("DELIVERY_SLOT_LAST_HOUR")
+                .map(|x| x.parse().unwrap())
+                .unwrap_or(defaults.last_hour),
+            slot_hours: env::var("DELIVERY_SLOT_HOURS")
+                .map(|x| x.parse().unwrap())
+                .unwrap_or(defaults.slot_hours),
+            capacity_per_slot: env::var("DELIVERY_SLOT_CAPACITY")
+                .map(|x| x.parse().unwrap())
+                .unwrap_or(defaults.capacity_per_slot),
+            lead_time: env::var("DELIVERY_SLOT_LEAD_TIME_HOURS")
+                .map(|x| Duration::hours(x.parse().unwrap()))
+                .unwrap_or(defaults.lead_time),
+        };
+        settings.validate()?;
+
+        Ok(settings)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.slot_hours == 0
+            || self.last_hour > 24
+            || self.first_hour + self.slot_hours > self.last_hour
+        {
+            return Err(format!(
+                "Delivery slots of {} hours don't fit between {}:00 and {}:00",
+                self.slot_hours, self.first_hour, self.last_hour
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+// Fixed slots every day with the same capacity each, counted against reservations held in process.
+// Stands in for the logistics service until it offers slots of its own
+pub struct ScheduledDeliverySlotProvider {
+    settings: DeliverySlotSettings,
+    // Slot id by reservation id
+    reservations: Mutex<HashMap<String, String>>,
+}
+
+impl ScheduledDeliverySlotProvider {
+    pub fn new(settings: DeliverySlotSettings) -> ScheduledDeliverySlotProvider {
+        ScheduledDeliverySlotProvider {
+            settings,
+            reservations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn slot_id(starts_at_utc: DateTime<Utc>) -> String {
+        starts_at_utc.format("%Y%m%dT%H%MZ").to_string()
+    }
+
+    fn is_slot(&self, slot_id: &str) -> bool {
+        match DateTime::parse_from_str(&format!("{}+0000", slot_id), "%Y%m%dT%H%MZ%z") {
+            Ok(starts_at) => {
+                let hour = starts_at.hour();
+                starts_at.minute() == 0
+                    && hour >= self.settings.first_hour
+                    && hour + self.settings.slot_hours <= self.settings.last_hour
+                    && (hour - self.settings.first_hour).is_multiple_of(self.settings.slot_hours)
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn reserved(&self, slot_id: &str) -> u32 {
+        self.reservations
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|reserved_slot_id| *reserved_slot_id == slot_id)
+            .count() as u32
+    }
+}
+
+#[async_trait]
+impl DeliverySlotProvider for ScheduledDeliverySlotProvider {
+    async fn slots(
+        &self,
+        _cart: &Cart,
+        from_utc: DateTime<Utc>,
+    ) -> Result<Vec<DeliverySlot>, String> {
+        let earliest_start = from_utc + self.settings.lead_time;
+        let today = from_utc
+            .duration_trunc(TimeDelta::days(1))
+            .map_err(|e| format!("Failed to work out delivery days: {}", e))?;
+
+        let slot_length = Duration::hours(self.settings.slot_hours as i64);
+        let mut slots = Vec::new();
+        for day in 0..=self.settings.days_ahead {
+            let mut starts_at_utc = today
+                + Duration::days(day as i64)
+                + Duration::hours(self.settings.first_hour as i64);
+            let day_end = today
+                + Duration::days(day as i64)
+                + Duration::hours(self.settings.last_hour as i64);
+
+            while starts_at_utc + slot_length <= day_end {
+                if starts_at_utc >= earliest_start {
+                    let id = ScheduledDeliverySlotProvider::slot_id(starts_at_utc);
+                    slots.push(DeliverySlot {
+                        reserved: self.reserved(&id),
+                        id,
+                        starts_at_utc,
+                        ends_at_utc: starts_at_utc + slot_length,
+                        capacity: self.settings.capacity_per_slot,
+                    });
+                }
+                starts_at_utc += slot_length;
+            }
+        }
+
+        Ok(slots)
+    }
+
+    async fn reserve_slot(&self, reservation_id: &str, slot_id: &str) -> Result<bool, String> {
+        if !self.is_slot(slot_id) {
+            return Err(format!("Unknown delivery slot {}", slot_id));
+        }
+
+        let mut reservations = self.reservations.lock().unwrap();
+        if let Some(reserved_slot_id) = reservations.get(reservation_id) {
+            return Ok(reserved_slot_id == slot_id);
+        }
+
+        let reserved = reservations
+            .values()
+            .filter(|reserved_slot_id| *reserved_slot_id == slot_id)
+            .count() as u32;
+        if reserved >= self.settings.capacity_per_slot {
+            return Ok(false);
+        }
+
+        reservations.insert(String::from(reservation_id), String::from(slot_id));
+        Ok(true)
+    }
+
+    async fn release_slot(&self, reservation_id: &str) -> Result<(), String> {
+        self.reservations.lock().unwrap().remove(reservation_id);
+        Ok(())
+    }
+}
@@ -1,22 +1,709 @@
-use std::collections::HashMap;
+use std::{cmp::Ordering, collections::HashMap, fmt};
 
+use chrono::{DateTime, Utc};
+use mongodb::bson::Bson;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use crate::events::Event;
+
+// Stored timestamps are written as BSON dates, so MongoDB can range query them and expire them with
+// TTL indexes. Elsewhere, e.g. in SQLite and exports, they take the extended JSON form of a date.
+// Documents written before this still hold epoch milliseconds, and RFC 3339 strings are accepted
+// from imports
+pub mod timestamp {
+    use chrono::{DateTime, Utc};
+    use mongodb::bson::{self, Bson};
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        at_utc: &DateTime<Utc>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        bson::DateTime::from_millis(at_utc.timestamp_millis()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Utc>, D::Error> {
+        let at_utc = match Bson::deserialize(deserializer)? {
+            Bson::DateTime(at_utc) => DateTime::from_timestamp_millis(at_utc.timestamp_millis()),
+            Bson::Int64(millis) => DateTime::from_timestamp_millis(millis),
+            Bson::Int32(millis) => DateTime::from_timestamp_millis(i64::from(millis)),
+            Bson::Double(millis) => DateTime::from_timestamp_millis(millis as i64),
+            Bson::String(at_utc) => DateTime::parse_from_rfc3339(&at_utc)
+                .ok()
+                .map(|at_utc| at_utc.with_timezone(&Utc)),
+            other => {
+                return Err(D::Error::custom(format!(
+                    "Expected a date, found {}",
+                    other
+                )))
+            }
+        };
+
+        at_utc.ok_or_else(|| D::Error::custom("Date is out of range"))
+    }
+
+    // For optional fields, which also need #[serde(default)]
+    pub fn deserialize_optional<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<Utc>>, D::Error> {
+        #[derive(Deserialize)]
+        struct Timestamp(#[serde(deserialize_with = "deserialize")] DateTime<Utc>);
+
+        Ok(Option::<Timestamp>::deserialize(deserializer)?.map(|timestamp| timestamp.0))
+    }
+}
+
+// Each kind of identifier gets its own type so e.g. a product id can't be passed where a cart id
+// is expected. They serialize as plain strings, so stored documents and API payloads are unchanged
+macro_rules! id_newtype {
+    ($name:ident) => {
+        #[derive(
+            Debug,
+            Clone,
+            Default,
+            PartialEq,
+            Eq,
+            Hash,
+            PartialOrd,
+            Ord,
+            Serialize,
+            Deserialize,
+            JsonSchema,
+        )]
+        #[serde(transparent)]
+        pub struct $name(String);
+
+        #[allow(dead_code)]
+        impl $name {
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+
+            pub fn is_empty(&self) -> bool {
+                self.0.is_empty()
+            }
+        }
+
+        impl From<String> for $name {
+            fn from(id: String) -> Self {
+                $name(id)
+            }
+        }
+
+        impl From<&str> for $name {
+            fn from(id: &str) -> Self {
+                $name(String::from(id))
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl From<&$name> for Bson {
+            fn from(id: &$name) -> Self {
+                Bson::String(id.0.clone())
+            }
+        }
+    };
+}
+
+id_newtype!(CartId);
+id_newtype!(ProductId);
+id_newtype!(OrderId);
+id_newtype!(PaymentId);
+
+impl CartId {
+    pub fn generate() -> CartId {
+        CartId(uuid::Uuid::new_v4().to_string())
+    }
+}
+
+#[allow(dead_code)]
+impl OrderId {
+    pub fn generate() -> OrderId {
+        OrderId(uuid::Uuid::new_v4().to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderStatus {
+    #[default]
+    Placed,
+    Paid,
+    Shipped,
+    Delivered,
+    Cancelled,
+}
+
+impl OrderStatus {
+    // Orders only move forward: placed, paid, shipped, delivered. They can be cancelled until they
+    // ship, and nothing follows delivery or cancellation
+    pub fn can_transition_to(&self, status: OrderStatus) -> bool {
+        matches!(
+            (self, status),
+            (OrderStatus::Placed, OrderStatus::Paid)
+                | (OrderStatus::Paid, OrderStatus::Shipped)
+                | (OrderStatus::Shipped, OrderStatus::Delivered)
+                | (
+                    OrderStatus::Placed | OrderStatus::Paid,
+                    OrderStatus::Cancelled
+                )
+        )
+    }
+}
+
+// Ways an order can break its invariants. Handlers turn these into their usual String errors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrderError {
+    NoLineItems,
+    InvalidTransition { from: OrderStatus, to: OrderStatus },
+    // The recorded history doesn't lead to the order's current status
+    InconsistentHistory,
+}
+
+impl fmt::Display for OrderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderError::NoLineItems => write!(f, "An order needs at least one product"),
+            OrderError::InvalidTransition { from, to } => {
+                write!(f, "An order can't go from {:?} to {:?}", from, to)
+            }
+            OrderError::InconsistentHistory => {
+                write!(f, "The order's status history doesn't match its status")
+            }
+        }
+    }
+}
+
+impl From<OrderError> for String {
+    fn from(e: OrderError) -> Self {
+        e.to_string()
+    }
+}
+
+// Ways a change to a cart can be refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CartError {
+    QuantityTooLarge { max_quantity: i32 },
+}
+
+impl fmt::Display for CartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartError::QuantityTooLarge { max_quantity } => {
+                write!(
+                    f,
+                    "A cart can't hold more than {} of a product",
+                    max_quantity
+                )
+            }
+        }
+    }
+}
+
+impl From<CartError> for String {
+    fn from(e: CartError) -> Self {
+        e.to_string()
+    }
+}
+
+// One entry in an order's timeline; from is None for the status the order was placed with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderStatusTransition {
+    pub from: Option<OrderStatus>,
+    pub to: OrderStatus,
+    #[serde(with = "timestamp")]
+    pub at_utc: DateTime<Utc>,
+    pub actor: String,
+    pub reason: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Order {
-    pub id: String,
-    pub products: Vec<String>,
-    pub payment_id: String,
-    pub created_at_utc: i64,
-    pub updated_at_utc: i64,
+    pub id: OrderId,
+    pub products: Vec<ProductId>,
+    pub payment_id: PaymentId,
+    #[serde(default)]
+    pub status: OrderStatus,
+    // Every status change in the order it happened, so the timeline never has to be reconstructed
+    #[serde(default)]
+    pub status_history: Vec<OrderStatusTransition>,
+    #[serde(with = "timestamp")]
+    pub created_at_utc: DateTime<Utc>,
+    #[serde(with = "timestamp")]
+    pub updated_at_utc: DateTime<Utc>,
     pub version: u32,
 }
 
+#[allow(dead_code)]
+impl Order {
+    // A newly placed order, with the placement as the first entry of its timeline
+    pub fn new(
+        id: OrderId,
+        products: Vec<ProductId>,
+        payment_id: PaymentId,
+        actor: &str,
+        at_utc: DateTime<Utc>,
+    ) -> Result<Order, OrderError> {
+        let order = Order {
+            id,
+            products,
+            payment_id,
+            status: OrderStatus::Placed,
+            status_history: vec![OrderStatusTransition {
+                from: None,
+                to: OrderStatus::Placed,
+                at_utc,
+                actor: String::from(actor),
+                reason: String::new(),
+            }],
+            created_at_utc: at_utc,
+            updated_at_utc: at_utc,
+            version: 0,
+        };
+        order.validate()?;
+
+        Ok(order)
+    }
+
+    // Checks an order that didn't come through new, e.g. one read from an import. Orders stored
+    // before the timeline was recorded have no history, which is accepted
+    pub fn validate(&self) -> Result<(), OrderError> {
+        if self.products.is_empty() {
+            return Err(OrderError::NoLineItems);
+        }
+
+        let mut status: Option<OrderStatus> = None;
+        for transition in &self.status_history {
+            match (status, transition.from) {
+                (None, None) => {}
+                (Some(from), Some(recorded_from))
+                    if from == recorded_from && from.can_transition_to(transition.to) => {}
+                _ => return Err(OrderError::InconsistentHistory),
+            }
+            status = Some(transition.to);
+        }
+
+        match status {
+            Some(status) if status != self.status => Err(OrderError::InconsistentHistory),
+            _ => Ok(()),
+        }
+    }
+
+    // Changes the status and records who changed it and why. Returns the recorded transition
+    pub fn transition_to(
+        &mut self,
+        status: OrderStatus,
+        actor: &str,
+        reason: &str,
+        at_utc: DateTime<Utc>,
+    ) -> Result<OrderStatusTransition, OrderError> {
+        if !self.status.can_transition_to(status) {
+            return Err(OrderError::InvalidTransition {
+                from: self.status,
+                to: status,
+            });
+        }
+
+        let transition = OrderStatusTransition {
+            from: Some(self.status),
+            to: status,
+            at_utc,
+            actor: String::from(actor),
+            reason: String::from(reason),
+        };
+        self.status_history.push(transition.clone());
+        self.status = status;
+
+        Ok(transition)
+    }
+}
+
+// One product in a cart. Lines keep the order products were first added in
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CartLineItem {
+    pub product_id: ProductId,
+    pub quantity: i32,
+    #[serde(with = "timestamp")]
+    pub added_at_utc: DateTime<Utc>,
+    // What one cost when it was first added, in the smallest currency unit. There is no catalog to
+    // price products from yet, so this stays empty for now
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit_price_cents: Option<i64>,
+}
+
+// Carts stored before line items kept products as a map of quantities by product id
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredProducts {
+    LineItems(Vec<CartLineItem>),
+    Quantities(HashMap<ProductId, i32>),
+}
+
+#[derive(Deserialize)]
+struct StoredCart {
+    id: CartId,
+    #[serde(default)]
+    user_id: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    is_default: bool,
+    products: StoredProducts,
+    #[serde(with = "timestamp")]
+    created_at_utc: DateTime<Utc>,
+    #[serde(with = "timestamp")]
+    updated_at_utc: DateTime<Utc>,
+    version: u32,
+}
+
+impl From<StoredCart> for Cart {
+    fn from(cart: StoredCart) -> Self {
+        let products = match cart.products {
+            StoredProducts::LineItems(line_items) => line_items,
+            StoredProducts::Quantities(quantities) => {
+                line_items_from_quantities(quantities, cart.created_at_utc)
+            }
+        };
+
+        Cart {
+            id: cart.id,
+            user_id: cart.user_id,
+            name: cart.name,
+            is_default: cart.is_default,
+            products,
+            created_at_utc: cart.created_at_utc,
+            updated_at_utc: cart.updated_at_utc,
+            version: cart.version,
+        }
+    }
+}
+
+// When products were added isn't known for quantities by product id, so they are all dated to
+// added_at_utc and ordered by product id
+pub fn line_items_from_quantities(
+    quantities: HashMap<ProductId, i32>,
+    added_at_utc: DateTime<Utc>,
+) -> Vec<CartLineItem> {
+    let mut line_items: Vec<CartLineItem> = quantities
+        .into_iter()
+        .map(|(product_id, quantity)| CartLineItem {
+            product_id,
+            quantity,
+            added_at_utc,
+            unit_price_cents: None,
+        })
+        .collect();
+    line_items.sort_by(|a, b| a.product_id.cmp(&b.product_id));
+
+    line_items
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "StoredCart")]
 pub struct Cart {
-    pub id: String,
-    pub products: HashMap<String, i32>,
-    pub created_at_utc: i64,
-    pub updated_at_utc: i64,
+    pub id: CartId,
+    #[serde(default)]
+    pub user_id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub is_default: bool,
+    pub products: Vec<CartLineItem>,
+    #[serde(with = "timestamp")]
+    pub created_at_utc: DateTime<Utc>,
+    #[serde(with = "timestamp")]
+    pub updated_at_utc: DateTime<Utc>,
     pub version: u32,
 }
+
+// The rules for changing what is in a cart. They only touch the cart itself and return the event
+// to publish, so callers decide how the change is stored
+impl Cart {
+    // Every product counted as many times as it is in the cart
+    pub fn item_count(&self) -> u64 {
+        self.products
+            .iter()
+            .map(|line_item| line_item.quantity.max(0) as u64)
+            .sum()
+    }
+
+    pub fn contains_product(&self, product_id: &ProductId) -> bool {
+        self.products
+            .iter()
+            .any(|line_item| line_item.product_id == *product_id)
+    }
+
+    fn quantity_of(&self, product_id: &ProductId) -> i32 {
+        self.products
+            .iter()
+            .find(|line_item| line_item.product_id == *product_id)
+            .map(|line_item| line_item.quantity)
+            .unwrap_or_default()
+    }
+
+    // A product already in the cart goes up in quantity and keeps its place. Refused when the
+    // product would end up with more than max_quantity
+    pub fn add_product(
+        &mut self,
+        product_id: &ProductId,
+        quantity: i32,
+        max_quantity: i32,
+        at_utc: DateTime<Utc>,
+    ) -> Result<Event, CartError> {
+        match self.quantity_of(product_id).checked_add(quantity) {
+            Some(total) if total <= max_quantity => Ok(self.increase(product_id, quantity, at_utc)),
+            _ => Err(CartError::QuantityTooLarge { max_quantity }),
+        }
+    }
+
+    fn increase(&mut self, product_id: &ProductId, quantity: i32, at_utc: DateTime<Utc>) -> Event {
+        match self
+            .products
+            .iter_mut()
+            .find(|line_item| line_item.product_id == *product_id)
+        {
+            Some(line_item) => line_item.quantity += quantity,
+            None => self.products.push(CartLineItem {
+                product_id: product_id.clone(),
+                quantity,
+                added_at_utc: at_utc,
+                unit_price_cents: None,
+            }),
+        }
+
+        Event::ProductAddedToCartEvent {
+            product_id: product_id.clone(),
+            quantity,
+        }
+    }
+
+    // Takes one off the quantity; the product leaves the cart when none are left
+    pub fn remove_product(&mut self, product_id: &ProductId) -> Result<Event, String> {
+        match self
+            .products
+            .iter()
+            .position(|line_item| line_item.product_id == *product_id)
+        {
+            Some(index) if self.products[index].quantity > 1 => self.products[index].quantity -= 1,
+            Some(index) => {
+                self.products.remove(index);
+            }
+            None => {
+                return Err(format!(
+                    "Product {} is not in Cart with id {}",
+                    product_id, self.id
+                ))
+            }
+        }
+
+        Ok(Event::ProductRemovedFromCartEvent {
+            product_id: product_id.clone(),
+            quantity: 1,
+        })
+    }
+
+    // Sets the quantity outright, zero taking the product out. The event is for the difference,
+    // and there is none when the quantity was that already
+    pub fn set_product_quantity(
+        &mut self,
+        product_id: &ProductId,
+        quantity: i32,
+        at_utc: DateTime<Utc>,
+    ) -> Option<Event> {
+        let current_quantity = self.quantity_of(product_id);
+
+        match quantity.cmp(&current_quantity) {
+            Ordering::Equal => None,
+            Ordering::Greater => {
+                Some(self.increase(product_id, quantity - current_quantity, at_utc))
+            }
+            Ordering::Less => {
+                match quantity {
+                    0 => self
+                        .products
+                        .retain(|line_item| line_item.product_id != *product_id),
+                    _ => self
+                        .products
+                        .iter_mut()
+                        .filter(|line_item| line_item.product_id == *product_id)
+                        .for_each(|line_item| line_item.quantity = quantity),
+                }
+
+                Some(Event::ProductRemovedFromCartEvent {
+                    product_id: product_id.clone(),
+                    quantity: current_quantity - quantity,
+                })
+            }
+        }
+    }
+
+    // Takes the product out whatever its quantity, e.g. once it is no longer sold. Returns whether
+    // it was in the cart
+    pub fn discard_product(&mut self, product_id: &ProductId) -> bool {
+        let line_items = self.products.len();
+        self.products
+            .retain(|line_item| line_item.product_id != *product_id);
+
+        self.products.len() != line_items
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static MAX_QUANTITY: i32 = 10;
+
+    fn empty_cart() -> Cart {
+        let now = Utc::now();
+
+        Cart {
+            id: CartId::from("cart-1"),
+            user_id: String::from("user-1"),
+            name: String::from("Cart"),
+            is_default: true,
+            products: Vec::new(),
+            created_at_utc: now,
+            updated_at_utc: now,
+            version: 0,
+        }
+    }
+
+    fn placed_order() -> Order {
+        Order::new(
+            OrderId::from("order-1"),
+            vec![ProductId::from("product-1")],
+            PaymentId::from("payment-1"),
+            "customer-1",
+            Utc::now(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn adding_a_product_in_the_cart_increments_its_line() {
+        let mut cart = empty_cart();
+        let apple = ProductId::from("apple");
+        let pear = ProductId::from("pear");
+
+        cart.add_product(&apple, 2, MAX_QUANTITY, Utc::now())
+            .unwrap();
+        cart.add_product(&pear, 1, MAX_QUANTITY, Utc::now())
+            .unwrap();
+        let event = cart
+            .add_product(&apple, 3, MAX_QUANTITY, Utc::now())
+            .unwrap();
+
+        assert_eq!(cart.products.len(), 2);
+        assert_eq!(cart.products[0].product_id, apple);
+        assert_eq!(cart.products[0].quantity, 5);
+        assert!(matches!(
+            event,
+            Event::ProductAddedToCartEvent { product_id, quantity: 3 } if product_id == apple
+        ));
+    }
+
+    #[test]
+    fn adding_past_the_maximum_quantity_is_refused() {
+        let mut cart = empty_cart();
+        let apple = ProductId::from("apple");
+        cart.add_product(&apple, MAX_QUANTITY, MAX_QUANTITY, Utc::now())
+            .unwrap();
+
+        assert_eq!(
+            cart.add_product(&apple, 1, MAX_QUANTITY, Utc::now()).err(),
+            Some(CartError::QuantityTooLarge {
+                max_quantity: MAX_QUANTITY
+            })
+        );
+        assert!(cart
+            .add_product(&apple, i32::MAX, i32::MAX, Utc::now())
+            .is_err());
+        assert_eq!(cart.products[0].quantity, MAX_QUANTITY);
+    }
+
+    #[test]
+    fn removing_the_last_unit_takes_the_product_out() {
+        let mut cart = empty_cart();
+        let apple = ProductId::from("apple");
+        cart.add_product(&apple, 2, MAX_QUANTITY, Utc::now())
+            .unwrap();
+
+        cart.remove_product(&apple).unwrap();
+        assert_eq!(cart.products[0].quantity, 1);
+
+        let event = cart.remove_product(&apple).unwrap();
+        assert!(cart.products.is_empty());
+        assert!(matches!(
+            event,
+            Event::ProductRemovedFromCartEvent { quantity: 1, .. }
+        ));
+        assert!(cart.remove_product(&apple).is_err());
+    }
+
+    #[test]
+    fn setting_a_quantity_raises_an_event_for_the_difference() {
+        let mut cart = empty_cart();
+        let apple = ProductId::from("apple");
+        cart.add_product(&apple, 2, MAX_QUANTITY, Utc::now())
+            .unwrap();
+
+        assert!(matches!(
+            cart.set_product_quantity(&apple, 5, Utc::now()),
+            Some(Event::ProductAddedToCartEvent { quantity: 3, .. })
+        ));
+        assert!(matches!(
+            cart.set_product_quantity(&apple, 1, Utc::now()),
+            Some(Event::ProductRemovedFromCartEvent { quantity: 4, .. })
+        ));
+        assert!(cart.set_product_quantity(&apple, 1, Utc::now()).is_none());
+        assert_eq!(cart.products[0].quantity, 1);
+
+        assert!(matches!(
+            cart.set_product_quantity(&apple, 0, Utc::now()),
+            Some(Event::ProductRemovedFromCartEvent { quantity: 1, .. })
+        ));
+        assert!(cart.products.is_empty());
+    }
+
+    #[test]
+    fn orders_only_move_through_valid_transitions() {
+        let mut order = placed_order();
+
+        order
+            .transition_to(OrderStatus::Paid, "payments", "", Utc::now())
+            .unwrap();
+        assert_eq!(
+            order
+                .transition_to(OrderStatus::Delivered, "courier", "", Utc::now())
+                .unwrap_err(),
+            OrderError::InvalidTransition {
+                from: OrderStatus::Paid,
+                to: OrderStatus::Delivered
+            }
+        );
+
+        order
+            .transition_to(
+                OrderStatus::Cancelled,
+                "customer-1",
+                "changed mind",
+                Utc::now(),
+            )
+            .unwrap();
+        assert!(order
+            .transition_to(OrderStatus::Paid, "payments", "", Utc::now())
+            .is_err());
+        assert_eq!(order.status, OrderStatus::Cancelled);
+        assert_eq!(order.status_history.len(), 3);
+        assert!(order.validate().is_ok());
+    }
+}
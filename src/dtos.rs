@@ -1,38 +1,383 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{circuit_breaker::CircuitState, domain::{timestamp, CartId, CartLineItem, OrderId, OrderStatus, ProductId}, retention::RetentionRule};
 
 pub trait Response{}
 
+// Where a client can go from a resource, keyed by relation, so it doesn't have to build URLs itself
+#[derive(Serialize, Deserialize)]
+pub struct Link {
+    pub href: String,
+    pub method: String
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct CreateCartResponse {
-    pub id: String
+    pub id: CartId,
+    // Only issued for guest carts, authorizes further changes to this cart
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cart_session_token: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub links: BTreeMap<String, Link>
 }
 impl Response for CreateCartResponse{}
 
 #[derive(Serialize, Deserialize)]
 pub struct CartResponse {
-    pub id: String,
-    pub products: HashMap<String, i32>,
+    pub id: CartId,
+    pub name: String,
+    pub is_default: bool,
+    pub products: Vec<CartLineItemResponse>,
+    // Filled in by the routes, which know the paths
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub links: BTreeMap<String, Link>
+}
+
+// Lines are listed in the order their products were first added
+#[derive(Serialize, Deserialize)]
+pub struct CartLineItemResponse {
+    pub product_id: ProductId,
+    pub quantity: i32,
+    pub added_at_utc: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unit_price_cents: Option<i64>
+}
+
+impl From<CartLineItem> for CartLineItemResponse {
+    fn from(line_item: CartLineItem) -> Self {
+        CartLineItemResponse { product_id: line_item.product_id, quantity: line_item.quantity, added_at_utc: line_item.added_at_utc, unit_price_cents: line_item.unit_price_cents }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PageInfo {
+    pub page: u32,
+    pub page_size: u32,
+    pub total: u64,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct GetCartsResponse {
-    pub carts: Vec<CartResponse>
+    pub carts: Vec<CartResponse>,
+    // Only present when listing every cart. Sent in the meta of the response rather than with the carts
+    #[serde(skip)]
+    pub page: Option<PageInfo>
 }
 impl Response for GetCartsResponse{}
 
 #[derive(Serialize, Deserialize)]
+pub struct ShareCartResponse {
+    pub token: String,
+    pub expires_at_utc: DateTime<Utc>,
+}
+impl Response for ShareCartResponse{}
+
+#[derive(Serialize)]
+pub struct DeliverySlotsResponse {
+    pub cart_id: CartId,
+    pub slots: Vec<DeliverySlotResponse>
+}
+impl Response for DeliverySlotsResponse{}
+
+#[derive(Serialize)]
+pub struct DeliverySlotResponse {
+    pub id: String,
+    pub starts_at_utc: DateTime<Utc>,
+    pub ends_at_utc: DateTime<Utc>,
+    pub remaining_capacity: u32,
+    pub available: bool
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SharedCartResponse {
+    pub name: String,
+    pub products: Vec<CartLineItemResponse>,
+}
+impl Response for SharedCartResponse{}
+
+#[derive(Serialize)]
+pub struct CustomerDataErasedResponse {
+    pub customer_id: String,
+    pub carts_anonymized: usize
+}
+impl Response for CustomerDataErasedResponse{}
+
+// Everything the service holds about a customer. Orders aren't linked to customers yet, so there are none to include
+#[derive(Serialize)]
+pub struct CustomerDataExportResponse {
+    pub customer_id: String,
+    pub exported_at_utc: DateTime<Utc>,
+    pub carts: Vec<CartResponse>
+}
+impl Response for CustomerDataExportResponse{}
+
+#[derive(Serialize, Deserialize)]
+pub struct OrderResponse {
+    pub id: OrderId,
+    pub status: OrderStatus,
+    pub products: Vec<ProductId>,
+    pub created_at_utc: DateTime<Utc>,
+    pub updated_at_utc: DateTime<Utc>,
+    // Filled in by the routes, which know the paths
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub links: BTreeMap<String, Link>
+}
+impl Response for OrderResponse{}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetOrdersResponse {
+    pub orders: Vec<OrderResponse>,
+    // Sent in the meta of the response rather than with the orders
+    #[serde(skip)]
+    pub page: Option<PageInfo>
+}
+impl Response for GetOrdersResponse{}
+
+#[derive(Serialize)]
+pub struct OrderTimelineResponse {
+    pub order_id: OrderId,
+    pub status: OrderStatus,
+    pub timeline: Vec<OrderStatusTransitionResponse>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub links: BTreeMap<String, Link>
+}
+impl Response for OrderTimelineResponse{}
+
+// The code is what the QR code shown to the customer encodes
+#[derive(Serialize)]
+pub struct PickupCodeResponse {
+    pub order_id: OrderId,
+    pub code: String,
+    pub expires_at_utc: DateTime<Utc>
+}
+impl Response for PickupCodeResponse{}
+
+#[derive(Serialize)]
+pub struct PickupVerificationResponse {
+    pub order_id: OrderId,
+    pub status: OrderStatus,
+    pub products: Vec<ProductId>
+}
+impl Response for PickupVerificationResponse{}
+
+#[derive(Serialize)]
+pub struct OrderStatusTransitionResponse {
+    pub from: Option<OrderStatus>,
+    pub to: OrderStatus,
+    pub at_utc: DateTime<Utc>,
+    pub actor: String,
+    pub reason: String
+}
+
+// One line of a bulk cart import, in the shape the legacy platform exports carts in
+#[derive(Deserialize)]
+pub struct ImportedCartRecord {
+    // A new id is generated when this is missing
+    #[serde(default)]
+    pub id: Option<CartId>,
+    #[serde(default)]
+    pub user_id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub is_default: bool,
+    #[serde(default)]
+    pub products: HashMap<ProductId, i32>,
+    // Epoch milliseconds as well as RFC 3339 dates are accepted
+    #[serde(default, deserialize_with = "timestamp::deserialize_optional")]
+    pub created_at_utc: Option<DateTime<Utc>>,
+    #[serde(default, deserialize_with = "timestamp::deserialize_optional")]
+    pub updated_at_utc: Option<DateTime<Utc>>
+}
+
+#[derive(Serialize)]
+pub struct CartImportResponse {
+    pub imported: usize,
+    pub failed: usize,
+    pub results: Vec<CartImportResult>
+}
+impl Response for CartImportResponse{}
+
+// The outcome for one line of the import, by its line number
+#[derive(Serialize)]
+pub struct CartImportResult {
+    pub line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cart_id: Option<CartId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>
+}
+
+// Figures for the ops dashboard. Orders carry no prices yet, so there are no order values to add up
+#[derive(Serialize)]
+pub struct StatsResponse {
+    pub carts: u64,
+    // Carts with at least one product in them
+    pub active_carts: u64,
+    pub average_items_per_active_cart: f64,
+    pub orders_by_status: HashMap<OrderStatus, u64>
+}
+impl Response for StatsResponse{}
+
+// How many documents each retention rule matched, and so purged unless this was a dry run
+#[derive(Serialize)]
+pub struct RetentionReportResponse {
+    pub dry_run: bool,
+    pub rules: Vec<RetentionRuleReport>
+}
+impl Response for RetentionReportResponse{}
+
+#[derive(Serialize)]
+pub struct RetentionRuleReport {
+    pub rule: RetentionRule,
+    pub matched: usize
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct AddProductToCartResponse {
-    pub cart_id: String
+    pub cart_id: CartId
 }
 impl Response for AddProductToCartResponse{}
 
+#[derive(Serialize, Deserialize)]
+pub struct EventCatalogEntry {
+    pub event_type: String,
+    pub family: String,
+    pub destination: String,
+    pub schema_version: u32,
+    pub schema: Value,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EventCatalogResponse {
+    pub published: Vec<EventCatalogEntry>,
+    pub consumed: Vec<EventCatalogEntry>,
+}
+impl Response for EventCatalogResponse{}
+
+#[derive(Deserialize, Default)]
+pub struct ReadinessQuery {
+    #[serde(default)]
+    pub verbose: bool
+}
+
+// status, latency_ms and error are only filled in for verbose readiness checks
+#[derive(Serialize)]
+pub struct DependencyStatus {
+    pub name: String,
+    pub kind: String,
+    pub circuit_state: CircuitState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>
+}
+
+#[derive(Serialize)]
+pub struct ReadinessResponse {
+    pub ready: bool,
+    pub maintenance: bool,
+    // Commands are refused, because of maintenance or a database failover. Queries still work, so
+    // this alone doesn't make the service unready
+    pub read_only: bool,
+    pub dependencies: Vec<DependencyStatus>,
+}
+impl Response for ReadinessResponse{}
+
+// The body of every JSON response from the handlers. data is null when the request failed and
+// errors is empty when it succeeded
+#[derive(Serialize)]
+pub struct Envelope<T> {
+    pub data: Option<T>,
+    pub meta: ResponseMeta,
+    pub errors: Vec<ApiError>
+}
+
+#[derive(Serialize)]
+pub struct ResponseMeta {
+    pub request_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page: Option<PageInfo>
+}
+
+// code is stable for clients to branch on, message is for people
 #[derive(Serialize, Deserialize)]
 pub struct ApiError {
-    pub error: String
+    pub code: String,
+    pub message: String
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SetMaintenanceModeRequest {
+    pub enabled: bool,
+    pub message: Option<String>
+}
+
+#[derive(Serialize)]
+pub struct MaintenanceModeResponse {
+    pub enabled: bool,
+    pub message: Option<String>
+}
+
+// Omitted fields keep their current value
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SetLogSamplingRequest {
+    pub sample_rate: Option<f64>,
+    pub slow_request_millis: Option<u64>
+}
+
+#[derive(Serialize)]
+pub struct LogSamplingResponse {
+    pub sample_rate: f64,
+    pub slow_request_millis: u64
+}
+
+#[derive(Serialize)]
+pub struct InfoResponse {
+    pub name: String,
+    pub version: String,
+    pub git_sha: String,
+    pub build_time: String,
+    pub features: Vec<String>
+}
+
+// RFC 7807 body for throttled requests; reason is a stable code clients can branch on
+#[derive(Serialize)]
+pub struct ProblemDetails {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    pub reason: String
+}
+
+// RFC 7807 body for request bodies that couldn't be read as the command. reason is one of
+// unsupported_media_type, malformed_json, missing_field, unknown_field or invalid_value
+#[derive(Serialize)]
+pub struct InvalidBodyProblemDetails {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    pub reason: String,
+    // Where in the body the problem is, e.g. "products.0" or "." for the top level
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>
 }
-impl Response for ApiError{}
 
 #[derive(Deserialize, Serialize)]
 pub struct EmptyResponse{}
@@ -0,0 +1,503 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use tokio::sync::Mutex;
+
+use crate::{
+    domain::{Cart, CartId, Order, OrderId, OrderStatus, ProductId},
+    repositories::{CartRepository, CartStats, OrderRepository},
+    uow::TransactionSession,
+};
+
+// Marks a stored value as encrypted, followed by the wrapped data key and the ciphertext
+pub static ENCRYPTED_FIELD_PREFIX: &str = "enc:v1:";
+
+// A new data key is generated this often, so a leaked one only exposes the values written with it
+static DATA_KEY_MAX_AGE: Duration = Duration::from_secs(3600);
+
+static NONCE_LENGTH: usize = 12;
+
+pub struct DataKey {
+    pub plaintext: Vec<u8>,
+    // The same key encrypted under the master key, which is what gets stored
+    pub wrapped: Vec<u8>,
+}
+
+// Holds the master key that data keys are encrypted under. The master key never leaves the
+// provider, so a KMS-backed provider keeps it out of the service entirely
+#[async_trait]
+pub trait KeyProvider {
+    // A fresh AES-256 data key
+    async fn generate_data_key(&self) -> Result<DataKey, String>;
+    async fn unwrap_data_key(&self, wrapped: &[u8]) -> Result<Vec<u8>, String>;
+}
+
+// The nonce is stored in front of the ciphertext
+fn seal(cipher: &Aes256Gcm, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+    match cipher.encrypt(&nonce, plaintext) {
+        Ok(ciphertext) => Ok([nonce.as_slice(), &ciphertext].concat()),
+        Err(e) => Err(format!("Failed to encrypt: {}", e)),
+    }
+}
+
+fn open(cipher: &Aes256Gcm, sealed: &[u8]) -> Result<Vec<u8>, String> {
+    if sealed.len() < NONCE_LENGTH {
+        return Err(String::from("Failed to decrypt: ciphertext is truncated"));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+
+    match cipher.decrypt(Nonce::from_slice(nonce), ciphertext) {
+        Ok(plaintext) => Ok(plaintext),
+        Err(e) => Err(format!("Failed to decrypt: {}", e)),
+    }
+}
+
+fn data_key_cipher(data_key: &[u8]) -> Result<Aes256Gcm, String> {
+    match Aes256Gcm::new_from_slice(data_key) {
+        Ok(cipher) => Ok(cipher),
+        Err(_) => Err(String::from("Data keys must be 32 bytes")),
+    }
+}
+
+// Keeps the master key in process, for local development and deployments without a KMS
+pub struct LocalKeyProvider {
+    master_key: Aes256Gcm,
+}
+
+impl LocalKeyProvider {
+    pub fn new(master_key: &[u8]) -> Result<LocalKeyProvider, String> {
+        match Aes256Gcm::new_from_slice(master_key) {
+            Ok(master_key) => Ok(LocalKeyProvider { master_key }),
+            Err(_) => Err(String::from("The master key must be 32 bytes")),
+        }
+    }
+}
+
+#[async_trait]
+impl KeyProvider for LocalKeyProvider {
+    async fn generate_data_key(&self) -> Result<DataKey, String> {
+        let plaintext = Aes256Gcm::generate_key(&mut OsRng).to_vec();
+
+        Ok(DataKey {
+            wrapped: seal(&self.master_key, &plaintext)?,
+            plaintext,
+        })
+    }
+
+    async fn unwrap_data_key(&self, wrapped: &[u8]) -> Result<Vec<u8>, String> {
+        open(&self.master_key, wrapped)
+    }
+}
+
+// Generates and unwraps data keys with an AWS KMS key, so the master key never leaves KMS
+#[cfg(feature = "kms")]
+pub struct KmsKeyProvider {
+    client: crate::http_client::HttpClient,
+    credentials: crate::aws::AwsCredentials,
+    key_id: String,
+}
+
+#[cfg(feature = "kms")]
+impl KmsKeyProvider {
+    pub fn new(
+        key_id: &str,
+        credentials: crate::aws::AwsCredentials,
+        settings: crate::http_client::HttpClientSettings,
+    ) -> Result<KmsKeyProvider, String> {
+        Ok(KmsKeyProvider {
+            client: crate::http_client::HttpClient::new("kms", settings, None)?,
+            credentials,
+            key_id: String::from(key_id),
+        })
+    }
+
+    async fn call(
+        &self,
+        action: &str,
+        body: serde_json::Value,
+    ) -> Result<serde_json::Value, String> {
+        let mut request = match self
+            .client
+            .client()
+            .post(format!(
+                "https://kms.{}.amazonaws.com/",
+                self.credentials.region
+            ))
+            .header("content-type", "application/x-amz-json-1.1")
+            .header("x-amz-target", format!("TrentService.{}", action))
+            .body(body.to_string())
+            .build()
+        {
+            Ok(request) => request,
+            Err(e) => return Err(format!("Failed to build KMS request: {}", e)),
+        };
+
+        crate::aws::sign(&mut request, &self.credentials, "kms")?;
+
+        match self.client.execute(request).await {
+            Ok(response) if response.status().is_success() => {
+                match response.json::<serde_json::Value>().await {
+                    Ok(response) => Ok(response),
+                    Err(e) => Err(format!("Invalid KMS {} response: {}", action, e)),
+                }
+            }
+            Ok(response) => Err(format!(
+                "KMS {} failed: {} {}",
+                action,
+                response.status(),
+                response.text().await.unwrap_or_default()
+            )),
+            Err(e) => Err(format!("KMS {} failed: {}", action, e)),
+        }
+    }
+}
+
+#[cfg(feature = "kms")]
+fn kms_blob(response: &serde_json::Value, field: &str) -> Result<Vec<u8>, String> {
+    use base64::engine::general_purpose::STANDARD;
+
+    match response[field].as_str().map(|blob| STANDARD.decode(blob)) {
+        Some(Ok(blob)) => Ok(blob),
+        _ => Err(format!("KMS response has no valid {}", field)),
+    }
+}
+
+#[cfg(feature = "kms")]
+#[async_trait]
+impl KeyProvider for KmsKeyProvider {
+    async fn generate_data_key(&self) -> Result<DataKey, String> {
+        let response = self
+            .call(
+                "GenerateDataKey",
+                serde_json::json!({"KeyId": self.key_id, "KeySpec": "AES_256"}),
+            )
+            .await?;
+
+        Ok(DataKey {
+            plaintext: kms_blob(&response, "Plaintext")?,
+            wrapped: kms_blob(&response, "CiphertextBlob")?,
+        })
+    }
+
+    async fn unwrap_data_key(&self, wrapped: &[u8]) -> Result<Vec<u8>, String> {
+        use base64::engine::general_purpose::STANDARD;
+
+        let response = self
+            .call(
+                "Decrypt",
+                serde_json::json!({"KeyId": self.key_id, "CiphertextBlob": STANDARD.encode(wrapped)}),
+            )
+            .await?;
+
+        kms_blob(&response, "Plaintext")
+    }
+}
+
+// Envelope encryption of individual field values: each value is encrypted with a data key, and the
+// data key is stored next to it wrapped by the key provider. Data keys are reused for
+// DATA_KEY_MAX_AGE and unwrapped ones are cached, so the provider is rarely called
+pub struct FieldEncryptor {
+    key_provider: Arc<dyn KeyProvider + Send + Sync>,
+    current_key: Mutex<Option<(DataKey, Instant)>>,
+    // Only grows by one key per DATA_KEY_MAX_AGE and replica
+    unwrapped_keys: Mutex<HashMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl FieldEncryptor {
+    pub fn new(key_provider: Arc<dyn KeyProvider + Send + Sync>) -> FieldEncryptor {
+        FieldEncryptor {
+            key_provider,
+            current_key: Mutex::new(None),
+            unwrapped_keys: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Empty values are left as they are, since there is nothing in them to protect
+    pub async fn encrypt(&self, value: &str) -> Result<String, String> {
+        if value.is_empty() {
+            return Ok(String::new());
+        }
+
+        let mut current_key = self.current_key.lock().await;
+        let data_key = match current_key.take() {
+            Some((data_key, generated_at)) if generated_at.elapsed() < DATA_KEY_MAX_AGE => {
+                (data_key, generated_at)
+            }
+            _ => (self.key_provider.generate_data_key().await?, Instant::now()),
+        };
+        let (data_key, _) = current_key.insert(data_key);
+
+        let cipher = data_key_cipher(&data_key.plaintext)?;
+        Ok(format!(
+            "{}{}:{}",
+            ENCRYPTED_FIELD_PREFIX,
+            URL_SAFE_NO_PAD.encode(&data_key.wrapped),
+            URL_SAFE_NO_PAD.encode(seal(&cipher, value.as_bytes())?)
+        ))
+    }
+
+    // Values without the prefix are returned as they are, so documents written before encryption
+    // was turned on can still be read
+    pub async fn decrypt(&self, value: &str) -> Result<String, String> {
+        let (wrapped, sealed) = match value
+            .strip_prefix(ENCRYPTED_FIELD_PREFIX)
+            .and_then(|encrypted| encrypted.split_once(':'))
+        {
+            Some(parts) => parts,
+            None => return Ok(String::from(value)),
+        };
+        let (wrapped, sealed) = match (
+            URL_SAFE_NO_PAD.decode(wrapped),
+            URL_SAFE_NO_PAD.decode(sealed),
+        ) {
+            (Ok(wrapped), Ok(sealed)) => (wrapped, sealed),
+            _ => return Err(String::from("Failed to decrypt: value is not valid base64")),
+        };
+
+        let mut unwrapped_keys = self.unwrapped_keys.lock().await;
+        if !unwrapped_keys.contains_key(&wrapped) {
+            let data_key = self.key_provider.unwrap_data_key(&wrapped).await?;
+            unwrapped_keys.insert(wrapped.clone(), data_key);
+        }
+
+        let cipher = data_key_cipher(&unwrapped_keys[&wrapped])?;
+        match String::from_utf8(open(&cipher, &sealed)?) {
+            Ok(value) => Ok(value),
+            Err(e) => Err(format!("Failed to decrypt: {}", e)),
+        }
+    }
+}
+
+// Encrypts the names customers give their carts before they are stored. User ids stay in
+// plaintext because carts are looked up by them
+pub struct EncryptingCartRepository {
+    inner: Arc<dyn CartRepository + Send + Sync>,
+    encryptor: Arc<FieldEncryptor>,
+}
+
+impl EncryptingCartRepository {
+    pub fn new(
+        inner: Arc<dyn CartRepository + Send + Sync>,
+        encryptor: Arc<FieldEncryptor>,
+    ) -> Self {
+        EncryptingCartRepository { inner, encryptor }
+    }
+
+    async fn encrypt(&self, mut cart: Cart) -> Result<Cart, String> {
+        cart.name = self.encryptor.encrypt(&cart.name).await?;
+        Ok(cart)
+    }
+
+    async fn decrypt(&self, mut cart: Cart) -> Result<Cart, String> {
+        cart.name = self.encryptor.decrypt(&cart.name).await?;
+        Ok(cart)
+    }
+
+    async fn decrypt_all(&self, carts: Vec<Cart>) -> Result<Vec<Cart>, String> {
+        let mut decrypted = Vec::with_capacity(carts.len());
+        for cart in carts {
+            decrypted.push(self.decrypt(cart).await?);
+        }
+
+        Ok(decrypted)
+    }
+}
+
+#[async_trait]
+impl CartRepository for EncryptingCartRepository {
+    async fn create(
+        &self,
+        id: CartId,
+        cart: Cart,
+        session: TransactionSession,
+    ) -> Result<Cart, String> {
+        let cart = self.encrypt(cart).await?;
+        self.decrypt(self.inner.create(id, cart, session).await?)
+            .await
+    }
+
+    async fn read<'a>(&self, id: &'a CartId) -> Result<Cart, String> {
+        self.decrypt(self.inner.read(id).await?).await
+    }
+
+    async fn read_all(&self) -> Result<Vec<Cart>, String> {
+        self.decrypt_all(self.inner.read_all().await?).await
+    }
+
+    async fn read_updated_before<'a>(
+        &self,
+        cutoff_utc: DateTime<Utc>,
+        after_id: Option<&'a CartId>,
+        limit: u32,
+    ) -> Result<Vec<Cart>, String> {
+        self.decrypt_all(
+            self.inner
+                .read_updated_before(cutoff_utc, after_id, limit)
+                .await?,
+        )
+        .await
+    }
+
+    async fn read_all_by_user_id<'a>(&self, user_id: &'a str) -> Result<Vec<Cart>, String> {
+        self.decrypt_all(self.inner.read_all_by_user_id(user_id).await?)
+            .await
+    }
+
+    async fn read_all_containing_product<'a>(
+        &self,
+        product_id: &'a ProductId,
+    ) -> Result<Vec<Cart>, String> {
+        self.decrypt_all(self.inner.read_all_containing_product(product_id).await?)
+            .await
+    }
+
+    async fn stats(&self) -> Result<CartStats, String> {
+        self.inner.stats().await
+    }
+
+    async fn update(
+        &self,
+        id: CartId,
+        cart: Cart,
+        session: TransactionSession,
+    ) -> Result<Cart, String> {
+        let cart = self.encrypt(cart).await?;
+        self.decrypt(self.inner.update(id, cart, session).await?)
+            .await
+    }
+
+    async fn upsert(
+        &self,
+        id: CartId,
+        cart: Cart,
+        session: TransactionSession,
+    ) -> Result<Cart, String> {
+        let cart = self.encrypt(cart).await?;
+        self.decrypt(self.inner.upsert(id, cart, session).await?)
+            .await
+    }
+
+    async fn delete(&self, id: &CartId, session: TransactionSession) -> Result<(), String> {
+        self.inner.delete(id, session).await
+    }
+
+    async fn ping(&self) -> Result<(), String> {
+        self.inner.ping().await
+    }
+}
+
+// Encrypts who changed the status of an order, the only thing on an order that identifies a person
+pub struct EncryptingOrderRepository {
+    inner: Arc<dyn OrderRepository + Send + Sync>,
+    encryptor: Arc<FieldEncryptor>,
+}
+
+impl EncryptingOrderRepository {
+    pub fn new(
+        inner: Arc<dyn OrderRepository + Send + Sync>,
+        encryptor: Arc<FieldEncryptor>,
+    ) -> Self {
+        EncryptingOrderRepository { inner, encryptor }
+    }
+
+    async fn encrypt(&self, mut order: Order) -> Result<Order, String> {
+        for transition in order.status_history.iter_mut() {
+            transition.actor = self.encryptor.encrypt(&transition.actor).await?;
+        }
+        Ok(order)
+    }
+
+    async fn decrypt(&self, mut order: Order) -> Result<Order, String> {
+        for transition in order.status_history.iter_mut() {
+            transition.actor = self.encryptor.decrypt(&transition.actor).await?;
+        }
+        Ok(order)
+    }
+}
+
+#[async_trait]
+impl OrderRepository for EncryptingOrderRepository {
+    async fn create(
+        &self,
+        id: OrderId,
+        order: Order,
+        session: TransactionSession,
+    ) -> Result<Order, String> {
+        let order = self.encrypt(order).await?;
+        self.decrypt(self.inner.create(id, order, session).await?)
+            .await
+    }
+
+    async fn read<'a>(&self, id: &'a OrderId) -> Result<Order, String> {
+        self.decrypt(self.inner.read(id).await?).await
+    }
+
+    async fn read_all(&self) -> Result<Vec<Order>, String> {
+        let mut decrypted = Vec::new();
+        for order in self.inner.read_all().await? {
+            decrypted.push(self.decrypt(order).await?);
+        }
+
+        Ok(decrypted)
+    }
+
+    async fn read_updated_before<'a>(
+        &self,
+        cutoff_utc: DateTime<Utc>,
+        after_id: Option<&'a OrderId>,
+        limit: u32,
+    ) -> Result<Vec<Order>, String> {
+        let mut decrypted = Vec::new();
+        for order in self
+            .inner
+            .read_updated_before(cutoff_utc, after_id, limit)
+            .await?
+        {
+            decrypted.push(self.decrypt(order).await?);
+        }
+
+        Ok(decrypted)
+    }
+
+    async fn count_by_status(&self) -> Result<HashMap<OrderStatus, u64>, String> {
+        self.inner.count_by_status().await
+    }
+
+    async fn update(
+        &self,
+        id: OrderId,
+        order: Order,
+        session: TransactionSession,
+    ) -> Result<Order, String> {
+        let order = self.encrypt(order).await?;
+        self.decrypt(self.inner.update(id, order, session).await?)
+            .await
+    }
+
+    async fn upsert(
+        &self,
+        id: OrderId,
+        order: Order,
+        session: TransactionSession,
+    ) -> Result<Order, String> {
+        let order = self.encrypt(order).await?;
+        self.decrypt(self.inner.upsert(id, order, session).await?)
+            .await
+    }
+
+    async fn delete(&self, id: &OrderId, session: TransactionSession) -> Result<(), String> {
+        self.inner.delete(id, session).await
+    }
+}
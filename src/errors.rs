@@ -0,0 +1,126 @@
+use std::fmt;
+
+use axum::http::StatusCode;
+
+use crate::i18n;
+
+// What went wrong from the caller's point of view, which decides the status an error is answered with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    // The request itself is wrong and the caller can fix it
+    Invalid,
+    Unauthenticated,
+    NotFound,
+    // The request conflicts with the state of what it targets, e.g. cancelling a shipped order
+    Conflict,
+    // The request is well-formed but refused, e.g. a wrong pickup code
+    Rejected,
+    Internal,
+}
+
+impl ErrorKind {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorKind::Invalid => StatusCode::BAD_REQUEST,
+            ErrorKind::Unauthenticated => StatusCode::UNAUTHORIZED,
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::Conflict => StatusCode::CONFLICT,
+            ErrorKind::Rejected => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// What handlers fail with. Errors the caller can act on name a message in the catalog, whose id is
+// the error code sent to clients, and are only put into words at the HTTP edge in the caller's
+// language. Anything else, e.g. a failing database, keeps the underlying error, which clients only
+// ever see redacted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandlerError {
+    Coded {
+        kind: ErrorKind,
+        code: &'static str,
+        args: Vec<(&'static str, String)>,
+    },
+    Internal(String),
+}
+
+impl HandlerError {
+    fn coded(kind: ErrorKind, code: &'static str, args: &[(&'static str, &str)]) -> HandlerError {
+        HandlerError::Coded {
+            kind,
+            code,
+            args: args
+                .iter()
+                .map(|(name, value)| (*name, String::from(*value)))
+                .collect(),
+        }
+    }
+
+    pub fn invalid(code: &'static str, args: &[(&'static str, &str)]) -> HandlerError {
+        HandlerError::coded(ErrorKind::Invalid, code, args)
+    }
+
+    pub fn unauthenticated(code: &'static str, args: &[(&'static str, &str)]) -> HandlerError {
+        HandlerError::coded(ErrorKind::Unauthenticated, code, args)
+    }
+
+    pub fn not_found(code: &'static str, args: &[(&'static str, &str)]) -> HandlerError {
+        HandlerError::coded(ErrorKind::NotFound, code, args)
+    }
+
+    pub fn conflict(code: &'static str, args: &[(&'static str, &str)]) -> HandlerError {
+        HandlerError::coded(ErrorKind::Conflict, code, args)
+    }
+
+    pub fn rejected(code: &'static str, args: &[(&'static str, &str)]) -> HandlerError {
+        HandlerError::coded(ErrorKind::Rejected, code, args)
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            HandlerError::Coded { kind, .. } => *kind,
+            HandlerError::Internal(_) => ErrorKind::Internal,
+        }
+    }
+
+    pub fn code(&self) -> Option<&'static str> {
+        match self {
+            HandlerError::Coded { code, .. } => Some(code),
+            HandlerError::Internal(_) => None,
+        }
+    }
+
+    // The message in the given locale. Internal errors aren't translated
+    pub fn message(&self, locale: &str) -> String {
+        match self {
+            HandlerError::Coded { code, args, .. } => {
+                let args: Vec<(&str, &str)> = args
+                    .iter()
+                    .map(|(name, value)| (*name, value.as_str()))
+                    .collect();
+                i18n::catalog().message(locale, code, &args)
+            }
+            HandlerError::Internal(error) => error.clone(),
+        }
+    }
+}
+
+// In the default locale, for logs
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message(i18n::DEFAULT_LOCALE))
+    }
+}
+
+impl From<String> for HandlerError {
+    fn from(error: String) -> Self {
+        HandlerError::Internal(error)
+    }
+}
+
+impl From<HandlerError> for String {
+    fn from(error: HandlerError) -> Self {
+        error.to_string()
+    }
+}
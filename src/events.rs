@@ -5,19 +5,40 @@ use amqprs::{
         QueueDeclareArguments,
     },
     connection::{Connection, OpenConnectionArguments},
-    BasicProperties, DELIVERY_MODE_PERSISTENT,
+    BasicProperties, FieldTable, FieldValue, DELIVERY_MODE_PERSISTENT,
 };
 use async_trait::async_trait;
-use serde::Serialize;
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::Mutex;
+use tracing::{event, Level};
+
+use crate::domain::{CartId, OrderId, PaymentId, ProductId};
 
 pub static PRODUCT_ADDED_TO_CART_QUEUE_NAME: &str = "product.added.to.cart";
 pub static PRODUCT_REMOVED_FROM_CART_QUEUE_NAME: &str = "product.removed.from.cart";
+pub static CART_DELETED_QUEUE_NAME: &str = "cart.deleted";
+pub static CUSTOMER_DATA_ERASED_QUEUE_NAME: &str = "customer.data.erased";
+pub static ORDER_CANCELLED_QUEUE_NAME: &str = "order.cancelled";
+
+pub static MAX_MESSAGE_PRIORITY: u8 = 10;
+
+// Per-event-type delivery settings, keyed by queue name and applied when the queue is declared
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct QueueSettings {
+    pub message_ttl_millis: Option<u32>,
+    pub priority: Option<u8>,
+}
 
 pub struct RabbitMqInitializationInfo {
     uri: String,
     port: u16,
     username: String,
     password: String,
+    queue_settings: HashMap<String, QueueSettings>,
 }
 
 impl RabbitMqInitializationInfo {
@@ -26,56 +47,262 @@ impl RabbitMqInitializationInfo {
         port: u16,
         username: String,
         password: String,
+        queue_settings: HashMap<String, QueueSettings>,
     ) -> RabbitMqInitializationInfo {
         RabbitMqInitializationInfo {
-            uri: uri,
-            port: port,
-            username: username,
-            password: password,
+            uri,
+            port,
+            username,
+            password,
+            queue_settings,
+        }
+    }
+
+    pub async fn open_connection(&self) -> Result<Connection, String> {
+        let connection = match Connection::open(&OpenConnectionArguments::new(
+            &self.uri,
+            self.port,
+            &self.username,
+            &self.password,
+        ))
+        .await
+        {
+            Ok(connection) => connection,
+            Err(e) => return Err(format!("Failed to open RabbitMQ connection: {}", e)),
+        };
+
+        match connection
+            .register_callback(DefaultConnectionCallback)
+            .await
+        {
+            Ok(()) => Ok(connection),
+            Err(e) => Err(format!("Failed to register connection callback: {}", e)),
         }
     }
 }
 
-#[derive(Serialize)]
+// Variant names are the event types consumers see on the wire, so they keep the Event suffix
+#[allow(clippy::enum_variant_names)]
+#[derive(Serialize, Deserialize, JsonSchema)]
 pub enum Event {
-    ProductAddedToCartEvent { product_id: String },
-    ProductRemovedFromCartEvent { product_id: String },
+    ProductAddedToCartEvent {
+        product_id: ProductId,
+        // Outbox messages written before the quantity was published added or removed a single unit
+        #[serde(default = "single_unit")]
+        quantity: i32,
+    },
+    ProductRemovedFromCartEvent {
+        product_id: ProductId,
+        #[serde(default = "single_unit")]
+        quantity: i32,
+    },
+    CartDeletedEvent {
+        cart_id: CartId,
+        deleted_at_utc: i64,
+    },
+    // Tells downstream services to erase what they hold about the customer too
+    CustomerDataErasedEvent {
+        customer_id: String,
+        erased_at_utc: i64,
+    },
+    // Tells inventory to release the products and payments to refund or void the payment
+    OrderCancelledEvent {
+        order_id: OrderId,
+        payment_id: PaymentId,
+        products: Vec<ProductId>,
+        reason: String,
+        cancelled_at_utc: i64,
+    },
+}
+
+fn single_unit() -> i32 {
+    1
+}
+
+impl Event {
+    // One instance of every variant, so listings such as the event catalog can enumerate them
+    pub fn all() -> Vec<Event> {
+        vec![
+            Event::ProductAddedToCartEvent {
+                product_id: ProductId::default(),
+                quantity: 1,
+            },
+            Event::ProductRemovedFromCartEvent {
+                product_id: ProductId::default(),
+                quantity: 1,
+            },
+            Event::CartDeletedEvent {
+                cart_id: CartId::default(),
+                deleted_at_utc: 0,
+            },
+            Event::CustomerDataErasedEvent {
+                customer_id: String::new(),
+                erased_at_utc: 0,
+            },
+            Event::OrderCancelledEvent {
+                order_id: OrderId::default(),
+                payment_id: PaymentId::default(),
+                products: Vec::new(),
+                reason: String::new(),
+                cancelled_at_utc: 0,
+            },
+        ]
+    }
+
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            Event::ProductAddedToCartEvent { .. } => "ProductAddedToCartEvent",
+            Event::ProductRemovedFromCartEvent { .. } => "ProductRemovedFromCartEvent",
+            Event::CartDeletedEvent { .. } => "CartDeletedEvent",
+            Event::CustomerDataErasedEvent { .. } => "CustomerDataErasedEvent",
+            Event::OrderCancelledEvent { .. } => "OrderCancelledEvent",
+        }
+    }
+
+    // Related events share a family so transports with coarser routing (e.g. SNS topics) can group them
+    pub fn family(&self) -> &'static str {
+        match self {
+            Event::ProductAddedToCartEvent { .. }
+            | Event::ProductRemovedFromCartEvent { .. }
+            | Event::CartDeletedEvent { .. } => "cart",
+            Event::CustomerDataErasedEvent { .. } => "customer",
+            Event::OrderCancelledEvent { .. } => "order",
+        }
+    }
+
+    pub fn destination_name(&self) -> &'static str {
+        match self {
+            Event::ProductAddedToCartEvent { .. } => PRODUCT_ADDED_TO_CART_QUEUE_NAME,
+            Event::ProductRemovedFromCartEvent { .. } => PRODUCT_REMOVED_FROM_CART_QUEUE_NAME,
+            Event::CartDeletedEvent { .. } => CART_DELETED_QUEUE_NAME,
+            Event::CustomerDataErasedEvent { .. } => CUSTOMER_DATA_ERASED_QUEUE_NAME,
+            Event::OrderCancelledEvent { .. } => ORDER_CANCELLED_QUEUE_NAME,
+        }
+    }
+
+    // Bumped whenever the payload of an event type changes shape
+    pub fn schema_version(&self) -> u32 {
+        match self {
+            Event::ProductAddedToCartEvent { .. } => 2,
+            Event::ProductRemovedFromCartEvent { .. } => 2,
+            Event::CartDeletedEvent { .. } => 1,
+            Event::CustomerDataErasedEvent { .. } => 1,
+            Event::OrderCancelledEvent { .. } => 1,
+        }
+    }
+
+    // The JSON schema of this event type as published, taken from the schema generated for the enum
+    pub fn json_schema(&self) -> Value {
+        let schema = schemars::schema_for!(Event);
+
+        schema
+            .as_value()
+            .get("oneOf")
+            .and_then(Value::as_array)
+            .and_then(|variants| {
+                variants.iter().find(|variant| {
+                    variant
+                        .get("properties")
+                        .and_then(|properties| properties.get(self.event_type()))
+                        .is_some()
+                })
+            })
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn to_json(&self, format: EventFormat) -> Result<String, String> {
+        let result = match format {
+            EventFormat::Tagged => serde_json::to_string(self),
+            EventFormat::Envelope => serde_json::to_value(self).and_then(|tagged| {
+                serde_json::to_string(&EventEnvelope {
+                    event_type: self.event_type(),
+                    schema_version: self.schema_version(),
+                    data: tagged.get(self.event_type()).unwrap_or(&Value::Null),
+                })
+            }),
+        };
+
+        result.map_err(|e| format!("Failed to serialize event: {}", e))
+    }
 }
 
+// How an event is laid out on the wire. Tagged is the layout every event has been published with,
+// {"<event type>": {...}}; envelope carries the event type and schema version next to the payload
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventFormat {
+    #[default]
+    Tagged,
+    Envelope,
+}
+
+#[derive(Serialize)]
+struct EventEnvelope<'a> {
+    event_type: &'a str,
+    schema_version: u32,
+    data: &'a Value,
+}
+
+// Where and how a message broker publishes an event. Unset, the event goes to its own destination
+// in the tagged format
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct Publication {
+    pub destination: Option<String>,
+    #[serde(default)]
+    pub format: EventFormat,
+}
+
+impl Publication {
+    pub fn destination<'a>(&'a self, event: &Event) -> &'a str {
+        self.destination
+            .as_deref()
+            .unwrap_or(event.destination_name())
+    }
+}
+
+pub static ALL_QUEUE_NAMES: [&str; 5] = [
+    PRODUCT_ADDED_TO_CART_QUEUE_NAME,
+    PRODUCT_REMOVED_FROM_CART_QUEUE_NAME,
+    CART_DELETED_QUEUE_NAME,
+    CUSTOMER_DATA_ERASED_QUEUE_NAME,
+    ORDER_CANCELLED_QUEUE_NAME,
+];
+
 #[async_trait]
 pub trait MessageBroker {
-    async fn publish_message(&self, event: &Event) -> Result<(), String>;
+    async fn publish_message(&self, event: &Event) -> Result<(), String> {
+        self.publish(event, &Publication::default()).await
+    }
+
+    async fn publish(&self, event: &Event, publication: &Publication) -> Result<(), String>;
+
+    // Checks the broker is reachable; brokers without a cheap check report healthy
+    async fn ping(&self) -> Result<(), String> {
+        Ok(())
+    }
 }
 
 pub struct RabbitMqMessageBroker {
     connection: Connection,
+    queue_settings: HashMap<String, QueueSettings>,
 }
 
 impl RabbitMqMessageBroker {
     pub async fn new(
         init_info: RabbitMqInitializationInfo,
     ) -> Result<RabbitMqMessageBroker, String> {
-        match Connection::open(&OpenConnectionArguments::new(
-            &init_info.uri,
-            init_info.port,
-            &init_info.username,
-            &init_info.password,
-        ))
-        .await
-        {
-            Ok(connection) => {
-                match connection
-                    .register_callback(DefaultConnectionCallback)
-                    .await
-                {
-                    Ok(()) => Ok(RabbitMqMessageBroker {
-                        connection: connection,
-                    }),
-                    Err(e) => Err(format!("Failed to register connection callback: {}", e)),
-                }
-            }
-            Err(e) => Err(format!("Failed to open RabbitMQ connection: {}", e)),
+        let message_broker = RabbitMqMessageBroker {
+            connection: init_info.open_connection().await?,
+            queue_settings: init_info.queue_settings,
+        };
+
+        // Declare every queue up front so their arguments are fixed at startup
+        for destination in ALL_QUEUE_NAMES {
+            message_broker.get_channel(destination).await?;
         }
+
+        Ok(message_broker)
     }
 
     pub async fn get_channel(&self, destination: &str) -> Result<Channel, String> {
@@ -93,7 +320,11 @@ impl RabbitMqMessageBroker {
                     .await
                     .unwrap();
                 channel
-                    .queue_declare(QueueDeclareArguments::durable_client_named(destination))
+                    .queue_declare(
+                        QueueDeclareArguments::durable_client_named(destination)
+                            .arguments(self.queue_arguments(destination))
+                            .finish(),
+                    )
                     .await
                     .unwrap();
                 channel
@@ -106,33 +337,62 @@ impl RabbitMqMessageBroker {
             Err(e) => Err(format!("Failed to get channel: {}", e)),
         }
     }
+
+    fn queue_arguments(&self, destination: &str) -> FieldTable {
+        let mut arguments = FieldTable::new();
+
+        if let Some(settings) = self.queue_settings.get(destination) {
+            if let Some(message_ttl_millis) = settings.message_ttl_millis {
+                arguments.insert(
+                    "x-message-ttl".try_into().unwrap(),
+                    FieldValue::I(message_ttl_millis as i32),
+                );
+            }
+
+            if settings.priority.is_some() {
+                arguments.insert(
+                    "x-max-priority".try_into().unwrap(),
+                    FieldValue::B(MAX_MESSAGE_PRIORITY),
+                );
+            }
+        }
+
+        arguments
+    }
 }
 
 #[async_trait]
 impl MessageBroker for RabbitMqMessageBroker {
-    async fn publish_message(&self, event: &Event) -> Result<(), String> {
-        let mut destination_name = String::new();
-        match event {
-            Event::ProductAddedToCartEvent { .. } => {
-                destination_name = String::from(PRODUCT_ADDED_TO_CART_QUEUE_NAME);
-            }
-            Event::ProductRemovedFromCartEvent { .. } => {
-                destination_name = String::from(PRODUCT_REMOVED_FROM_CART_QUEUE_NAME);
-            }
+    async fn ping(&self) -> Result<(), String> {
+        match self.connection.is_open() {
+            true => Ok(()),
+            false => Err(String::from("RabbitMQ connection is closed")),
         }
+    }
+
+    async fn publish(&self, event: &Event, publication: &Publication) -> Result<(), String> {
+        let destination_name = publication.destination(event);
 
-        match self.get_channel(&destination_name).await {
+        match self.get_channel(destination_name).await {
             Ok(channel) => {
                 let mut delivery_properties = BasicProperties::default();
                 delivery_properties.with_delivery_mode(DELIVERY_MODE_PERSISTENT);
 
-                match serde_json::to_string(&event) {
+                if let Some(priority) = self
+                    .queue_settings
+                    .get(destination_name)
+                    .and_then(|settings| settings.priority)
+                {
+                    delivery_properties.with_priority(priority.min(MAX_MESSAGE_PRIORITY));
+                }
+
+                match event.to_json(publication.format) {
                     Ok(x) => {
                         match channel
                             .basic_publish(
                                 delivery_properties,
                                 x.into_bytes(),
-                                BasicPublishArguments::new(&destination_name, ""),
+                                BasicPublishArguments::new(destination_name, ""),
                             )
                             .await
                         {
@@ -140,10 +400,290 @@ impl MessageBroker for RabbitMqMessageBroker {
                             Err(e) => Err(format!("Failed to publish event to broker: {}", e)),
                         }
                     }
-                    Err(e) => Err(format!("Failed to serialize event: {}", e)),
+                    Err(e) => Err(e),
                 }
             }
             Err(e) => Err(format!("Failed to publish event to broker: {}", e)),
         }
     }
 }
+
+// Publishes each event type to its own JetStream stream, using the queue name as the subject
+#[cfg(feature = "nats")]
+pub struct NatsJetStreamMessageBroker {
+    client: async_nats::Client,
+    jetstream: async_nats::jetstream::Context,
+}
+
+#[cfg(feature = "nats")]
+impl NatsJetStreamMessageBroker {
+    pub async fn new(url: &str) -> Result<NatsJetStreamMessageBroker, String> {
+        match async_nats::connect(url).await {
+            Ok(client) => {
+                let jetstream = async_nats::jetstream::new(client.clone());
+
+                for subject in ALL_QUEUE_NAMES {
+                    if let Err(e) = jetstream
+                        .get_or_create_stream(async_nats::jetstream::stream::Config {
+                            name: stream_name(subject),
+                            subjects: vec![String::from(subject)],
+                            ..Default::default()
+                        })
+                        .await
+                    {
+                        return Err(format!("Failed to create stream for {}: {}", subject, e));
+                    }
+                }
+
+                Ok(NatsJetStreamMessageBroker { client, jetstream })
+            }
+            Err(e) => Err(format!("Failed to open NATS connection: {}", e)),
+        }
+    }
+}
+
+// JetStream stream names may not contain dots, e.g. "product.added.to.cart" -> "PRODUCT_ADDED_TO_CART"
+#[cfg(feature = "nats")]
+fn stream_name(subject: &str) -> String {
+    subject.replace('.', "_").to_uppercase()
+}
+
+#[cfg(feature = "nats")]
+#[async_trait]
+impl MessageBroker for NatsJetStreamMessageBroker {
+    async fn ping(&self) -> Result<(), String> {
+        match self.client.connection_state() {
+            async_nats::connection::State::Connected => Ok(()),
+            state => Err(format!("NATS connection is {:?}", state)),
+        }
+    }
+
+    // Other destinations need a stream of their own, e.g. the one consumers of a renamed subject
+    // still read from
+    async fn publish(&self, event: &Event, publication: &Publication) -> Result<(), String> {
+        match event.to_json(publication.format) {
+            Ok(x) => match self
+                .jetstream
+                .publish(String::from(publication.destination(event)), x.into())
+                .await
+            {
+                // The second await waits for the stream to acknowledge it has stored the message
+                Ok(ack) => match ack.await {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(format!("Event was not acknowledged by broker: {}", e)),
+                },
+                Err(e) => Err(format!("Failed to publish event to broker: {}", e)),
+            },
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(feature = "sns")]
+pub struct SnsInitializationInfo {
+    pub credentials: crate::aws::AwsCredentials,
+    // Topic ARNs are this prefix followed by the event family, e.g. "arn:aws:sns:eu-west-1:123456789012:eshop-"
+    pub topic_arn_prefix: String,
+}
+
+// Publishes each event to the SNS topic of its family, with the event type as a message attribute
+// so SQS subscriptions can filter on it
+#[cfg(feature = "sns")]
+pub struct SnsMessageBroker {
+    client: crate::http_client::HttpClient,
+    init_info: SnsInitializationInfo,
+}
+
+#[cfg(feature = "sns")]
+impl SnsMessageBroker {
+    // Failures are already counted by the broker's own circuit breaker, so the client doesn't get one
+    pub fn new(
+        init_info: SnsInitializationInfo,
+        settings: crate::http_client::HttpClientSettings,
+    ) -> Result<SnsMessageBroker, String> {
+        Ok(SnsMessageBroker {
+            client: crate::http_client::HttpClient::new("sns", settings, None)?,
+            init_info,
+        })
+    }
+}
+
+#[cfg(feature = "sns")]
+#[async_trait]
+impl MessageBroker for SnsMessageBroker {
+    // Topics are per family, so another destination names the topic after the prefix instead
+    async fn publish(&self, event: &Event, publication: &Publication) -> Result<(), String> {
+        let message = event.to_json(publication.format)?;
+        let topic_arn = format!(
+            "{}{}",
+            self.init_info.topic_arn_prefix,
+            publication.destination.as_deref().unwrap_or(event.family())
+        );
+
+        let mut request = match self
+            .client
+            .client()
+            .post(format!(
+                "https://sns.{}.amazonaws.com/",
+                self.init_info.credentials.region
+            ))
+            .form(&[
+                ("Action", "Publish"),
+                ("Version", "2010-03-31"),
+                ("TopicArn", topic_arn.as_str()),
+                ("Message", message.as_str()),
+                ("MessageAttributes.entry.1.Name", "event_type"),
+                ("MessageAttributes.entry.1.Value.DataType", "String"),
+                (
+                    "MessageAttributes.entry.1.Value.StringValue",
+                    event.event_type(),
+                ),
+            ])
+            .build()
+        {
+            Ok(request) => request,
+            Err(e) => return Err(format!("Failed to build SNS request: {}", e)),
+        };
+
+        crate::aws::sign(&mut request, &self.init_info.credentials, "sns")?;
+
+        match self.client.execute(request).await {
+            Ok(response) if response.status().is_success() => Ok(()),
+            Ok(response) => Err(format!(
+                "Failed to publish event to broker: {} {}",
+                response.status(),
+                response.text().await.unwrap_or_default()
+            )),
+            Err(e) => Err(format!("Failed to publish event to broker: {}", e)),
+        }
+    }
+}
+
+// Keeps published events in process, for tests and instances that run without a broker
+#[derive(Clone, Default)]
+pub struct InMemoryMessageBroker {
+    published_events: Arc<Mutex<Vec<String>>>,
+}
+
+impl InMemoryMessageBroker {
+    pub fn new() -> InMemoryMessageBroker {
+        InMemoryMessageBroker::default()
+    }
+
+    // Serialized events in the order they were published
+    #[allow(dead_code)]
+    pub async fn published_events(&self) -> Vec<String> {
+        self.published_events.lock().await.clone()
+    }
+}
+
+#[async_trait]
+impl MessageBroker for InMemoryMessageBroker {
+    async fn publish(&self, event: &Event, publication: &Publication) -> Result<(), String> {
+        let x = event.to_json(publication.format)?;
+        self.published_events.lock().await.push(x);
+        Ok(())
+    }
+}
+
+// Writes events to the log instead of a broker, for running the service without RabbitMQ
+pub struct LoggingMessageBroker {}
+
+#[async_trait]
+impl MessageBroker for LoggingMessageBroker {
+    async fn publish(&self, event: &Event, publication: &Publication) -> Result<(), String> {
+        let x = event.to_json(publication.format)?;
+        event!(
+            Level::INFO,
+            "Published event to {}: {}",
+            publication.destination(event),
+            x
+        );
+        Ok(())
+    }
+}
+
+// A copy of an event type's events published somewhere else or in another format, while consumers
+// move over from one to the other. Copies stop once the transition window ends
+#[derive(Debug, Clone, Deserialize)]
+pub struct DualPublication {
+    #[serde(flatten)]
+    pub publication: Publication,
+    pub until_utc: Option<DateTime<Utc>>,
+}
+
+// Publishes every event as usual, then publishes the copies configured for its event type. A copy
+// that fails fails the publish, so consumers of either destination never miss an event
+pub struct DualPublishingMessageBroker {
+    inner: Arc<dyn MessageBroker + Send + Sync>,
+    dual_publications: HashMap<String, Vec<DualPublication>>,
+}
+
+impl DualPublishingMessageBroker {
+    // dual_publications is keyed by event type
+    pub fn new(
+        inner: Arc<dyn MessageBroker + Send + Sync>,
+        dual_publications: HashMap<String, Vec<DualPublication>>,
+    ) -> Result<DualPublishingMessageBroker, String> {
+        for (event_type, publications) in &dual_publications {
+            if !Event::all()
+                .iter()
+                .any(|event| event.event_type() == event_type)
+            {
+                return Err(format!(
+                    "Cannot dual publish unknown event type {}",
+                    event_type
+                ));
+            }
+
+            if publications
+                .iter()
+                .any(|dual_publication| dual_publication.publication == Publication::default())
+            {
+                return Err(format!(
+                    "A dual publication of {} needs another destination or format",
+                    event_type
+                ));
+            }
+        }
+
+        Ok(DualPublishingMessageBroker {
+            inner,
+            dual_publications,
+        })
+    }
+}
+
+#[async_trait]
+impl MessageBroker for DualPublishingMessageBroker {
+    async fn publish_message(&self, event: &Event) -> Result<(), String> {
+        self.inner.publish_message(event).await?;
+
+        let now_utc = Utc::now();
+        for dual_publication in self
+            .dual_publications
+            .get(event.event_type())
+            .into_iter()
+            .flatten()
+            .filter(|dual_publication| {
+                dual_publication
+                    .until_utc
+                    .is_none_or(|until_utc| now_utc < until_utc)
+            })
+        {
+            self.inner
+                .publish(event, &dual_publication.publication)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn publish(&self, event: &Event, publication: &Publication) -> Result<(), String> {
+        self.inner.publish(event, publication).await
+    }
+
+    async fn ping(&self) -> Result<(), String> {
+        self.inner.ping().await
+    }
+}
@@ -0,0 +1,145 @@
+// Checkout doesn't exist yet, so nothing asks for variants so far. Once it does, the assignments
+// are meant to be recorded on the order and carried by its events
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct VariantAssignment {
+    pub experiment: String,
+    pub variant: String,
+}
+
+// Assigns callers to experiment variants. The same caller always lands in the same variant of an
+// experiment, so they see one checkout pipeline throughout
+#[async_trait]
+pub trait ExperimentAssigner {
+    // None when the experiment doesn't exist or isn't running
+    async fn variant(&self, experiment: &str, caller_id: &str) -> Option<VariantAssignment>;
+    // The caller's variant of every running experiment
+    async fn assignments(&self, caller_id: &str) -> Vec<VariantAssignment>;
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Variant {
+    pub name: String,
+    // Share of callers relative to the other variants' weights
+    pub weight: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Experiment {
+    pub name: String,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    pub variants: Vec<Variant>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+// Read from EXPERIMENTS_PATH, e.g. {"experiments": [{"name": "checkout_pipeline", "variants":
+// [{"name": "old", "weight": 90}, {"name": "new", "weight": 10}]}]}
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExperimentConfig {
+    #[serde(default)]
+    pub experiments: Vec<Experiment>,
+}
+
+impl ExperimentConfig {
+    pub fn from_file(path: &str) -> Result<ExperimentConfig, String> {
+        let config: ExperimentConfig = match std::fs::read_to_string(path) {
+            Ok(config) => serde_json::from_str(&config)
+                .map_err(|e| format!("Invalid experiments in {}: {}", path, e))?,
+            Err(e) => return Err(format!("Failed to read experiments {}: {}", path, e)),
+        };
+
+        for experiment in &config.experiments {
+            if experiment
+                .variants
+                .iter()
+                .map(|variant| variant.weight)
+                .sum::<u32>()
+                == 0
+            {
+                return Err(format!(
+                    "Experiment {} in {} needs a variant with a positive weight",
+                    experiment.name, path
+                ));
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+// Buckets callers by a hash of the experiment and caller id, so assignments need no storage and
+// are the same on every replica. Changing an experiment's weights reshuffles its callers
+pub struct ConfigFileExperimentAssigner {
+    config: ExperimentConfig,
+}
+
+impl ConfigFileExperimentAssigner {
+    pub fn new(config: ExperimentConfig) -> ConfigFileExperimentAssigner {
+        ConfigFileExperimentAssigner { config }
+    }
+
+    pub fn from_file(path: &str) -> Result<ConfigFileExperimentAssigner, String> {
+        Ok(ConfigFileExperimentAssigner::new(
+            ExperimentConfig::from_file(path)?,
+        ))
+    }
+
+    fn assign(experiment: &Experiment, caller_id: &str) -> Option<VariantAssignment> {
+        let total_weight: u32 = experiment
+            .variants
+            .iter()
+            .map(|variant| variant.weight)
+            .sum();
+        if !experiment.enabled || total_weight == 0 {
+            return None;
+        }
+
+        let digest = Sha256::digest(format!("{}:{}", experiment.name, caller_id));
+        let mut bucket =
+            (u64::from_be_bytes(digest[..8].try_into().unwrap()) % total_weight as u64) as u32;
+
+        experiment
+            .variants
+            .iter()
+            .find(|variant| match bucket < variant.weight {
+                true => true,
+                false => {
+                    bucket -= variant.weight;
+                    false
+                }
+            })
+            .map(|variant| VariantAssignment {
+                experiment: experiment.name.clone(),
+                variant: variant.name.clone(),
+            })
+    }
+}
+
+#[async_trait]
+impl ExperimentAssigner for ConfigFileExperimentAssigner {
+    async fn variant(&self, experiment: &str, caller_id: &str) -> Option<VariantAssignment> {
+        self.config
+            .experiments
+            .iter()
+            .find(|x| x.name == experiment)
+            .and_then(|experiment| ConfigFileExperimentAssigner::assign(experiment, caller_id))
+    }
+
+    async fn assignments(&self, caller_id: &str) -> Vec<VariantAssignment> {
+        self.config
+            .experiments
+            .iter()
+            .filter_map(|experiment| ConfigFileExperimentAssigner::assign(experiment, caller_id))
+            .collect()
+    }
+}
@@ -0,0 +1,160 @@
+use axum::{
+    body::Bytes,
+    extract::{FromRequest, OptionalFromRequest, Request},
+    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::de::DeserializeOwned;
+use serde_json::error::Category;
+
+use crate::{dtos::InvalidBodyProblemDetails, throttling::PROBLEM_JSON_CONTENT_TYPE};
+
+// Like axum's Json, but a body that can't be read as T is answered with a problem+json body saying
+// what is wrong and where, instead of a plain text message
+pub struct StrictJson<T>(pub T);
+
+pub struct InvalidBody {
+    status: StatusCode,
+    reason: &'static str,
+    detail: String,
+    field: Option<String>,
+    line: Option<usize>,
+    column: Option<usize>,
+}
+
+impl InvalidBody {
+    fn unsupported_media_type() -> InvalidBody {
+        InvalidBody {
+            status: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            reason: "unsupported_media_type",
+            detail: format!("Expected request with `{}: application/json`", CONTENT_TYPE),
+            field: None,
+            line: None,
+            column: None,
+        }
+    }
+
+    fn from_json_error(field: String, error: serde_json::Error) -> InvalidBody {
+        let message = error.to_string();
+
+        // serde only reports which field is missing or unknown in the message
+        let reason = match error.classify() {
+            Category::Syntax | Category::Eof | Category::Io => "malformed_json",
+            Category::Data if message.starts_with("missing field") => "missing_field",
+            Category::Data if message.starts_with("unknown field") => "unknown_field",
+            Category::Data => "invalid_value",
+        };
+
+        // The path of a missing field ends at the object it is missing from
+        let field = match (reason, message.split('`').nth(1)) {
+            ("missing_field", Some(name)) if field == "." => String::from(name),
+            ("missing_field", Some(name)) => format!("{}.{}", field, name),
+            _ => field,
+        };
+
+        InvalidBody {
+            status: StatusCode::BAD_REQUEST,
+            reason,
+            detail: message,
+            field: (reason != "malformed_json").then_some(field),
+            line: Some(error.line()),
+            column: Some(error.column()),
+        }
+    }
+}
+
+impl IntoResponse for InvalidBody {
+    fn into_response(self) -> Response {
+        (
+            self.status,
+            [(CONTENT_TYPE, PROBLEM_JSON_CONTENT_TYPE)],
+            Json(InvalidBodyProblemDetails {
+                problem_type: String::from("about:blank"),
+                title: String::from(self.status.canonical_reason().unwrap_or_default()),
+                status: self.status.as_u16(),
+                detail: self.detail,
+                reason: String::from(self.reason),
+                field: self.field,
+                line: self.line,
+                column: self.column,
+            }),
+        )
+            .into_response()
+    }
+}
+
+// application/json, optionally with parameters, or any +json type
+fn has_json_content_type(headers: &HeaderMap) -> bool {
+    let content_type = match headers.get(CONTENT_TYPE).and_then(|h| h.to_str().ok()) {
+        Some(content_type) => content_type,
+        None => return false,
+    };
+
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence == "application/json"
+        || (essence.starts_with("application/") && essence.ends_with("+json"))
+}
+
+impl<T, S> FromRequest<S> for StrictJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if !has_json_content_type(request.headers()) {
+            return Err(InvalidBody::unsupported_media_type().into_response());
+        }
+
+        // Bodies over the size limit are still refused the way axum refuses them
+        let body = match Bytes::from_request(request, state).await {
+            Ok(body) => body,
+            Err(rejection) => return Err(rejection.into_response()),
+        };
+
+        let mut deserializer = serde_json::Deserializer::from_slice(&body);
+        let value = match serde_path_to_error::deserialize(&mut deserializer) {
+            Ok(value) => value,
+            Err(e) => {
+                let field = e.path().to_string();
+                return Err(InvalidBody::from_json_error(field, e.into_inner()).into_response());
+            }
+        };
+
+        // Anything after the value is as much a mistake as a broken value
+        match deserializer.end() {
+            Ok(()) => Ok(StrictJson(value)),
+            Err(e) => Err(InvalidBody::from_json_error(String::from("."), e).into_response()),
+        }
+    }
+}
+
+// For bodies that may be left out: a request without Content-Type and without a body gives None
+impl<T, S> OptionalFromRequest<S> for StrictJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &S) -> Result<Option<Self>, Self::Rejection> {
+        if request.headers().contains_key(CONTENT_TYPE) {
+            return <StrictJson<T> as FromRequest<S>>::from_request(request, state)
+                .await
+                .map(Some);
+        }
+
+        match Bytes::from_request(request, state).await {
+            Ok(body) if body.is_empty() => Ok(None),
+            Ok(_) => Err(InvalidBody::unsupported_media_type().into_response()),
+            Err(rejection) => Err(rejection.into_response()),
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use std::{collections::HashMap, env, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use crate::{
+    domain::{Cart, CartId, Order, OrderId, OrderStatus, ProductId},
+    events::{Event, MessageBroker, Publication},
+    repositories::{CartRepository, CartStats, OrderRepository},
+    uow::TransactionSession,
+};
+
+pub static INJECTED_FAULTS_TOTAL: &str = "order_service_injected_faults_total";
+
+#[derive(Debug, Clone, Default)]
+pub struct FaultSettings {
+    // Added to every call; each call waits between latency and twice that
+    pub latency: Duration,
+    // Share of calls, between 0 and 1, that fail without reaching the dependency
+    pub error_rate: f64,
+}
+
+impl FaultSettings {
+    fn from_env(prefix: &str) -> Result<FaultSettings, String> {
+        let latency_var = format!("{}_LATENCY_MS", prefix);
+        let error_rate_var = format!("{}_ERROR_RATE", prefix);
+
+        let settings = FaultSettings {
+            latency: match env::var(&latency_var) {
+                Ok(latency) => match latency.parse() {
+                    Ok(latency) => Duration::from_millis(latency),
+                    Err(e) => return Err(format!("Invalid {}: {}", latency_var, e)),
+                },
+                Err(_) => Duration::ZERO,
+            },
+            error_rate: match env::var(&error_rate_var) {
+                Ok(error_rate) => match error_rate.parse() {
+                    Ok(error_rate) => error_rate,
+                    Err(e) => return Err(format!("Invalid {}: {}", error_rate_var, e)),
+                },
+                Err(_) => 0.0,
+            },
+        };
+
+        match (0.0..=1.0).contains(&settings.error_rate) {
+            true => Ok(settings),
+            false => Err(format!("{} must be between 0 and 1", error_rate_var)),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        !self.latency.is_zero() || self.error_rate > 0.0
+    }
+}
+
+// Faults injected into the repositories and the message broker, for checking that retries,
+// circuit breakers and rollbacks behave in development. Never enable this in production
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjectionSettings {
+    pub repositories: FaultSettings,
+    pub message_broker: FaultSettings,
+}
+
+impl FaultInjectionSettings {
+    // None unless FAULT_INJECTION_ENABLED is true. FAULT_INJECTION_REPOSITORY_LATENCY_MS,
+    // FAULT_INJECTION_REPOSITORY_ERROR_RATE and their FAULT_INJECTION_BROKER_ counterparts set
+    // the faults
+    pub fn from_env() -> Result<Option<FaultInjectionSettings>, String> {
+        if !env::var("FAULT_INJECTION_ENABLED").is_ok_and(|enabled| enabled == "true") {
+            return Ok(None);
+        }
+
+        Ok(Some(FaultInjectionSettings {
+            repositories: FaultSettings::from_env("FAULT_INJECTION_REPOSITORY")?,
+            message_broker: FaultSettings::from_env("FAULT_INJECTION_BROKER")?,
+        }))
+    }
+}
+
+pub struct FaultInjector {
+    dependency: String,
+    settings: FaultSettings,
+}
+
+impl FaultInjector {
+    pub fn new(dependency: &str, settings: FaultSettings) -> FaultInjector {
+        // Printed rather than logged, since the state is built before logging is set up
+        if settings.is_active() {
+            eprintln!(
+                "Injecting faults into {}: {}ms latency, {} error rate",
+                dependency,
+                settings.latency.as_millis(),
+                settings.error_rate
+            );
+        }
+
+        FaultInjector {
+            dependency: String::from(dependency),
+            settings,
+        }
+    }
+
+    // Waits out the injected latency, then fails if this call was picked to fail
+    async fn inject(&self) -> Result<(), String> {
+        if !self.settings.latency.is_zero() {
+            let jitter = (uuid::Uuid::new_v4().as_u128() % 1000) as u32;
+            tokio::time::sleep(self.settings.latency + self.settings.latency * jitter / 1000).await;
+        }
+
+        let roll = (uuid::Uuid::new_v4().as_u128() % 1_000_000) as f64 / 1_000_000.0;
+        match roll < self.settings.error_rate {
+            true => {
+                metrics::counter!(INJECTED_FAULTS_TOTAL, "dependency" => self.dependency.clone())
+                    .increment(1);
+                Err(format!("Injected fault in {}", self.dependency))
+            }
+            false => Ok(()),
+        }
+    }
+}
+
+pub struct FaultInjectingOrderRepository {
+    inner: Arc<dyn OrderRepository + Send + Sync>,
+    fault_injector: Arc<FaultInjector>,
+}
+
+impl FaultInjectingOrderRepository {
+    pub fn new(
+        inner: Arc<dyn OrderRepository + Send + Sync>,
+        fault_injector: Arc<FaultInjector>,
+    ) -> Self {
+        FaultInjectingOrderRepository {
+            inner,
+            fault_injector,
+        }
+    }
+}
+
+#[async_trait]
+impl OrderRepository for FaultInjectingOrderRepository {
+    async fn create(
+        &self,
+        id: OrderId,
+        order: Order,
+        session: TransactionSession,
+    ) -> Result<Order, String> {
+        self.fault_injector.inject().await?;
+        self.inner.create(id, order, session).await
+    }
+
+    async fn read<'a>(&self, id: &'a OrderId) -> Result<Order, String> {
+        self.fault_injector.inject().await?;
+        self.inner.read(id).await
+    }
+
+    async fn read_all(&self) -> Result<Vec<Order>, String> {
+        self.fault_injector.inject().await?;
+        self.inner.read_all().await
+    }
+
+    async fn read_updated_before<'a>(
+        &self,
+        cutoff_utc: DateTime<Utc>,
+        after_id: Option<&'a OrderId>,
+        limit: u32,
+    ) -> Result<Vec<Order>, String> {
+        self.fault_injector.inject().await?;
+        self.inner
+            .read_updated_before(cutoff_utc, after_id, limit)
+            .await
+    }
+
+    async fn count_by_status(&self) -> Result<HashMap<OrderStatus, u64>, String> {
+        self.fault_injector.inject().await?;
+        self.inner.count_by_status().await
+    }
+
+    async fn update(
+        &self,
+        id: OrderId,
+        order: Order,
+        session: TransactionSession,
+    ) -> Result<Order, String> {
+        self.fault_injector.inject().await?;
+        self.inner.update(id, order, session).await
+    }
+
+    async fn upsert(
+        &self,
+        id: OrderId,
+        order: Order,
+        session: TransactionSession,
+    ) -> Result<Order, String> {
+        self.fault_injector.inject().await?;
+        self.inner.upsert(id, order, session).await
+    }
+
+    async fn delete(&self, id: &OrderId, session: TransactionSession) -> Result<(), String> {
+        self.fault_injector.inject().await?;
+        self.inner.delete(id, session).await
+    }
+}
+
+pub struct FaultInjectingCartRepository {
+    inner: Arc<dyn CartRepository + Send + Sync>,
+    fault_injector: Arc<FaultInjector>,
+}
+
+impl FaultInjectingCartRepository {
+    pub fn new(
+        inner: Arc<dyn CartRepository + Send + Sync>,
+        fault_injector: Arc<FaultInjector>,
+    ) -> Self {
+        FaultInjectingCartRepository {
+            inner,
+            fault_injector,
+        }
+    }
+}
+
+#[async_trait]
+impl CartRepository for FaultInjectingCartRepository {
+    async fn create(
+        &self,
+        id: CartId,
+        cart: Cart,
+        session: TransactionSession,
+    ) -> Result<Cart, String> {
+        self.fault_injector.inject().await?;
+        self.inner.create(id, cart, session).await
+    }
+
+    async fn read<'a>(&self, id: &'a CartId) -> Result<Cart, String> {
+        self.fault_injector.inject().await?;
+        self.inner.read(id).await
+    }
+
+    async fn read_all(&self) -> Result<Vec<Cart>, String> {
+        self.fault_injector.inject().await?;
+        self.inner.read_all().await
+    }
+
+    async fn read_updated_before<'a>(
+        &self,
+        cutoff_utc: DateTime<Utc>,
+        after_id: Option<&'a CartId>,
+        limit: u32,
+    ) -> Result<Vec<Cart>, String> {
+        self.fault_injector.inject().await?;
+        self.inner
+            .read_updated_before(cutoff_utc, after_id, limit)
+            .await
+    }
+
+    async fn read_all_by_user_id<'a>(&self, user_id: &'a str) -> Result<Vec<Cart>, String> {
+        self.fault_injector.inject().await?;
+        self.inner.read_all_by_user_id(user_id).await
+    }
+
+    async fn read_all_containing_product<'a>(
+        &self,
+        product_id: &'a ProductId,
+    ) -> Result<Vec<Cart>, String> {
+        self.fault_injector.inject().await?;
+        self.inner.read_all_containing_product(product_id).await
+    }
+
+    async fn stats(&self) -> Result<CartStats, String> {
+        self.fault_injector.inject().await?;
+        self.inner.stats().await
+    }
+
+    async fn update(
+        &self,
+        id: CartId,
+        cart: Cart,
+        session: TransactionSession,
+    ) -> Result<Cart, String> {
+        self.fault_injector.inject().await?;
+        self.inner.update(id, cart, session).await
+    }
+
+    async fn upsert(
+        &self,
+        id: CartId,
+        cart: Cart,
+        session: TransactionSession,
+    ) -> Result<Cart, String> {
+        self.fault_injector.inject().await?;
+        self.inner.upsert(id, cart, session).await
+    }
+
+    // Health probes see the same faults, so /readyz reflects what the handlers experience
+    async fn ping(&self) -> Result<(), String> {
+        self.fault_injector.inject().await?;
+        self.inner.ping().await
+    }
+
+    async fn delete(&self, id: &CartId, session: TransactionSession) -> Result<(), String> {
+        self.fault_injector.inject().await?;
+        self.inner.delete(id, session).await
+    }
+}
+
+pub struct FaultInjectingMessageBroker {
+    inner: Arc<dyn MessageBroker + Send + Sync>,
+    fault_injector: Arc<FaultInjector>,
+}
+
+impl FaultInjectingMessageBroker {
+    pub fn new(
+        inner: Arc<dyn MessageBroker + Send + Sync>,
+        fault_injector: Arc<FaultInjector>,
+    ) -> Self {
+        FaultInjectingMessageBroker {
+            inner,
+            fault_injector,
+        }
+    }
+}
+
+#[async_trait]
+impl MessageBroker for FaultInjectingMessageBroker {
+    async fn publish(&self, event: &Event, publication: &Publication) -> Result<(), String> {
+        self.fault_injector.inject().await?;
+        self.inner.publish(event, publication).await
+    }
+
+    async fn ping(&self) -> Result<(), String> {
+        self.fault_injector.inject().await?;
+        self.inner.ping().await
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    circuit_breaker::{CircuitBreaker, CircuitState},
+    events::MessageBroker,
+    repositories::CartRepository,
+};
+
+// A probe that hangs is reported as down rather than holding up the readiness response
+static HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Clone)]
+enum Probe {
+    Persistence(Arc<dyn CartRepository + Send + Sync>),
+    MessageBroker(Arc<dyn MessageBroker + Send + Sync>),
+}
+
+#[derive(Clone)]
+pub struct HealthCheck {
+    pub healthy: bool,
+    pub latency: Duration,
+    pub error: Option<String>,
+}
+
+// An external system the service needs to serve requests, as reported by /readyz
+#[derive(Clone)]
+pub struct Dependency {
+    name: String,
+    kind: &'static str,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    probe: Probe,
+}
+
+impl Dependency {
+    pub fn persistence(
+        repository: Arc<dyn CartRepository + Send + Sync>,
+        circuit_breaker: Option<Arc<CircuitBreaker>>,
+    ) -> Dependency {
+        Dependency::new(
+            "persistence",
+            circuit_breaker,
+            Probe::Persistence(repository),
+        )
+    }
+
+    pub fn message_broker(
+        message_broker: Arc<dyn MessageBroker + Send + Sync>,
+        circuit_breaker: Option<Arc<CircuitBreaker>>,
+    ) -> Dependency {
+        Dependency::new(
+            "message_broker",
+            circuit_breaker,
+            Probe::MessageBroker(message_broker),
+        )
+    }
+
+    // Breakers are named after the backend they guard, so prefer that name when there is one
+    fn new(
+        kind: &'static str,
+        circuit_breaker: Option<Arc<CircuitBreaker>>,
+        probe: Probe,
+    ) -> Dependency {
+        Dependency {
+            name: String::from(
+                circuit_breaker
+                    .as_ref()
+                    .map(|circuit_breaker| circuit_breaker.name())
+                    .unwrap_or(kind),
+            ),
+            kind,
+            circuit_breaker,
+            probe,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn kind(&self) -> &'static str {
+        self.kind
+    }
+
+    pub fn circuit_state(&self) -> CircuitState {
+        match &self.circuit_breaker {
+            Some(circuit_breaker) => circuit_breaker.state(),
+            None => CircuitState::Closed,
+        }
+    }
+
+    pub async fn check(&self) -> HealthCheck {
+        let started = Instant::now();
+        let ping = async {
+            match &self.probe {
+                Probe::Persistence(repository) => repository.ping().await,
+                Probe::MessageBroker(message_broker) => message_broker.ping().await,
+            }
+        };
+
+        let result = match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, ping).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("Timed out after {:?}", HEALTH_CHECK_TIMEOUT)),
+        };
+
+        HealthCheck {
+            healthy: result.is_ok(),
+            latency: started.elapsed(),
+            error: result.err(),
+        }
+    }
+}
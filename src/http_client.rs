@@ -0,0 +1,289 @@
+use std::{env, sync::Arc, time::Duration};
+
+use axum::{
+    extract::Request,
+    http::{header::AUTHORIZATION, HeaderMap, HeaderName, HeaderValue, Method},
+    middleware::Next,
+    response::Response,
+};
+use tracing::{event, Level};
+
+use crate::{
+    circuit_breaker::CircuitBreaker, client_credentials::ClientCredentialsTokenProvider,
+    throttling::ThrottleReason,
+};
+
+pub static HTTP_CLIENT_REQUESTS_TOTAL: &str = "order_service_http_client_requests_total";
+pub static HTTP_CLIENT_RETRIES_TOTAL: &str = "order_service_http_client_retries_total";
+
+pub static REQUEST_ID_HEADER: &str = "x-request-id";
+
+// Incoming headers that identify the request across services. They are copied onto every outbound
+// call made while handling it
+static PROPAGATED_HEADERS: [&str; 4] = [
+    "traceparent",
+    "tracestate",
+    "x-request-id",
+    "x-correlation-id",
+];
+
+tokio::task_local! {
+    static TRACE_HEADERS: HeaderMap;
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpClientSettings {
+    // Applies to each attempt, unless the request already set its own timeout
+    pub timeout: Duration,
+    pub retry_attempts: u32,
+    // Doubled after every failed attempt
+    pub retry_base_delay: Duration,
+    pub pool_max_idle_per_host: usize,
+}
+
+impl Default for HttpClientSettings {
+    fn default() -> Self {
+        HttpClientSettings {
+            timeout: Duration::from_secs(5),
+            retry_attempts: 3,
+            retry_base_delay: Duration::from_millis(100),
+            pool_max_idle_per_host: 32,
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "sns"), allow(dead_code))]
+impl HttpClientSettings {
+    pub fn from_env() -> HttpClientSettings {
+        let defaults = HttpClientSettings::default();
+
+        HttpClientSettings {
+            timeout: env::var("HTTP_CLIENT_TIMEOUT_MILLIS")
+                .map(|x| Duration::from_millis(x.parse().unwrap()))
+                .unwrap_or(defaults.timeout),
+            retry_attempts: env::var("HTTP_CLIENT_RETRY_ATTEMPTS")
+                .map(|x| x.parse().unwrap())
+                .unwrap_or(defaults.retry_attempts),
+            retry_base_delay: env::var("HTTP_CLIENT_RETRY_BASE_DELAY_MILLIS")
+                .map(|x| Duration::from_millis(x.parse().unwrap()))
+                .unwrap_or(defaults.retry_base_delay),
+            pool_max_idle_per_host: env::var("HTTP_CLIENT_POOL_MAX_IDLE_PER_HOST")
+                .map(|x| x.parse().unwrap())
+                .unwrap_or(defaults.pool_max_idle_per_host),
+        }
+    }
+}
+
+// Makes the identifying headers of the request being handled available to outbound calls. Requests
+// that arrive without a request id are given one, which is also returned to the caller
+pub async fn trace_context_middleware(request: Request, next: Next) -> Response {
+    let mut trace_headers = HeaderMap::new();
+    for name in PROPAGATED_HEADERS {
+        if let Some(value) = request.headers().get(name) {
+            trace_headers.insert(HeaderName::from_static(name), value.clone());
+        }
+    }
+
+    let request_id = match trace_headers.get(REQUEST_ID_HEADER) {
+        Some(request_id) => request_id.clone(),
+        None => {
+            let request_id = HeaderValue::from_str(&uuid::Uuid::new_v4().to_string()).unwrap();
+            trace_headers.insert(REQUEST_ID_HEADER, request_id.clone());
+            request_id
+        }
+    };
+
+    let mut response = TRACE_HEADERS.scope(trace_headers, next.run(request)).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    response
+}
+
+// The id of the request being handled, if it came through trace_context_middleware
+pub fn current_request_id() -> Option<String> {
+    TRACE_HEADERS
+        .try_with(|trace_headers| {
+            trace_headers
+                .get(REQUEST_ID_HEADER)
+                .and_then(|request_id| request_id.to_str().ok())
+                .map(String::from)
+        })
+        .ok()
+        .flatten()
+}
+
+// The client every integration with another service should go through. Connections are pooled per
+// host, each attempt gets a timeout and the caller's trace headers, and failures are retried with
+// exponential backoff. A request is only resent when that can't duplicate its effect: connection
+// failures are always retried because nothing reached the server, but timeouts and 5xx/429
+// responses only for idempotent methods
+#[cfg_attr(not(feature = "sns"), allow(dead_code))]
+pub struct HttpClient {
+    name: String,
+    client: reqwest::Client,
+    settings: HttpClientSettings,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    // Sends a client-credentials token for this audience with every request
+    bearer_token: Option<(Arc<ClientCredentialsTokenProvider>, String)>,
+}
+
+enum AttemptError {
+    Retryable(String),
+    Fatal(String),
+}
+
+#[cfg_attr(not(feature = "sns"), allow(dead_code))]
+impl HttpClient {
+    pub fn new(
+        name: &str,
+        settings: HttpClientSettings,
+        circuit_breaker: Option<Arc<CircuitBreaker>>,
+    ) -> Result<HttpClient, String> {
+        match reqwest::Client::builder()
+            .pool_max_idle_per_host(settings.pool_max_idle_per_host)
+            .build()
+        {
+            Ok(client) => Ok(HttpClient {
+                name: String::from(name),
+                client,
+                settings,
+                circuit_breaker,
+                bearer_token: None,
+            }),
+            Err(e) => Err(format!("Failed to create HTTP client {}: {}", name, e)),
+        }
+    }
+
+    // For the payment and product service clients, which don't exist yet
+    #[allow(dead_code)]
+    pub fn with_bearer_token(
+        mut self,
+        token_provider: Arc<ClientCredentialsTokenProvider>,
+        audience: &str,
+    ) -> HttpClient {
+        self.bearer_token = Some((token_provider, String::from(audience)));
+        self
+    }
+
+    // For building requests to pass to execute
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    // Responses with a 4xx status are returned for the caller to interpret. Anything the remote
+    // service couldn't handle is an error, so it also counts against the circuit breaker
+    pub async fn execute(&self, request: reqwest::Request) -> Result<reqwest::Response, String> {
+        let idempotent = matches!(
+            *request.method(),
+            Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS
+        );
+        let mut attempt = 0;
+
+        loop {
+            // Streaming bodies can't be replayed, so those requests get a single attempt
+            let this_attempt = match request.try_clone() {
+                Some(this_attempt) => this_attempt,
+                None => return self.finish(self.send(request, idempotent).await),
+            };
+
+            match self.send(this_attempt, idempotent).await {
+                Err(AttemptError::Retryable(e)) if attempt < self.settings.retry_attempts => {
+                    attempt += 1;
+                    let delay = self.settings.retry_base_delay * 2u32.pow(attempt - 1);
+                    event!(
+                        Level::WARN,
+                        "{} call to {} failed, retrying in {:?} ({}/{}): {}",
+                        self.name,
+                        request.url(),
+                        delay,
+                        attempt,
+                        self.settings.retry_attempts,
+                        e
+                    );
+                    metrics::counter!(HTTP_CLIENT_RETRIES_TOTAL, "client" => self.name.clone())
+                        .increment(1);
+                    tokio::time::sleep(delay).await;
+                }
+                result => return self.finish(result),
+            }
+        }
+    }
+
+    fn finish(
+        &self,
+        result: Result<reqwest::Response, AttemptError>,
+    ) -> Result<reqwest::Response, String> {
+        let (outcome, result) = match result {
+            Ok(response) => ("success", Ok(response)),
+            Err(AttemptError::Retryable(e)) | Err(AttemptError::Fatal(e)) => ("failure", Err(e)),
+        };
+        metrics::counter!(HTTP_CLIENT_REQUESTS_TOTAL, "client" => self.name.clone(), "outcome" => outcome)
+            .increment(1);
+
+        result
+    }
+
+    async fn send(
+        &self,
+        mut request: reqwest::Request,
+        idempotent: bool,
+    ) -> Result<reqwest::Response, AttemptError> {
+        if request.timeout().is_none() {
+            *request.timeout_mut() = Some(self.settings.timeout);
+        }
+        let _ = TRACE_HEADERS.try_with(|trace_headers| {
+            for (name, value) in trace_headers {
+                request.headers_mut().insert(name, value.clone());
+            }
+        });
+        if let Some((token_provider, audience)) = &self.bearer_token {
+            // Boxed because the provider fetches tokens through an HttpClient of its own
+            let authorization = match Box::pin(token_provider.token(audience)).await {
+                Ok(token) => HeaderValue::from_str(&format!("Bearer {}", token)),
+                Err(e) => return Err(AttemptError::Fatal(e)),
+            };
+            match authorization {
+                Ok(authorization) => {
+                    request.headers_mut().insert(AUTHORIZATION, authorization);
+                }
+                Err(e) => return Err(AttemptError::Fatal(format!("Invalid token: {}", e))),
+            }
+        }
+
+        let url = request.url().clone();
+        let mut connect_failed = false;
+        let call = async {
+            match self.client.execute(request).await {
+                Ok(response)
+                    if response.status().is_server_error()
+                        || response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS =>
+                {
+                    Err(format!(
+                        "{} responded to {} with {}: {}",
+                        self.name,
+                        url,
+                        response.status(),
+                        response.text().await.unwrap_or_default()
+                    ))
+                }
+                Ok(response) => Ok(response),
+                Err(e) => {
+                    connect_failed = e.is_connect();
+                    Err(format!("{} call to {} failed: {}", self.name, url, e))
+                }
+            }
+        };
+
+        let result = match &self.circuit_breaker {
+            Some(circuit_breaker) => circuit_breaker.call(call).await,
+            None => call.await,
+        };
+
+        match result {
+            Ok(response) => Ok(response),
+            // An open breaker won't close within the retry delays, so retrying only adds latency
+            Err(e) if ThrottleReason::from_error(&e).is_some() => Err(AttemptError::Fatal(e)),
+            Err(e) if idempotent || connect_failed => Err(AttemptError::Retryable(e)),
+            Err(e) => Err(AttemptError::Fatal(e)),
+        }
+    }
+}
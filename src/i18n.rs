@@ -0,0 +1,233 @@
+use std::{collections::HashMap, env, sync::OnceLock};
+
+use axum::{
+    extract::Request,
+    http::{
+        header::{ACCEPT_LANGUAGE, CONTENT_LANGUAGE},
+        HeaderValue,
+    },
+    middleware::Next,
+    response::Response,
+};
+
+pub static DEFAULT_LOCALE: &str = "en";
+
+// Resources in Fluent syntax, one per locale. LOCALES_PATH can point at a directory of further
+// '<locale>.ftl' files, which add locales or replace messages of these without a rebuild
+static BUILT_IN_RESOURCES: [(&str, &str); 2] = [
+    ("en", include_str!("locales/en.ftl")),
+    ("de", include_str!("locales/de.ftl")),
+];
+
+static CATALOG: OnceLock<MessageCatalog> = OnceLock::new();
+
+tokio::task_local! {
+    static LOCALE: String;
+}
+
+#[derive(Debug, Clone)]
+enum Segment {
+    Text(String),
+    Variable(String),
+}
+
+// A message with its '{ $name }' placeables split out
+#[derive(Debug, Clone)]
+struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    fn parse(value: &str) -> Template {
+        let mut segments = Vec::new();
+        let mut rest = value;
+
+        while let Some(start) = rest.find('{') {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => break,
+            };
+            match rest[start + 1..end].trim().strip_prefix('$') {
+                Some(name) => {
+                    if start > 0 {
+                        segments.push(Segment::Text(String::from(&rest[..start])));
+                    }
+                    segments.push(Segment::Variable(String::from(name.trim())));
+                }
+                None => segments.push(Segment::Text(String::from(&rest[..=end]))),
+            }
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Text(String::from(rest)));
+        }
+
+        Template { segments }
+    }
+
+    fn render(&self, args: &[(&str, &str)]) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match segment {
+                Segment::Text(text) => text.clone(),
+                Segment::Variable(name) => args
+                    .iter()
+                    .find(|(arg_name, _)| arg_name == name)
+                    .map(|(_, value)| String::from(*value))
+                    .unwrap_or_else(|| format!("{{${}}}", name)),
+            })
+            .collect()
+    }
+}
+
+// Messages by locale and id. Ids double as the error codes clients map messages by
+#[derive(Debug, Clone)]
+pub struct MessageCatalog {
+    locales: HashMap<String, HashMap<String, Template>>,
+}
+
+impl Default for MessageCatalog {
+    fn default() -> Self {
+        let mut catalog = MessageCatalog {
+            locales: HashMap::new(),
+        };
+        for (locale, resource) in BUILT_IN_RESOURCES {
+            catalog.add_resource(locale, resource);
+        }
+
+        catalog
+    }
+}
+
+impl MessageCatalog {
+    pub fn from_env() -> Result<MessageCatalog, String> {
+        let mut catalog = MessageCatalog::default();
+
+        if let Ok(path) = env::var("LOCALES_PATH") {
+            let entries = std::fs::read_dir(&path)
+                .map_err(|e| format!("Failed to read locales in {}: {}", path, e))?;
+            for entry in entries.flatten() {
+                let file_path = entry.path();
+                let locale = match (
+                    file_path.extension().and_then(|x| x.to_str()),
+                    file_path.file_stem().and_then(|x| x.to_str()),
+                ) {
+                    (Some("ftl"), Some(locale)) => String::from(locale),
+                    _ => continue,
+                };
+                let resource = std::fs::read_to_string(&file_path)
+                    .map_err(|e| format!("Failed to read locale {}: {}", file_path.display(), e))?;
+                catalog.add_resource(&locale, &resource);
+            }
+        }
+
+        Ok(catalog)
+    }
+
+    // Takes 'id = message' entries. Indented lines continue the message above them and lines
+    // starting with '#' are comments
+    pub fn add_resource(&mut self, locale: &str, resource: &str) {
+        let messages = self.locales.entry(locale.to_ascii_lowercase()).or_default();
+        let mut entry: Option<(String, String)> = None;
+
+        for line in resource.lines() {
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with([' ', '\t']) {
+                if let Some((_, value)) = entry.as_mut() {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                }
+                continue;
+            }
+
+            if let Some((id, value)) = entry.take() {
+                messages.insert(id, Template::parse(&value));
+            }
+            if let Some((id, value)) = line.split_once('=') {
+                entry = Some((String::from(id.trim()), String::from(value.trim())));
+            }
+        }
+        if let Some((id, value)) = entry {
+            messages.insert(id, Template::parse(&value));
+        }
+    }
+
+    // Falls back to the default locale for messages that haven't been translated, and to the id
+    // itself for unknown messages
+    pub fn message(&self, locale: &str, id: &str, args: &[(&str, &str)]) -> String {
+        [locale, DEFAULT_LOCALE]
+            .iter()
+            .find_map(|locale| self.locales.get(*locale).and_then(|x| x.get(id)))
+            .map(|template| template.render(args))
+            .unwrap_or_else(|| String::from(id))
+    }
+
+    // The best locale of an Accept-Language header there are messages for, e.g. 'de' for
+    // 'de-CH, fr;q=0.8'
+    pub fn negotiate(&self, accept_language: &str) -> String {
+        let mut ranges: Vec<(&str, f32)> = accept_language
+            .split(',')
+            .filter_map(|range| {
+                let mut parts = range.split(';');
+                let tag = parts.next()?.trim();
+                let quality = parts
+                    .find_map(|parameter| parameter.trim().strip_prefix("q="))
+                    .and_then(|quality| quality.parse().ok())
+                    .unwrap_or(1.0);
+                (!tag.is_empty() && quality > 0.0).then_some((tag, quality))
+            })
+            .collect();
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        ranges
+            .iter()
+            .find_map(|(tag, _)| {
+                let tag = tag.to_ascii_lowercase();
+                let primary = tag.split('-').next().unwrap_or_default();
+                let locale = [tag.as_str(), primary]
+                    .into_iter()
+                    .find(|candidate| self.locales.contains_key(*candidate))
+                    .map(String::from);
+                locale
+            })
+            .unwrap_or_else(|| String::from(DEFAULT_LOCALE))
+    }
+}
+
+// Installed once at startup, before the server starts handling requests
+pub fn install(catalog: MessageCatalog) {
+    CATALOG.set(catalog).ok();
+}
+
+pub fn catalog() -> &'static MessageCatalog {
+    CATALOG.get_or_init(MessageCatalog::default)
+}
+
+// The locale negotiated for the request being handled
+pub fn current_locale() -> String {
+    LOCALE
+        .try_with(String::clone)
+        .unwrap_or_else(|_| String::from(DEFAULT_LOCALE))
+}
+
+pub async fn locale_middleware(request: Request, next: Next) -> Response {
+    let locale = match request
+        .headers()
+        .get(ACCEPT_LANGUAGE)
+        .and_then(|accept_language| accept_language.to_str().ok())
+    {
+        Some(accept_language) => catalog().negotiate(accept_language),
+        None => String::from(DEFAULT_LOCALE),
+    };
+
+    let mut response = LOCALE.scope(locale.clone(), next.run(request)).await;
+    if let Ok(content_language) = HeaderValue::from_str(&locale) {
+        response
+            .headers_mut()
+            .insert(CONTENT_LANGUAGE, content_language);
+    }
+    response
+}
@@ -0,0 +1,420 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{header::CONTENT_TYPE, Extensions, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use mongodb::{bson::doc, Client, Collection};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{event, Level};
+
+use crate::{
+    api_response::ApiResponse,
+    auth::{CartSession, Claims},
+    domain::timestamp,
+    leader_election::is_duplicate_key,
+};
+
+pub static IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+// Set on responses replayed from an earlier request with the same key
+pub static IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+pub static DEFAULT_IDEMPOTENCY_KEY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+// How long a key stays claimed by a request that hasn't completed. A process that dies while
+// handling it never releases the key, so a retry may take it over once this has passed
+static IDEMPOTENCY_KEY_LEASE: Duration = Duration::from_secs(60);
+static MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+// Requests with a key are read whole to be fingerprinted, so their bodies are capped
+static IDEMPOTENT_REQUEST_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+// What a key was first used for and, once that request completed, what it was answered with
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IdempotencyRecord {
+    #[serde(rename = "_id")]
+    pub key: String,
+    pub request_fingerprint: String,
+    // None while the first request is still being handled
+    pub response: Option<StoredResponse>,
+    // Until the first request completes, when its lease on the key runs out
+    #[serde(with = "timestamp")]
+    pub expires_at_utc: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait IdempotencyStore {
+    // Stores the record unless its key is already taken by a record that hasn't expired, in which
+    // case the existing record is returned instead
+    async fn claim(&self, record: IdempotencyRecord) -> Result<Option<IdempotencyRecord>, String>;
+    // Keeps the response until expires_at_utc
+    async fn complete(
+        &self,
+        key: &str,
+        response: StoredResponse,
+        expires_at_utc: DateTime<Utc>,
+    ) -> Result<(), String>;
+    // Frees the key again, so a retry is handled as a new request
+    async fn release(&self, key: &str) -> Result<(), String>;
+}
+
+// For backends that only ever run as a single process
+#[derive(Default)]
+pub struct InMemoryIdempotencyStore {
+    records: Mutex<HashMap<String, IdempotencyRecord>>,
+}
+
+impl InMemoryIdempotencyStore {
+    pub fn new() -> InMemoryIdempotencyStore {
+        InMemoryIdempotencyStore::default()
+    }
+}
+
+#[async_trait]
+impl IdempotencyStore for InMemoryIdempotencyStore {
+    async fn claim(&self, record: IdempotencyRecord) -> Result<Option<IdempotencyRecord>, String> {
+        let mut records = self.records.lock().unwrap();
+
+        let now = Utc::now();
+        records.retain(|_, record| record.expires_at_utc > now);
+
+        match records.get(&record.key) {
+            Some(existing) => Ok(Some(existing.clone())),
+            None => {
+                records.insert(record.key.clone(), record);
+                Ok(None)
+            }
+        }
+    }
+
+    async fn complete(
+        &self,
+        key: &str,
+        response: StoredResponse,
+        expires_at_utc: DateTime<Utc>,
+    ) -> Result<(), String> {
+        if let Some(record) = self.records.lock().unwrap().get_mut(key) {
+            record.response = Some(response);
+            record.expires_at_utc = expires_at_utc;
+        }
+
+        Ok(())
+    }
+
+    async fn release(&self, key: &str) -> Result<(), String> {
+        self.records.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+// One document per key, removed by the TTL index on expires_at_utc from the migrations
+pub struct MongoDbIdempotencyStore {
+    idempotency_collection: Collection<IdempotencyRecord>,
+}
+
+impl MongoDbIdempotencyStore {
+    pub fn new(client: &Client, database: &str, collection: &str) -> MongoDbIdempotencyStore {
+        MongoDbIdempotencyStore {
+            idempotency_collection: client.database(database).collection(collection),
+        }
+    }
+}
+
+#[async_trait]
+impl IdempotencyStore for MongoDbIdempotencyStore {
+    // The TTL monitor only removes expired records about once a minute, so until then they are
+    // replaced here
+    async fn claim(&self, record: IdempotencyRecord) -> Result<Option<IdempotencyRecord>, String> {
+        let key = record.key.clone();
+        let now = mongodb::bson::DateTime::from_millis(Utc::now().timestamp_millis());
+
+        match self
+            .idempotency_collection
+            .find_one_and_replace(doc! {"_id": &key, "expires_at_utc": {"$lte": now}}, &record)
+            .await
+        {
+            Ok(Some(_)) => return Ok(None),
+            Ok(None) => {}
+            Err(e) => return Err(format!("Failed to store idempotency key: {}", e)),
+        }
+
+        match self.idempotency_collection.insert_one(record).await {
+            Ok(_) => Ok(None),
+            Err(e) if is_duplicate_key(&e) => {
+                match self
+                    .idempotency_collection
+                    .find_one(doc! {"_id": &key})
+                    .await
+                {
+                    Ok(existing) => Ok(existing),
+                    Err(e) => Err(format!("Failed to read idempotency key: {}", e)),
+                }
+            }
+            Err(e) => Err(format!("Failed to store idempotency key: {}", e)),
+        }
+    }
+
+    async fn complete(
+        &self,
+        key: &str,
+        response: StoredResponse,
+        expires_at_utc: DateTime<Utc>,
+    ) -> Result<(), String> {
+        let response = match mongodb::bson::to_bson(&response) {
+            Ok(response) => response,
+            Err(e) => return Err(format!("Failed to serialize response: {}", e)),
+        };
+        let expires_at_utc =
+            mongodb::bson::DateTime::from_millis(expires_at_utc.timestamp_millis());
+
+        match self
+            .idempotency_collection
+            .update_one(
+                doc! {"_id": key},
+                doc! {"$set": {"response": response, "expires_at_utc": expires_at_utc}},
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Failed to store idempotent response: {}", e)),
+        }
+    }
+
+    async fn release(&self, key: &str) -> Result<(), String> {
+        match self
+            .idempotency_collection
+            .delete_one(doc! {"_id": key})
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Failed to release idempotency key: {}", e)),
+        }
+    }
+}
+
+pub struct IdempotencyKeys {
+    store: Arc<dyn IdempotencyStore + Send + Sync>,
+    ttl: Duration,
+}
+
+impl IdempotencyKeys {
+    pub fn new(store: Arc<dyn IdempotencyStore + Send + Sync>, ttl: Duration) -> IdempotencyKeys {
+        IdempotencyKeys { store, ttl }
+    }
+}
+
+fn sha256(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+
+    format!("{:x}", hasher.finalize())
+}
+
+// Who the authentication middleware verified the caller to be. Keys are scoped to that rather than
+// to the credentials, so a retry sent with a refreshed token is still recognized. Guests creating a
+// cart have neither, and nothing keeps one guest from picking another's key, so they get no scope
+fn caller(extensions: &Extensions) -> Option<String> {
+    if let Some(claims) = extensions.get::<Claims>() {
+        return Some(format!("user {} {}", claims.iss, claims.sub));
+    }
+    if let Some(cart_session) = extensions.get::<CartSession>() {
+        return Some(format!("cart {}", cart_session.cart_id));
+    }
+
+    None
+}
+
+// A POST or PUT sent with an Idempotency-Key header is handled once; retries with the same key get
+// the first response back until the key expires. Keys are scoped to the caller, so this has to run
+// after authentication, and reusing one for a different request is refused. Callers authentication
+// hasn't identified are handled as if they sent no key, as replaying to them could hand one guest
+// another's cart session token. Server errors aren't kept, so they can be retried
+pub async fn idempotency_middleware(
+    State(idempotency_keys): State<Arc<IdempotencyKeys>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !matches!(*request.method(), Method::POST | Method::PUT) {
+        return next.run(request).await;
+    }
+
+    let key = match request.headers().get(IDEMPOTENCY_KEY_HEADER) {
+        Some(key) => match key.to_str() {
+            Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LENGTH => {
+                String::from(key)
+            }
+            _ => {
+                return ApiResponse::error(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_idempotency_key",
+                    format!(
+                        "Idempotency key must be between 1 and {} visible ASCII characters",
+                        MAX_IDEMPOTENCY_KEY_LENGTH
+                    ),
+                )
+                .into_response()
+            }
+        },
+        None => return next.run(request).await,
+    };
+    let caller = match caller(request.extensions()) {
+        Some(caller) => caller,
+        None => return next.run(request).await,
+    };
+
+    let (parts, body) = request.into_parts();
+    let body = match to_bytes(body, IDEMPOTENT_REQUEST_BODY_LIMIT).await {
+        Ok(body) => body,
+        Err(_) => {
+            return ApiResponse::error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "request_too_large",
+                format!(
+                    "Requests with an idempotency key can't be larger than {} bytes",
+                    IDEMPOTENT_REQUEST_BODY_LIMIT
+                ),
+            )
+            .into_response()
+        }
+    };
+
+    let scoped_key = sha256(&[caller.as_bytes(), key.as_bytes()]);
+    let request_fingerprint = sha256(&[
+        parts.method.as_str().as_bytes(),
+        parts.uri.to_string().as_bytes(),
+        &body,
+    ]);
+
+    let claimed = idempotency_keys
+        .store
+        .claim(IdempotencyRecord {
+            key: scoped_key.clone(),
+            request_fingerprint: request_fingerprint.clone(),
+            response: None,
+            expires_at_utc: Utc::now()
+                + TimeDelta::from_std(IDEMPOTENCY_KEY_LEASE).unwrap_or(TimeDelta::zero()),
+        })
+        .await;
+
+    match claimed {
+        Ok(None) => {}
+        Ok(Some(existing)) if existing.request_fingerprint != request_fingerprint => {
+            return ApiResponse::error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "idempotency_key_reused",
+                String::from("Idempotency key was already used for a different request"),
+            )
+            .into_response()
+        }
+        Ok(Some(IdempotencyRecord {
+            response: Some(response),
+            ..
+        })) => return replay(response),
+        Ok(Some(_)) => {
+            return ApiResponse::error(
+                StatusCode::CONFLICT,
+                "idempotency_request_in_progress",
+                String::from("A request with this idempotency key is still being handled"),
+            )
+            .into_response()
+        }
+        Err(e) => {
+            event!(Level::WARN, "Failed to claim idempotency key: {}", e);
+            return ApiResponse::error(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "idempotency_unavailable",
+                String::from("Idempotency keys can't be checked right now"),
+            )
+            .into_response();
+        }
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+
+    if response.status().is_server_error() {
+        if let Err(e) = idempotency_keys.store.release(&scoped_key).await {
+            event!(Level::WARN, "{}", e);
+        }
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            if let Err(e) = idempotency_keys.store.release(&scoped_key).await {
+                event!(Level::WARN, "{}", e);
+            }
+            return ApiResponse::error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+                format!("Failed to read response: {}", e),
+            )
+            .into_response();
+        }
+    };
+
+    // Handlers answer in JSON; anything else is passed through without being kept
+    let stored = match std::str::from_utf8(&body) {
+        Ok(stored_body) => {
+            idempotency_keys
+                .store
+                .complete(
+                    &scoped_key,
+                    StoredResponse {
+                        status: parts.status.as_u16(),
+                        content_type: parts
+                            .headers
+                            .get(CONTENT_TYPE)
+                            .and_then(|content_type| content_type.to_str().ok())
+                            .map(String::from),
+                        body: String::from(stored_body),
+                    },
+                    Utc::now()
+                        + TimeDelta::from_std(idempotency_keys.ttl).unwrap_or(TimeDelta::zero()),
+                )
+                .await
+        }
+        Err(_) => idempotency_keys.store.release(&scoped_key).await,
+    };
+    if let Err(e) = stored {
+        event!(Level::WARN, "{}", e);
+    }
+
+    Response::from_parts(parts, Body::from(body))
+}
+
+fn replay(stored: StoredResponse) -> Response {
+    let mut response = Response::new(Body::from(stored.body));
+    *response.status_mut() =
+        StatusCode::from_u16(stored.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+    let headers = response.headers_mut();
+    if let Some(content_type) = stored
+        .content_type
+        .and_then(|content_type| HeaderValue::from_str(&content_type).ok())
+    {
+        headers.insert(CONTENT_TYPE, content_type);
+    }
+    headers.insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+
+    response
+}
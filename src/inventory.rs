@@ -0,0 +1,359 @@
+// Checkout doesn't exist yet, so nothing asks the inventory service for stock so far
+#![allow(dead_code)]
+
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+
+use crate::{client_credentials::ClientCredentialsTokenProvider, domain::ProductId};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StockItem {
+    pub product_id: ProductId,
+    pub quantity: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StockLevel {
+    pub product_id: ProductId,
+    pub available: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReservationOutcome {
+    // False when any item is short, in which case nothing is held
+    pub reserved: bool,
+    pub unavailable: Vec<ProductId>,
+}
+
+// Synchronous stock checks against the inventory service. Every call takes the deadline of the work
+// it is part of, so the inventory service stops as soon as the caller has given up on the answer
+#[async_trait]
+pub trait InventoryClient {
+    async fn check_stock(
+        &self,
+        product_ids: &[ProductId],
+        deadline: Instant,
+    ) -> Result<Vec<StockLevel>, String>;
+    // Reserving again with the same id returns the original outcome, so retries are safe
+    async fn reserve_stock(
+        &self,
+        reservation_id: &str,
+        items: &[StockItem],
+        deadline: Instant,
+    ) -> Result<ReservationOutcome, String>;
+    async fn release_reservation(
+        &self,
+        reservation_id: &str,
+        deadline: Instant,
+    ) -> Result<(), String>;
+}
+
+fn remaining(deadline: Instant) -> Result<Duration, String> {
+    match deadline.checked_duration_since(Instant::now()) {
+        Some(remaining) if !remaining.is_zero() => Ok(remaining),
+        _ => Err(String::from(
+            "Deadline exceeded before calling the inventory service",
+        )),
+    }
+}
+
+// Stands in for the inventory service in tests and when INVENTORY_SERVICE_URL isn't set. Products
+// without a stock level set have default_available units
+pub struct InMemoryInventoryClient {
+    default_available: u32,
+    stock: Mutex<HashMap<ProductId, u32>>,
+    reservations: Mutex<HashMap<String, (Vec<StockItem>, ReservationOutcome)>>,
+}
+
+impl InMemoryInventoryClient {
+    pub fn new(default_available: u32) -> InMemoryInventoryClient {
+        InMemoryInventoryClient {
+            default_available,
+            stock: Mutex::new(HashMap::new()),
+            reservations: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_stock(&self, product_id: &ProductId, available: u32) {
+        self.stock
+            .lock()
+            .unwrap()
+            .insert(product_id.clone(), available);
+    }
+}
+
+#[async_trait]
+impl InventoryClient for InMemoryInventoryClient {
+    async fn check_stock(
+        &self,
+        product_ids: &[ProductId],
+        deadline: Instant,
+    ) -> Result<Vec<StockLevel>, String> {
+        remaining(deadline)?;
+        let stock = self.stock.lock().unwrap();
+
+        Ok(product_ids
+            .iter()
+            .map(|product_id| StockLevel {
+                product_id: product_id.clone(),
+                available: *stock.get(product_id).unwrap_or(&self.default_available),
+            })
+            .collect())
+    }
+
+    async fn reserve_stock(
+        &self,
+        reservation_id: &str,
+        items: &[StockItem],
+        deadline: Instant,
+    ) -> Result<ReservationOutcome, String> {
+        remaining(deadline)?;
+        let mut reservations = self.reservations.lock().unwrap();
+        if let Some((_, outcome)) = reservations.get(reservation_id) {
+            return Ok(outcome.clone());
+        }
+
+        let mut stock = self.stock.lock().unwrap();
+        let unavailable: Vec<ProductId> = items
+            .iter()
+            .filter(|item| {
+                *stock
+                    .get(&item.product_id)
+                    .unwrap_or(&self.default_available)
+                    < item.quantity
+            })
+            .map(|item| item.product_id.clone())
+            .collect();
+
+        let outcome = ReservationOutcome {
+            reserved: unavailable.is_empty(),
+            unavailable,
+        };
+        if outcome.reserved {
+            for item in items {
+                let available = stock
+                    .entry(item.product_id.clone())
+                    .or_insert(self.default_available);
+                *available -= item.quantity;
+            }
+        }
+
+        reservations.insert(
+            String::from(reservation_id),
+            (items.to_vec(), outcome.clone()),
+        );
+        Ok(outcome)
+    }
+
+    async fn release_reservation(
+        &self,
+        reservation_id: &str,
+        deadline: Instant,
+    ) -> Result<(), String> {
+        remaining(deadline)?;
+        // Locked in the same order as reserve_stock
+        let mut reservations = self.reservations.lock().unwrap();
+        let mut stock = self.stock.lock().unwrap();
+
+        if let Some((items, outcome)) = reservations.remove(reservation_id) {
+            if outcome.reserved {
+                for item in items {
+                    *stock
+                        .entry(item.product_id)
+                        .or_insert(self.default_available) += item.quantity;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "grpc")]
+mod proto {
+    tonic::include_proto!("inventory.v1");
+}
+
+// Calls the inventory service over gRPC. The remaining time until the deadline is sent as the
+// grpc-timeout, so the server can drop work the caller will no longer wait for
+#[cfg(feature = "grpc")]
+pub struct GrpcInventoryClient {
+    client: proto::inventory_client::InventoryClient<tonic::transport::Channel>,
+    bearer_token: Option<(Arc<ClientCredentialsTokenProvider>, String)>,
+}
+
+#[cfg(feature = "grpc")]
+impl GrpcInventoryClient {
+    // Connects on first use, so the service can start before the inventory service is reachable
+    pub fn connect_lazy(endpoint: &str) -> Result<GrpcInventoryClient, String> {
+        match tonic::transport::Endpoint::from_shared(String::from(endpoint)) {
+            Ok(endpoint) => Ok(GrpcInventoryClient {
+                client: proto::inventory_client::InventoryClient::new(endpoint.connect_lazy()),
+                bearer_token: None,
+            }),
+            Err(e) => Err(format!(
+                "Invalid inventory service endpoint {}: {}",
+                endpoint, e
+            )),
+        }
+    }
+
+    // Sends a client-credentials token for this audience with every call
+    pub fn with_bearer_token(
+        mut self,
+        token_provider: Arc<ClientCredentialsTokenProvider>,
+        audience: &str,
+    ) -> GrpcInventoryClient {
+        self.bearer_token = Some((token_provider, String::from(audience)));
+        self
+    }
+
+    async fn request<T>(&self, message: T, deadline: Instant) -> Result<tonic::Request<T>, String> {
+        let mut request = tonic::Request::new(message);
+        request.set_timeout(remaining(deadline)?);
+
+        if let Some((token_provider, audience)) = &self.bearer_token {
+            match format!("Bearer {}", token_provider.token(audience).await?).parse() {
+                Ok(authorization) => {
+                    request
+                        .metadata_mut()
+                        .insert("authorization", authorization);
+                }
+                Err(e) => return Err(format!("Invalid token: {}", e)),
+            }
+        }
+
+        Ok(request)
+    }
+}
+
+#[cfg(feature = "grpc")]
+#[async_trait]
+impl InventoryClient for GrpcInventoryClient {
+    async fn check_stock(
+        &self,
+        product_ids: &[ProductId],
+        deadline: Instant,
+    ) -> Result<Vec<StockLevel>, String> {
+        let request = self
+            .request(
+                proto::CheckStockRequest {
+                    product_ids: product_ids.iter().map(|id| id.to_string()).collect(),
+                },
+                deadline,
+            )
+            .await?;
+
+        match self.client.clone().check_stock(request).await {
+            Ok(response) => Ok(response
+                .into_inner()
+                .levels
+                .into_iter()
+                .map(|level| StockLevel {
+                    product_id: ProductId::from(level.product_id),
+                    available: level.available,
+                })
+                .collect()),
+            Err(e) => Err(format!("Failed to check stock: {}", e)),
+        }
+    }
+
+    async fn reserve_stock(
+        &self,
+        reservation_id: &str,
+        items: &[StockItem],
+        deadline: Instant,
+    ) -> Result<ReservationOutcome, String> {
+        let request = self
+            .request(
+                proto::ReserveStockRequest {
+                    reservation_id: String::from(reservation_id),
+                    items: items
+                        .iter()
+                        .map(|item| proto::StockItem {
+                            product_id: item.product_id.to_string(),
+                            quantity: item.quantity,
+                        })
+                        .collect(),
+                },
+                deadline,
+            )
+            .await?;
+
+        match self.client.clone().reserve_stock(request).await {
+            Ok(response) => {
+                let response = response.into_inner();
+                Ok(ReservationOutcome {
+                    reserved: response.reserved,
+                    unavailable: response
+                        .unavailable_product_ids
+                        .into_iter()
+                        .map(ProductId::from)
+                        .collect(),
+                })
+            }
+            Err(e) => Err(format!(
+                "Failed to reserve stock for reservation {}: {}",
+                reservation_id, e
+            )),
+        }
+    }
+
+    async fn release_reservation(
+        &self,
+        reservation_id: &str,
+        deadline: Instant,
+    ) -> Result<(), String> {
+        let request = self
+            .request(
+                proto::ReleaseReservationRequest {
+                    reservation_id: String::from(reservation_id),
+                },
+                deadline,
+            )
+            .await?;
+
+        match self.client.clone().release_reservation(request).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!(
+                "Failed to release reservation {}: {}",
+                reservation_id, e
+            )),
+        }
+    }
+}
+
+// Uses the inventory service at INVENTORY_SERVICE_URL when built with the grpc feature, otherwise
+// an in-memory stand-in with INVENTORY_DEFAULT_STOCK (default 100) of every product. Calls carry a
+// token for INVENTORY_SERVICE_AUDIENCE when that and a token provider are available
+pub fn inventory_client_from_env(
+    token_provider: Option<Arc<ClientCredentialsTokenProvider>>,
+) -> Result<Box<dyn InventoryClient + Send + Sync>, String> {
+    #[cfg(feature = "grpc")]
+    if let Ok(endpoint) = env::var("INVENTORY_SERVICE_URL") {
+        let client = GrpcInventoryClient::connect_lazy(&endpoint)?;
+
+        return Ok(
+            match (token_provider, env::var("INVENTORY_SERVICE_AUDIENCE")) {
+                (Some(token_provider), Ok(audience)) => {
+                    Box::new(client.with_bearer_token(token_provider, &audience))
+                }
+                _ => Box::new(client),
+            },
+        );
+    }
+    #[cfg(not(feature = "grpc"))]
+    let _ = token_provider;
+
+    Ok(Box::new(InMemoryInventoryClient::new(
+        env::var("INVENTORY_DEFAULT_STOCK")
+            .map(|x| x.parse().unwrap())
+            .unwrap_or(100),
+    )))
+}
@@ -0,0 +1,224 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use mongodb::{
+    bson::{doc, DateTime, Document},
+    error::{ErrorKind, WriteFailure},
+    Client, Collection,
+};
+use tokio::task::JoinHandle;
+use tracing::{event, Level};
+
+pub static LEADER: &str = "order_service_leader";
+
+static DUPLICATE_KEY_ERROR_CODE: i32 = 11000;
+
+// A named lease that at most one holder owns until it expires
+#[async_trait]
+pub trait LeaseStore {
+    // Acquires or renews the lease, returning false while another holder owns it
+    async fn try_acquire(
+        &self,
+        name: &str,
+        holder: &str,
+        duration: Duration,
+    ) -> Result<bool, String>;
+    // Gives the lease up early; does nothing unless holder still owns it
+    async fn release(&self, name: &str, holder: &str) -> Result<(), String>;
+}
+
+// For backends that only ever run as a single process, so leases only need to be shared in memory
+#[derive(Default)]
+pub struct InMemoryLeaseStore {
+    leases: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl InMemoryLeaseStore {
+    pub fn new() -> InMemoryLeaseStore {
+        InMemoryLeaseStore::default()
+    }
+}
+
+#[async_trait]
+impl LeaseStore for InMemoryLeaseStore {
+    async fn try_acquire(
+        &self,
+        name: &str,
+        holder: &str,
+        duration: Duration,
+    ) -> Result<bool, String> {
+        let mut leases = self.leases.lock().unwrap();
+
+        match leases.get(name) {
+            Some((current_holder, expires_at))
+                if current_holder != holder && *expires_at > Instant::now() =>
+            {
+                Ok(false)
+            }
+            _ => {
+                leases.insert(
+                    String::from(name),
+                    (String::from(holder), Instant::now() + duration),
+                );
+                Ok(true)
+            }
+        }
+    }
+
+    async fn release(&self, name: &str, holder: &str) -> Result<(), String> {
+        let mut leases = self.leases.lock().unwrap();
+
+        if leases
+            .get(name)
+            .is_some_and(|(current_holder, _)| current_holder == holder)
+        {
+            leases.remove(name);
+        }
+
+        Ok(())
+    }
+}
+
+// Stores one document per lease, keyed by name. Taking over is a single findAndModify that only
+// matches when the caller already holds the lease or it has expired; otherwise the upsert collides
+// with the existing _id and the caller stays a follower
+pub struct MongoDbLeaseStore {
+    lease_collection: Collection<Document>,
+}
+
+impl MongoDbLeaseStore {
+    pub fn new(client: &Client, database: &str, collection: &str) -> MongoDbLeaseStore {
+        MongoDbLeaseStore {
+            lease_collection: client.database(database).collection(collection),
+        }
+    }
+}
+
+pub fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+    match error.kind.as_ref() {
+        ErrorKind::Command(e) => e.code == DUPLICATE_KEY_ERROR_CODE,
+        ErrorKind::Write(WriteFailure::WriteError(e)) => e.code == DUPLICATE_KEY_ERROR_CODE,
+        _ => false,
+    }
+}
+
+#[async_trait]
+impl LeaseStore for MongoDbLeaseStore {
+    async fn try_acquire(
+        &self,
+        name: &str,
+        holder: &str,
+        duration: Duration,
+    ) -> Result<bool, String> {
+        let now = DateTime::now();
+        let expires_at =
+            DateTime::from_millis(now.timestamp_millis() + duration.as_millis() as i64);
+
+        match self
+            .lease_collection
+            .find_one_and_update(
+                doc! {"_id": name, "$or": [{"holder": holder}, {"expires_at": {"$lt": now}}]},
+                doc! {"$set": {"holder": holder, "expires_at": expires_at}},
+            )
+            .upsert(true)
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(e) if is_duplicate_key(&e) => Ok(false),
+            Err(e) => Err(format!("Failed to acquire lease {}: {}", name, e)),
+        }
+    }
+
+    async fn release(&self, name: &str, holder: &str) -> Result<(), String> {
+        match self
+            .lease_collection
+            .delete_one(doc! {"_id": name, "holder": holder})
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Failed to release lease {}: {}", name, e)),
+        }
+    }
+}
+
+// Keeps renewing a lease in the background so that exactly one replica considers itself leader.
+// If the leader dies its lease expires and another replica takes over on its next renewal attempt
+pub struct LeaderElector {
+    lease_store: Arc<dyn LeaseStore + Send + Sync>,
+    lease_name: String,
+    holder: String,
+    lease_duration: Duration,
+    is_leader: AtomicBool,
+}
+
+impl LeaderElector {
+    pub fn new(
+        lease_store: Arc<dyn LeaseStore + Send + Sync>,
+        lease_name: &str,
+        lease_duration: Duration,
+    ) -> LeaderElector {
+        LeaderElector {
+            lease_store,
+            lease_name: String::from(lease_name),
+            holder: format!(
+                "{}-{}",
+                std::env::var("HOSTNAME").unwrap_or(String::from("eshop-orders")),
+                uuid::Uuid::new_v4()
+            ),
+            lease_duration,
+            is_leader: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.is_leader.load(Ordering::SeqCst)
+    }
+
+    // Renews well within the lease duration so a slow round trip doesn't hand leadership away
+    pub fn start(self: Arc<Self>) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                self.renew().await;
+                tokio::time::sleep(self.lease_duration / 3).await;
+            }
+        })
+    }
+
+    async fn renew(&self) {
+        let is_leader = match self
+            .lease_store
+            .try_acquire(&self.lease_name, &self.holder, self.lease_duration)
+            .await
+        {
+            Ok(is_leader) => is_leader,
+            // Without a confirmed lease another replica may take over, so step down to be safe
+            Err(e) => {
+                event!(Level::WARN, "{}", e);
+                false
+            }
+        };
+
+        if self.is_leader.swap(is_leader, Ordering::SeqCst) != is_leader {
+            event!(
+                Level::WARN,
+                "{} {} leadership of {}",
+                self.holder,
+                if is_leader { "acquired" } else { "lost" },
+                self.lease_name
+            );
+        }
+
+        metrics::gauge!(LEADER, "lease" => self.lease_name.clone()).set(if is_leader {
+            1.0
+        } else {
+            0.0
+        });
+    }
+}
@@ -0,0 +1,53 @@
+// The service is also built as a library so benches can drive the handlers directly
+pub mod access_log;
+pub mod api_response;
+pub mod auth;
+pub mod authorization;
+#[cfg(any(feature = "sns", feature = "kms", feature = "csfle"))]
+pub mod aws;
+pub mod bootstrap;
+pub mod circuit_breaker;
+pub mod client_credentials;
+pub mod compensation;
+pub mod consistency;
+pub mod consumers;
+pub mod cqrs;
+#[cfg(feature = "csfle")]
+pub mod csfle;
+pub mod data_transfer;
+pub mod decorators;
+pub mod delivery_slots;
+pub mod domain;
+pub mod dtos;
+pub mod encryption;
+pub mod errors;
+pub mod events;
+pub mod experiments;
+pub mod extractors;
+pub mod fault_injection;
+pub mod health;
+pub mod i18n;
+pub mod http_client;
+pub mod idempotency;
+pub mod inventory;
+pub mod leader_election;
+pub mod load_shedding;
+pub mod loadgen;
+pub mod locking;
+pub mod log_sampling;
+pub mod mediator;
+pub mod migrations;
+#[cfg(feature = "otel")]
+pub mod otlp;
+pub mod outbox;
+pub mod rate_limiting;
+pub mod redaction;
+pub mod repositories;
+pub mod retention;
+pub mod routes;
+pub mod scheduler;
+pub mod signing;
+pub mod state;
+pub mod throttling;
+pub mod timezone;
+pub mod uow;
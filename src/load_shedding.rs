@@ -0,0 +1,163 @@
+use std::{
+    collections::VecDeque,
+    env,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use tracing::{event, Level};
+
+use crate::throttling::{throttled_response, ThrottleReason};
+
+pub static REQUESTS_SHED_TOTAL: &str = "order_service_requests_shed_total";
+
+// Latency percentiles are calculated over this many of the most recent requests
+static LATENCY_WINDOW: usize = 1000;
+// Sorting the window is too expensive to do per request, so p99 is refreshed every this many
+static P99_REFRESH_INTERVAL: usize = 50;
+
+#[derive(Debug, Clone)]
+pub struct LoadSheddingSettings {
+    pub max_in_flight: usize,
+    pub max_p99_latency: Duration,
+    pub retry_after_seconds: u64,
+}
+
+impl Default for LoadSheddingSettings {
+    fn default() -> Self {
+        LoadSheddingSettings {
+            max_in_flight: 512,
+            max_p99_latency: Duration::from_secs(2),
+            retry_after_seconds: 1,
+        }
+    }
+}
+
+impl LoadSheddingSettings {
+    pub fn from_env() -> LoadSheddingSettings {
+        let defaults = LoadSheddingSettings::default();
+
+        LoadSheddingSettings {
+            max_in_flight: env::var("LOAD_SHED_MAX_IN_FLIGHT")
+                .map(|x| x.parse().unwrap())
+                .unwrap_or(defaults.max_in_flight),
+            max_p99_latency: env::var("LOAD_SHED_MAX_P99_LATENCY_MILLIS")
+                .map(|x| Duration::from_millis(x.parse().unwrap()))
+                .unwrap_or(defaults.max_p99_latency),
+            retry_after_seconds: env::var("LOAD_SHED_RETRY_AFTER_SECONDS")
+                .map(|x| x.parse().unwrap())
+                .unwrap_or(defaults.retry_after_seconds),
+        }
+    }
+}
+
+// Tracks in-flight requests and recent latency. Requests over max_in_flight are always rejected.
+// While p99 latency is over its threshold the in-flight limit is halved instead of shedding
+// everything, so enough requests still complete for the latency window to recover
+pub struct LoadShedder {
+    settings: LoadSheddingSettings,
+    in_flight: AtomicUsize,
+    latencies: Mutex<VecDeque<Duration>>,
+    completed: AtomicUsize,
+    p99_latency_micros: AtomicU64,
+}
+
+struct InFlightGuard<'a>(&'a AtomicUsize);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl LoadShedder {
+    pub fn new(settings: LoadSheddingSettings) -> LoadShedder {
+        LoadShedder {
+            settings,
+            in_flight: AtomicUsize::new(0),
+            latencies: Mutex::new(VecDeque::with_capacity(LATENCY_WINDOW)),
+            completed: AtomicUsize::new(0),
+            p99_latency_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn p99_latency(&self) -> Duration {
+        Duration::from_micros(self.p99_latency_micros.load(Ordering::Relaxed))
+    }
+
+    // Returns the reason the request should be rejected, if any
+    fn shed_reason(&self, in_flight: usize) -> Option<&'static str> {
+        if in_flight > self.settings.max_in_flight {
+            return Some("in_flight");
+        }
+
+        if self.p99_latency() > self.settings.max_p99_latency
+            && in_flight > self.settings.max_in_flight / 2
+        {
+            return Some("latency");
+        }
+
+        None
+    }
+
+    fn record_latency(&self, latency: Duration) {
+        let mut latencies = self.latencies.lock().unwrap();
+        latencies.push_back(latency);
+        while latencies.len() > LATENCY_WINDOW {
+            latencies.pop_front();
+        }
+
+        if self
+            .completed
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(P99_REFRESH_INTERVAL)
+        {
+            let mut sorted: Vec<Duration> = latencies.iter().copied().collect();
+            sorted.sort();
+
+            let p99 = sorted[(sorted.len() * 99 / 100).min(sorted.len() - 1)];
+            self.p99_latency_micros
+                .store(p99.as_micros() as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+pub async fn load_shedding_middleware(
+    State(load_shedder): State<Arc<LoadShedder>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let in_flight = load_shedder.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+    let _guard = InFlightGuard(&load_shedder.in_flight);
+
+    if let Some(reason) = load_shedder.shed_reason(in_flight) {
+        event!(
+            Level::WARN,
+            "Shedding request to {} ({} in flight, p99 {:?})",
+            request.uri().path(),
+            in_flight,
+            load_shedder.p99_latency()
+        );
+        metrics::counter!(REQUESTS_SHED_TOTAL, "reason" => reason).increment(1);
+
+        return throttled_response(
+            ThrottleReason::Overloaded,
+            "The service is overloaded, please retry later",
+            load_shedder.settings.retry_after_seconds,
+        );
+    }
+
+    let started = Instant::now();
+    let response = next.run(request).await;
+    load_shedder.record_latency(started.elapsed());
+
+    response
+}
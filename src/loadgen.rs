@@ -0,0 +1,382 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use reqwest::{header::CONTENT_TYPE, Client, Method};
+use serde_json::{json, Value};
+use tokio::{
+    sync::Mutex,
+    task::JoinSet,
+    time::{interval, MissedTickBehavior},
+};
+
+use crate::auth::CART_SESSION_HEADER;
+
+// New carts are only a small share of traffic; shoppers mostly revisit carts they already have
+static CREATE_CART_RATIO: f64 = 0.1;
+// Guest carts the generator keeps working with, so the data set doesn't grow without bound
+static MAX_ACTIVE_CARTS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Operation {
+    CreateCart,
+    GetCart,
+    AddProduct,
+    RemoveProduct,
+}
+
+impl Operation {
+    fn name(&self) -> &'static str {
+        match self {
+            Operation::CreateCart => "create_cart",
+            Operation::GetCart => "get_cart",
+            Operation::AddProduct => "add_product",
+            Operation::RemoveProduct => "remove_product",
+        }
+    }
+}
+
+// `loadgen [--target <url>] [--rps <n>] [--duration <seconds>] [--read-ratio <0..1>]
+// [--products <id,id,...>]`. Requests are sent at a fixed rate whether or not earlier ones have
+// completed, so a slow service shows up as rising latency instead of a lower request rate
+struct LoadgenOptions {
+    target: String,
+    rps: u32,
+    duration: Duration,
+    read_ratio: f64,
+    products: Vec<String>,
+}
+
+fn parse_option<T: FromStr>(arg: &str, value: &str) -> Result<T, String>
+where
+    T::Err: fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("Invalid {} {}: {}", arg, value, e))
+}
+
+impl LoadgenOptions {
+    fn parse(args: &[String]) -> Result<LoadgenOptions, String> {
+        let mut options = LoadgenOptions {
+            target: String::from("http://localhost:3000"),
+            rps: 10,
+            duration: Duration::from_secs(60),
+            read_ratio: 0.8,
+            products: (1..=50).map(|i| format!("product-{}", i)).collect(),
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let value = match args.next() {
+                Some(value) => value,
+                None => return Err(format!("{} needs a value", arg)),
+            };
+
+            match arg.as_str() {
+                "--target" => options.target = value.trim_end_matches('/').to_string(),
+                "--rps" => options.rps = parse_option(arg, value)?,
+                "--duration" => options.duration = Duration::from_secs(parse_option(arg, value)?),
+                "--read-ratio" => options.read_ratio = parse_option(arg, value)?,
+                "--products" => {
+                    options.products = value
+                        .split(',')
+                        .filter(|product| !product.is_empty())
+                        .map(String::from)
+                        .collect()
+                }
+                other => return Err(format!("Unknown option {}", other)),
+            }
+        }
+
+        if options.rps == 0 {
+            return Err(String::from("--rps must be at least 1"));
+        }
+        if !(0.0..=1.0).contains(&options.read_ratio) {
+            return Err(String::from("--read-ratio must be between 0 and 1"));
+        }
+        if options.products.is_empty() {
+            return Err(String::from("--products needs at least one product id"));
+        }
+
+        Ok(options)
+    }
+}
+
+// A guest cart created during the run, along with what the generator has put in it
+#[derive(Clone)]
+struct ActiveCart {
+    id: String,
+    cart_session_token: String,
+    products: Vec<String>,
+}
+
+#[derive(Default)]
+struct OperationStats {
+    latencies: Vec<Duration>,
+    errors: usize,
+}
+
+impl OperationStats {
+    fn percentile(&self, percentile: f64) -> Duration {
+        match self.latencies.len() {
+            0 => Duration::ZERO,
+            len => self.latencies[((len - 1) as f64 * percentile).round() as usize],
+        }
+    }
+}
+
+// A uniformly distributed number in [0, 1), taken from the random bits of a v4 UUID
+fn random() -> f64 {
+    (uuid::Uuid::new_v4().as_u128() >> 80) as f64 / (1u64 << 48) as f64
+}
+
+fn pick<T>(items: &[T]) -> &T {
+    &items[((random() * items.len() as f64) as usize).min(items.len() - 1)]
+}
+
+struct LoadGenerator {
+    client: Client,
+    options: LoadgenOptions,
+    carts: Mutex<Vec<ActiveCart>>,
+}
+
+impl LoadGenerator {
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        cart_session_token: Option<&str>,
+        body: Option<Value>,
+    ) -> Result<String, String> {
+        let mut request = self
+            .client
+            .request(method, format!("{}{}", self.options.target, path));
+        if let Some(cart_session_token) = cart_session_token {
+            request = request.header(CART_SESSION_HEADER, cart_session_token);
+        }
+        if let Some(body) = body {
+            request = request
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.to_string());
+        }
+
+        match request.send().await {
+            Ok(response) if response.status().is_success() => match response.text().await {
+                Ok(body) => Ok(body),
+                Err(e) => Err(format!("Failed to read response: {}", e)),
+            },
+            Ok(response) => Err(format!("{} returned {}", path, response.status())),
+            Err(e) => Err(format!("{} failed: {}", path, e)),
+        }
+    }
+
+    async fn create_cart(&self) -> Result<(), String> {
+        let body = self.request(Method::POST, "/carts", None, None).await?;
+        let response: Value = match serde_json::from_str(&body) {
+            Ok(response) => response,
+            Err(e) => return Err(format!("Invalid create cart response: {}", e)),
+        };
+
+        let cart = &response["data"];
+        match (
+            cart.get("id").and_then(Value::as_str),
+            cart.get("cart_session_token").and_then(Value::as_str),
+        ) {
+            (Some(id), Some(cart_session_token)) => {
+                let mut carts = self.carts.lock().await;
+                if carts.len() >= MAX_ACTIVE_CARTS {
+                    carts.remove(0);
+                }
+                carts.push(ActiveCart {
+                    id: String::from(id),
+                    cart_session_token: String::from(cart_session_token),
+                    products: Vec::new(),
+                });
+                Ok(())
+            }
+            _ => Err(String::from(
+                "Create cart response has no id or cart session token",
+            )),
+        }
+    }
+
+    async fn change_product(
+        &self,
+        cart: &ActiveCart,
+        product_id: String,
+        add: bool,
+    ) -> Result<(), String> {
+        let path = match add {
+            true => "/carts/addProductToCart",
+            false => "/carts/removeProductFromCart",
+        };
+        self.request(
+            Method::PUT,
+            path,
+            Some(&cart.cart_session_token),
+            Some(json!({"cart_id": cart.id, "product_id": product_id})),
+        )
+        .await?;
+
+        let mut carts = self.carts.lock().await;
+        if let Some(active_cart) = carts
+            .iter_mut()
+            .find(|active_cart| active_cart.id == cart.id)
+        {
+            match add {
+                true => active_cart.products.push(product_id),
+                false => {
+                    if let Some(index) = active_cart.products.iter().position(|p| *p == product_id)
+                    {
+                        active_cart.products.remove(index);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Chooses the next operation from the configured mix and runs it
+    async fn run_one(&self) -> (Operation, Result<(), String>, Duration) {
+        let cart = {
+            let carts = self.carts.lock().await;
+            match carts.is_empty() || random() < CREATE_CART_RATIO {
+                true => None,
+                false => Some(pick(&carts).clone()),
+            }
+        };
+
+        let started = Instant::now();
+        let (operation, result) = match cart {
+            None => (Operation::CreateCart, self.create_cart().await),
+            Some(cart) if random() < self.options.read_ratio => (
+                Operation::GetCart,
+                self.request(
+                    Method::GET,
+                    &format!("/carts/{}", cart.id),
+                    Some(&cart.cart_session_token),
+                    None,
+                )
+                .await
+                .map(|_| ()),
+            ),
+            Some(cart) if !cart.products.is_empty() && random() < 0.5 => {
+                let product_id = pick(&cart.products).clone();
+                (
+                    Operation::RemoveProduct,
+                    self.change_product(&cart, product_id, false).await,
+                )
+            }
+            Some(cart) => {
+                let product_id = pick(&self.options.products).clone();
+                (
+                    Operation::AddProduct,
+                    self.change_product(&cart, product_id, true).await,
+                )
+            }
+        };
+
+        (operation, result, started.elapsed())
+    }
+}
+
+fn print_report(stats: &mut HashMap<Operation, OperationStats>, elapsed: Duration) {
+    let total: usize = stats.values().map(|stats| stats.latencies.len()).sum();
+    println!(
+        "{} requests in {:.1}s ({:.1} req/s)",
+        total,
+        elapsed.as_secs_f64(),
+        total as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "{:<16}{:>10}{:>10}{:>12}{:>12}{:>12}{:>12}",
+        "operation", "requests", "errors", "p50 ms", "p90 ms", "p99 ms", "max ms"
+    );
+
+    let mut operations: Vec<_> = stats.iter_mut().collect();
+    operations.sort_by_key(|(operation, _)| **operation);
+    for (operation, stats) in operations {
+        stats.latencies.sort();
+        let millis = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        println!(
+            "{:<16}{:>10}{:>10}{:>12.2}{:>12.2}{:>12.2}{:>12.2}",
+            operation.name(),
+            stats.latencies.len(),
+            stats.errors,
+            millis(stats.percentile(0.5)),
+            millis(stats.percentile(0.9)),
+            millis(stats.percentile(0.99)),
+            millis(stats.percentile(1.0))
+        );
+    }
+}
+
+// Drives a running instance with guest shoppers creating, viewing and changing carts, then
+// reports latency percentiles per operation. Failed requests count as errors without stopping
+// the run. Meant for capacity tests against staging, never against production data
+pub async fn run(args: &[String]) -> Result<(), String> {
+    let options = LoadgenOptions::parse(args)?;
+    let client = match Client::builder().timeout(Duration::from_secs(30)).build() {
+        Ok(client) => client,
+        Err(e) => return Err(format!("Failed to build HTTP client: {}", e)),
+    };
+
+    eprintln!(
+        "Sending {} req/s to {} for {}s",
+        options.rps,
+        options.target,
+        options.duration.as_secs()
+    );
+    let mut ticker = interval(Duration::from_secs(1) / options.rps);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Burst);
+    let duration = options.duration;
+    let generator = Arc::new(LoadGenerator {
+        client,
+        options,
+        carts: Mutex::new(Vec::new()),
+    });
+
+    let mut stats: HashMap<Operation, OperationStats> = HashMap::new();
+    let mut first_errors: Vec<String> = Vec::new();
+    let mut record = |(operation, result, latency): (Operation, Result<(), String>, Duration)| {
+        let stats = stats.entry(operation).or_default();
+        stats.latencies.push(latency);
+        if let Err(e) = result {
+            stats.errors += 1;
+            if first_errors.len() < 5 {
+                first_errors.push(e);
+            }
+        }
+    };
+
+    let started = Instant::now();
+    let mut requests = JoinSet::new();
+    while started.elapsed() < duration {
+        ticker.tick().await;
+        let generator = generator.clone();
+        requests.spawn(async move { generator.run_one().await });
+
+        while let Some(result) = requests.try_join_next() {
+            if let Ok(result) = result {
+                record(result);
+            }
+        }
+    }
+    while let Some(result) = requests.join_next().await {
+        if let Ok(result) = result {
+            record(result);
+        }
+    }
+
+    for e in &first_errors {
+        eprintln!("Error: {}", e);
+    }
+    print_report(&mut stats, started.elapsed());
+    Ok(())
+}
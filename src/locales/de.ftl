@@ -0,0 +1,25 @@
+cart_id_required = Die Warenkorb-ID darf nicht leer sein
+product_id_required = Die Produkt-ID darf nicht leer sein
+quantity_not_positive = Die Menge muss größer als null sein
+quantity_negative = Die Menge darf nicht negativ sein
+quantity_too_large = Die Menge darf nicht größer als { $max_quantity } sein
+order_id_required = Die Bestell-ID darf nicht leer sein
+customer_id_required = Die Kunden-ID darf nicht leer sein
+user_id_required = Die Benutzer-ID darf nicht leer sein
+token_required = Das Token darf nicht leer sein
+authentication_required = Eine Anmeldung ist erforderlich
+
+cart_not_found = Der Warenkorb mit der ID { $cart_id } wurde nicht gefunden
+order_not_found = Die Bestellung mit der ID { $order_id } wurde nicht gefunden
+carts_not_found = Die Warenkörbe des Benutzers { $user_id } wurden nicht gefunden
+default_cart_exists = Der Benutzer { $user_id } hat bereits einen Standardwarenkorb
+default_cart_missing = Der Benutzer { $user_id } hat keinen Standardwarenkorb
+guest_cart_cannot_be_default = Gastwarenkörbe können keine Standardwarenkörbe sein
+not_a_shared_cart_token = Das Token gehört zu keinem geteilten Warenkorb
+invalid_shared_cart_token = Das Token des geteilten Warenkorbs ist ungültig oder abgelaufen
+invalid_cart_session_token = Das Sitzungstoken des Warenkorbs ist ungültig oder abgelaufen
+cart_session_token_mismatch = Das Sitzungstoken gewährt keinen Zugriff auf den Warenkorb mit der ID { $cart_id }
+cart_already_claimed = Der Warenkorb mit der ID { $cart_id } wurde bereits übernommen
+order_not_ready_for_pickup = Die Bestellung mit der ID { $order_id } ist nicht abholbereit
+invalid_pickup_code = Ungültiger Abholcode
+order_not_cancellable = Die Bestellung mit der ID { $order_id } kann nicht mehr storniert werden
@@ -0,0 +1,28 @@
+# Messages returned to clients, keyed by the error code they are sent with. Handlers fail with the
+# code and its arguments, and the message is only rendered once the response is built
+
+cart_id_required = Cart ID cannot be null or empty
+product_id_required = Product ID cannot be null or empty
+quantity_not_positive = Quantity must be greater than zero
+quantity_negative = Quantity cannot be negative
+quantity_too_large = Quantity cannot be more than { $max_quantity }
+order_id_required = Order ID cannot be null or empty
+customer_id_required = Customer ID cannot be null or empty
+user_id_required = User ID cannot be null or empty
+token_required = Token cannot be null or empty
+authentication_required = An authenticated user is required
+
+cart_not_found = Failed to find Cart with ID { $cart_id }
+order_not_found = Failed to find Order with ID { $order_id }
+carts_not_found = Failed to find Carts for user { $user_id }
+default_cart_exists = User { $user_id } already has a default cart
+default_cart_missing = No default Cart exists for user { $user_id }
+guest_cart_cannot_be_default = Guest carts can't be default carts
+not_a_shared_cart_token = Token is not a shared cart token
+invalid_shared_cart_token = Shared cart token is invalid or has expired
+invalid_cart_session_token = Cart session token is invalid or has expired
+cart_session_token_mismatch = Cart session token does not grant access to Cart with ID { $cart_id }
+cart_already_claimed = Cart with ID { $cart_id } has already been claimed
+order_not_ready_for_pickup = Order with ID { $order_id } is not ready for pickup
+invalid_pickup_code = Invalid pickup code
+order_not_cancellable = Order with ID { $order_id } can no longer be cancelled
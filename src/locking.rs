@@ -0,0 +1,106 @@
+use std::{
+    env,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tracing::{event, Level};
+
+use crate::{leader_election::LeaseStore, throttling::ThrottleReason};
+
+pub static LOCK_ACQUISITIONS_TOTAL: &str = "order_service_lock_acquisitions_total";
+pub static LOCK_WAIT_SECONDS: &str = "order_service_lock_wait_seconds";
+
+static LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(25);
+
+#[derive(Debug, Clone)]
+pub struct DistributedLockSettings {
+    // Upper bound on how long a crashed holder can keep others waiting
+    pub lease_duration: Duration,
+    pub acquire_timeout: Duration,
+}
+
+impl Default for DistributedLockSettings {
+    fn default() -> Self {
+        DistributedLockSettings {
+            lease_duration: Duration::from_secs(10),
+            acquire_timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+impl DistributedLockSettings {
+    pub fn from_env() -> DistributedLockSettings {
+        let defaults = DistributedLockSettings::default();
+
+        DistributedLockSettings {
+            lease_duration: env::var("CART_LOCK_LEASE_SECONDS")
+                .map(|x| Duration::from_secs(x.parse().unwrap()))
+                .unwrap_or(defaults.lease_duration),
+            acquire_timeout: env::var("CART_LOCK_TIMEOUT_MILLIS")
+                .map(|x| Duration::from_millis(x.parse().unwrap()))
+                .unwrap_or(defaults.acquire_timeout),
+        }
+    }
+}
+
+// Mutual exclusion across replicas built on the same leases as leader election. Each acquisition
+// uses its own holder id, so two calls on one replica exclude each other too
+pub struct DistributedLock {
+    lease_store: Arc<dyn LeaseStore + Send + Sync>,
+    settings: DistributedLockSettings,
+}
+
+impl DistributedLock {
+    pub fn new(
+        lease_store: Arc<dyn LeaseStore + Send + Sync>,
+        settings: DistributedLockSettings,
+    ) -> DistributedLock {
+        DistributedLock {
+            lease_store,
+            settings,
+        }
+    }
+
+    // Runs operation while holding the named lock, giving up once acquire_timeout has passed
+    pub async fn run<T, E, F>(&self, name: &str, operation: F) -> Result<T, E>
+    where
+        E: From<String>,
+        F: Future<Output = Result<T, E>>,
+    {
+        let holder = uuid::Uuid::new_v4().to_string();
+        let started = Instant::now();
+
+        loop {
+            if self
+                .lease_store
+                .try_acquire(name, &holder, self.settings.lease_duration)
+                .await?
+            {
+                break;
+            }
+
+            if started.elapsed() >= self.settings.acquire_timeout {
+                metrics::counter!(LOCK_ACQUISITIONS_TOTAL, "outcome" => "timeout").increment(1);
+                return Err(ThrottleReason::ConcurrencyLimit
+                    .error(&format!("Timed out waiting for lock on {}", name))
+                    .into());
+            }
+
+            tokio::time::sleep(LOCK_RETRY_INTERVAL).await;
+        }
+
+        metrics::counter!(LOCK_ACQUISITIONS_TOTAL, "outcome" => "acquired").increment(1);
+        metrics::histogram!(LOCK_WAIT_SECONDS).record(started.elapsed().as_secs_f64());
+
+        let result = operation.await;
+
+        // The lease expires on its own if this fails, so only the wait for the next caller is longer
+        if let Err(e) = self.lease_store.release(name, &holder).await {
+            event!(Level::WARN, "{}", e);
+        }
+
+        result
+    }
+}
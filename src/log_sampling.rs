@@ -0,0 +1,159 @@
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::http::Response;
+use tower_http::trace::OnResponse;
+use tracing::{event, Level, Span};
+
+// The sample rate is stored as parts per million so it can be swapped atomically
+static SAMPLE_RATE_SCALE: f64 = 1_000_000.0;
+
+#[derive(Debug, Clone)]
+pub struct LogSamplingSettings {
+    // Fraction of successful, fast requests that are logged, between 0 and 1
+    pub sample_rate: f64,
+    // Requests taking at least this long are always logged
+    pub slow_request_threshold: Duration,
+}
+
+impl Default for LogSamplingSettings {
+    fn default() -> Self {
+        LogSamplingSettings {
+            sample_rate: 1.0,
+            slow_request_threshold: Duration::from_secs(1),
+        }
+    }
+}
+
+impl LogSamplingSettings {
+    pub fn from_env() -> Result<LogSamplingSettings, String> {
+        let defaults = LogSamplingSettings::default();
+
+        let settings = LogSamplingSettings {
+            sample_rate: env::var("LOG_SAMPLE_RATE")
+                .map(|x| x.parse().unwrap())
+                .unwrap_or(defaults.sample_rate),
+            slow_request_threshold: env::var("LOG_SLOW_REQUEST_MILLIS")
+                .map(|x| Duration::from_millis(x.parse().unwrap()))
+                .unwrap_or(defaults.slow_request_threshold),
+        };
+        settings.validate()?;
+
+        Ok(settings)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match (0.0..=1.0).contains(&self.sample_rate) {
+            true => Ok(()),
+            false => Err(format!(
+                "Log sample rate must be between 0 and 1, got {}",
+                self.sample_rate
+            )),
+        }
+    }
+}
+
+// Decides which requests are logged when they complete. Server errors and slow requests are always
+// logged, everything else is sampled. Shared between the trace layer and the admin endpoint so the
+// rates can be changed without a restart
+pub struct LogSampler {
+    sample_rate_ppm: AtomicU32,
+    slow_request_threshold_micros: AtomicU64,
+}
+
+impl LogSampler {
+    pub fn new(settings: LogSamplingSettings) -> LogSampler {
+        let log_sampler = LogSampler {
+            sample_rate_ppm: AtomicU32::new(0),
+            slow_request_threshold_micros: AtomicU64::new(0),
+        };
+        log_sampler.update(settings);
+
+        log_sampler
+    }
+
+    pub fn update(&self, settings: LogSamplingSettings) {
+        self.sample_rate_ppm.store(
+            (settings.sample_rate * SAMPLE_RATE_SCALE) as u32,
+            Ordering::Relaxed,
+        );
+        self.slow_request_threshold_micros.store(
+            settings.slow_request_threshold.as_micros() as u64,
+            Ordering::Relaxed,
+        );
+    }
+
+    pub fn settings(&self) -> LogSamplingSettings {
+        LogSamplingSettings {
+            sample_rate: self.sample_rate_ppm.load(Ordering::Relaxed) as f64 / SAMPLE_RATE_SCALE,
+            slow_request_threshold: Duration::from_micros(
+                self.slow_request_threshold_micros.load(Ordering::Relaxed),
+            ),
+        }
+    }
+
+    // The level to log a completed request at, or None if it was not sampled
+    fn level(&self, is_server_error: bool, latency: Duration) -> Option<Level> {
+        if is_server_error {
+            return Some(Level::ERROR);
+        }
+
+        if latency.as_micros() as u64 >= self.slow_request_threshold_micros.load(Ordering::Relaxed)
+        {
+            return Some(Level::WARN);
+        }
+
+        let roll = (uuid::Uuid::new_v4().as_u128() % SAMPLE_RATE_SCALE as u128) as u32;
+        (roll < self.sample_rate_ppm.load(Ordering::Relaxed)).then_some(Level::DEBUG)
+    }
+}
+
+// Replaces the trace layer's default of logging every response at DEBUG
+#[derive(Clone)]
+pub struct SampledOnResponse {
+    log_sampler: Arc<LogSampler>,
+}
+
+impl SampledOnResponse {
+    pub fn new(log_sampler: Arc<LogSampler>) -> SampledOnResponse {
+        SampledOnResponse { log_sampler }
+    }
+}
+
+impl<B> OnResponse<B> for SampledOnResponse {
+    fn on_response(self, response: &Response<B>, latency: Duration, _: &Span) {
+        let status = response.status().as_u16();
+        let latency_ms = latency.as_millis() as u64;
+
+        match self
+            .log_sampler
+            .level(response.status().is_server_error(), latency)
+        {
+            Some(Level::ERROR) => event!(
+                Level::ERROR,
+                status,
+                latency_ms,
+                "finished processing request"
+            ),
+            Some(Level::WARN) => event!(
+                Level::WARN,
+                status,
+                latency_ms,
+                "finished processing request"
+            ),
+            Some(_) => event!(
+                Level::DEBUG,
+                status,
+                latency_ms,
+                "finished processing request"
+            ),
+            None => {}
+        }
+    }
+}
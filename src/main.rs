@@ -1,95 +1,91 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
 use axum::{
+    extract::DefaultBodyLimit,
     http::Method,
-    middleware::from_fn_with_state,
+    middleware::{from_fn, from_fn_with_state},
     routing::{get, post, put},
     Router,
 };
-use axum_prometheus::PrometheusMetricLayer;
-use cqrs::{
-    AddProductToCartCommandHandler, CreateCartCommandHandler, GetCartsQueryHandler,
-    RemoveProductFromCartCommandHandler,
+use axum_prometheus::{
+    metrics_exporter_prometheus::{Matcher, PrometheusBuilder},
+    utils::SECONDS_DURATION_BUCKETS,
+    PrometheusMetricLayerBuilder, AXUM_HTTP_REQUESTS_DURATION_SECONDS,
 };
 use dotenv::dotenv;
-use events::{RabbitMqInitializationInfo, RabbitMqMessageBroker};
-use mongodb::Client;
-use repositories::{MongoDbCartRepository, MongoDbInitializationInfo, MongoDbOrderRepository};
-use routes::{add_product_to_cart, create_cart, get_cart_by_id, index, remove_product_from_cart};
-use state::AppState;
+use eshop_orders::{
+    access_log::{self, AccessLog, AccessLogSettings},
+    auth, authorization, bootstrap, consistency,
+    cqrs::{CART_ITEMS, CART_ITEMS_BUCKETS},
+    data_transfer, http_client,
+    i18n::{self, MessageCatalog},
+    idempotency,
+    load_shedding::{self, LoadShedder, LoadSheddingSettings},
+    loadgen,
+    log_sampling::SampledOnResponse,
+    migrations,
+    outbox::DEFAULT_OUTBOX_RELAY_SCHEDULE,
+    rate_limiting,
+    redaction::{self, RedactingMakeWriter, Redactor},
+    retention::{RetentionJob, RetentionSettings},
+    routes::{
+        add_product_to_cart, apply_retention_rules_dry_run, cancel_order, claim_guest_cart,
+        clone_shared_cart, create_cart, delete_cart, erase_customer_data, export_customer_data,
+        get_all_carts, get_all_orders, get_cart_by_id, get_carts_containing_product,
+        get_delivery_slots, get_event_catalog, get_my_carts, get_order_by_id, get_order_timeline,
+        get_pickup_code, get_shared_cart, get_stats, import_carts, index, info, readyz,
+        reload_rate_limits, remove_product_from_cart, set_default_cart, set_log_sampling,
+        set_maintenance_mode, set_product_quantity, share_cart, verify_pickup_code,
+        ADD_PRODUCT_TO_CART_PATH, CANCEL_ORDER_PATH, CART_ITEM_PATH, CART_PATH,
+        DELIVERY_SLOTS_PATH, ORDER_PATH, ORDER_TIMELINE_PATH, PICKUP_CODE_PATH,
+        REMOVE_PRODUCT_FROM_CART_PATH, SHARE_CART_PATH, VERIFY_PICKUP_PATH,
+    },
+    scheduler::Scheduler,
+    timezone,
+};
 use std::env;
-use tokio::sync::Mutex;
+use tokio::{signal, sync::watch};
 use tower::ServiceBuilder;
 use tower_http::{cors::CorsLayer, trace::TraceLayer};
 
-use crate::uow::OrderUnitOfWork;
-
-mod auth;
-mod cqrs;
-mod domain;
-mod dtos;
-mod events;
-mod repositories;
-mod routes;
-mod state;
-mod uow;
+// Cart imports carry whole exports from the legacy platform, far more than the default 2MB
+static CART_IMPORT_BODY_LIMIT: usize = 64 * 1024 * 1024;
 
 #[tokio::main]
 async fn main() {
     dotenv().ok();
 
-    let order_db_info = MongoDbInitializationInfo {
-        uri: String::from(env::var("MONGODB_URI").unwrap()),
-        database: String::from(env::var("MONGODB_DB").unwrap()),
-        collection: String::from(env::var("MONGODB_ORDER_COLLECTION").unwrap()),
-    };
-
-    let cart_db_info = MongoDbInitializationInfo {
-        uri: String::from(env::var("MONGODB_URI").unwrap()),
-        database: String::from(env::var("MONGODB_DB").unwrap()),
-        collection: String::from(env::var("MONGODB_CARTS_COLLECTION").unwrap()),
+    // `eshop-orders migrate [--dry-run]` applies pending schema migrations and exits, `export` and
+    // `import` dump and restore carts or orders as NDJSON, and `loadgen` drives a running instance
+    // with synthetic traffic
+    let args: Vec<String> = env::args().collect();
+    let command = match args.get(1).map(String::as_str) {
+        Some("migrate") => {
+            Some(migrations::migrate(args.iter().any(|arg| arg == "--dry-run")).await)
+        }
+        Some("export") => Some(data_transfer::export(&args[2..]).await),
+        Some("import") => Some(data_transfer::import(&args[2..]).await),
+        Some("loadgen") => Some(loadgen::run(&args[2..]).await),
+        _ => None,
     };
+    if let Some(result) = command {
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
 
-    let client: Client = Client::with_uri_str(&cart_db_info.uri).await.unwrap();
-
-    let order_repository = Arc::new(MongoDbOrderRepository::new(&order_db_info, &client).await);
-    let cart_repository = Arc::new(MongoDbCartRepository::new(&cart_db_info, &client).await);
-
-    let message_broker = Arc::new(
-        RabbitMqMessageBroker::new(RabbitMqInitializationInfo::new(
-            String::from(env::var("RABBITMQ_URI").unwrap()),
-            env::var("RABBITMQ_PORT").unwrap().parse().unwrap(),
-            String::from(env::var("RABBITMQ_USER").unwrap()),
-            String::from(env::var("RABBITMQ_PASS").unwrap()),
-        ))
-        .await
-        .unwrap(),
-    );
+        return;
+    }
 
-    let client_session = Arc::new(Mutex::new(client.start_session().await.unwrap()));
+    let state = Arc::new(bootstrap::app_state_from_env().await.unwrap());
 
-    let uow = Arc::new(OrderUnitOfWork::new(
-        order_repository,
-        cart_repository,
-        message_broker,
-        client_session,
-    ));
+    // Credentials and personal data are scrubbed from every log line, and from the internal error
+    // messages returned to clients
+    let redactor = Redactor::from_env();
+    redaction::install(redactor.clone());
 
-    let create_cart_command_handler = Arc::new(CreateCartCommandHandler::new(uow.clone()));
-    let get_carts_query_handle = Arc::new(GetCartsQueryHandler::new(uow.clone()));
-    let add_product_to_cart_command_handler =
-        Arc::new(AddProductToCartCommandHandler::new(uow.clone()));
-    let remove_product_from_cart_command_handler =
-        Arc::new(RemoveProductFromCartCommandHandler::new(uow.clone()));
-
-    let state = Arc::new(AppState {
-        create_cart_command_handler: create_cart_command_handler,
-        get_carts_query_handle: get_carts_query_handle,
-        add_product_to_cart_command_handler: add_product_to_cart_command_handler,
-        remove_product_from_cart_command_handler: remove_product_from_cart_command_handler,
-        auth0_domain: String::from(env::var("AUTH0_DOMAIN").unwrap()),
-        auth0_audience: String::from(env::var("AUTH0_AUDIENCE").unwrap()),
-    });
+    // Error messages are sent in the language asked for in Accept-Language, where translated
+    i18n::install(MessageCatalog::from_env().unwrap());
 
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::DEBUG)
@@ -99,10 +95,96 @@ async fn main() {
         .with_file(true)
         .with_line_number(true)
         .with_current_span(true)
-        .with_writer(std::fs::File::create(String::from(env::var("LOG_PATH").unwrap())).unwrap())
+        .with_writer(RedactingMakeWriter::new(
+            std::fs::File::create(env::var("LOG_PATH").unwrap()).unwrap(),
+            redactor,
+        ))
         .init();
 
-    let (prometheus_layer, metrics_handle) = PrometheusMetricLayer::pair();
+    // Buckets are set for the metrics that are alerted on by their distribution; other histograms
+    // are rendered as summaries
+    let buckets: [(&str, &[f64]); 2] = [
+        (
+            AXUM_HTTP_REQUESTS_DURATION_SECONDS,
+            SECONDS_DURATION_BUCKETS,
+        ),
+        (CART_ITEMS, CART_ITEMS_BUCKETS),
+    ];
+    let mut prometheus_builder = PrometheusBuilder::new();
+    for (name, buckets) in buckets {
+        prometheus_builder = prometheus_builder
+            .set_buckets_for_metric(Matcher::Full(name.to_string()), buckets)
+            .unwrap();
+    }
+    let recorder = prometheus_builder.build_recorder();
+    let prometheus_handle = recorder.handle();
+
+    // /metrics is always served; with the otel feature the same metrics can also be pushed over
+    // OTLP, for backends that ingest OTLP without scraping
+    #[cfg(feature = "otel")]
+    let _meter_provider = eshop_orders::otlp::install_recorder(
+        recorder,
+        eshop_orders::otlp::OtlpMetricsSettings::from_env().unwrap(),
+        &buckets,
+    )
+    .unwrap();
+    #[cfg(not(feature = "otel"))]
+    metrics::set_global_recorder(recorder).unwrap();
+
+    let (prometheus_layer, metrics_handle) = PrometheusMetricLayerBuilder::new()
+        .with_metrics_from_fn(|| prometheus_handle)
+        .build_pair();
+
+    // Periodic work is registered here and started once the metrics recorder is installed
+    let mut scheduler = Scheduler::new().with_leader_election(Arc::new(
+        bootstrap::leader_elector_from_env().await.unwrap(),
+    ));
+    let retention_settings = RetentionSettings::from_env().unwrap();
+    if !retention_settings.rules.is_empty() {
+        scheduler
+            .register(
+                "retention",
+                &retention_settings.schedule,
+                Arc::new(RetentionJob::new(
+                    state.mediator.clone(),
+                    retention_settings.dry_run,
+                )),
+            )
+            .unwrap();
+    }
+    if let Some(outbox_relay) = state.outbox_relay.clone() {
+        scheduler
+            .register(
+                "outbox_relay",
+                &env::var("OUTBOX_RELAY_SCHEDULE")
+                    .unwrap_or(String::from(DEFAULT_OUTBOX_RELAY_SCHEDULE)),
+                outbox_relay,
+            )
+            .unwrap();
+    }
+    scheduler.start();
+
+    // Set once the server has stopped, so the consumer finishes the event it is handling and stops
+    let (shutdown_sender, shutdown) = watch::channel(false);
+    let event_consumer = bootstrap::event_consumer_from_env(state.mediator.clone())
+        .unwrap()
+        .map(|event_consumer| event_consumer.start(shutdown));
+
+    let load_shedder = Arc::new(LoadShedder::new(LoadSheddingSettings::from_env()));
+
+    // Logging every request is too expensive at production traffic, so only errors, slow requests
+    // and a sample of the rest are logged as they complete
+    let log_sampler = state.log_sampler.clone();
+
+    // Budgets per route group and caller, from RATE_LIMIT_POLICY_PATH when it is set
+    let rate_limiter = state.rate_limiter.clone();
+
+    // Lets clients retry POSTs and PUTs sent with an Idempotency-Key header. Keys are scoped to the
+    // caller, so it is layered inside each route's authentication
+    let idempotency_keys = state.idempotency_keys.clone();
+
+    // One record per request for the log pipeline, kept apart from the application log
+    let access_log = Arc::new(AccessLog::new(AccessLogSettings::from_env().unwrap()).unwrap());
 
     let listener =
         tokio::net::TcpListener::bind(format!("0.0.0.0:{}", env::var("AXUM_PORT").unwrap()))
@@ -113,48 +195,479 @@ async fn main() {
         listener,
         Router::new()
             .route("/", get(index))
-            .route("/metrics", get(|| async move { metrics_handle.render() }))
             .route(
-                "/carts",
-                post(create_cart).route_layer(from_fn_with_state(
+                "/metrics",
+                get(|| async move { metrics_handle.render() }).route_layer(from_fn_with_state(
                     state.clone(),
-                    auth::authentication_middleware,
+                    auth::ops_authorization_middleware,
                 )),
             )
             .route(
-                "/carts/{id}",
-                get(get_cart_by_id).route_layer(from_fn_with_state(
+                "/info",
+                get(info).route_layer(from_fn_with_state(
                     state.clone(),
-                    auth::authentication_middleware,
+                    auth::ops_authorization_middleware,
                 )),
             )
+            .route("/readyz", get(readyz))
             .route(
-                "/carts/addProductToCart",
-                put(add_product_to_cart).route_layer(from_fn_with_state(
-                    state.clone(),
-                    auth::authentication_middleware,
-                )),
+                "/carts",
+                post(create_cart)
+                    .route_layer(from_fn_with_state(
+                        idempotency_keys.clone(),
+                        idempotency::idempotency_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::guest_or_authentication_middleware,
+                    )),
             )
             .route(
-                "/carts/removeProductFromCart",
-                put(remove_product_from_cart).route_layer(from_fn_with_state(
-                    state.clone(),
-                    auth::authentication_middleware,
-                )),
+                "/carts",
+                get(get_all_carts)
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                "/carts/me",
+                get(get_my_carts)
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                CART_PATH,
+                get(get_cart_by_id)
+                    .delete(delete_cart)
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::cart_session_or_authentication_middleware,
+                    )),
+            )
+            .route(
+                DELIVERY_SLOTS_PATH,
+                get(get_delivery_slots)
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::cart_session_or_authentication_middleware,
+                    )),
+            )
+            .route(
+                ADD_PRODUCT_TO_CART_PATH,
+                put(add_product_to_cart)
+                    .route_layer(from_fn_with_state(
+                        idempotency_keys.clone(),
+                        idempotency::idempotency_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::cart_session_or_authentication_middleware,
+                    )),
+            )
+            .route(
+                REMOVE_PRODUCT_FROM_CART_PATH,
+                put(remove_product_from_cart)
+                    .route_layer(from_fn_with_state(
+                        idempotency_keys.clone(),
+                        idempotency::idempotency_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::cart_session_or_authentication_middleware,
+                    )),
+            )
+            .route(
+                CART_ITEM_PATH,
+                put(set_product_quantity)
+                    .route_layer(from_fn_with_state(
+                        idempotency_keys.clone(),
+                        idempotency::idempotency_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::cart_session_or_authentication_middleware,
+                    )),
+            )
+            .route(
+                "/carts/setDefaultCart",
+                put(set_default_cart)
+                    .route_layer(from_fn_with_state(
+                        idempotency_keys.clone(),
+                        idempotency::idempotency_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                SHARE_CART_PATH,
+                post(share_cart)
+                    .route_layer(from_fn_with_state(
+                        idempotency_keys.clone(),
+                        idempotency::idempotency_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                "/carts/{id}/claim",
+                post(claim_guest_cart)
+                    .route_layer(from_fn_with_state(
+                        idempotency_keys.clone(),
+                        idempotency::idempotency_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route("/shared-carts/{token}", get(get_shared_cart))
+            .route(
+                "/admin/event-catalog",
+                get(get_event_catalog)
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                "/admin/maintenance",
+                post(set_maintenance_mode)
+                    .route_layer(from_fn_with_state(
+                        idempotency_keys.clone(),
+                        idempotency::idempotency_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                "/admin/log-sampling",
+                post(set_log_sampling)
+                    .route_layer(from_fn_with_state(
+                        idempotency_keys.clone(),
+                        idempotency::idempotency_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                "/admin/rate-limits/reload",
+                post(reload_rate_limits)
+                    .route_layer(from_fn_with_state(
+                        idempotency_keys.clone(),
+                        idempotency::idempotency_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                "/admin/carts",
+                get(get_carts_containing_product)
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                "/admin/stats",
+                get(get_stats)
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                "/admin/carts/import",
+                post(import_carts)
+                    .layer(DefaultBodyLimit::max(CART_IMPORT_BODY_LIMIT))
+                    .route_layer(from_fn_with_state(
+                        idempotency_keys.clone(),
+                        idempotency::idempotency_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                "/admin/retention/dry-run",
+                post(apply_retention_rules_dry_run)
+                    .route_layer(from_fn_with_state(
+                        idempotency_keys.clone(),
+                        idempotency::idempotency_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                "/admin/customers/{id}/erase",
+                post(erase_customer_data)
+                    .route_layer(from_fn_with_state(
+                        idempotency_keys.clone(),
+                        idempotency::idempotency_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                "/admin/customers/{id}/export",
+                get(export_customer_data)
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                "/orders",
+                get(get_all_orders)
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                ORDER_PATH,
+                get(get_order_by_id)
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                ORDER_TIMELINE_PATH,
+                get(get_order_timeline)
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                CANCEL_ORDER_PATH,
+                post(cancel_order)
+                    .route_layer(from_fn_with_state(
+                        idempotency_keys.clone(),
+                        idempotency::idempotency_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                PICKUP_CODE_PATH,
+                get(get_pickup_code)
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                VERIFY_PICKUP_PATH,
+                post(verify_pickup_code)
+                    .route_layer(from_fn_with_state(
+                        idempotency_keys.clone(),
+                        idempotency::idempotency_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
+            )
+            .route(
+                "/shared-carts/{token}/clone",
+                post(clone_shared_cart)
+                    .route_layer(from_fn_with_state(
+                        idempotency_keys.clone(),
+                        idempotency::idempotency_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        authorization::authorization_middleware,
+                    ))
+                    .route_layer(from_fn_with_state(
+                        state.clone(),
+                        auth::authentication_middleware,
+                    )),
             )
             .with_state(state)
+            .layer(from_fn_with_state(
+                load_shedder,
+                load_shedding::load_shedding_middleware,
+            ))
+            .layer(from_fn_with_state(
+                rate_limiter,
+                rate_limiting::rate_limiting_middleware,
+            ))
+            .layer(from_fn(consistency::consistency_middleware))
+            .layer(from_fn(timezone::timezone_middleware))
+            .layer(from_fn(i18n::locale_middleware))
+            .layer(from_fn(http_client::trace_context_middleware))
+            .layer(from_fn_with_state(
+                access_log,
+                access_log::access_log_middleware,
+            ))
             .layer(prometheus_layer)
             .layer(
                 ServiceBuilder::new()
-                    .layer(TraceLayer::new_for_http())
+                    .layer(
+                        TraceLayer::new_for_http()
+                            .on_request(())
+                            .on_response(SampledOnResponse::new(log_sampler)),
+                    )
                     .layer(CorsLayer::very_permissive().allow_methods([
                         Method::GET,
                         Method::POST,
                         Method::PUT,
                         Method::DELETE,
                     ])),
-            ),
+            )
+            .into_make_service_with_connect_info::<SocketAddr>(),
     )
+    .with_graceful_shutdown(shutdown_signal())
     .await
     .unwrap();
+
+    shutdown_sender.send(true).ok();
+    if let Some(event_consumer) = event_consumer {
+        event_consumer.await.ok();
+    }
+}
+
+// Ctrl+C, or SIGTERM as sent by container runtimes
+async fn shutdown_signal() {
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .unwrap()
+            .recv()
+            .await;
+    };
+
+    tokio::select! {
+        _ = signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
 }
@@ -0,0 +1,95 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    sync::Arc,
+};
+
+use crate::{
+    cqrs::{Command, CommandHandler, Query, QueryHandler},
+    decorators::{
+        LoggingHandler, MaintenanceHandler, MaintenanceMode, MetricsHandler, ValidatingHandler,
+    },
+    errors::HandlerError,
+};
+
+// Dispatches commands and queries to the handler registered for their type, so routes only
+// need the mediator instead of one field per handler. Every handler is wrapped in logging and
+// metrics, and commands are validated before they are handled and refused during maintenance or
+// while the database has no writable primary
+#[derive(Default)]
+pub struct Mediator {
+    command_handlers: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+    query_handlers: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+    maintenance_mode: Arc<MaintenanceMode>,
+}
+
+impl Mediator {
+    pub fn new() -> Mediator {
+        Mediator::default()
+    }
+
+    pub fn with_maintenance_mode(maintenance_mode: MaintenanceMode) -> Mediator {
+        Mediator {
+            maintenance_mode: Arc::new(maintenance_mode),
+            ..Mediator::default()
+        }
+    }
+
+    pub fn maintenance_mode(&self) -> &MaintenanceMode {
+        &self.maintenance_mode
+    }
+
+    pub fn register_command_handler<C, H>(&mut self, handler: H)
+    where
+        C: Command + Sync + 'static,
+        H: CommandHandler<C> + Send + Sync + 'static,
+    {
+        let handler: Arc<dyn CommandHandler<C> + Send + Sync> = Arc::new(LoggingHandler::new(
+            MetricsHandler::new(MaintenanceHandler::new(
+                ValidatingHandler::new(handler),
+                self.maintenance_mode.clone(),
+            )),
+        ));
+        self.command_handlers
+            .insert(TypeId::of::<C>(), Box::new(handler));
+    }
+
+    pub fn register_query_handler<Q, H>(&mut self, handler: H)
+    where
+        Q: Query + Send + 'static,
+        H: QueryHandler<Q> + Send + Sync + 'static,
+    {
+        let handler: Arc<dyn QueryHandler<Q> + Send + Sync> =
+            Arc::new(LoggingHandler::new(MetricsHandler::new(handler)));
+        self.query_handlers
+            .insert(TypeId::of::<Q>(), Box::new(handler));
+    }
+
+    pub async fn send<C>(&self, input: &C) -> Result<C::Response, HandlerError>
+    where
+        C: Command + Sync + 'static,
+    {
+        match self
+            .command_handlers
+            .get(&TypeId::of::<C>())
+            .and_then(|handler| handler.downcast_ref::<Arc<dyn CommandHandler<C> + Send + Sync>>())
+        {
+            Some(handler) => handler.handle(input).await,
+            None => Err(format!("No handler registered for {}", std::any::type_name::<C>()).into()),
+        }
+    }
+
+    pub async fn query<Q>(&self, input: Option<Q>) -> Result<Q::Response, HandlerError>
+    where
+        Q: Query + Send + 'static,
+    {
+        match self
+            .query_handlers
+            .get(&TypeId::of::<Q>())
+            .and_then(|handler| handler.downcast_ref::<Arc<dyn QueryHandler<Q> + Send + Sync>>())
+        {
+            Some(handler) => handler.handle(input).await,
+            None => Err(format!("No handler registered for {}", std::any::type_name::<Q>()).into()),
+        }
+    }
+}
@@ -0,0 +1,457 @@
+use std::{env, time::Duration};
+
+use chrono::{DateTime, Utc};
+use futures_util::{future::BoxFuture, TryStreamExt};
+use mongodb::{
+    bson::{doc, Document},
+    options::IndexOptions,
+    Client, Database, IndexModel,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use crate::{cqrs::DEFAULT_CART_NAME, domain::timestamp};
+
+pub struct MigrationContext {
+    pub database: Database,
+    pub carts_collection: String,
+    pub orders_collection: String,
+    pub outbox_collection: String,
+    pub idempotency_collection: String,
+}
+
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub up: fn(&MigrationContext) -> BoxFuture<'_, Result<(), String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppliedMigration {
+    pub version: u32,
+    pub description: String,
+    #[serde(with = "timestamp")]
+    pub applied_at_utc: DateTime<Utc>,
+}
+
+// Every schema change is appended here with the next version number, never edited once shipped
+pub fn all_migrations() -> Vec<Migration> {
+    vec![
+        Migration {
+            version: 1,
+            description: "Create unique id indexes on carts and orders",
+            up: |context| Box::pin(create_id_indexes(context)),
+        },
+        Migration {
+            version: 2,
+            description: "Backfill cart owner, name and default flag",
+            up: |context| Box::pin(backfill_cart_ownership(context)),
+        },
+        Migration {
+            version: 3,
+            description: "Create user_id index on carts",
+            up: |context| Box::pin(create_cart_user_id_index(context)),
+        },
+        Migration {
+            version: 4,
+            description: "Convert epoch millisecond timestamps to dates",
+            up: |context| Box::pin(convert_timestamps_to_dates(context)),
+        },
+        Migration {
+            version: 5,
+            description: "Create wildcard index on cart products",
+            up: |context| Box::pin(create_cart_products_index(context)),
+        },
+        Migration {
+            version: 6,
+            description: "Convert cart products to line items",
+            up: |context| Box::pin(convert_cart_products_to_line_items(context)),
+        },
+        Migration {
+            version: 7,
+            description: "Replace wildcard index on cart products with a product_id index",
+            up: |context| Box::pin(replace_cart_products_index(context)),
+        },
+        Migration {
+            version: 8,
+            description: "Create outbox indexes",
+            up: |context| Box::pin(create_outbox_indexes(context)),
+        },
+        Migration {
+            version: 9,
+            description: "Create TTL index on idempotency keys",
+            up: |context| Box::pin(create_idempotency_keys_index(context)),
+        },
+        Migration {
+            version: 10,
+            description: "Create updated_at_utc indexes on carts and orders",
+            up: |context| Box::pin(create_updated_at_indexes(context)),
+        },
+    ]
+}
+
+pub struct MigrationRunner {
+    context: MigrationContext,
+    migrations_collection: String,
+    migrations: Vec<Migration>,
+}
+
+impl MigrationRunner {
+    pub fn new(
+        context: MigrationContext,
+        migrations_collection: String,
+        mut migrations: Vec<Migration>,
+    ) -> MigrationRunner {
+        migrations.sort_by_key(|m| m.version);
+
+        MigrationRunner {
+            context,
+            migrations_collection,
+            migrations,
+        }
+    }
+
+    pub async fn applied_versions(&self) -> Result<Vec<u32>, String> {
+        let collection = self
+            .context
+            .database
+            .collection::<AppliedMigration>(&self.migrations_collection);
+
+        match collection.find(doc! {}).await {
+            Ok(cursor) => match cursor.try_collect::<Vec<AppliedMigration>>().await {
+                Ok(applied) => Ok(applied.into_iter().map(|m| m.version).collect()),
+                Err(e) => Err(format!("Failed to read applied migrations: {}", e)),
+            },
+            Err(e) => Err(format!("Failed to read applied migrations: {}", e)),
+        }
+    }
+
+    // Applies pending migrations in version order, or only reports them when dry_run is set
+    pub async fn run(&self, dry_run: bool) -> Result<Vec<&Migration>, String> {
+        let applied_versions = self.applied_versions().await?;
+        let pending: Vec<&Migration> = self
+            .migrations
+            .iter()
+            .filter(|m| !applied_versions.contains(&m.version))
+            .collect();
+
+        if dry_run {
+            return Ok(pending);
+        }
+
+        let collection = self
+            .context
+            .database
+            .collection::<AppliedMigration>(&self.migrations_collection);
+
+        for migration in pending.iter() {
+            event!(
+                Level::INFO,
+                "Applying migration {}: {}",
+                migration.version,
+                migration.description
+            );
+
+            if let Err(e) = (migration.up)(&self.context).await {
+                return Err(format!("Migration {} failed: {}", migration.version, e));
+            }
+
+            if let Err(e) = collection
+                .insert_one(AppliedMigration {
+                    version: migration.version,
+                    description: String::from(migration.description),
+                    applied_at_utc: Utc::now(),
+                })
+                .await
+            {
+                return Err(format!(
+                    "Failed to record migration {}: {}",
+                    migration.version, e
+                ));
+            }
+        }
+
+        Ok(pending)
+    }
+}
+
+// Entry point for the `migrate [--dry-run]` CLI subcommand
+pub async fn migrate(dry_run: bool) -> Result<(), String> {
+    let client = match Client::with_uri_str(env::var("MONGODB_URI").unwrap()).await {
+        Ok(client) => client,
+        Err(e) => return Err(format!("Failed to connect to MongoDB: {}", e)),
+    };
+
+    let runner = MigrationRunner::new(
+        MigrationContext {
+            database: client.database(&env::var("MONGODB_DB").unwrap()),
+            carts_collection: env::var("MONGODB_CARTS_COLLECTION").unwrap(),
+            orders_collection: env::var("MONGODB_ORDER_COLLECTION").unwrap(),
+            outbox_collection: env::var("MONGODB_OUTBOX_COLLECTION")
+                .unwrap_or(String::from("outbox")),
+            idempotency_collection: env::var("MONGODB_IDEMPOTENCY_COLLECTION")
+                .unwrap_or(String::from("idempotency_keys")),
+        },
+        env::var("MONGODB_MIGRATIONS_COLLECTION").unwrap_or(String::from("migrations")),
+        all_migrations(),
+    );
+
+    let migrations = runner.run(dry_run).await?;
+
+    if migrations.is_empty() {
+        println!("Database schema is up to date");
+    }
+
+    for migration in migrations {
+        match dry_run {
+            true => println!(
+                "Pending migration {}: {}",
+                migration.version, migration.description
+            ),
+            false => println!(
+                "Applied migration {}: {}",
+                migration.version, migration.description
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+async fn create_unique_id_index(
+    context: &MigrationContext,
+    collection: &str,
+) -> Result<(), String> {
+    match context
+        .database
+        .collection::<Document>(collection)
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! {"id": 1})
+                .options(IndexOptions::builder().unique(true).build())
+                .build(),
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!(
+            "Failed to create id index on {}: {}",
+            collection, e
+        )),
+    }
+}
+
+async fn create_id_indexes(context: &MigrationContext) -> Result<(), String> {
+    create_unique_id_index(context, &context.carts_collection).await?;
+    create_unique_id_index(context, &context.orders_collection).await
+}
+
+async fn backfill_cart_ownership(context: &MigrationContext) -> Result<(), String> {
+    match context
+        .database
+        .collection::<Document>(&context.carts_collection)
+        .update_many(
+            doc! {"user_id": {"$exists": false}},
+            doc! {"$set": {"user_id": "", "name": DEFAULT_CART_NAME, "is_default": false}},
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to backfill carts: {}", e)),
+    }
+}
+
+async fn create_cart_user_id_index(context: &MigrationContext) -> Result<(), String> {
+    match context
+        .database
+        .collection::<Document>(&context.carts_collection)
+        .create_index(IndexModel::builder().keys(doc! {"user_id": 1}).build())
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to create user_id index on carts: {}", e)),
+    }
+}
+
+async fn convert_timestamps_to_dates(context: &MigrationContext) -> Result<(), String> {
+    let legacy_timestamps = doc! {"$or": [
+        {"created_at_utc": {"$type": "number"}},
+        {"updated_at_utc": {"$type": "number"}},
+    ]};
+    let to_dates = doc! {
+        "created_at_utc": {"$toDate": "$created_at_utc"},
+        "updated_at_utc": {"$toDate": "$updated_at_utc"},
+    };
+
+    if let Err(e) = context
+        .database
+        .collection::<Document>(&context.carts_collection)
+        .update_many(
+            legacy_timestamps.clone(),
+            vec![doc! {"$set": to_dates.clone()}],
+        )
+        .await
+    {
+        return Err(format!("Failed to convert cart timestamps: {}", e));
+    }
+
+    // Each entry of the order timeline carries its own timestamp
+    let mut order_dates = to_dates;
+    order_dates.insert(
+        "status_history",
+        doc! {"$map": {
+            "input": {"$ifNull": ["$status_history", []]},
+            "as": "transition",
+            "in": {"$mergeObjects": [
+                "$$transition",
+                {"at_utc": {"$toDate": "$$transition.at_utc"}},
+            ]},
+        }},
+    );
+
+    match context
+        .database
+        .collection::<Document>(&context.orders_collection)
+        .update_many(legacy_timestamps, vec![doc! {"$set": order_dates}])
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to convert order timestamps: {}", e)),
+    }
+}
+
+// Product ids are the keys of the products document, which only a wildcard index can cover
+async fn create_cart_products_index(context: &MigrationContext) -> Result<(), String> {
+    match context
+        .database
+        .collection::<Document>(&context.carts_collection)
+        .create_index(IndexModel::builder().keys(doc! {"products.$**": 1}).build())
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to create products index on carts: {}", e)),
+    }
+}
+
+// Lines are ordered by product id, as carts converted on read are. When products were added wasn't
+// recorded, so every line is dated to when its cart was created
+async fn convert_cart_products_to_line_items(context: &MigrationContext) -> Result<(), String> {
+    match context
+        .database
+        .collection::<Document>(&context.carts_collection)
+        .update_many(
+            doc! {"products": {"$type": "object"}},
+            vec![doc! {"$set": {"products": {"$map": {
+                "input": {"$sortArray": {"input": {"$objectToArray": "$products"}, "sortBy": {"k": 1}}},
+                "as": "product",
+                "in": {
+                    "product_id": "$$product.k",
+                    "quantity": "$$product.v",
+                    "added_at_utc": "$created_at_utc",
+                },
+            }}}}],
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to convert cart products: {}", e)),
+    }
+}
+
+async fn replace_cart_products_index(context: &MigrationContext) -> Result<(), String> {
+    let carts = context
+        .database
+        .collection::<Document>(&context.carts_collection);
+
+    if let Err(e) = carts.drop_index("products.$**_1").await {
+        return Err(format!("Failed to drop wildcard index on carts: {}", e));
+    }
+
+    match carts
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! {"products.product_id": 1})
+                .build(),
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to create products index on carts: {}", e)),
+    }
+}
+
+// The relay looks for unsent messages oldest first, and sent ones are kept for a week to look into
+// before they expire
+async fn create_outbox_indexes(context: &MigrationContext) -> Result<(), String> {
+    let outbox = context
+        .database
+        .collection::<Document>(&context.outbox_collection);
+
+    let indexes = vec![
+        IndexModel::builder()
+            .keys(doc! {"sent_at_utc": 1, "created_at_utc": 1, "sequence": 1})
+            .build(),
+        IndexModel::builder()
+            .keys(doc! {"sent_at_utc": 1})
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(7 * 24 * 60 * 60))
+                    .build(),
+            )
+            .build(),
+    ];
+
+    match outbox.create_indexes(indexes).await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Failed to create indexes on the outbox: {}", e)),
+    }
+}
+
+// Every key carries the date it expires at, so the index removes it once that date passes
+async fn create_idempotency_keys_index(context: &MigrationContext) -> Result<(), String> {
+    match context
+        .database
+        .collection::<Document>(&context.idempotency_collection)
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! {"expires_at_utc": 1})
+                .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+                .build(),
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!(
+            "Failed to create expires_at_utc index on idempotency keys: {}",
+            e
+        )),
+    }
+}
+
+// Retention looks for documents that haven't been updated since its cutoff
+async fn create_updated_at_index(
+    context: &MigrationContext,
+    collection: &str,
+) -> Result<(), String> {
+    match context
+        .database
+        .collection::<Document>(collection)
+        .create_index(
+            IndexModel::builder()
+                .keys(doc! {"updated_at_utc": 1})
+                .build(),
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!(
+            "Failed to create updated_at_utc index on {}: {}",
+            collection, e
+        )),
+    }
+}
+
+async fn create_updated_at_indexes(context: &MigrationContext) -> Result<(), String> {
+    create_updated_at_index(context, &context.carts_collection).await?;
+    create_updated_at_index(context, &context.orders_collection).await
+}
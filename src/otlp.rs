@@ -0,0 +1,281 @@
+use std::{
+    collections::HashMap,
+    env,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+    SharedString, Unit,
+};
+use metrics_util::layers::FanoutBuilder;
+use opentelemetry::{
+    metrics::{Meter, MeterProvider},
+    KeyValue,
+};
+use opentelemetry_otlp::{MetricExporter, WithExportConfig, WithHttpConfig};
+use opentelemetry_sdk::{
+    metrics::{PeriodicReader, SdkMeterProvider},
+    Resource,
+};
+
+static DEFAULT_SERVICE_NAME: &str = "eshop-orders";
+
+#[derive(Debug, Clone)]
+pub struct OtlpMetricsSettings {
+    // The full URL metrics are posted to, e.g. "https://otlp-gateway-prod-eu-west-2.grafana.net/otlp/v1/metrics"
+    pub endpoint: String,
+    // Sent with every export, usually the ingest credentials
+    pub headers: HashMap<String, String>,
+    pub interval: Duration,
+    pub service_name: String,
+}
+
+impl OtlpMetricsSettings {
+    // None unless OTLP_METRICS_ENDPOINT is set. OTLP_METRICS_HEADERS is a comma separated list of
+    // name=value pairs, OTLP_METRICS_INTERVAL_SECS defaults to 60 and OTEL_SERVICE_NAME to
+    // "eshop-orders"
+    pub fn from_env() -> Result<Option<OtlpMetricsSettings>, String> {
+        let endpoint = match env::var("OTLP_METRICS_ENDPOINT") {
+            Ok(endpoint) => endpoint,
+            Err(_) => return Ok(None),
+        };
+
+        let mut headers = HashMap::new();
+        for header in env::var("OTLP_METRICS_HEADERS")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|header| !header.trim().is_empty())
+        {
+            match header.split_once('=') {
+                Some((name, value)) => {
+                    headers.insert(String::from(name.trim()), String::from(value.trim()))
+                }
+                None => return Err(format!("Invalid OTLP_METRICS_HEADERS entry: {}", header)),
+            };
+        }
+
+        Ok(Some(OtlpMetricsSettings {
+            endpoint,
+            headers,
+            interval: match env::var("OTLP_METRICS_INTERVAL_SECS") {
+                Ok(interval) => match interval.parse() {
+                    Ok(interval) => Duration::from_secs(interval),
+                    Err(e) => return Err(format!("Invalid OTLP_METRICS_INTERVAL_SECS: {}", e)),
+                },
+                Err(_) => Duration::from_secs(60),
+            },
+            service_name: env::var("OTEL_SERVICE_NAME")
+                .unwrap_or(String::from(DEFAULT_SERVICE_NAME)),
+        }))
+    }
+}
+
+// Installs the recorder the service records its metrics through. With OTLP settings every metric
+// also goes to an OTel meter that pushes to the collector, so /metrics and the push carry the same
+// names and labels. The returned provider must be kept alive for as long as metrics are pushed
+pub fn install_recorder<R>(
+    recorder: R,
+    settings: Option<OtlpMetricsSettings>,
+    buckets: &[(&str, &[f64])],
+) -> Result<Option<SdkMeterProvider>, String>
+where
+    R: Recorder + Sync + 'static,
+{
+    let settings = match settings {
+        Some(settings) => settings,
+        None => {
+            return match metrics::set_global_recorder(recorder) {
+                Ok(()) => Ok(None),
+                Err(e) => Err(format!("Failed to install metrics recorder: {}", e)),
+            }
+        }
+    };
+
+    let exporter = match MetricExporter::builder()
+        .with_http()
+        .with_endpoint(settings.endpoint)
+        .with_headers(settings.headers)
+        .build()
+    {
+        Ok(exporter) => exporter,
+        Err(e) => return Err(format!("Failed to build OTLP metrics exporter: {}", e)),
+    };
+    let meter_provider = SdkMeterProvider::builder()
+        .with_reader(
+            PeriodicReader::builder(exporter)
+                .with_interval(settings.interval)
+                .build(),
+        )
+        .with_resource(
+            Resource::builder()
+                .with_service_name(settings.service_name)
+                .build(),
+        )
+        .build();
+
+    let mut otlp_recorder = OtlpRecorder::new(meter_provider.meter(DEFAULT_SERVICE_NAME));
+    for (name, buckets) in buckets {
+        otlp_recorder = otlp_recorder.with_buckets(name, buckets);
+    }
+
+    let fanout = FanoutBuilder::default()
+        .add_recorder(recorder)
+        .add_recorder(otlp_recorder)
+        .build();
+    match metrics::set_global_recorder(fanout) {
+        Ok(()) => Ok(Some(meter_provider)),
+        Err(e) => Err(format!("Failed to install metrics recorder: {}", e)),
+    }
+}
+
+fn attributes(key: &Key) -> Vec<KeyValue> {
+    key.labels()
+        .map(|label| KeyValue::new(label.key().to_string(), label.value().to_string()))
+        .collect()
+}
+
+struct OtlpCounter {
+    counter: opentelemetry::metrics::Counter<u64>,
+    attributes: Vec<KeyValue>,
+    value: AtomicU64,
+}
+
+impl CounterFn for OtlpCounter {
+    fn increment(&self, value: u64) {
+        self.value.fetch_add(value, Ordering::Relaxed);
+        self.counter.add(value, &self.attributes);
+    }
+
+    // OTel counters only go up by a delta, so an absolute value adds what it grew by
+    fn absolute(&self, value: u64) {
+        let previous = self.value.fetch_max(value, Ordering::Relaxed);
+        if value > previous {
+            self.counter.add(value - previous, &self.attributes);
+        }
+    }
+}
+
+struct OtlpGauge {
+    gauge: opentelemetry::metrics::Gauge<f64>,
+    attributes: Vec<KeyValue>,
+    // The f64 bits of the last value, so increments and decrements can be reported as a new value
+    value: AtomicU64,
+}
+
+impl OtlpGauge {
+    fn update(&self, change: impl Fn(f64) -> f64) {
+        let mut value = 0.0;
+        let _ = self
+            .value
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                value = change(f64::from_bits(bits));
+                Some(value.to_bits())
+            });
+        self.gauge.record(value, &self.attributes);
+    }
+}
+
+impl GaugeFn for OtlpGauge {
+    fn increment(&self, value: f64) {
+        self.update(|current| current + value);
+    }
+
+    fn decrement(&self, value: f64) {
+        self.update(|current| current - value);
+    }
+
+    fn set(&self, value: f64) {
+        self.update(|_| value);
+    }
+}
+
+struct OtlpHistogram {
+    histogram: opentelemetry::metrics::Histogram<f64>,
+    attributes: Vec<KeyValue>,
+}
+
+impl HistogramFn for OtlpHistogram {
+    fn record(&self, value: f64) {
+        self.histogram.record(value, &self.attributes);
+    }
+}
+
+// Forwards metrics recorded through the `metrics` macros to OTel instruments. Instruments are
+// created once per name and label set and reused, since the macros look them up on every call
+struct OtlpRecorder {
+    meter: Meter,
+    buckets: HashMap<String, Vec<f64>>,
+    counters: Mutex<HashMap<Key, Arc<OtlpCounter>>>,
+    gauges: Mutex<HashMap<Key, Arc<OtlpGauge>>>,
+    histograms: Mutex<HashMap<Key, Arc<OtlpHistogram>>>,
+}
+
+impl OtlpRecorder {
+    fn new(meter: Meter) -> OtlpRecorder {
+        OtlpRecorder {
+            meter,
+            buckets: HashMap::new(),
+            counters: Mutex::new(HashMap::new()),
+            gauges: Mutex::new(HashMap::new()),
+            histograms: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Same buckets as /metrics, so dashboards read the same distribution from either
+    fn with_buckets(mut self, name: &str, buckets: &[f64]) -> OtlpRecorder {
+        self.buckets.insert(String::from(name), buckets.to_vec());
+        self
+    }
+}
+
+impl Recorder for OtlpRecorder {
+    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        let mut counters = self.counters.lock().unwrap();
+        let counter = counters.entry(key.clone()).or_insert_with(|| {
+            Arc::new(OtlpCounter {
+                counter: self.meter.u64_counter(key.name().to_string()).build(),
+                attributes: attributes(key),
+                value: AtomicU64::new(0),
+            })
+        });
+        Counter::from_arc(counter.clone())
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        let mut gauges = self.gauges.lock().unwrap();
+        let gauge = gauges.entry(key.clone()).or_insert_with(|| {
+            Arc::new(OtlpGauge {
+                gauge: self.meter.f64_gauge(key.name().to_string()).build(),
+                attributes: attributes(key),
+                value: AtomicU64::new(0.0f64.to_bits()),
+            })
+        });
+        Gauge::from_arc(gauge.clone())
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms.entry(key.clone()).or_insert_with(|| {
+            let builder = self.meter.f64_histogram(key.name().to_string());
+            Arc::new(OtlpHistogram {
+                histogram: match self.buckets.get(key.name()) {
+                    Some(buckets) => builder.with_boundaries(buckets.clone()).build(),
+                    None => builder.build(),
+                },
+                attributes: attributes(key),
+            })
+        });
+        Histogram::from_arc(histogram.clone())
+    }
+}
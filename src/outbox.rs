@@ -0,0 +1,187 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
+use mongodb::{
+    action::Action,
+    bson::{self, doc},
+    Client, Collection,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    domain::timestamp,
+    events::{Event, MessageBroker},
+    repositories::lock_session,
+    scheduler::Job,
+    uow::{TransactionSession, EVENTS_PUBLISHED_TOTAL, EVENT_PUBLISH_FAILURES_TOTAL},
+};
+
+pub static DEFAULT_OUTBOX_RELAY_SCHEDULE: &str = "* * * * * *";
+pub static DEFAULT_OUTBOX_RELAY_BATCH_SIZE: i64 = 100;
+
+// An event waiting to be published. Events written in the same commit share created_at_utc and
+// keep their order through sequence
+#[derive(Serialize, Deserialize)]
+pub struct OutboxMessage {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub event: Event,
+    #[serde(with = "timestamp")]
+    pub created_at_utc: DateTime<Utc>,
+    pub sequence: u32,
+}
+
+impl OutboxMessage {
+    pub fn from_events(events: Vec<Event>, created_at_utc: DateTime<Utc>) -> Vec<OutboxMessage> {
+        events
+            .into_iter()
+            .enumerate()
+            .map(|(sequence, event)| OutboxMessage {
+                id: uuid::Uuid::new_v4().to_string(),
+                event,
+                created_at_utc,
+                sequence: sequence as u32,
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+pub trait OutboxStore {
+    // Written with the session of the transaction making the changes the events are about
+    async fn append(
+        &self,
+        messages: &[OutboxMessage],
+        session: TransactionSession,
+    ) -> Result<(), String>;
+    // The oldest messages not published yet, in the order they were appended
+    async fn read_pending(&self, limit: i64) -> Result<Vec<OutboxMessage>, String>;
+    async fn mark_sent(&self, id: &str) -> Result<(), String>;
+}
+
+// Published messages are kept with a sent_at_utc date, which the TTL index from the migrations
+// expires them by
+pub struct MongoDbOutboxStore {
+    outbox_collection: Collection<OutboxMessage>,
+}
+
+impl MongoDbOutboxStore {
+    // The client has to be the one the transactions' sessions are started from
+    pub fn new(client: &Client, database: &str, collection: &str) -> MongoDbOutboxStore {
+        MongoDbOutboxStore {
+            outbox_collection: client.database(database).collection(collection),
+        }
+    }
+}
+
+#[async_trait]
+impl OutboxStore for MongoDbOutboxStore {
+    async fn append(
+        &self,
+        messages: &[OutboxMessage],
+        session: TransactionSession,
+    ) -> Result<(), String> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+
+        let mut guard = lock_session(&session).await;
+
+        match self
+            .outbox_collection
+            .insert_many(messages)
+            .optional(guard.as_deref_mut(), |action, s| action.session(s))
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Failed to write events to the outbox: {}", e)),
+        }
+    }
+
+    async fn read_pending(&self, limit: i64) -> Result<Vec<OutboxMessage>, String> {
+        let cursor = match self
+            .outbox_collection
+            .find(doc! {"sent_at_utc": null})
+            .sort(doc! {"created_at_utc": 1, "sequence": 1})
+            .limit(limit)
+            .await
+        {
+            Ok(cursor) => cursor,
+            Err(e) => return Err(format!("Failed to read the outbox: {}", e)),
+        };
+
+        cursor
+            .try_collect()
+            .await
+            .map_err(|e| format!("Failed to read the outbox: {}", e))
+    }
+
+    async fn mark_sent(&self, id: &str) -> Result<(), String> {
+        match self
+            .outbox_collection
+            .update_one(
+                doc! {"_id": id},
+                doc! {"$set": {"sent_at_utc": bson::DateTime::now()}},
+            )
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!(
+                "Failed to mark outbox message {} as sent: {}",
+                id, e
+            )),
+        }
+    }
+}
+
+// Publishes what the commits left in the outbox. Delivery is at least once: a message published
+// right before a crash is published again, as it was never marked sent
+pub struct OutboxRelay {
+    outbox_store: Arc<dyn OutboxStore + Send + Sync>,
+    message_broker: Arc<dyn MessageBroker + Send + Sync>,
+    batch_size: i64,
+}
+
+impl OutboxRelay {
+    pub fn new(
+        outbox_store: Arc<dyn OutboxStore + Send + Sync>,
+        message_broker: Arc<dyn MessageBroker + Send + Sync>,
+        batch_size: i64,
+    ) -> OutboxRelay {
+        OutboxRelay {
+            outbox_store,
+            message_broker,
+            batch_size,
+        }
+    }
+}
+
+#[async_trait]
+impl Job for OutboxRelay {
+    // Stops at the first failure so events are never published out of order
+    async fn run(&self) -> Result<(), String> {
+        for message in self.outbox_store.read_pending(self.batch_size).await? {
+            let result = self.message_broker.publish_message(&message.event).await;
+
+            let counter_name = match result {
+                Ok(()) => EVENTS_PUBLISHED_TOTAL,
+                Err(_) => EVENT_PUBLISH_FAILURES_TOTAL,
+            };
+            metrics::counter!(counter_name, "event_type" => message.event.event_type())
+                .increment(1);
+
+            if let Err(e) = result {
+                return Err(format!(
+                    "Failed to publish outbox message {}: {}",
+                    message.id, e
+                ));
+            }
+
+            self.outbox_store.mark_sent(&message.id).await?;
+        }
+
+        Ok(())
+    }
+}
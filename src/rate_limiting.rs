@@ -0,0 +1,400 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::header::AUTHORIZATION,
+    middleware::Next,
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{event, Level};
+
+use crate::{
+    auth::Claims,
+    throttling::{throttled_response, ThrottleReason},
+};
+
+pub static REQUESTS_RATE_LIMITED_TOTAL: &str = "order_service_requests_rate_limited_total";
+
+// Routes that belong to no group share this group's budgets
+static DEFAULT_GROUP: &str = "default";
+// Buckets of callers that have gone quiet are dropped every this many requests
+static BUCKET_CLEANUP_INTERVAL: usize = 1024;
+
+// Requests refill the bucket at a steady rate and burst is how many can be made back to back
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    pub requests_per_second: f64,
+    pub burst: u32,
+}
+
+// Anonymous callers are counted per client address and authenticated callers per verified identity,
+// so signed-in users aren't held back by others behind the same NAT. Requests whose token fails
+// authentication are charged to the client address like anonymous ones. A missing budget is unlimited
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitBudgets {
+    pub anonymous: Option<RateLimit>,
+    pub authenticated: Option<RateLimit>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteGroup {
+    pub name: String,
+    // Each as "<METHOD> <path>" with the path as registered with the router, e.g. "PUT /carts/{id}"
+    pub routes: Vec<String>,
+    #[serde(flatten)]
+    pub budgets: RateLimitBudgets,
+}
+
+// Read from RATE_LIMIT_POLICY_PATH. Without a policy nothing is rate limited
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitPolicy {
+    #[serde(default)]
+    pub default: RateLimitBudgets,
+    #[serde(default)]
+    pub groups: Vec<RouteGroup>,
+}
+
+impl RateLimitPolicy {
+    pub fn from_file(path: &str) -> Result<RateLimitPolicy, String> {
+        let policy: RateLimitPolicy = match std::fs::read_to_string(path) {
+            Ok(policy) => serde_json::from_str(&policy)
+                .map_err(|e| format!("Invalid rate limit policy in {}: {}", path, e))?,
+            Err(e) => return Err(format!("Failed to read rate limit policy {}: {}", path, e)),
+        };
+
+        let limits = policy
+            .groups
+            .iter()
+            .map(|group| &group.budgets)
+            .chain([&policy.default]);
+        for limit in limits
+            .flat_map(|budgets| [budgets.anonymous, budgets.authenticated])
+            .flatten()
+        {
+            if limit.requests_per_second <= 0.0 || limit.burst == 0 {
+                return Err(format!(
+                    "Rate limits in {} need a positive rate and burst",
+                    path
+                ));
+            }
+        }
+
+        Ok(policy)
+    }
+
+    fn group(&self, route: &str) -> (&str, &RateLimitBudgets) {
+        self.groups
+            .iter()
+            .find(|group| {
+                group
+                    .routes
+                    .iter()
+                    .any(|group_route| group_route.eq_ignore_ascii_case(route))
+            })
+            .map(|group| (group.name.as_str(), &group.budgets))
+            .unwrap_or((DEFAULT_GROUP, &self.default))
+    }
+
+    fn group_by_name(&self, name: &str) -> &RateLimitBudgets {
+        self.groups
+            .iter()
+            .find(|group| group.name == name)
+            .map(|group| &group.budgets)
+            .unwrap_or(&self.default)
+    }
+}
+
+impl RateLimitBudgets {
+    fn limit(&self, caller: &Caller) -> Option<&RateLimit> {
+        match caller {
+            Caller::Anonymous(_) | Caller::Unverified(_) => self.anonymous.as_ref(),
+            Caller::Authenticated(_) => self.authenticated.as_ref(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Caller {
+    Anonymous(IpAddr),
+    // Requests from this address that carried a token authentication didn't accept. Kept apart from
+    // the anonymous bucket so guests behind the same NAT don't hold back signed-in users
+    Unverified(IpAddr),
+    // The issuer and subject of a token authentication has verified
+    Authenticated(String),
+}
+
+struct TokenBucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    // Takes a token if there is one, otherwise returns how long until there will be. Without take
+    // the token is only looked for
+    fn take(&mut self, limit: &RateLimit, now: Instant, take: bool) -> Result<(), Duration> {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.requests_per_second).min(limit.burst as f64);
+        self.refilled_at = now;
+
+        match self.tokens >= 1.0 {
+            true => {
+                if take {
+                    self.tokens -= 1.0;
+                }
+                Ok(())
+            }
+            false => Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / limit.requests_per_second,
+            )),
+        }
+    }
+
+    fn is_full(&self, limit: &RateLimit, now: Instant) -> bool {
+        self.tokens + now.duration_since(self.refilled_at).as_secs_f64() * limit.requests_per_second
+            >= limit.burst as f64
+    }
+}
+
+// Token buckets per route group and caller. The policy can be reloaded from its file through the
+// admin endpoint, which starts every caller over with a full bucket
+pub struct RateLimiter {
+    path: Option<String>,
+    policy: RwLock<RateLimitPolicy>,
+    buckets: Mutex<HashMap<(String, Caller), TokenBucket>>,
+    requests: AtomicUsize,
+}
+
+impl RateLimiter {
+    pub fn new(policy: RateLimitPolicy) -> RateLimiter {
+        RateLimiter {
+            path: None,
+            policy: RwLock::new(policy),
+            buckets: Mutex::new(HashMap::new()),
+            requests: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn from_file(path: &str) -> Result<RateLimiter, String> {
+        Ok(RateLimiter {
+            path: Some(String::from(path)),
+            ..RateLimiter::new(RateLimitPolicy::from_file(path)?)
+        })
+    }
+
+    pub fn policy(&self) -> RateLimitPolicy {
+        self.policy.read().unwrap().clone()
+    }
+
+    // A policy that fails to load leaves the current one in place
+    pub fn reload(&self) -> Result<RateLimitPolicy, String> {
+        let policy = match &self.path {
+            Some(path) => RateLimitPolicy::from_file(path)?,
+            None => return Err(String::from("No rate limit policy file is configured")),
+        };
+
+        *self.policy.write().unwrap() = policy.clone();
+        self.buckets.lock().unwrap().clear();
+
+        Ok(policy)
+    }
+
+    // Returns the group and how long to wait if the caller is over its budget
+    fn check(&self, route: &str, caller: Caller) -> Result<(), (String, Duration)> {
+        self.charge(route, caller, true)
+    }
+
+    // Like check, but leaves the caller's budget as it is
+    fn peek(&self, route: &str, caller: Caller) -> Result<(), (String, Duration)> {
+        self.charge(route, caller, false)
+    }
+
+    fn charge(&self, route: &str, caller: Caller, take: bool) -> Result<(), (String, Duration)> {
+        let policy = self.policy.read().unwrap();
+        let (group, budgets) = policy.group(route);
+        let limit = match budgets.limit(&caller) {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if self
+            .requests
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(BUCKET_CLEANUP_INTERVAL)
+        {
+            buckets.retain(|(bucket_group, bucket_caller), bucket| {
+                match policy.group_by_name(bucket_group).limit(bucket_caller) {
+                    Some(limit) => !bucket.is_full(limit, now),
+                    None => false,
+                }
+            });
+        }
+
+        buckets
+            .entry((String::from(group), caller))
+            .or_insert(TokenBucket {
+                tokens: limit.burst as f64,
+                refilled_at: now,
+            })
+            .take(limit, now, take)
+            .map_err(|retry_after| (String::from(group), retry_after))
+    }
+}
+
+fn route(request: &Request) -> Option<String> {
+    request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|matched_path| format!("{} {}", request.method(), matched_path.as_str()))
+}
+
+fn rate_limited_response(
+    route: &str,
+    caller: &str,
+    group: String,
+    retry_after: Duration,
+) -> Response {
+    event!(
+        Level::WARN,
+        "Rate limited {} from {} ({} group)",
+        route,
+        caller,
+        group
+    );
+    metrics::counter!(REQUESTS_RATE_LIMITED_TOTAL, "group" => group).increment(1);
+
+    throttled_response(
+        ThrottleReason::RateLimited,
+        "Too many requests, please slow down",
+        retry_after.as_secs_f64().ceil() as u64,
+    )
+}
+
+// Charges a caller whose token authentication has just verified to its own budget. Returns the
+// response to refuse the request with when the caller is over it
+pub fn check_verified(
+    rate_limiter: &RateLimiter,
+    request: &Request,
+    claims: &Claims,
+) -> Option<Response> {
+    let route = route(request)?;
+    let identity = format!("{} {}", claims.iss, claims.sub);
+
+    match rate_limiter.check(&route, Caller::Authenticated(identity)) {
+        Ok(()) => None,
+        Err((group, retry_after)) => Some(rate_limited_response(
+            &route,
+            &claims.sub,
+            group,
+            retry_after,
+        )),
+    }
+}
+
+pub async fn rate_limiting_middleware(
+    State(rate_limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    // Unmatched requests are answered by the router's fallback and cost nothing
+    let route = match route(&request) {
+        Some(route) => route,
+        None => return next.run(request).await,
+    };
+    let address = peer.ip().to_canonical();
+
+    // A token can't be told apart from a made up one until authentication has checked it, so the
+    // request is let through only while its address has budget left and is charged to the address
+    // afterwards unless it turned out verified. Verified callers are charged by check_verified
+    if !request.headers().contains_key(AUTHORIZATION) {
+        if let Err((group, retry_after)) = rate_limiter.check(&route, Caller::Anonymous(address)) {
+            return rate_limited_response(&route, &address.to_string(), group, retry_after);
+        }
+
+        return next.run(request).await;
+    }
+
+    if let Err((group, retry_after)) = rate_limiter.peek(&route, Caller::Unverified(address)) {
+        return rate_limited_response(&route, &address.to_string(), group, retry_after);
+    }
+
+    let response = next.run(request).await;
+    if response.extensions().get::<Claims>().is_none() {
+        let _ = rate_limiter.check(&route, Caller::Unverified(address));
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use axum::{
+        body::Body, extract::connect_info::MockConnectInfo, http::StatusCode, middleware,
+        routing::get, Router,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn router(rate_limiter: RateLimiter) -> Router {
+        Router::new()
+            .route("/carts", get(|| async { "carts" }))
+            .layer(middleware::from_fn_with_state(
+                Arc::new(rate_limiter),
+                rate_limiting_middleware,
+            ))
+            .layer(MockConnectInfo(SocketAddr::from((
+                Ipv4Addr::LOCALHOST,
+                4000,
+            ))))
+    }
+
+    #[tokio::test]
+    async fn made_up_tokens_do_not_escape_the_anonymous_limit() {
+        let router = router(RateLimiter::new(RateLimitPolicy {
+            default: RateLimitBudgets {
+                anonymous: Some(RateLimit {
+                    requests_per_second: 0.001,
+                    burst: 2,
+                }),
+                authenticated: None,
+            },
+            groups: vec![],
+        }));
+
+        let mut statuses = vec![];
+        for n in 0..3 {
+            let request = Request::get("/carts")
+                .header(AUTHORIZATION, format!("Bearer x{}", n))
+                .body(Body::empty())
+                .unwrap();
+            statuses.push(router.clone().oneshot(request).await.unwrap().status());
+        }
+
+        assert_eq!(
+            statuses,
+            [
+                StatusCode::OK,
+                StatusCode::OK,
+                StatusCode::TOO_MANY_REQUESTS
+            ]
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use std::{env, io, sync::OnceLock};
+
+use serde_json::Value;
+use tracing_subscriber::fmt::MakeWriter;
+
+static REDACTED: &str = "[REDACTED]";
+
+// Fields that hold credentials or personal data. Names are compared case-insensitively with '-'
+// treated as '_', so header names match too
+static DEFAULT_DENYLIST: [&str; 14] = [
+    "authorization",
+    "cookie",
+    "token",
+    "access_token",
+    "refresh_token",
+    "api_key",
+    "x_api_key",
+    "x_cart_session",
+    "secret",
+    "password",
+    "address",
+    "shipping_address",
+    "billing_address",
+    "payment_id",
+];
+
+// Signatures of JWTs and of the service's own signed tokens are at least this long, which keeps
+// dotted version numbers and file names from being mistaken for tokens
+static MIN_TOKEN_SIGNATURE_LEN: usize = 32;
+
+static REDACTOR: OnceLock<Redactor> = OnceLock::new();
+
+#[derive(Debug, Clone)]
+pub struct Redactor {
+    denylist: Vec<String>,
+}
+
+impl Default for Redactor {
+    fn default() -> Self {
+        Redactor::new(DEFAULT_DENYLIST.iter().map(|name| name.to_string()))
+    }
+}
+
+impl Redactor {
+    pub fn new(denylist: impl IntoIterator<Item = String>) -> Redactor {
+        Redactor {
+            denylist: denylist.into_iter().map(|name| normalize(&name)).collect(),
+        }
+    }
+
+    // LOG_REDACT_FIELDS adds comma separated field names to the default denylist
+    pub fn from_env() -> Redactor {
+        let extra_fields = env::var("LOG_REDACT_FIELDS").unwrap_or_default();
+
+        Redactor::new(
+            DEFAULT_DENYLIST.iter().map(|name| name.to_string()).chain(
+                extra_fields
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(String::from),
+            ),
+        )
+    }
+
+    pub fn is_denied(&self, field_name: &str) -> bool {
+        self.denylist.contains(&normalize(field_name))
+    }
+
+    // Denied fields lose their value wherever they are nested, and every remaining string is
+    // scanned for tokens
+    pub fn redact_json(&self, value: &mut Value) {
+        match value {
+            Value::Object(fields) => {
+                for (name, value) in fields.iter_mut() {
+                    match self.is_denied(name) {
+                        true => *value = Value::String(String::from(REDACTED)),
+                        false => self.redact_json(value),
+                    }
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(|value| self.redact_json(value)),
+            Value::String(text) => *text = self.redact_text(text),
+            _ => {}
+        }
+    }
+
+    // Free text such as error messages can't be parsed, so tokens are recognised by their shape,
+    // denied fields by 'name=value', 'name: value' or '"name":"value"' and bearer credentials by
+    // the word before them
+    pub fn redact_text(&self, text: &str) -> String {
+        let mut redacted = String::with_capacity(text.len());
+        // Set once a value has to be redacted: whether it runs to the next delimiter, as field
+        // values may contain spaces, or is a single word
+        let mut pending_value: Option<bool> = None;
+        let mut in_value = false;
+
+        for (word, separator) in words(text) {
+            if in_value {
+                if ends_value(separator) {
+                    in_value = false;
+                    redacted.push_str(separator);
+                }
+                continue;
+            }
+
+            match pending_value.take() {
+                Some(to_delimiter) => {
+                    redacted.push_str(REDACTED);
+                    if to_delimiter && !ends_value(separator) {
+                        in_value = true;
+                        continue;
+                    }
+                }
+                None if is_token(word) => redacted.push_str(REDACTED),
+                None => {
+                    redacted.push_str(word);
+                    let name = word.trim_matches('"');
+                    if self.is_denied(name) && is_value_separator(separator) {
+                        pending_value = Some(true);
+                    } else if name.eq_ignore_ascii_case("bearer") && separator == " " {
+                        pending_value = Some(false);
+                    }
+                }
+            }
+            redacted.push_str(separator);
+        }
+
+        redacted
+    }
+}
+
+// Installed once at startup, before any logging, so error messages returned to clients are
+// redacted the same way as the log
+pub fn install(redactor: Redactor) {
+    REDACTOR.set(redactor).ok();
+}
+
+pub fn redactor() -> &'static Redactor {
+    REDACTOR.get_or_init(Redactor::default)
+}
+
+pub fn redact(text: &str) -> String {
+    redactor().redact_text(text)
+}
+
+fn normalize(field_name: &str) -> String {
+    field_name.trim().to_ascii_lowercase().replace('-', "_")
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '"')
+}
+
+// Splits text into words and the separators that follow them
+fn words(text: &str) -> impl Iterator<Item = (&str, &str)> {
+    let mut rest = text;
+
+    std::iter::from_fn(move || {
+        if rest.is_empty() {
+            return None;
+        }
+
+        let word_end = rest.find(|c| !is_word_char(c)).unwrap_or(rest.len());
+        let separator_end = rest[word_end..]
+            .find(is_word_char)
+            .map(|i| word_end + i)
+            .unwrap_or(rest.len());
+        let (word, separator) = (&rest[..word_end], &rest[word_end..separator_end]);
+        rest = &rest[separator_end..];
+
+        Some((word, separator))
+    })
+}
+
+// Whatever follows a field name and one of these is taken to be its value
+fn is_value_separator(separator: &str) -> bool {
+    matches!(separator.trim(), "=" | ":")
+}
+
+fn ends_value(separator: &str) -> bool {
+    separator.contains([',', ';', '&', ')', '}', ']', '\n'])
+}
+
+// JWTs and tokens issued by TokenSigner are three base64url segments separated by dots
+fn is_token(word: &str) -> bool {
+    let word = word.trim_matches(|c| matches!(c, '"' | '.'));
+    let segments: Vec<&str> = word.split('.').collect();
+
+    segments.len() == 3
+        && segments.iter().all(|segment| {
+            !segment.is_empty()
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        && segments[2].len() >= MIN_TOKEN_SIGNATURE_LEN
+}
+
+// Wraps the log writer so every line is redacted before it is written. Each event is formatted
+// into one buffer and written with a single call, so a line never arrives split across writes
+pub struct RedactingMakeWriter<M> {
+    inner: M,
+    redactor: Redactor,
+}
+
+impl<M> RedactingMakeWriter<M> {
+    pub fn new(inner: M, redactor: Redactor) -> RedactingMakeWriter<M> {
+        RedactingMakeWriter { inner, redactor }
+    }
+}
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for RedactingMakeWriter<M> {
+    type Writer = RedactingWriter<'a, M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter {
+            inner: self.inner.make_writer(),
+            redactor: &self.redactor,
+        }
+    }
+}
+
+pub struct RedactingWriter<'a, W> {
+    inner: W,
+    redactor: &'a Redactor,
+}
+
+impl<W: io::Write> io::Write for RedactingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        let mut redacted = String::with_capacity(text.len());
+        for line in text.split_inclusive('\n') {
+            let (line, line_ending) = match line.strip_suffix('\n') {
+                Some(line) => (line, "\n"),
+                None => (line, ""),
+            };
+
+            match serde_json::from_str::<Value>(line) {
+                Ok(mut value) => {
+                    self.redactor.redact_json(&mut value);
+                    redacted.push_str(&value.to_string());
+                }
+                Err(_) => redacted.push_str(&self.redactor.redact_text(line)),
+            }
+            redacted.push_str(line_ending);
+        }
+
+        self.inner.write_all(redacted.as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
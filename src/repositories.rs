@@ -1,11 +1,108 @@
 use std::{collections::HashMap, sync::Arc};
 
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use futures_util::TryStreamExt;
-use mongodb::{bson::doc, Client, ClientSession, Collection};
-use tokio::sync::Mutex;
+use mongodb::{
+    action::Action,
+    bson::{self, doc},
+    options::{CollectionOptions, ReadPreference, SelectionCriteria},
+    Client, ClientSession, Collection, Database,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sqlx::{sqlite::SqliteRow, Row, SqlitePool};
+use tokio::sync::{Mutex, MutexGuard};
 
-use crate::domain::{Cart, Order};
+use crate::{
+    consistency,
+    domain::{Cart, CartId, Order, OrderId, OrderStatus, ProductId},
+    uow::TransactionSession,
+};
+
+// Totals across every stored cart
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CartStats {
+    pub carts: u64,
+    // Carts with at least one product in them
+    pub active_carts: u64,
+    // The quantities of every product in every cart added up
+    pub items: u64,
+}
+
+// Cart updates only apply on top of the version that was read; a stale version yields this error
+pub static VERSION_CONFLICT_ERROR: &str = "Version conflict";
+
+pub fn is_version_conflict(error: &str) -> bool {
+    error.contains(VERSION_CONFLICT_ERROR)
+}
+
+fn cart_version_conflict(id: &CartId) -> String {
+    format!("{} on Cart with id {}", VERSION_CONFLICT_ERROR, id)
+}
+
+fn order_version_conflict(id: &OrderId) -> String {
+    format!("{} on Order with id {}", VERSION_CONFLICT_ERROR, id)
+}
+
+// What reading or writing a cart or order that doesn't exist fails with, so handlers can tell it
+// from the database failing
+pub static NOT_FOUND_ERROR: &str = "Document not found";
+
+pub fn is_not_found(error: &str) -> bool {
+    error.contains(NOT_FOUND_ERROR)
+}
+
+fn cart_not_found(id: &CartId) -> String {
+    format!("{}: Cart with id {}", NOT_FOUND_ERROR, id)
+}
+
+fn order_not_found(id: &OrderId) -> String {
+    format!("{}: Order with id {}", NOT_FOUND_ERROR, id)
+}
+
+// How MongoDB reports a replica set without a writable primary, e.g. during an election or planned
+// maintenance. Writes fail until a primary is back, but secondaries can still answer reads
+static PRIMARY_UNAVAILABLE_ERRORS: [&str; 7] = [
+    "NotWritablePrimary",
+    "NotPrimaryNoSecondaryOk",
+    "NotPrimaryOrSecondary",
+    "PrimarySteppedDown",
+    "InterruptedDueToReplStateChange",
+    "LegacyNotPrimary",
+    // Server selection timing out while looking for the primary
+    "suitable for criteria ReadPreference(Primary)",
+];
+
+pub fn is_primary_unavailable(error: &str) -> bool {
+    PRIMARY_UNAVAILABLE_ERRORS
+        .iter()
+        .any(|primary_unavailable| error.contains(primary_unavailable))
+}
+
+// Every update is stamped here rather than in the handlers, so no write can forget to. Returns the
+// version the stored entity must still have for the update to apply
+fn stamp_cart_update(cart: &mut Cart) -> u32 {
+    let expected_version = cart.version;
+    cart.version += 1;
+    cart.updated_at_utc = Utc::now();
+
+    expected_version
+}
+
+fn stamp_order_update(order: &mut Order) -> u32 {
+    let expected_version = order.version;
+    order.version += 1;
+    order.updated_at_utc = Utc::now();
+
+    expected_version
+}
+
+pub async fn lock_session(session: &TransactionSession) -> Option<MutexGuard<'_, ClientSession>> {
+    match session.client_session() {
+        Some(client_session) => Some(client_session.lock().await),
+        None => None,
+    }
+}
 
 #[derive(Debug)]
 pub struct MongoDbInitializationInfo {
@@ -14,52 +111,97 @@ pub struct MongoDbInitializationInfo {
     pub collection: String,
 }
 
+#[allow(dead_code)]
 #[async_trait]
 pub trait OrderRepository {
     async fn create(
         &self,
-        id: String,
+        id: OrderId,
         order: Order,
-        session: Arc<Mutex<ClientSession>>,
+        session: TransactionSession,
     ) -> Result<Order, String>;
-    async fn read<'a>(&self, id: &'a str) -> Result<Order, String>;
+    async fn read<'a>(&self, id: &'a OrderId) -> Result<Order, String>;
     async fn read_all(&self) -> Result<Vec<Order>, String>;
+    // Up to limit orders last updated before the cutoff, ordered by id and starting after after_id,
+    // so they can be gone through in batches
+    async fn read_updated_before<'a>(
+        &self,
+        cutoff_utc: DateTime<Utc>,
+        after_id: Option<&'a OrderId>,
+        limit: u32,
+    ) -> Result<Vec<Order>, String>;
+    async fn count_by_status(&self) -> Result<HashMap<OrderStatus, u64>, String>;
     async fn update(
         &self,
-        id: String,
+        id: OrderId,
+        order: Order,
+        session: TransactionSession,
+    ) -> Result<Order, String>;
+    // Inserts the order or replaces the stored one as is, without checking or bumping its version
+    async fn upsert(
+        &self,
+        id: OrderId,
         order: Order,
-        session: Arc<Mutex<ClientSession>>,
+        session: TransactionSession,
     ) -> Result<Order, String>;
-    async fn delete(&self, id: &str, session: Arc<Mutex<ClientSession>>);
+    async fn delete(&self, id: &OrderId, session: TransactionSession) -> Result<(), String>;
 }
 
+#[allow(dead_code)]
 #[async_trait]
 pub trait CartRepository {
     async fn create(
         &self,
-        id: String,
+        id: CartId,
         cart: Cart,
-        session: Arc<Mutex<ClientSession>>,
+        session: TransactionSession,
     ) -> Result<Cart, String>;
-    async fn read<'a>(&self, id: &'a str) -> Result<Cart, String>;
+    async fn read<'a>(&self, id: &'a CartId) -> Result<Cart, String>;
     async fn read_all(&self) -> Result<Vec<Cart>, String>;
+    // Up to limit carts last updated before the cutoff, ordered by id and starting after after_id,
+    // so they can be gone through in batches
+    async fn read_updated_before<'a>(
+        &self,
+        cutoff_utc: DateTime<Utc>,
+        after_id: Option<&'a CartId>,
+        limit: u32,
+    ) -> Result<Vec<Cart>, String>;
+    async fn read_all_by_user_id<'a>(&self, user_id: &'a str) -> Result<Vec<Cart>, String>;
+    // Every cart with the product in it, whatever the quantity
+    async fn read_all_containing_product<'a>(
+        &self,
+        product_id: &'a ProductId,
+    ) -> Result<Vec<Cart>, String>;
+    async fn stats(&self) -> Result<CartStats, String>;
     async fn update(
         &self,
-        id: String,
+        id: CartId,
+        cart: Cart,
+        session: TransactionSession,
+    ) -> Result<Cart, String>;
+    // Inserts the cart or replaces the stored one as is, without checking or bumping its version
+    async fn upsert(
+        &self,
+        id: CartId,
         cart: Cart,
-        session: Arc<Mutex<ClientSession>>,
+        session: TransactionSession,
     ) -> Result<Cart, String>;
-    async fn delete(&self, id: &str, session: Arc<Mutex<ClientSession>>);
+    async fn delete(&self, id: &CartId, session: TransactionSession) -> Result<(), String>;
+
+    // Checks the backing store is reachable; in-memory stores always are
+    async fn ping(&self) -> Result<(), String> {
+        Ok(())
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct InMemoryOrderRepository {
-    orders: Arc<Mutex<HashMap<String, Order>>>,
+    orders: Arc<Mutex<HashMap<OrderId, Order>>>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct InMemoryCartRepository {
-    carts: Arc<Mutex<HashMap<String, Cart>>>,
+    carts: Arc<Mutex<HashMap<CartId, Cart>>>,
 }
 
 impl InMemoryOrderRepository {
@@ -82,23 +224,23 @@ impl InMemoryCartRepository {
 impl OrderRepository for InMemoryOrderRepository {
     async fn create(
         &self,
-        id: String,
+        id: OrderId,
         order: Order,
-        _: Arc<Mutex<ClientSession>>,
+        _: TransactionSession,
     ) -> Result<Order, String> {
         let mut lock = self.orders.lock().await;
         lock.insert(id.clone(), order.clone());
-        match lock.get(id.as_str()) {
+        match lock.get(&id) {
             Some(x) => Ok(x.clone()),
-            None => Err(format!("Order with id {} did not exist", id)),
+            None => Err(order_not_found(&id)),
         }
     }
 
-    async fn read<'a>(&self, id: &'a str) -> Result<Order, String> {
+    async fn read<'a>(&self, id: &'a OrderId) -> Result<Order, String> {
         let lock = self.orders.lock().await;
         match lock.get(id) {
             Some(x) => Ok(x.clone()),
-            None => Err(format!("Order with id {} did not exist", id)),
+            None => Err(order_not_found(id)),
         }
     }
 
@@ -113,47 +255,93 @@ impl OrderRepository for InMemoryOrderRepository {
         Ok(orders_to_return)
     }
 
+    async fn read_updated_before<'a>(
+        &self,
+        cutoff_utc: DateTime<Utc>,
+        after_id: Option<&'a OrderId>,
+        limit: u32,
+    ) -> Result<Vec<Order>, String> {
+        let lock = self.orders.lock().await;
+
+        let mut orders: Vec<Order> = lock
+            .values()
+            .filter(|order| order.updated_at_utc < cutoff_utc)
+            .filter(|order| after_id.is_none_or(|after_id| order.id.as_str() > after_id.as_str()))
+            .cloned()
+            .collect();
+        orders.sort_by(|a, b| a.id.as_str().cmp(b.id.as_str()));
+        orders.truncate(limit as usize);
+
+        Ok(orders)
+    }
+
+    async fn count_by_status(&self) -> Result<HashMap<OrderStatus, u64>, String> {
+        let lock = self.orders.lock().await;
+
+        let mut counts = HashMap::new();
+        for order in lock.values() {
+            *counts.entry(order.status).or_insert(0) += 1;
+        }
+
+        Ok(counts)
+    }
+
     async fn update(
         &self,
-        id: String,
-        order: Order,
-        _: Arc<Mutex<ClientSession>>,
+        id: OrderId,
+        mut order: Order,
+        _: TransactionSession,
     ) -> Result<Order, String> {
         let mut lock = self.orders.lock().await;
-        lock.insert(id.clone(), order.clone());
-        match lock.get(id.as_str()) {
-            Some(x) => Ok(x.clone()),
-            None => Err(format!("Order with id {} did not exist", id)),
+        let expected_version = stamp_order_update(&mut order);
+        match lock.get(&id) {
+            Some(existing) if existing.version != expected_version => {
+                Err(order_version_conflict(&id))
+            }
+            Some(_) => {
+                lock.insert(id, order.clone());
+                Ok(order)
+            }
+            None => Err(order_not_found(&id)),
         }
     }
 
-    async fn delete(&self, id: &str, _: Arc<Mutex<ClientSession>>) {
+    async fn upsert(
+        &self,
+        id: OrderId,
+        order: Order,
+        _: TransactionSession,
+    ) -> Result<Order, String> {
         let mut lock = self.orders.lock().await;
-        lock.remove_entry(id);
+        lock.insert(id, order.clone());
+        Ok(order)
+    }
+
+    async fn delete(&self, id: &OrderId, _: TransactionSession) -> Result<(), String> {
+        let mut lock = self.orders.lock().await;
+        match lock.remove(id) {
+            Some(_) => Ok(()),
+            None => Err(order_not_found(id)),
+        }
     }
 }
 
 #[async_trait]
 impl CartRepository for InMemoryCartRepository {
-    async fn create(
-        &self,
-        id: String,
-        cart: Cart,
-        _: Arc<Mutex<ClientSession>>,
-    ) -> Result<Cart, String> {
+    async fn create(&self, id: CartId, cart: Cart, _: TransactionSession) -> Result<Cart, String> {
         let mut lock = self.carts.lock().await;
         lock.insert(id.clone(), cart.clone());
-        match lock.get(id.as_str()) {
+        match lock.get(&id) {
             Some(x) => Ok(x.clone()),
-            None => Err(format!("Cart with id {} did not exist", id)),
+            None => Err(cart_not_found(&id)),
         }
     }
 
-    async fn read<'a>(&self, id: &'a str) -> Result<Cart, String> {
+    async fn read<'a>(&self, id: &'a CartId) -> Result<Cart, String> {
         let lock = self.carts.lock().await;
         match lock.get(id) {
             Some(x) => Ok(x.clone()),
-            None => Err(format!("Cart with id {} did not exist", id)),
+            None => Err(cart_not_found(id)),
         }
     }
 
@@ -168,34 +356,115 @@ impl CartRepository for InMemoryCartRepository {
         Ok(orders_to_return)
     }
 
+    async fn read_updated_before<'a>(
+        &self,
+        cutoff_utc: DateTime<Utc>,
+        after_id: Option<&'a CartId>,
+        limit: u32,
+    ) -> Result<Vec<Cart>, String> {
+        let lock = self.carts.lock().await;
+
+        let mut carts: Vec<Cart> = lock
+            .values()
+            .filter(|cart| cart.updated_at_utc < cutoff_utc)
+            .filter(|cart| after_id.is_none_or(|after_id| cart.id.as_str() > after_id.as_str()))
+            .cloned()
+            .collect();
+        carts.sort_by(|a, b| a.id.as_str().cmp(b.id.as_str()));
+        carts.truncate(limit as usize);
+
+        Ok(carts)
+    }
+
+    async fn read_all_by_user_id<'a>(&self, user_id: &'a str) -> Result<Vec<Cart>, String> {
+        let lock = self.carts.lock().await;
+
+        Ok(lock
+            .values()
+            .filter(|cart| cart.user_id == user_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn read_all_containing_product<'a>(
+        &self,
+        product_id: &'a ProductId,
+    ) -> Result<Vec<Cart>, String> {
+        let lock = self.carts.lock().await;
+
+        Ok(lock
+            .values()
+            .filter(|cart| cart.contains_product(product_id))
+            .cloned()
+            .collect())
+    }
+
+    async fn stats(&self) -> Result<CartStats, String> {
+        let lock = self.carts.lock().await;
+
+        let mut stats = CartStats::default();
+        for cart in lock.values() {
+            let items = cart.item_count();
+            stats.carts += 1;
+            stats.active_carts += u64::from(items > 0);
+            stats.items += items;
+        }
+
+        Ok(stats)
+    }
+
     async fn update(
         &self,
-        id: String,
-        cart: Cart,
-        _: Arc<Mutex<ClientSession>>,
+        id: CartId,
+        mut cart: Cart,
+        _: TransactionSession,
     ) -> Result<Cart, String> {
         let mut lock = self.carts.lock().await;
-        lock.insert(id.clone(), cart.clone());
-        match lock.get(id.as_str()) {
-            Some(x) => Ok(x.clone()),
-            None => Err(format!("Cart with id {} did not exist", id)),
+        let expected_version = stamp_cart_update(&mut cart);
+        match lock.get(&id) {
+            Some(existing) if existing.version != expected_version => {
+                Err(cart_version_conflict(&id))
+            }
+            Some(_) => {
+                lock.insert(id, cart.clone());
+                Ok(cart)
+            }
+            None => Err(cart_not_found(&id)),
         }
     }
 
-    async fn delete(&self, id: &str, _: Arc<Mutex<ClientSession>>) {
+    async fn upsert(&self, id: CartId, cart: Cart, _: TransactionSession) -> Result<Cart, String> {
+        let mut lock = self.carts.lock().await;
+        lock.insert(id, cart.clone());
+        Ok(cart)
+    }
+
+    async fn delete(&self, id: &CartId, _: TransactionSession) -> Result<(), String> {
         let mut lock = self.carts.lock().await;
-        lock.remove_entry(id);
+        match lock.remove(id) {
+            Some(_) => Ok(()),
+            None => Err(cart_not_found(id)),
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct MongoDbOrderRepository {
     order_collection: Collection<Order>,
+    order_read_collection: Collection<Order>,
+}
+
+#[derive(Deserialize)]
+struct StatusCount {
+    #[serde(rename = "_id")]
+    status: OrderStatus,
+    count: u64,
 }
 
 #[derive(Clone)]
 pub struct MongoDbCartRepository {
     cart_collection: Collection<Cart>,
+    cart_read_collection: Collection<Cart>,
 }
 
 impl MongoDbOrderRepository {
@@ -204,6 +473,7 @@ impl MongoDbOrderRepository {
 
         MongoDbOrderRepository {
             order_collection: database.collection(&info.collection),
+            order_read_collection: primary_preferred_collection(&database, &info.collection),
         }
     }
 }
@@ -214,35 +484,74 @@ impl MongoDbCartRepository {
 
         MongoDbCartRepository {
             cart_collection: database.collection(&info.collection),
+            cart_read_collection: primary_preferred_collection(&database, &info.collection),
         }
     }
 }
 
+// Reads outside a transaction prefer the primary but fall back to a secondary while there is none,
+// so queries keep working through a failover. Writes, and the reads in their transactions, still
+// need the primary
+fn primary_preferred_collection<T: Send + Sync>(database: &Database, name: &str) -> Collection<T> {
+    database.collection_with_options(
+        name,
+        CollectionOptions::builder()
+            .selection_criteria(SelectionCriteria::ReadPreference(
+                ReadPreference::PrimaryPreferred { options: None },
+            ))
+            .build(),
+    )
+}
+
+// Reads for a client that sent a consistency token wait until the node they go to has caught up
+// with it, so clients see their own writes even when the read lands on a secondary
+async fn causal_session<T: Send + Sync>(
+    collection: &Collection<T>,
+) -> Result<Option<ClientSession>, String> {
+    let token = match consistency::requested() {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+
+    match collection
+        .client()
+        .start_session()
+        .causal_consistency(true)
+        .await
+    {
+        Ok(mut session) => {
+            token.apply(&mut session);
+            Ok(Some(session))
+        }
+        Err(e) => Err(format!("Failed to start MongoDB session: {}", e)),
+    }
+}
+
 #[async_trait]
 impl OrderRepository for MongoDbOrderRepository {
     async fn create(
         &self,
-        id: String,
+        id: OrderId,
         order: Order,
-        session: Arc<Mutex<ClientSession>>,
+        session: TransactionSession,
     ) -> Result<Order, String> {
-        let mut guard = session.lock().await;
+        let mut guard = lock_session(&session).await;
 
         match self
             .order_collection
             .insert_one(order)
-            .session(&mut *guard)
+            .optional(guard.as_deref_mut(), |action, s| action.session(s))
             .await
         {
             Ok(_) => match self
                 .order_collection
                 .find_one(doc! {"id": &id})
-                .session(&mut *guard)
+                .optional(guard.as_deref_mut(), |action, s| action.session(s))
                 .await
             {
                 Ok(find_one_order_option) => match find_one_order_option {
                     Some(p) => Ok(p),
-                    None => Err(format!("Failed to find Order with id {}", id)),
+                    None => Err(order_not_found(&id)),
                 },
                 Err(e) => Err(format!("Failed to insert Order: {}", e)),
             },
@@ -250,11 +559,18 @@ impl OrderRepository for MongoDbOrderRepository {
         }
     }
 
-    async fn read<'a>(&self, id: &'a str) -> Result<Order, String> {
-        match self.order_collection.find_one(doc! {"id": &id}).await {
+    async fn read<'a>(&self, id: &'a OrderId) -> Result<Order, String> {
+        let mut session = causal_session(&self.order_read_collection).await?;
+
+        match self
+            .order_read_collection
+            .find_one(doc! {"id": &id})
+            .optional(session.as_mut(), |action, s| action.session(s))
+            .await
+        {
             Ok(find_one_order_option) => match find_one_order_option {
                 Some(p) => Ok(p),
-                None => Err(format!("Failed to find Order with id {}", id)),
+                None => Err(order_not_found(id)),
             },
             Err(e) => Err(format!("Failed to insert Order: {}", e)),
         }
@@ -263,7 +579,7 @@ impl OrderRepository for MongoDbOrderRepository {
     async fn read_all(&self) -> Result<Vec<Order>, String> {
         let mut orders_to_return = Vec::new();
 
-        match self.order_collection.find(doc! {}).await {
+        match self.order_read_collection.find(doc! {}).await {
             Ok(mut found_orders) => {
                 while let Ok(Some(order)) = found_orders.try_next().await {
                     orders_to_return.push(order.clone())
@@ -271,49 +587,198 @@ impl OrderRepository for MongoDbOrderRepository {
 
                 Ok(orders_to_return)
             }
-            Err(_) => Err(format!("Failed to find Orders")),
+            Err(_) => Err("Failed to find Orders".to_string()),
+        }
+    }
+
+    // Read from the primary, as whatever is found is written back straight away
+    async fn read_updated_before<'a>(
+        &self,
+        cutoff_utc: DateTime<Utc>,
+        after_id: Option<&'a OrderId>,
+        limit: u32,
+    ) -> Result<Vec<Order>, String> {
+        let cutoff = bson::DateTime::from_millis(cutoff_utc.timestamp_millis());
+        let mut filter = doc! {"updated_at_utc": {"$lt": cutoff}};
+        if let Some(after_id) = after_id {
+            filter.insert("id", doc! {"$gt": after_id.as_str()});
+        }
+
+        match self
+            .order_collection
+            .find(filter)
+            .sort(doc! {"id": 1})
+            .limit(i64::from(limit))
+            .await
+        {
+            Ok(mut found_orders) => {
+                let mut orders_to_return = Vec::new();
+                loop {
+                    match found_orders.try_next().await {
+                        Ok(Some(order)) => orders_to_return.push(order),
+                        Ok(None) => return Ok(orders_to_return),
+                        Err(e) => {
+                            return Err(format!(
+                                "Failed to find Orders updated before {}: {}",
+                                cutoff_utc, e
+                            ))
+                        }
+                    }
+                }
+            }
+            Err(e) => Err(format!(
+                "Failed to find Orders updated before {}: {}",
+                cutoff_utc, e
+            )),
+        }
+    }
+
+    // Orders stored before statuses were recorded count as placed
+    async fn count_by_status(&self) -> Result<HashMap<OrderStatus, u64>, String> {
+        let pipeline = vec![
+            doc! {"$group": {"_id": {"$ifNull": ["$status", "placed"]}, "count": {"$sum": 1}}},
+        ];
+
+        let mut status_counts = match self
+            .order_read_collection
+            .aggregate(pipeline)
+            .with_type::<StatusCount>()
+            .await
+        {
+            Ok(status_counts) => status_counts,
+            Err(e) => return Err(format!("Failed to count Orders by status: {}", e)),
+        };
+
+        let mut counts = HashMap::new();
+        loop {
+            match status_counts.try_next().await {
+                Ok(Some(status_count)) => {
+                    counts.insert(status_count.status, status_count.count);
+                }
+                Ok(None) => return Ok(counts),
+                Err(e) => return Err(format!("Failed to count Orders by status: {}", e)),
+            }
         }
     }
 
     async fn update(
         &self,
-        id: String,
+        id: OrderId,
+        mut order: Order,
+        session: TransactionSession,
+    ) -> Result<Order, String> {
+        let mut guard = lock_session(&session).await;
+
+        let expected_version = stamp_order_update(&mut order);
+
+        match self
+            .order_collection
+            .replace_one(doc! {"id": &id, "version": expected_version}, order)
+            .optional(guard.as_deref_mut(), |action, s| action.session(s))
+            .await
+        {
+            Ok(result) => match self
+                .order_collection
+                .find_one(doc! {"id": &id})
+                .optional(guard.as_deref_mut(), |action, s| action.session(s))
+                .await
+            {
+                Ok(find_one_order_option) => match find_one_order_option {
+                    Some(_) if result.matched_count == 0 => Err(order_version_conflict(&id)),
+                    Some(p) => Ok(p),
+                    None => Err(order_not_found(&id)),
+                },
+                Err(e) => Err(format!("Failed to update Order: {}", e)),
+            },
+            Err(e) => Err(format!("Failed to update Order: {}", e)),
+        }
+    }
+
+    async fn upsert(
+        &self,
+        id: OrderId,
         order: Order,
-        session: Arc<Mutex<ClientSession>>,
+        session: TransactionSession,
     ) -> Result<Order, String> {
-        todo!()
+        let mut guard = lock_session(&session).await;
+
+        match self
+            .order_collection
+            .replace_one(doc! {"id": &id}, order)
+            .upsert(true)
+            .optional(guard.as_deref_mut(), |action, s| action.session(s))
+            .await
+        {
+            Ok(_) => match self
+                .order_collection
+                .find_one(doc! {"id": &id})
+                .optional(guard.as_deref_mut(), |action, s| action.session(s))
+                .await
+            {
+                Ok(find_one_order_option) => match find_one_order_option {
+                    Some(p) => Ok(p),
+                    None => Err(order_not_found(&id)),
+                },
+                Err(e) => Err(format!("Failed to upsert Order: {}", e)),
+            },
+            Err(e) => Err(format!("Failed to upsert Order: {}", e)),
+        }
     }
 
-    async fn delete(&self, id: &str, session: Arc<Mutex<ClientSession>>) {
-        todo!()
+    async fn delete(&self, id: &OrderId, session: TransactionSession) -> Result<(), String> {
+        let mut guard = lock_session(&session).await;
+
+        match self
+            .order_collection
+            .delete_one(doc! {"id": id})
+            .optional(guard.as_deref_mut(), |action, s| action.session(s))
+            .await
+        {
+            Ok(result) if result.deleted_count == 0 => Err(order_not_found(id)),
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Failed to delete Order: {}", e)),
+        }
     }
 }
 
 #[async_trait]
 impl CartRepository for MongoDbCartRepository {
+    async fn ping(&self) -> Result<(), String> {
+        match self
+            .cart_collection
+            .client()
+            .database("admin")
+            .run_command(doc! {"ping": 1})
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Failed to ping MongoDB: {}", e)),
+        }
+    }
+
     async fn create(
         &self,
-        id: String,
+        id: CartId,
         cart: Cart,
-        session: Arc<Mutex<ClientSession>>,
+        session: TransactionSession,
     ) -> Result<Cart, String> {
-        let mut guard = session.lock().await;
+        let mut guard = lock_session(&session).await;
 
         match self
             .cart_collection
             .insert_one(cart)
-            .session(&mut *guard)
+            .optional(guard.as_deref_mut(), |action, s| action.session(s))
             .await
         {
             Ok(_) => match self
                 .cart_collection
                 .find_one(doc! {"id": &id})
-                .session(&mut *guard)
+                .optional(guard.as_deref_mut(), |action, s| action.session(s))
                 .await
             {
                 Ok(find_one_cart_option) => match find_one_cart_option {
                     Some(p) => Ok(p),
-                    None => Err(format!("Failed to find Cart with id {}", id)),
+                    None => Err(cart_not_found(&id)),
                 },
                 Err(e) => Err(format!("Failed to insert Cart: {}", e)),
             },
@@ -321,11 +786,18 @@ impl CartRepository for MongoDbCartRepository {
         }
     }
 
-    async fn read<'a>(&self, id: &'a str) -> Result<Cart, String> {
-        match self.cart_collection.find_one(doc! {"id": &id}).await {
+    async fn read<'a>(&self, id: &'a CartId) -> Result<Cart, String> {
+        let mut session = causal_session(&self.cart_read_collection).await?;
+
+        match self
+            .cart_read_collection
+            .find_one(doc! {"id": &id})
+            .optional(session.as_mut(), |action, s| action.session(s))
+            .await
+        {
             Ok(find_one_cart_option) => match find_one_cart_option {
                 Some(p) => Ok(p),
-                None => Err(format!("Failed to find Cart with id {}", id)),
+                None => Err(cart_not_found(id)),
             },
             Err(e) => Err(format!("Failed to insert Cart: {}", e)),
         }
@@ -334,7 +806,7 @@ impl CartRepository for MongoDbCartRepository {
     async fn read_all(&self) -> Result<Vec<Cart>, String> {
         let mut carts_to_return = Vec::new();
 
-        match self.cart_collection.find(doc! {}).await {
+        match self.cart_read_collection.find(doc! {}).await {
             Ok(mut found_carts) => {
                 while let Ok(Some(cart)) = found_carts.try_next().await {
                     carts_to_return.push(cart.clone())
@@ -342,41 +814,573 @@ impl CartRepository for MongoDbCartRepository {
 
                 Ok(carts_to_return)
             }
-            Err(_) => Err(format!("Failed to find Carts")),
+            Err(_) => Err("Failed to find Carts".to_string()),
+        }
+    }
+
+    // Read from the primary, as whatever is found is written back straight away
+    async fn read_updated_before<'a>(
+        &self,
+        cutoff_utc: DateTime<Utc>,
+        after_id: Option<&'a CartId>,
+        limit: u32,
+    ) -> Result<Vec<Cart>, String> {
+        let cutoff = bson::DateTime::from_millis(cutoff_utc.timestamp_millis());
+        let mut filter = doc! {"updated_at_utc": {"$lt": cutoff}};
+        if let Some(after_id) = after_id {
+            filter.insert("id", doc! {"$gt": after_id.as_str()});
+        }
+
+        match self
+            .cart_collection
+            .find(filter)
+            .sort(doc! {"id": 1})
+            .limit(i64::from(limit))
+            .await
+        {
+            Ok(mut found_carts) => {
+                let mut carts_to_return = Vec::new();
+                loop {
+                    match found_carts.try_next().await {
+                        Ok(Some(cart)) => carts_to_return.push(cart),
+                        Ok(None) => return Ok(carts_to_return),
+                        Err(e) => {
+                            return Err(format!(
+                                "Failed to find Carts updated before {}: {}",
+                                cutoff_utc, e
+                            ))
+                        }
+                    }
+                }
+            }
+            Err(e) => Err(format!(
+                "Failed to find Carts updated before {}: {}",
+                cutoff_utc, e
+            )),
+        }
+    }
+
+    async fn read_all_by_user_id<'a>(&self, user_id: &'a str) -> Result<Vec<Cart>, String> {
+        let mut carts_to_return = Vec::new();
+
+        match self
+            .cart_read_collection
+            .find(doc! {"user_id": user_id})
+            .await
+        {
+            Ok(mut found_carts) => {
+                while let Ok(Some(cart)) = found_carts.try_next().await {
+                    carts_to_return.push(cart)
+                }
+
+                Ok(carts_to_return)
+            }
+            Err(e) => Err(format!("Failed to find Carts for user {}: {}", user_id, e)),
+        }
+    }
+
+    async fn read_all_containing_product<'a>(
+        &self,
+        product_id: &'a ProductId,
+    ) -> Result<Vec<Cart>, String> {
+        let mut carts_to_return = Vec::new();
+
+        match self
+            .cart_read_collection
+            .find(doc! {"products.product_id": product_id.as_str()})
+            .await
+        {
+            Ok(mut found_carts) => loop {
+                match found_carts.try_next().await {
+                    Ok(Some(cart)) => carts_to_return.push(cart),
+                    Ok(None) => return Ok(carts_to_return),
+                    Err(e) => {
+                        return Err(format!(
+                            "Failed to find Carts containing Product {}: {}",
+                            product_id, e
+                        ))
+                    }
+                }
+            },
+            Err(e) => Err(format!(
+                "Failed to find Carts containing Product {}: {}",
+                product_id, e
+            )),
+        }
+    }
+
+    async fn stats(&self) -> Result<CartStats, String> {
+        let pipeline = vec![
+            doc! {"$project": {"items": {"$sum": {"$ifNull": ["$products.quantity", []]}}}},
+            doc! {"$group": {
+                "_id": null,
+                "carts": {"$sum": 1},
+                "active_carts": {"$sum": {"$cond": [{"$gt": ["$items", 0]}, 1, 0]}},
+                "items": {"$sum": "$items"},
+            }},
+        ];
+
+        let mut stats = match self
+            .cart_read_collection
+            .aggregate(pipeline)
+            .with_type::<CartStats>()
+            .await
+        {
+            Ok(stats) => stats,
+            Err(e) => return Err(format!("Failed to aggregate Cart stats: {}", e)),
+        };
+
+        // An empty collection yields no group at all
+        match stats.try_next().await {
+            Ok(stats) => Ok(stats.unwrap_or_default()),
+            Err(e) => Err(format!("Failed to aggregate Cart stats: {}", e)),
         }
     }
 
     async fn update(
         &self,
-        id: String,
+        id: CartId,
+        mut cart: Cart,
+        session: TransactionSession,
+    ) -> Result<Cart, String> {
+        let mut guard = lock_session(&session).await;
+
+        let expected_version = stamp_cart_update(&mut cart);
+
+        match self
+            .cart_collection
+            .replace_one(doc! {"id": &id, "version": expected_version}, cart)
+            .optional(guard.as_deref_mut(), |action, s| action.session(s))
+            .await
+        {
+            Ok(result) => match self
+                .cart_collection
+                .find_one(doc! {"id": &id})
+                .optional(guard.as_deref_mut(), |action, s| action.session(s))
+                .await
+            {
+                Ok(find_one_cart_option) => match find_one_cart_option {
+                    Some(_) if result.matched_count == 0 => Err(cart_version_conflict(&id)),
+                    Some(p) => Ok(p),
+                    None => Err(cart_not_found(&id)),
+                },
+                Err(e) => Err(format!("Failed to update Cart: {}", e)),
+            },
+            Err(e) => Err(format!("Failed to update Cart: {}", e)),
+        }
+    }
+
+    async fn upsert(
+        &self,
+        id: CartId,
         cart: Cart,
-        session: Arc<Mutex<ClientSession>>,
+        session: TransactionSession,
     ) -> Result<Cart, String> {
-        let mut guard = session.lock().await;
+        let mut guard = lock_session(&session).await;
 
         match self
             .cart_collection
             .replace_one(doc! {"id": &id}, cart)
-            .session(&mut *guard)
+            .upsert(true)
+            .optional(guard.as_deref_mut(), |action, s| action.session(s))
             .await
         {
             Ok(_) => match self
                 .cart_collection
                 .find_one(doc! {"id": &id})
-                .session(&mut *guard)
+                .optional(guard.as_deref_mut(), |action, s| action.session(s))
                 .await
             {
                 Ok(find_one_cart_option) => match find_one_cart_option {
                     Some(p) => Ok(p),
-                    None => Err(format!("Failed to find Cart with id {}", id)),
+                    None => Err(cart_not_found(&id)),
                 },
-                Err(e) => Err(format!("Failed to update Cart: {}", e)),
+                Err(e) => Err(format!("Failed to upsert Cart: {}", e)),
+            },
+            Err(e) => Err(format!("Failed to upsert Cart: {}", e)),
+        }
+    }
+
+    async fn delete(&self, id: &CartId, session: TransactionSession) -> Result<(), String> {
+        let mut guard = lock_session(&session).await;
+
+        match self
+            .cart_collection
+            .delete_one(doc! {"id": id})
+            .optional(guard.as_deref_mut(), |action, s| action.session(s))
+            .await
+        {
+            Ok(result) if result.deleted_count == 0 => Err(cart_not_found(id)),
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Failed to delete Cart: {}", e)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SqliteOrderRepository {
+    pool: SqlitePool,
+}
+
+#[derive(Clone)]
+pub struct SqliteCartRepository {
+    pool: SqlitePool,
+}
+
+// Entities are stored as JSON documents so the tables mirror the Mongo collections
+fn to_document<T: Serialize>(entity: &T) -> Result<String, String> {
+    match serde_json::to_string(entity) {
+        Ok(document) => Ok(document),
+        Err(e) => Err(format!("Failed to serialize document: {}", e)),
+    }
+}
+
+fn from_document<T: DeserializeOwned>(row: &SqliteRow) -> Result<T, String> {
+    match serde_json::from_str(row.get::<&str, _>("document")) {
+        Ok(entity) => Ok(entity),
+        Err(e) => Err(format!("Failed to deserialize document: {}", e)),
+    }
+}
+
+impl SqliteOrderRepository {
+    pub async fn new(pool: &SqlitePool) -> Result<Self, String> {
+        match sqlx::query(
+            "CREATE TABLE IF NOT EXISTS orders (id TEXT PRIMARY KEY NOT NULL, document TEXT NOT NULL)",
+        )
+        .execute(pool)
+        .await
+        {
+            Ok(_) => Ok(SqliteOrderRepository { pool: pool.clone() }),
+            Err(e) => Err(format!("Failed to create orders table: {}", e)),
+        }
+    }
+}
+
+impl SqliteCartRepository {
+    pub async fn new(pool: &SqlitePool) -> Result<Self, String> {
+        let statements = [
+            "CREATE TABLE IF NOT EXISTS carts (id TEXT PRIMARY KEY NOT NULL, user_id TEXT NOT NULL, document TEXT NOT NULL)",
+            "CREATE INDEX IF NOT EXISTS carts_user_id ON carts (user_id)",
+        ];
+
+        for statement in statements {
+            if let Err(e) = sqlx::query(statement).execute(pool).await {
+                return Err(format!("Failed to create carts table: {}", e));
+            }
+        }
+
+        Ok(SqliteCartRepository { pool: pool.clone() })
+    }
+}
+
+#[async_trait]
+impl OrderRepository for SqliteOrderRepository {
+    async fn create(
+        &self,
+        id: OrderId,
+        order: Order,
+        _: TransactionSession,
+    ) -> Result<Order, String> {
+        match sqlx::query("INSERT INTO orders (id, document) VALUES (?, ?)")
+            .bind(id.as_str())
+            .bind(to_document(&order)?)
+            .execute(&self.pool)
+            .await
+        {
+            Ok(_) => self.read(&id).await,
+            Err(e) => Err(format!("Failed to insert Order: {}", e)),
+        }
+    }
+
+    async fn read<'a>(&self, id: &'a OrderId) -> Result<Order, String> {
+        match sqlx::query("SELECT document FROM orders WHERE id = ?")
+            .bind(id.as_str())
+            .fetch_optional(&self.pool)
+            .await
+        {
+            Ok(Some(row)) => from_document(&row),
+            Ok(None) => Err(order_not_found(id)),
+            Err(e) => Err(format!("Failed to find Order: {}", e)),
+        }
+    }
+
+    async fn read_all(&self) -> Result<Vec<Order>, String> {
+        match sqlx::query("SELECT document FROM orders")
+            .fetch_all(&self.pool)
+            .await
+        {
+            Ok(rows) => rows.iter().map(from_document).collect(),
+            Err(_) => Err("Failed to find Orders".to_string()),
+        }
+    }
+
+    // Dates are kept in the documents as extended JSON, with the epoch milliseconds as a string
+    async fn read_updated_before<'a>(
+        &self,
+        cutoff_utc: DateTime<Utc>,
+        after_id: Option<&'a OrderId>,
+        limit: u32,
+    ) -> Result<Vec<Order>, String> {
+        match sqlx::query(
+            "SELECT document FROM orders WHERE CAST(json_extract(document, '$.updated_at_utc.\"$date\".\"$numberLong\"') AS INTEGER) < ? AND id > ? ORDER BY id LIMIT ?",
+        )
+        .bind(cutoff_utc.timestamp_millis())
+        .bind(after_id.map(|id| id.as_str()).unwrap_or_default())
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        {
+            Ok(rows) => rows.iter().map(from_document).collect(),
+            Err(e) => Err(format!("Failed to find Orders updated before {}: {}", cutoff_utc, e)),
+        }
+    }
+
+    async fn count_by_status(&self) -> Result<HashMap<OrderStatus, u64>, String> {
+        let rows = match sqlx::query(
+            "SELECT COALESCE(json_extract(document, '$.status'), 'placed') AS status, COUNT(*) AS count FROM orders GROUP BY 1",
+        )
+        .fetch_all(&self.pool)
+        .await
+        {
+            Ok(rows) => rows,
+            Err(e) => return Err(format!("Failed to count Orders by status: {}", e)),
+        };
+
+        let mut counts = HashMap::new();
+        for row in rows {
+            let status = match serde_json::from_value(row.get::<String, _>("status").into()) {
+                Ok(status) => status,
+                Err(e) => return Err(format!("Failed to count Orders by status: {}", e)),
+            };
+            counts.insert(status, row.get::<i64, _>("count") as u64);
+        }
+
+        Ok(counts)
+    }
+
+    async fn update(
+        &self,
+        id: OrderId,
+        mut order: Order,
+        _: TransactionSession,
+    ) -> Result<Order, String> {
+        let expected_version = stamp_order_update(&mut order);
+
+        match sqlx::query(
+            "UPDATE orders SET document = ? WHERE id = ? AND json_extract(document, '$.version') = ?",
+        )
+        .bind(to_document(&order)?)
+        .bind(id.as_str())
+        .bind(expected_version)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(result) if result.rows_affected() == 0 => match self.read(&id).await {
+                Ok(_) => Err(order_version_conflict(&id)),
+                Err(e) => Err(e),
             },
+            Ok(_) => self.read(&id).await,
+            Err(e) => Err(format!("Failed to update Order: {}", e)),
+        }
+    }
+
+    async fn upsert(
+        &self,
+        id: OrderId,
+        order: Order,
+        _: TransactionSession,
+    ) -> Result<Order, String> {
+        match sqlx::query(
+            "INSERT INTO orders (id, document) VALUES (?, ?) ON CONFLICT (id) DO UPDATE SET document = excluded.document",
+        )
+        .bind(id.as_str())
+        .bind(to_document(&order)?)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(_) => self.read(&id).await,
+            Err(e) => Err(format!("Failed to upsert Order: {}", e)),
+        }
+    }
+
+    async fn delete(&self, id: &OrderId, _: TransactionSession) -> Result<(), String> {
+        match sqlx::query("DELETE FROM orders WHERE id = ?")
+            .bind(id.as_str())
+            .execute(&self.pool)
+            .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(order_not_found(id)),
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Failed to delete Order: {}", e)),
+        }
+    }
+}
+
+#[async_trait]
+impl CartRepository for SqliteCartRepository {
+    async fn ping(&self) -> Result<(), String> {
+        match sqlx::query("SELECT 1").execute(&self.pool).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Failed to ping SQLite: {}", e)),
+        }
+    }
+
+    async fn create(&self, id: CartId, cart: Cart, _: TransactionSession) -> Result<Cart, String> {
+        match sqlx::query("INSERT INTO carts (id, user_id, document) VALUES (?, ?, ?)")
+            .bind(id.as_str())
+            .bind(&cart.user_id)
+            .bind(to_document(&cart)?)
+            .execute(&self.pool)
+            .await
+        {
+            Ok(_) => self.read(&id).await,
+            Err(e) => Err(format!("Failed to insert Cart: {}", e)),
+        }
+    }
+
+    async fn read<'a>(&self, id: &'a CartId) -> Result<Cart, String> {
+        match sqlx::query("SELECT document FROM carts WHERE id = ?")
+            .bind(id.as_str())
+            .fetch_optional(&self.pool)
+            .await
+        {
+            Ok(Some(row)) => from_document(&row),
+            Ok(None) => Err(cart_not_found(id)),
+            Err(e) => Err(format!("Failed to find Cart: {}", e)),
+        }
+    }
+
+    async fn read_all(&self) -> Result<Vec<Cart>, String> {
+        match sqlx::query("SELECT document FROM carts")
+            .fetch_all(&self.pool)
+            .await
+        {
+            Ok(rows) => rows.iter().map(from_document).collect(),
+            Err(_) => Err("Failed to find Carts".to_string()),
+        }
+    }
+
+    // Dates are kept in the documents as extended JSON, with the epoch milliseconds as a string
+    async fn read_updated_before<'a>(
+        &self,
+        cutoff_utc: DateTime<Utc>,
+        after_id: Option<&'a CartId>,
+        limit: u32,
+    ) -> Result<Vec<Cart>, String> {
+        match sqlx::query(
+            "SELECT document FROM carts WHERE CAST(json_extract(document, '$.updated_at_utc.\"$date\".\"$numberLong\"') AS INTEGER) < ? AND id > ? ORDER BY id LIMIT ?",
+        )
+        .bind(cutoff_utc.timestamp_millis())
+        .bind(after_id.map(|id| id.as_str()).unwrap_or_default())
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        {
+            Ok(rows) => rows.iter().map(from_document).collect(),
+            Err(e) => Err(format!("Failed to find Carts updated before {}: {}", cutoff_utc, e)),
+        }
+    }
+
+    async fn read_all_by_user_id<'a>(&self, user_id: &'a str) -> Result<Vec<Cart>, String> {
+        match sqlx::query("SELECT document FROM carts WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await
+        {
+            Ok(rows) => rows.iter().map(from_document).collect(),
+            Err(e) => Err(format!("Failed to find Carts for user {}: {}", user_id, e)),
+        }
+    }
+
+    async fn read_all_containing_product<'a>(
+        &self,
+        product_id: &'a ProductId,
+    ) -> Result<Vec<Cart>, String> {
+        // Carts written before line items still hold a map of quantities by product id until they
+        // are next written
+        match sqlx::query(
+            "SELECT document FROM carts WHERE EXISTS (SELECT 1 FROM json_each(document, '$.products') WHERE json_extract(value, '$.product_id') = ? OR key = ?)",
+        )
+        .bind(product_id.as_str())
+        .bind(product_id.as_str())
+        .fetch_all(&self.pool)
+        .await
+        {
+            Ok(rows) => rows.iter().map(from_document).collect(),
+            Err(e) => Err(format!(
+                "Failed to find Carts containing Product {}: {}",
+                product_id, e
+            )),
+        }
+    }
+
+    async fn stats(&self) -> Result<CartStats, String> {
+        match sqlx::query(
+            "SELECT COUNT(*) AS carts, COALESCE(SUM(items > 0), 0) AS active_carts, COALESCE(SUM(items), 0) AS items FROM (SELECT (SELECT COALESCE(SUM(COALESCE(json_extract(value, '$.quantity'), value)), 0) FROM json_each(document, '$.products')) AS items FROM carts)",
+        )
+        .fetch_one(&self.pool)
+        .await
+        {
+            Ok(row) => Ok(CartStats {
+                carts: row.get::<i64, _>("carts") as u64,
+                active_carts: row.get::<i64, _>("active_carts") as u64,
+                items: row.get::<i64, _>("items") as u64,
+            }),
+            Err(e) => Err(format!("Failed to aggregate Cart stats: {}", e)),
+        }
+    }
+
+    async fn update(
+        &self,
+        id: CartId,
+        mut cart: Cart,
+        _: TransactionSession,
+    ) -> Result<Cart, String> {
+        let expected_version = stamp_cart_update(&mut cart);
+
+        match sqlx::query(
+            "UPDATE carts SET user_id = ?, document = ? WHERE id = ? AND json_extract(document, '$.version') = ?",
+        )
+        .bind(&cart.user_id)
+        .bind(to_document(&cart)?)
+        .bind(id.as_str())
+        .bind(expected_version)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(result) if result.rows_affected() == 0 => match self.read(&id).await {
+                Ok(_) => Err(cart_version_conflict(&id)),
+                Err(e) => Err(e),
+            },
+            Ok(_) => self.read(&id).await,
             Err(e) => Err(format!("Failed to update Cart: {}", e)),
         }
     }
 
-    async fn delete(&self, id: &str, session: Arc<Mutex<ClientSession>>) {
-        todo!()
+    async fn upsert(&self, id: CartId, cart: Cart, _: TransactionSession) -> Result<Cart, String> {
+        match sqlx::query(
+            "INSERT INTO carts (id, user_id, document) VALUES (?, ?, ?) ON CONFLICT (id) DO UPDATE SET user_id = excluded.user_id, document = excluded.document",
+        )
+        .bind(id.as_str())
+        .bind(&cart.user_id)
+        .bind(to_document(&cart)?)
+        .execute(&self.pool)
+        .await
+        {
+            Ok(_) => self.read(&id).await,
+            Err(e) => Err(format!("Failed to upsert Cart: {}", e)),
+        }
+    }
+
+    async fn delete(&self, id: &CartId, _: TransactionSession) -> Result<(), String> {
+        match sqlx::query("DELETE FROM carts WHERE id = ?")
+            .bind(id.as_str())
+            .execute(&self.pool)
+            .await
+        {
+            Ok(result) if result.rows_affected() == 0 => Err(cart_not_found(id)),
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Failed to delete Cart: {}", e)),
+        }
     }
 }
@@ -0,0 +1,121 @@
+use std::{env, sync::Arc};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cqrs::ApplyRetentionRulesCommand, domain::OrderStatus, mediator::Mediator, scheduler::Job,
+};
+
+pub static RETENTION_DOCUMENTS_TOTAL: &str = "order_service_retention_documents_total";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionEntity {
+    Carts,
+    Orders,
+}
+
+impl RetentionEntity {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RetentionEntity::Carts => "carts",
+            RetentionEntity::Orders => "orders",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionAction {
+    Delete,
+    // Keeps the document for reporting but strips everything that identifies the customer
+    Anonymize,
+}
+
+impl RetentionAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RetentionAction::Delete => "delete",
+            RetentionAction::Anonymize => "anonymize",
+        }
+    }
+}
+
+// e.g. {"entity": "carts", "action": "delete", "max_age_days": 90} or
+// {"entity": "orders", "action": "anonymize", "max_age_days": 2555, "statuses": ["delivered"]}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetentionRule {
+    pub entity: RetentionEntity,
+    pub action: RetentionAction,
+    // Measured from the last time the document was updated
+    pub max_age_days: u32,
+    // Only applies to orders; the rule covers orders in any status when empty
+    #[serde(default)]
+    pub statuses: Vec<OrderStatus>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetentionSettings {
+    pub rules: Vec<RetentionRule>,
+    // Cron expression with seconds
+    pub schedule: String,
+    // Only reports what the rules match, without changing anything
+    pub dry_run: bool,
+}
+
+impl Default for RetentionSettings {
+    fn default() -> Self {
+        RetentionSettings {
+            rules: Vec::new(),
+            schedule: String::from("0 0 3 * * *"),
+            dry_run: false,
+        }
+    }
+}
+
+impl RetentionSettings {
+    // RETENTION_RULES is a JSON array of rules. Nothing is purged unless it is set
+    pub fn from_env() -> Result<RetentionSettings, String> {
+        let defaults = RetentionSettings::default();
+
+        Ok(RetentionSettings {
+            rules: match env::var("RETENTION_RULES") {
+                Ok(rules) => match serde_json::from_str(&rules) {
+                    Ok(rules) => rules,
+                    Err(e) => return Err(format!("Invalid RETENTION_RULES: {}", e)),
+                },
+                Err(_) => defaults.rules,
+            },
+            schedule: env::var("RETENTION_SCHEDULE").unwrap_or(defaults.schedule),
+            dry_run: env::var("RETENTION_DRY_RUN")
+                .map(|dry_run| dry_run == "true")
+                .unwrap_or(defaults.dry_run),
+        })
+    }
+}
+
+// Applies the retention rules the mediator was built with on every scheduled run
+pub struct RetentionJob {
+    mediator: Arc<Mediator>,
+    dry_run: bool,
+}
+
+impl RetentionJob {
+    pub fn new(mediator: Arc<Mediator>, dry_run: bool) -> RetentionJob {
+        RetentionJob { mediator, dry_run }
+    }
+}
+
+#[async_trait]
+impl Job for RetentionJob {
+    async fn run(&self) -> Result<(), String> {
+        self.mediator
+            .send(&ApplyRetentionRulesCommand {
+                dry_run: self.dry_run,
+            })
+            .await
+            .map(|_| ())
+            .map_err(String::from)
+    }
+}
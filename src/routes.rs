@@ -1,42 +1,522 @@
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
 
-use axum::{extract::{Path, State}, http::StatusCode, Json};
-use serde_json::{json, Value};
+use axum::{extract::{Path, Query, State}, http::{Extensions, HeaderMap, Method, StatusCode}, response::{IntoResponse, Response}, Extension};
+use chrono::DateTime;
+use futures_util::future::join_all;
+use tracing::{event, Level};
 
-use crate::{cqrs::{AddProductToCartCommand, CommandHandler, CreateCartCommand, GetCartsQuery, QueryHandler, RemoveProductFromCartCommand}, dtos::ApiError, state::AppState};
+use crate::{api_response::ApiResponse, circuit_breaker::CircuitState, consumers::InboundEvent, auth::{CartSession, Claims, CART_SESSION_HEADER}, cqrs::{AddProductToCartCommand, ApplyRetentionRulesCommand, CancelOrderCommand, ClaimGuestCartCommand, CloneSharedCartCommand, CreateCartCommand, DeleteCartCommand, EraseCustomerDataCommand, ExportCustomerDataQuery, GetCartsContainingProductQuery, GetCartsQuery, GetDeliverySlotsQuery, GetOrderByIdQuery, GetOrdersQuery, GetOrderTimelineQuery, GetPickupCodeQuery, GetSharedCartQuery, GetStatsQuery, GetUserCartsQuery, ImportCartsCommand, RemoveProductFromCartCommand, SetDefaultCartCommand, SetProductQuantityCommand, ShareCartCommand, VerifyPickupCodeQuery}, domain::{CartId, OrderId, ProductId}, dtos::{DependencyStatus, EventCatalogEntry, EventCatalogResponse, GetCartsResponse, GetOrdersResponse, InfoResponse, Link, LogSamplingResponse, MaintenanceModeResponse, ReadinessQuery, ReadinessResponse, SetLogSamplingRequest, SetMaintenanceModeRequest}, errors::HandlerError, events::Event, extractors::StrictJson, http_client::REQUEST_ID_HEADER, i18n, log_sampling::LogSamplingSettings, rate_limiting::RateLimitPolicy, redaction, repositories::is_version_conflict, state::AppState, throttling::{throttled_response, ThrottleReason}};
+
+// Paths the router is built from, shared with the links handed out in responses
+pub static CART_PATH: &str = "/carts/{id}";
+pub static ADD_PRODUCT_TO_CART_PATH: &str = "/carts/addProductToCart";
+pub static REMOVE_PRODUCT_FROM_CART_PATH: &str = "/carts/removeProductFromCart";
+pub static CART_ITEM_PATH: &str = "/carts/{id}/items/{product_id}";
+pub static SHARE_CART_PATH: &str = "/carts/{id}/share";
+pub static DELIVERY_SLOTS_PATH: &str = "/carts/{id}/deliverySlots";
+pub static ORDER_PATH: &str = "/orders/{id}";
+pub static ORDER_TIMELINE_PATH: &str = "/orders/{id}/timeline";
+pub static CANCEL_ORDER_PATH: &str = "/orders/{id}/cancel";
+pub static PICKUP_CODE_PATH: &str = "/orders/{id}/pickup-code";
+pub static VERIFY_PICKUP_PATH: &str = "/orders/{id}/pickup/verify";
+
+fn link(method: Method, path: &str, id: &str) -> Link {
+    Link{href: path.replace("{id}", id), method: String::from(method.as_str())}
+}
+
+// Only signed in customers can share carts, so guests aren't offered the link. There is no checkout
+// endpoint yet to link to
+fn cart_links(id: &CartId, signed_in: bool) -> BTreeMap<String, Link> {
+    let mut links = BTreeMap::from([
+        (String::from("self"), link(Method::GET, CART_PATH, id.as_str())),
+        (String::from("delete"), link(Method::DELETE, CART_PATH, id.as_str())),
+        (String::from("add-item"), link(Method::PUT, ADD_PRODUCT_TO_CART_PATH, id.as_str())),
+        (String::from("remove-item"), link(Method::PUT, REMOVE_PRODUCT_FROM_CART_PATH, id.as_str())),
+        // Still holds the {product_id} placeholder for the client to fill in
+        (String::from("set-item-quantity"), link(Method::PUT, CART_ITEM_PATH, id.as_str()))
+    ]);
+    if signed_in {
+        links.insert(String::from("share"), link(Method::POST, SHARE_CART_PATH, id.as_str()));
+    }
+
+    links
+}
+
+fn add_cart_links(response: &mut GetCartsResponse, signed_in: bool) {
+    for cart in &mut response.carts {
+        cart.links = cart_links(&cart.id, signed_in);
+    }
+}
+
+fn add_order_links(response: &mut GetOrdersResponse) {
+    for order in &mut response.orders {
+        order.links = order_links(&order.id);
+    }
+}
+
+fn order_links(id: &OrderId) -> BTreeMap<String, Link> {
+    BTreeMap::from([
+        (String::from("self"), link(Method::GET, ORDER_PATH, id.as_str())),
+        (String::from("timeline"), link(Method::GET, ORDER_TIMELINE_PATH, id.as_str()))
+    ])
+}
 
 pub async fn index() -> &'static str {
     "Hello, World!"
 }
 
-pub async fn get_cart_by_id(Path(id): Path<String>, State(state): State<Arc<AppState>>) -> (StatusCode, Json<Value>){
+pub async fn get_event_catalog() -> ApiResponse<EventCatalogResponse> {
+    let published = Event::all().iter().map(|event| EventCatalogEntry {
+        event_type: String::from(event.event_type()),
+        family: String::from(event.family()),
+        destination: String::from(event.destination_name()),
+        schema_version: event.schema_version(),
+        schema: event.json_schema()
+    }).collect();
+
+    let consumed = InboundEvent::all().iter().map(|event| EventCatalogEntry {
+        event_type: String::from(event.event_type()),
+        family: String::from(event.family()),
+        destination: String::from(event.source_name()),
+        schema_version: event.schema_version(),
+        schema: event.json_schema()
+    }).collect();
+
+    ApiResponse::new(StatusCode::OK, EventCatalogResponse{published, consumed})
+}
+
+pub async fn info() -> ApiResponse<InfoResponse> {
+    let build_time = env!("BUILD_TIMESTAMP").parse().ok().and_then(|timestamp| DateTime::from_timestamp(timestamp, 0)).map(|build_time| build_time.to_rfc3339()).unwrap_or_default();
+
+    let mut features = Vec::new();
+    if cfg!(feature = "nats") {
+        features.push(String::from("nats"));
+    }
+    if cfg!(feature = "sns") {
+        features.push(String::from("sns"));
+    }
+    if cfg!(feature = "grpc") {
+        features.push(String::from("grpc"));
+    }
+    if cfg!(feature = "kms") {
+        features.push(String::from("kms"));
+    }
+    if cfg!(feature = "csfle") {
+        features.push(String::from("csfle"));
+    }
+
+    ApiResponse::new(StatusCode::OK, InfoResponse{
+        name: String::from(env!("CARGO_PKG_NAME")),
+        version: String::from(env!("CARGO_PKG_VERSION")),
+        git_sha: String::from(env!("GIT_SHA")),
+        build_time,
+        features
+    })
+}
+
+// Throttled requests get a problem+json body and Retry-After so clients know when to try again.
+// Errors with a code are sent with it, in the caller's language, and the status their kind calls for
+fn error_response(error: HandlerError) -> Response {
+    match &error {
+        HandlerError::Coded { kind, code, .. } => ApiResponse::error(kind.status(), code, redaction::redact(&error.message(&i18n::current_locale()))).into_response(),
+        HandlerError::Internal(error) => match ThrottleReason::from_error(error) {
+            Some((reason, detail)) => throttled_response(reason, detail, reason.default_retry_after_seconds()),
+            None if is_version_conflict(error) => ApiResponse::error(StatusCode::CONFLICT, "version_conflict", error.clone()).into_response(),
+            None => ApiResponse::error(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", redaction::redact(error)).into_response()
+        }
+    }
+}
+
+// ?verbose=true also probes every dependency, so incidents can be triaged from a single request
+pub async fn readyz(Query(params): Query<ReadinessQuery>, State(state): State<Arc<AppState>>) -> ApiResponse<ReadinessResponse> {
+    let health_checks = match params.verbose {
+        true => join_all(state.dependencies.iter().map(|dependency| dependency.check())).await.into_iter().map(Some).collect(),
+        false => vec![None; state.dependencies.len()]
+    };
+
+    let dependencies: Vec<DependencyStatus> = state.dependencies.iter().zip(health_checks).map(|(dependency, health_check)| DependencyStatus {
+        name: String::from(dependency.name()),
+        kind: String::from(dependency.kind()),
+        circuit_state: dependency.circuit_state(),
+        status: health_check.as_ref().map(|x| String::from(if x.healthy { "up" } else { "down" })),
+        latency_ms: health_check.as_ref().map(|x| x.latency.as_secs_f64() * 1000.0),
+        error: health_check.and_then(|x| x.error)
+    }).collect();
+
+    // An open breaker means requests would fail fast and maintenance means writes are refused, so ask
+    // load balancers to route elsewhere
+    let maintenance = state.mediator.maintenance_mode().is_enabled();
+    let read_only = state.mediator.maintenance_mode().is_read_only();
+    let ready = !maintenance && dependencies.iter().all(|dependency| dependency.circuit_state != CircuitState::Open && dependency.status.as_deref() != Some("down"));
+
+    match ready {
+        true => ApiResponse::new(StatusCode::OK, ReadinessResponse{ready, maintenance, read_only, dependencies}),
+        false => ApiResponse::new(StatusCode::SERVICE_UNAVAILABLE, ReadinessResponse{ready, maintenance, read_only, dependencies})
+    }
+}
+
+pub async fn set_maintenance_mode(Extension(claims): Extension<Claims>, State(state): State<Arc<AppState>>, StrictJson(request): StrictJson<SetMaintenanceModeRequest>) -> ApiResponse<MaintenanceModeResponse> {
+    let maintenance_mode = state.mediator.maintenance_mode();
+    match request.enabled {
+        true => maintenance_mode.enable(request.message),
+        false => maintenance_mode.disable()
+    }
+
+    event!(Level::WARN, "Maintenance mode {} by {}", if request.enabled { "enabled" } else { "disabled" }, claims.sub);
+
+    let enabled = maintenance_mode.is_enabled();
+    ApiResponse::new(StatusCode::OK, MaintenanceModeResponse{enabled, message: enabled.then(|| maintenance_mode.message())})
+}
+
+pub async fn set_log_sampling(Extension(claims): Extension<Claims>, State(state): State<Arc<AppState>>, StrictJson(request): StrictJson<SetLogSamplingRequest>) -> Response {
+    let current = state.log_sampler.settings();
+    let settings = LogSamplingSettings {
+        sample_rate: request.sample_rate.unwrap_or(current.sample_rate),
+        slow_request_threshold: request.slow_request_millis.map(Duration::from_millis).unwrap_or(current.slow_request_threshold)
+    };
+    if let Err(e) = settings.validate() {
+        return ApiResponse::error(StatusCode::BAD_REQUEST, "invalid_log_sampling", e).into_response();
+    }
+
+    state.log_sampler.update(settings.clone());
+    event!(Level::WARN, "Log sampling set to {} of requests, slower than {:?} always logged, by {}", settings.sample_rate, settings.slow_request_threshold, claims.sub);
+
+    ApiResponse::new(StatusCode::OK, LogSamplingResponse{sample_rate: settings.sample_rate, slow_request_millis: settings.slow_request_threshold.as_millis() as u64}).into_response()
+}
+
+// Picks up edits to the rate limit policy file without a restart
+pub async fn reload_rate_limits(Extension(claims): Extension<Claims>, State(state): State<Arc<AppState>>) -> Response {
+    match state.rate_limiter.reload() {
+        Ok(policy) => {
+            event!(Level::WARN, "Rate limit policy reloaded by {}", claims.sub);
+            ApiResponse::<RateLimitPolicy>::new(StatusCode::OK, policy).into_response()
+        },
+        Err(e) => {
+            event!(Level::ERROR, "Failed to reload rate limit policy: {}", e);
+            ApiResponse::error(StatusCode::UNPROCESSABLE_ENTITY, "invalid_rate_limit_policy", e).into_response()
+        }
+    }
+}
+
+// Guest callers may only access the cart their cart session token was issued for. The cart id of
+// these routes is in the body, which the authorization policy can't see
+fn cart_session_allows(extensions: &Extensions, cart_id: &CartId) -> bool {
+    match extensions.get::<CartSession>() {
+        Some(cart_session) => cart_session.cart_id == *cart_id,
+        None => true
+    }
+}
+
+// Empty for guests, whose carts have no owner
+fn caller_user_id(extensions: &Extensions) -> String {
+    extensions.get::<Claims>().map(|claims| claims.sub.clone()).unwrap_or_default()
+}
+
+fn cart_session_forbidden(cart_id: &CartId) -> ApiResponse<()> {
+    ApiResponse::error(StatusCode::FORBIDDEN, "forbidden", format!("Cart session does not grant access to Cart with ID {}", cart_id))
+}
+
+// Guest access to the cart is checked by the authorization policy, users only see their own carts
+pub async fn get_cart_by_id(Path(id): Path<CartId>, extensions: Extensions, State(state): State<Arc<AppState>>) -> Response{
     let input = GetCartsQuery {
-        id: id.to_string()
+        id,
+        user_id: caller_user_id(&extensions),
+        ..Default::default()
+    };
+
+    match state.mediator.query(Some(input)).await {
+        Ok(mut response)=> {
+            add_cart_links(&mut response, extensions.get::<Claims>().is_some());
+            ApiResponse::new(StatusCode::OK, response).into_response()
+        },
+        Err(e) => error_response(e)
+    }
+}
+
+// Guests may delete the cart their cart session is for, users only their own carts
+pub async fn delete_cart(Path(id): Path<CartId>, extensions: Extensions, State(state): State<Arc<AppState>>) -> Response {
+    let delete_cart_command = DeleteCartCommand {
+        user_id: caller_user_id(&extensions),
+        id
     };
 
-    match state.get_carts_query_handle.handle(Some(input)).await {
-        Ok(response)=> (StatusCode::OK, Json(json!(response))),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!(ApiError{error: e})))
+    match state.mediator.send(&delete_cart_command).await {
+        Ok(response) => ApiResponse::new(StatusCode::NO_CONTENT, response).into_response(),
+        Err(e) => error_response(e)
+    }
+}
+
+// Guest access to the cart is checked by the authorization policy, users only see their own carts
+pub async fn get_delivery_slots(Path(id): Path<CartId>, extensions: Extensions, State(state): State<Arc<AppState>>) -> Response {
+    match state.mediator.query(Some(GetDeliverySlotsQuery{user_id: caller_user_id(&extensions), cart_id: id})).await {
+        Ok(response)=> ApiResponse::new(StatusCode::OK, response).into_response(),
+        Err(e) => error_response(e)
+    }
+}
+
+pub async fn get_all_carts(Query(input): Query<GetCartsQuery>, State(state): State<Arc<AppState>>) -> Response{
+    match state.mediator.query(Some(GetCartsQuery{id: CartId::default(), ..input})).await {
+        Ok(mut response)=> {
+            let page = response.page.take();
+            add_cart_links(&mut response, true);
+            ApiResponse::new(StatusCode::OK, response).with_page(page).into_response()
+        },
+        Err(e) => error_response(e)
     }
 }
 
-pub async fn create_cart(state: State<Arc<AppState>>, Json(create_cart_command): Json<CreateCartCommand>) -> (StatusCode, Json<Value>) {
-    match state.create_cart_command_handler.handle(&create_cart_command).await {
-        Ok(response) => (StatusCode::CREATED, Json(json!(response))),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!(ApiError{error: e})))
+pub async fn get_carts_containing_product(Query(input): Query<GetCartsContainingProductQuery>, State(state): State<Arc<AppState>>) -> Response{
+    match state.mediator.query(Some(input)).await {
+        Ok(mut response)=> {
+            add_cart_links(&mut response, true);
+            ApiResponse::new(StatusCode::OK, response).into_response()
+        },
+        Err(e) => error_response(e)
     }
 }
 
-pub async fn add_product_to_cart(state: State<Arc<AppState>>, Json(add_product_to_cart_command): Json<AddProductToCartCommand>) -> (StatusCode, Json<Value>) {
-    match state.add_product_to_cart_command_handler.handle(&add_product_to_cart_command).await {
-        Ok(response) => (StatusCode::OK, Json(json!(response))),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!(ApiError{error: e})))
+pub async fn get_my_carts(Extension(claims): Extension<Claims>, State(state): State<Arc<AppState>>) -> Response{
+    let input = GetUserCartsQuery {
+        user_id: claims.sub
+    };
+
+    match state.mediator.query(Some(input)).await {
+        Ok(mut response)=> {
+            add_cart_links(&mut response, true);
+            ApiResponse::new(StatusCode::OK, response).into_response()
+        },
+        Err(e) => error_response(e)
+    }
+}
+
+// The body is optional, without one the cart gets the default name
+pub async fn create_cart(extensions: Extensions, state: State<Arc<AppState>>, create_cart_command: Option<StrictJson<CreateCartCommand>>) -> Response {
+    let mut create_cart_command = create_cart_command.map(|StrictJson(command)| command).unwrap_or_default();
+
+    // Callers without claims are guests and receive a cart session token instead
+    create_cart_command.user_id = caller_user_id(&extensions);
+
+    match state.mediator.send(&create_cart_command).await {
+        Ok(mut response) => {
+            response.links = cart_links(&response.id, extensions.get::<Claims>().is_some());
+            ApiResponse::new(StatusCode::CREATED, response).into_response()
+        },
+        Err(e) => error_response(e)
     }
 }
 
-pub async fn remove_product_from_cart(state: State<Arc<AppState>>, Json(remove_product_from_cart_command): Json<RemoveProductFromCartCommand>) -> (StatusCode, Json<Value>) {
-    match state.remove_product_from_cart_command_handler.handle(&remove_product_from_cart_command).await {
-        Ok(response) => (StatusCode::NO_CONTENT, Json(json!(response))),
-        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Json(json!(ApiError{error: e})))
+pub async fn add_product_to_cart(extensions: Extensions, headers: HeaderMap, state: State<Arc<AppState>>, StrictJson(mut add_product_to_cart_command): StrictJson<AddProductToCartCommand>) -> Response {
+    add_product_to_cart_command.client_request_id = headers.get(REQUEST_ID_HEADER).and_then(|h| h.to_str().ok()).map(String::from);
+
+    add_product_to_cart_command.user_id = caller_user_id(&extensions);
+
+    if let Some(cart_session) = extensions.get::<CartSession>() {
+        if add_product_to_cart_command.cart_id.is_empty() {
+            add_product_to_cart_command.cart_id = cart_session.cart_id.clone();
+        }
     }
-}
\ No newline at end of file
+
+    if !cart_session_allows(&extensions, &add_product_to_cart_command.cart_id) {
+        return cart_session_forbidden(&add_product_to_cart_command.cart_id).into_response();
+    }
+
+    match state.mediator.send(&add_product_to_cart_command).await {
+        Ok(response) => ApiResponse::new(StatusCode::OK, response).into_response(),
+        Err(e) => error_response(e)
+    }
+}
+
+pub async fn remove_product_from_cart(extensions: Extensions, state: State<Arc<AppState>>, StrictJson(mut remove_product_from_cart_command): StrictJson<RemoveProductFromCartCommand>) -> Response {
+    remove_product_from_cart_command.user_id = caller_user_id(&extensions);
+
+    if !cart_session_allows(&extensions, &remove_product_from_cart_command.cart_id) {
+        return cart_session_forbidden(&remove_product_from_cart_command.cart_id).into_response();
+    }
+
+    match state.mediator.send(&remove_product_from_cart_command).await {
+        Ok(response) => ApiResponse::new(StatusCode::NO_CONTENT, response).into_response(),
+        Err(e) => error_response(e)
+    }
+}
+
+pub async fn set_product_quantity(Path((cart_id, product_id)): Path<(CartId, ProductId)>, extensions: Extensions, state: State<Arc<AppState>>, StrictJson(mut set_product_quantity_command): StrictJson<SetProductQuantityCommand>) -> Response {
+    set_product_quantity_command.user_id = caller_user_id(&extensions);
+    set_product_quantity_command.cart_id = cart_id;
+    set_product_quantity_command.product_id = product_id;
+
+    match state.mediator.send(&set_product_quantity_command).await {
+        Ok(response) => ApiResponse::new(StatusCode::NO_CONTENT, response).into_response(),
+        Err(e) => error_response(e)
+    }
+}
+
+pub async fn set_default_cart(Extension(claims): Extension<Claims>, state: State<Arc<AppState>>, StrictJson(mut set_default_cart_command): StrictJson<SetDefaultCartCommand>) -> Response {
+    set_default_cart_command.user_id = claims.sub;
+
+    match state.mediator.send(&set_default_cart_command).await {
+        Ok(response) => ApiResponse::new(StatusCode::NO_CONTENT, response).into_response(),
+        Err(e) => error_response(e)
+    }
+}
+
+pub async fn share_cart(Path(id): Path<CartId>, Extension(claims): Extension<Claims>, State(state): State<Arc<AppState>>) -> Response {
+    let share_cart_command = ShareCartCommand {
+        user_id: claims.sub,
+        cart_id: id
+    };
+
+    match state.mediator.send(&share_cart_command).await {
+        Ok(response) => ApiResponse::new(StatusCode::CREATED, response).into_response(),
+        Err(e) => error_response(e)
+    }
+}
+
+pub async fn get_shared_cart(Path(token): Path<String>, State(state): State<Arc<AppState>>) -> Response {
+    let input = GetSharedCartQuery {
+        token
+    };
+
+    match state.mediator.query(Some(input)).await {
+        Ok(response)=> ApiResponse::new(StatusCode::OK, response).into_response(),
+        Err(e) => error_response(e)
+    }
+}
+
+pub async fn clone_shared_cart(Path(token): Path<String>, Extension(claims): Extension<Claims>, State(state): State<Arc<AppState>>) -> Response {
+    let clone_shared_cart_command = CloneSharedCartCommand {
+        user_id: claims.sub,
+        token
+    };
+
+    match state.mediator.send(&clone_shared_cart_command).await {
+        Ok(response) => ApiResponse::new(StatusCode::CREATED, response).into_response(),
+        Err(e) => error_response(e)
+    }
+}
+
+pub async fn claim_guest_cart(Path(id): Path<CartId>, Extension(claims): Extension<Claims>, headers: HeaderMap, State(state): State<Arc<AppState>>) -> Response {
+    let cart_session_token = match headers.get(CART_SESSION_HEADER).and_then(|h| h.to_str().ok()) {
+        Some(token) => String::from(token),
+        None => return ApiResponse::error(StatusCode::BAD_REQUEST, "missing_header", format!("Missing {} header", CART_SESSION_HEADER)).into_response()
+    };
+
+    let claim_guest_cart_command = ClaimGuestCartCommand {
+        user_id: claims.sub,
+        cart_id: id,
+        cart_session_token
+    };
+
+    match state.mediator.send(&claim_guest_cart_command).await {
+        Ok(response) => ApiResponse::new(StatusCode::NO_CONTENT, response).into_response(),
+        Err(e) => error_response(e)
+    }
+}
+
+// Orders don't record who placed them yet, so they are only open to support staff
+pub async fn get_order_by_id(Path(id): Path<OrderId>, State(state): State<Arc<AppState>>) -> Response {
+    match state.mediator.query(Some(GetOrderByIdQuery{id})).await {
+        Ok(mut response)=> {
+            response.links = order_links(&response.id);
+            ApiResponse::new(StatusCode::OK, response).into_response()
+        },
+        Err(e) => error_response(e)
+    }
+}
+
+pub async fn cancel_order(Path(id): Path<OrderId>, Extension(claims): Extension<Claims>, State(state): State<Arc<AppState>>, StrictJson(mut cancel_order_command): StrictJson<CancelOrderCommand>) -> Response {
+    cancel_order_command.id = id;
+    cancel_order_command.cancelled_by = claims.sub;
+
+    match state.mediator.send(&cancel_order_command).await {
+        Ok(mut response)=> {
+            response.links = order_links(&response.id);
+            ApiResponse::new(StatusCode::OK, response).into_response()
+        },
+        Err(e) => error_response(e)
+    }
+}
+
+pub async fn get_all_orders(Query(input): Query<GetOrdersQuery>, State(state): State<Arc<AppState>>) -> Response {
+    match state.mediator.query(Some(input)).await {
+        Ok(mut response)=> {
+            let page = response.page.take();
+            add_order_links(&mut response);
+            ApiResponse::new(StatusCode::OK, response).with_page(page).into_response()
+        },
+        Err(e) => error_response(e)
+    }
+}
+
+pub async fn get_order_timeline(Path(id): Path<OrderId>, State(state): State<Arc<AppState>>) -> Response {
+    match state.mediator.query(Some(GetOrderTimelineQuery{id})).await {
+        Ok(mut response)=> {
+            response.links = BTreeMap::from([(String::from("self"), link(Method::GET, ORDER_TIMELINE_PATH, response.order_id.as_str()))]);
+            ApiResponse::new(StatusCode::OK, response).into_response()
+        },
+        Err(e) => error_response(e)
+    }
+}
+
+// Orders don't record who placed them yet either, so support staff hand pickup codes out
+pub async fn get_pickup_code(Path(id): Path<OrderId>, State(state): State<Arc<AppState>>) -> Response {
+    match state.mediator.query(Some(GetPickupCodeQuery{id})).await {
+        Ok(response)=> ApiResponse::new(StatusCode::OK, response).into_response(),
+        Err(e) => error_response(e)
+    }
+}
+
+pub async fn verify_pickup_code(Path(id): Path<OrderId>, State(state): State<Arc<AppState>>, StrictJson(mut verify_pickup_code_query): StrictJson<VerifyPickupCodeQuery>) -> Response {
+    verify_pickup_code_query.id = id;
+
+    match state.mediator.query(Some(verify_pickup_code_query)).await {
+        Ok(response)=> ApiResponse::new(StatusCode::OK, response).into_response(),
+        Err(e) => error_response(e)
+    }
+}
+
+pub async fn erase_customer_data(Path(customer_id): Path<String>, Extension(claims): Extension<Claims>, State(state): State<Arc<AppState>>) -> Response {
+    let erase_customer_data_command = EraseCustomerDataCommand {
+        customer_id,
+        requested_by: claims.sub
+    };
+
+    match state.mediator.send(&erase_customer_data_command).await {
+        Ok(response) => ApiResponse::new(StatusCode::OK, response).into_response(),
+        Err(e) => error_response(e)
+    }
+}
+
+// The body is NDJSON, one exported cart per line. Records that fail are reported by line number
+// without failing the request
+pub async fn import_carts(State(state): State<Arc<AppState>>, body: String) -> Response {
+    let import_carts_command = ImportCartsCommand {
+        lines: body.lines().map(String::from).collect()
+    };
+
+    match state.mediator.send(&import_carts_command).await {
+        Ok(response) => ApiResponse::new(StatusCode::OK, response).into_response(),
+        Err(e) => error_response(e)
+    }
+}
+
+// Reports what the retention rules would purge right now without changing anything
+pub async fn apply_retention_rules_dry_run(State(state): State<Arc<AppState>>) -> Response {
+    match state.mediator.send(&ApplyRetentionRulesCommand{dry_run: true}).await {
+        Ok(response) => ApiResponse::new(StatusCode::OK, response).into_response(),
+        Err(e) => error_response(e)
+    }
+}
+
+pub async fn export_customer_data(Path(customer_id): Path<String>, State(state): State<Arc<AppState>>) -> Response {
+    match state.mediator.query(Some(ExportCustomerDataQuery{customer_id})).await {
+        Ok(response)=> ApiResponse::new(StatusCode::OK, response).into_response(),
+        Err(e) => error_response(e)
+    }
+}
+
+pub async fn get_stats(State(state): State<Arc<AppState>>) -> Response {
+    match state.mediator.query(Some(GetStatsQuery{})).await {
+        Ok(response)=> ApiResponse::new(StatusCode::OK, response).into_response(),
+        Err(e) => error_response(e)
+    }
+}
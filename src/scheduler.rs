@@ -0,0 +1,162 @@
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use cron::Schedule;
+use tokio::task::JoinHandle;
+use tracing::{event, Level};
+
+use crate::leader_election::LeaderElector;
+
+pub static JOB_RUNS_TOTAL: &str = "order_service_job_runs_total";
+pub static JOB_SKIPPED_TOTAL: &str = "order_service_job_skipped_total";
+pub static JOB_DURATION_SECONDS: &str = "order_service_job_duration_seconds";
+
+#[async_trait]
+pub trait Job {
+    async fn run(&self) -> Result<(), String>;
+}
+
+struct ScheduledJob {
+    name: String,
+    schedule: Schedule,
+    job: Arc<dyn Job + Send + Sync>,
+    running: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+pub struct Scheduler {
+    jobs: Vec<ScheduledJob>,
+    leader_elector: Option<Arc<LeaderElector>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            jobs: Vec::new(),
+            leader_elector: None,
+        }
+    }
+
+    // With several replicas running, jobs only run on the one currently holding the lease
+    pub fn with_leader_election(mut self, leader_elector: Arc<LeaderElector>) -> Scheduler {
+        self.leader_elector = Some(leader_elector);
+        self
+    }
+
+    // Registers a job against a cron expression with seconds, e.g. "0 */5 * * * *" for every 5 minutes
+    pub fn register(
+        &mut self,
+        name: &str,
+        cron_expression: &str,
+        job: Arc<dyn Job + Send + Sync>,
+    ) -> Result<(), String> {
+        match Schedule::from_str(cron_expression) {
+            Ok(schedule) => {
+                self.jobs.push(ScheduledJob {
+                    name: String::from(name),
+                    schedule,
+                    job,
+                    running: Arc::new(AtomicBool::new(false)),
+                });
+
+                Ok(())
+            }
+            Err(e) => Err(format!(
+                "Invalid cron expression '{}' for job {}: {}",
+                cron_expression, name, e
+            )),
+        }
+    }
+
+    // Spawns one timer task per job; a tick is skipped while the previous run is still in flight
+    pub fn start(self) -> Vec<JoinHandle<()>> {
+        let mut handles: Vec<JoinHandle<()>> = self
+            .jobs
+            .into_iter()
+            .map(|scheduled_job| {
+                tokio::spawn(run_schedule(scheduled_job, self.leader_elector.clone()))
+            })
+            .collect();
+
+        if let Some(leader_elector) = self.leader_elector {
+            handles.push(leader_elector.start());
+        }
+
+        handles
+    }
+}
+
+async fn run_schedule(scheduled_job: ScheduledJob, leader_elector: Option<Arc<LeaderElector>>) {
+    loop {
+        let next_run = match scheduled_job.schedule.upcoming(Utc).next() {
+            Some(next_run) => next_run,
+            None => {
+                event!(
+                    Level::WARN,
+                    "Job {} has no upcoming runs, stopping",
+                    scheduled_job.name
+                );
+                return;
+            }
+        };
+
+        if let Ok(delay) = (next_run - Utc::now()).to_std() {
+            tokio::time::sleep(delay).await;
+        }
+
+        if let Some(leader_elector) = &leader_elector {
+            if !leader_elector.is_leader() {
+                event!(
+                    Level::DEBUG,
+                    "Not the leader, leaving job {} to another replica",
+                    scheduled_job.name
+                );
+                continue;
+            }
+        }
+
+        if scheduled_job.running.swap(true, Ordering::SeqCst) {
+            event!(
+                Level::WARN,
+                "Job {} is still running, skipping this run",
+                scheduled_job.name
+            );
+            metrics::counter!(JOB_SKIPPED_TOTAL, "job" => scheduled_job.name.clone()).increment(1);
+            continue;
+        }
+
+        let name = scheduled_job.name.clone();
+        let job = scheduled_job.job.clone();
+        let running = scheduled_job.running.clone();
+
+        tokio::spawn(async move {
+            let started = Instant::now();
+
+            let outcome = match job.run().await {
+                Ok(()) => {
+                    event!(Level::DEBUG, "Job {} completed", name);
+                    "success"
+                }
+                Err(e) => {
+                    event!(Level::WARN, "Job {} failed: {}", name, e);
+                    "failure"
+                }
+            };
+
+            metrics::counter!(JOB_RUNS_TOTAL, "job" => name.clone(), "outcome" => outcome)
+                .increment(1);
+            metrics::histogram!(JOB_DURATION_SECONDS, "job" => name)
+                .record(started.elapsed().as_secs_f64());
+
+            running.store(false, Ordering::SeqCst);
+        });
+    }
+}
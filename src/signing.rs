@@ -0,0 +1,86 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Issues and verifies HMAC-signed, expiring tokens in the form '<payload>.<expiry>.<signature>'
+#[derive(Clone)]
+pub struct TokenSigner {
+    secret: Vec<u8>,
+}
+
+impl TokenSigner {
+    pub fn new(secret: String) -> TokenSigner {
+        TokenSigner {
+            secret: secret.into_bytes(),
+        }
+    }
+
+    pub fn sign(&self, payload: &str, expires_at_utc: i64) -> String {
+        let encoded_payload = URL_SAFE_NO_PAD.encode(payload);
+        let unsigned_token = format!("{}.{}", encoded_payload, expires_at_utc);
+
+        format!(
+            "{}.{}",
+            unsigned_token,
+            URL_SAFE_NO_PAD.encode(self.signature(&unsigned_token))
+        )
+    }
+
+    pub fn verify(&self, token: &str) -> Result<String, String> {
+        let (unsigned_token, encoded_signature) = match token.rsplit_once('.') {
+            Some(parts) => parts,
+            None => return Err(String::from("Token is not formatted correctly")),
+        };
+
+        let signature = match URL_SAFE_NO_PAD.decode(encoded_signature) {
+            Ok(s) => s,
+            Err(_) => return Err(String::from("Token signature is not formatted correctly")),
+        };
+
+        let mut mac = self.mac();
+        mac.update(unsigned_token.as_bytes());
+        if mac.verify_slice(&signature).is_err() {
+            return Err(String::from("Token signature is invalid"));
+        }
+
+        let (encoded_payload, expires_at_utc) = match unsigned_token.split_once('.') {
+            Some(parts) => parts,
+            None => return Err(String::from("Token is not formatted correctly")),
+        };
+
+        match expires_at_utc.parse::<i64>() {
+            Ok(expires_at_utc) if expires_at_utc > now_utc_millis() => {}
+            Ok(_) => return Err(String::from("Token has expired")),
+            Err(_) => return Err(String::from("Token expiry is not formatted correctly")),
+        }
+
+        match URL_SAFE_NO_PAD.decode(encoded_payload) {
+            Ok(payload) => match String::from_utf8(payload) {
+                Ok(payload) => Ok(payload),
+                Err(_) => Err(String::from("Token payload is not formatted correctly")),
+            },
+            Err(_) => Err(String::from("Token payload is not formatted correctly")),
+        }
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any length")
+    }
+
+    fn signature(&self, unsigned_token: &str) -> Vec<u8> {
+        let mut mac = self.mac();
+        mac.update(unsigned_token.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+pub fn now_utc_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("oops")
+        .as_millis() as i64
+}
@@ -1,16 +1,28 @@
 use std::sync::Arc;
 
-use crate::cqrs::{
-    AddProductToCartCommandHandler, CreateCartCommandHandler, GetCartsQueryHandler,
-    RemoveProductFromCartCommandHandler,
+use crate::{
+    auth::{OpsAccess, TokenIssuer},
+    authorization::AuthorizationPolicy,
+    health::Dependency,
+    idempotency::IdempotencyKeys,
+    log_sampling::LogSampler,
+    mediator::Mediator,
+    outbox::OutboxRelay,
+    rate_limiting::RateLimiter,
+    signing::TokenSigner,
 };
 
 #[derive(Clone)]
 pub struct AppState {
-    pub create_cart_command_handler: Arc<CreateCartCommandHandler>,
-    pub get_carts_query_handle: Arc<GetCartsQueryHandler>,
-    pub add_product_to_cart_command_handler: Arc<AddProductToCartCommandHandler>,
-    pub remove_product_from_cart_command_handler: Arc<RemoveProductFromCartCommandHandler>,
-    pub auth0_domain: String,
-    pub auth0_audience: String,
+    pub mediator: Arc<Mediator>,
+    pub cart_session_token_signer: TokenSigner,
+    pub dependencies: Vec<Dependency>,
+    pub token_issuers: Vec<TokenIssuer>,
+    pub ops_access: OpsAccess,
+    pub authorization_policy: AuthorizationPolicy,
+    pub log_sampler: Arc<LogSampler>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub idempotency_keys: Arc<IdempotencyKeys>,
+    // Only set when events go through the outbox, for the scheduler to run
+    pub outbox_relay: Option<Arc<OutboxRelay>>,
 }
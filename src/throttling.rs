@@ -0,0 +1,110 @@
+use axum::{
+    http::{
+        header::{CONTENT_TYPE, RETRY_AFTER},
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+
+use crate::dtos::ProblemDetails;
+
+pub static PROBLEM_JSON_CONTENT_TYPE: &str = "application/problem+json";
+
+// Handlers report errors as strings, so throttling errors are recognized by this prefix followed by
+// the reason code, e.g. "Unavailable [dependency_unavailable]: mongodb is unavailable"
+static THROTTLED_ERROR_PREFIX: &str = "Unavailable [";
+
+// Why a request was refused before it could do any work. The code is sent to clients so they can
+// tell a brief overload from an outage and back off accordingly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThrottleReason {
+    Overloaded,
+    DependencyUnavailable,
+    ConcurrencyLimit,
+    Maintenance,
+    RateLimited,
+}
+
+impl ThrottleReason {
+    fn all() -> [ThrottleReason; 5] {
+        [
+            ThrottleReason::Overloaded,
+            ThrottleReason::DependencyUnavailable,
+            ThrottleReason::ConcurrencyLimit,
+            ThrottleReason::Maintenance,
+            ThrottleReason::RateLimited,
+        ]
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ThrottleReason::Overloaded => "overloaded",
+            ThrottleReason::DependencyUnavailable => "dependency_unavailable",
+            ThrottleReason::ConcurrencyLimit => "concurrency_limit",
+            ThrottleReason::Maintenance => "maintenance",
+            ThrottleReason::RateLimited => "rate_limited",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ThrottleReason::Overloaded
+            | ThrottleReason::DependencyUnavailable
+            | ThrottleReason::ConcurrencyLimit
+            | ThrottleReason::Maintenance => StatusCode::SERVICE_UNAVAILABLE,
+            ThrottleReason::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    // Used when the code raising the error doesn't know better
+    pub fn default_retry_after_seconds(&self) -> u64 {
+        match self {
+            ThrottleReason::Overloaded => 1,
+            ThrottleReason::DependencyUnavailable => 5,
+            ThrottleReason::ConcurrencyLimit => 1,
+            ThrottleReason::Maintenance => 60,
+            ThrottleReason::RateLimited => 1,
+        }
+    }
+
+    pub fn error(&self, detail: &str) -> String {
+        format!("{}{}]: {}", THROTTLED_ERROR_PREFIX, self.code(), detail)
+    }
+
+    // Recovers the reason and detail from an error built with ThrottleReason::error
+    pub fn from_error(error: &str) -> Option<(ThrottleReason, &str)> {
+        let (code, detail) = error
+            .strip_prefix(THROTTLED_ERROR_PREFIX)?
+            .split_once("]: ")?;
+
+        ThrottleReason::all()
+            .into_iter()
+            .find(|reason| reason.code() == code)
+            .map(|reason| (reason, detail))
+    }
+}
+
+pub fn throttled_response(
+    reason: ThrottleReason,
+    detail: &str,
+    retry_after_seconds: u64,
+) -> Response {
+    let status = reason.status();
+
+    (
+        status,
+        [
+            (CONTENT_TYPE, String::from(PROBLEM_JSON_CONTENT_TYPE)),
+            (RETRY_AFTER, retry_after_seconds.to_string()),
+        ],
+        Json(ProblemDetails {
+            problem_type: String::from("about:blank"),
+            title: String::from(status.canonical_reason().unwrap_or_default()),
+            status: status.as_u16(),
+            detail: String::from(detail),
+            reason: String::from(reason.code()),
+        }),
+    )
+        .into_response()
+}
@@ -0,0 +1,118 @@
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Query, Request},
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE},
+        StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, FixedOffset};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::api_response::ApiResponse;
+
+pub static TIMEZONE_HEADER: &str = "x-timezone";
+
+#[derive(Deserialize)]
+struct TimezoneQuery {
+    tz: Option<String>,
+}
+
+// Accepts 'UTC', 'Z' or an offset such as '+02:00', '-0530' or '+01'. Zone names would need the
+// tz database, which the service doesn't ship with
+pub fn parse_offset(timezone: &str) -> Result<FixedOffset, String> {
+    let timezone = timezone.trim();
+    if timezone.eq_ignore_ascii_case("utc") || timezone.eq_ignore_ascii_case("z") {
+        return Ok(FixedOffset::east_opt(0).unwrap());
+    }
+
+    let invalid = || {
+        format!(
+            "Unknown time zone {}, expected UTC or an offset such as +02:00",
+            timezone
+        )
+    };
+    let (sign, offset) = match timezone.split_at_checked(1) {
+        Some(("+", offset)) => (1, offset),
+        Some(("-", offset)) => (-1, offset),
+        _ => return Err(invalid()),
+    };
+    let digits = offset.replace(':', "");
+    let (hours, minutes) = match digits.len() {
+        2 => (digits.as_str(), "0"),
+        4 => digits.split_at(2),
+        _ => return Err(invalid()),
+    };
+
+    match (hours.parse::<i32>(), minutes.parse::<i32>()) {
+        (Ok(hours), Ok(minutes)) if hours <= 14 && minutes < 60 => {
+            FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).ok_or_else(invalid)
+        }
+        _ => Err(invalid()),
+    }
+}
+
+// Timestamps are stored and sent in UTC. Callers that ask for another zone through X-Timezone or
+// ?tz= get every timestamp in JSON responses converted to it, with the offset spelled out
+pub async fn timezone_middleware(request: Request, next: Next) -> Response {
+    let requested_timezone = match request.headers().get(TIMEZONE_HEADER) {
+        Some(timezone) => timezone.to_str().ok().map(String::from),
+        // A '+' in a query string decodes to a space unless it is escaped
+        None => Query::<TimezoneQuery>::try_from_uri(request.uri())
+            .ok()
+            .and_then(|query| query.0.tz)
+            .map(|timezone| timezone.replace(' ', "+")),
+    };
+    let offset = match requested_timezone.as_deref().map(parse_offset) {
+        Some(Ok(offset)) => offset,
+        Some(Err(e)) => {
+            return ApiResponse::error(StatusCode::BAD_REQUEST, "invalid_timezone", e)
+                .into_response()
+        }
+        None => return next.run(request).await,
+    };
+
+    let response = next.run(request).await;
+    let is_json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+    if !is_json {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let mut value: Value = match to_bytes(body, usize::MAX)
+        .await
+        .ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+    {
+        Some(value) => value,
+        None => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    convert_timestamps(&mut value, &offset);
+
+    parts.headers.remove(CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(value.to_string()))
+}
+
+fn convert_timestamps(value: &mut Value, offset: &FixedOffset) {
+    match value {
+        Value::Object(fields) => fields
+            .values_mut()
+            .for_each(|value| convert_timestamps(value, offset)),
+        Value::Array(values) => values
+            .iter_mut()
+            .for_each(|value| convert_timestamps(value, offset)),
+        Value::String(text) => {
+            if let Ok(timestamp) = DateTime::parse_from_rfc3339(text) {
+                *text = timestamp.with_timezone(offset).to_rfc3339();
+            }
+        }
+        _ => {}
+    }
+}
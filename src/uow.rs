@@ -1,32 +1,68 @@
 use std::sync::Arc;
 
 use async_trait::async_trait;
-use mongodb::ClientSession;
+use chrono::Utc;
+use mongodb::{Client, ClientSession};
 use tokio::sync::Mutex;
 use tracing::{event, Level};
 
 use crate::{
+    compensation::{CompensatingCartRepository, CompensatingOrderRepository, CompensationLog},
+    consistency::{self, ConsistencyToken},
     events::{Event, MessageBroker},
+    outbox::{OutboxMessage, OutboxStore},
     repositories::{CartRepository, OrderRepository},
 };
 
+pub static EVENTS_PUBLISHED_TOTAL: &str = "order_service_events_published_total";
+pub static EVENT_PUBLISH_FAILURES_TOTAL: &str = "order_service_event_publish_failures_total";
+
+// What the repository calls taking part in one transaction share. Backends without multi-document
+// transactions (SQLite, in-memory) run without a client session and undo the transaction's writes
+// from its compensation log instead. The events a transaction raises wait here until it commits, so
+// requests running at the same time never publish, drop or undo each other's. Writes made outside
+// of a transaction take a default session
+#[derive(Clone, Default)]
+pub struct TransactionSession {
+    client_session: Option<Arc<Mutex<ClientSession>>>,
+    events: Arc<Mutex<Vec<Event>>>,
+    compensation_log: Arc<CompensationLog>,
+}
+
+impl TransactionSession {
+    pub fn client_session(&self) -> Option<&Arc<Mutex<ClientSession>>> {
+        self.client_session.as_ref()
+    }
+
+    pub fn compensation_log(&self) -> &CompensationLog {
+        &self.compensation_log
+    }
+
+    // Published once the transaction commits, and dropped if it is rolled back
+    pub async fn push_event(&self, event: Event) {
+        self.events.lock().await.push(event);
+    }
+}
+
 #[async_trait]
 pub trait UnitOfWork {
+    #[allow(dead_code)]
     async fn get_order_repository(&self) -> Arc<dyn OrderRepository + Send + Sync>;
     async fn get_cart_repository(&self) -> Arc<dyn CartRepository + Send + Sync>;
-    async fn get_events_to_publish(&self) -> Arc<Mutex<Vec<Event>>>;
-    async fn begin_transaction(&self) -> Arc<Mutex<ClientSession>>;
-    async fn commit(&self) -> Result<(), String>;
-    async fn rollback(&self) -> Result<(), String>;
+    async fn begin_transaction(&self) -> Result<TransactionSession, String>;
+    async fn commit(&self, session: TransactionSession) -> Result<(), String>;
+    async fn rollback(&self, session: TransactionSession) -> Result<(), String>;
 }
 
 #[derive(Clone)]
 pub struct OrderUnitOfWork {
+    #[allow(dead_code)]
     order_repository: Arc<dyn OrderRepository + Send + Sync>,
     cart_repository: Arc<dyn CartRepository + Send + Sync>,
     message_broker: Arc<dyn MessageBroker + Send + Sync>,
-    events_to_publish: Arc<Mutex<Vec<Event>>>,
-    client_session: Arc<Mutex<ClientSession>>,
+    // Starts the sessions of transactions, None when the backend doesn't support them
+    transaction_client: Option<Client>,
+    outbox_store: Option<Arc<dyn OutboxStore + Send + Sync>>,
 }
 
 impl OrderUnitOfWork {
@@ -34,16 +70,49 @@ impl OrderUnitOfWork {
         order_repository: Arc<dyn OrderRepository + Send + Sync>,
         cart_repository: Arc<dyn CartRepository + Send + Sync>,
         message_broker: Arc<dyn MessageBroker + Send + Sync>,
-        client_session: Arc<Mutex<ClientSession>>,
+        transaction_client: Option<Client>,
     ) -> OrderUnitOfWork {
         OrderUnitOfWork {
-            order_repository: order_repository,
-            cart_repository: cart_repository,
-            message_broker: message_broker,
-            events_to_publish: Arc::new(Mutex::new(Vec::new())),
-            client_session: client_session,
+            order_repository,
+            cart_repository,
+            message_broker,
+            transaction_client,
+            outbox_store: None,
         }
     }
+
+    // For backends that can't run transactions, so a rollback undoes the writes made in the
+    // transaction as best it can
+    pub fn with_compensation(mut self) -> OrderUnitOfWork {
+        self.order_repository = Arc::new(CompensatingOrderRepository::new(self.order_repository));
+        self.cart_repository = Arc::new(CompensatingCartRepository::new(self.cart_repository));
+        self
+    }
+
+    // Undoes the transaction's writes, leaving its events to the caller
+    async fn abort(&self, session: &TransactionSession) -> Result<(), String> {
+        if let Some(client_session) = &session.client_session {
+            client_session
+                .lock()
+                .await
+                .abort_transaction()
+                .await
+                .map_err(|e| format!("Failed to abort transaction: {}", e))?;
+        }
+        session.compensation_log.compensate().await;
+
+        Ok(())
+    }
+
+    // Events are written to the outbox in the transaction instead of being published after it
+    // commits, so a crash in between can't lose them. A relay publishes them from there
+    pub fn with_outbox(
+        mut self,
+        outbox_store: Arc<dyn OutboxStore + Send + Sync>,
+    ) -> OrderUnitOfWork {
+        self.outbox_store = Some(outbox_store);
+        self
+    }
 }
 
 #[async_trait]
@@ -56,35 +125,69 @@ impl UnitOfWork for OrderUnitOfWork {
         self.cart_repository.clone()
     }
 
-    async fn get_events_to_publish(&self) -> Arc<Mutex<Vec<Event>>> {
-        self.events_to_publish.clone()
-    }
+    async fn begin_transaction(&self) -> Result<TransactionSession, String> {
+        let client = match &self.transaction_client {
+            Some(client) => client,
+            None => return Ok(TransactionSession::default()),
+        };
 
-    async fn begin_transaction(&self) -> Arc<Mutex<ClientSession>> {
-        self.client_session
-            .lock()
+        let mut client_session = client
+            .start_session()
             .await
+            .map_err(|e| format!("Failed to start MongoDB session: {}", e))?;
+        client_session
             .start_transaction()
             .await
-            .unwrap();
+            .map_err(|e| format!("Failed to start transaction: {}", e))?;
 
-        self.client_session.clone()
+        Ok(TransactionSession {
+            client_session: Some(Arc::new(Mutex::new(client_session))),
+            ..TransactionSession::default()
+        })
     }
-    async fn commit(&self) -> Result<(), String> {
+    async fn commit(&self, session: TransactionSession) -> Result<(), String> {
         event!(Level::TRACE, "Committing changes");
 
-        self.client_session
-            .lock()
-            .await
-            .commit_transaction()
-            .await
-            .unwrap();
+        let mut lock = session.events.lock().await;
+        if let Some(outbox_store) = &self.outbox_store {
+            let messages = OutboxMessage::from_events(lock.drain(..).collect(), Utc::now());
+
+            // The changes must not be committed without their events, so the transaction is
+            // aborted and the events go back to the session the way they were
+            if let Err(e) = outbox_store.append(&messages, session.clone()).await {
+                lock.extend(messages.into_iter().map(|message| message.event));
+                if let Err(abort_error) = self.abort(&session).await {
+                    event!(Level::WARN, "{}", abort_error);
+                }
+                return Err(e);
+            }
+        }
+
+        if let Some(client_session) = &session.client_session {
+            let mut client_session = client_session.lock().await;
+            client_session
+                .commit_transaction()
+                .await
+                .map_err(|e| format!("Failed to commit transaction: {}", e))?;
+
+            if let Some(token) = ConsistencyToken::from_session(&client_session) {
+                consistency::observe(token);
+            }
+        }
+        session.compensation_log.clear().await;
 
-        let mut lock = self.events_to_publish.lock().await;
         let mut event_results = Vec::new();
         for e in lock.iter() {
             event!(Level::TRACE, "publishing event");
-            event_results.push(self.message_broker.publish_message(e).await);
+            let result = self.message_broker.publish_message(e).await;
+
+            let counter_name = match result {
+                Ok(()) => EVENTS_PUBLISHED_TOTAL,
+                Err(_) => EVENT_PUBLISH_FAILURES_TOTAL,
+            };
+            metrics::counter!(counter_name, "event_type" => e.event_type()).increment(1);
+
+            event_results.push(result);
         }
 
         let mut single_event_failed = false;
@@ -107,14 +210,9 @@ impl UnitOfWork for OrderUnitOfWork {
         Ok(())
     }
 
-    async fn rollback(&self) -> Result<(), String> {
-        self.client_session
-            .lock()
-            .await
-            .abort_transaction()
-            .await
-            .unwrap();
+    async fn rollback(&self, session: TransactionSession) -> Result<(), String> {
+        session.events.lock().await.clear();
 
-        Ok(())
+        self.abort(&session).await
     }
 }
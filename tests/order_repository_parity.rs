@@ -0,0 +1,193 @@
+// Every OrderRepository has to answer updates and deletes the way the in-memory one does, as that is
+// what local runs and the benches use. The MongoDB cases need a server, so they are ignored unless
+// asked for: MONGODB_TEST_URI=mongodb://localhost:27017 cargo test -- --ignored
+use std::env;
+
+use chrono::{TimeDelta, Utc};
+use eshop_orders::{
+    domain::{Order, OrderId, PaymentId, ProductId},
+    repositories::{
+        is_not_found, is_version_conflict, InMemoryOrderRepository, MongoDbInitializationInfo,
+        MongoDbOrderRepository, OrderRepository, SqliteOrderRepository,
+    },
+    uow::TransactionSession,
+};
+use mongodb::Client;
+use sqlx::sqlite::SqlitePoolOptions;
+
+type DynOrderRepository = dyn OrderRepository + Send + Sync;
+
+fn placed_order() -> Order {
+    Order::new(
+        OrderId::generate(),
+        vec![ProductId::from("product-1")],
+        PaymentId::from("payment-1"),
+        "customer-1",
+        Utc::now(),
+    )
+    .unwrap()
+}
+
+async fn update_of_missing_order_is_not_found(repository: &DynOrderRepository) {
+    let order = placed_order();
+
+    let result = repository
+        .update(order.id.clone(), order, TransactionSession::default())
+        .await;
+
+    assert!(is_not_found(&result.unwrap_err()));
+}
+
+async fn update_bumps_the_version(repository: &DynOrderRepository) {
+    let order = placed_order();
+    let created_order = repository
+        .create(order.id.clone(), order, TransactionSession::default())
+        .await
+        .unwrap();
+
+    let updated_order = repository
+        .update(
+            created_order.id.clone(),
+            created_order.clone(),
+            TransactionSession::default(),
+        )
+        .await
+        .unwrap();
+    let read_order = repository.read(&created_order.id).await.unwrap();
+
+    assert_eq!(updated_order.version, created_order.version + 1);
+    assert_eq!(read_order.version, updated_order.version);
+    assert!(read_order.updated_at_utc >= created_order.updated_at_utc);
+}
+
+async fn update_of_stale_order_is_a_version_conflict(repository: &DynOrderRepository) {
+    let order = placed_order();
+    let created_order = repository
+        .create(order.id.clone(), order, TransactionSession::default())
+        .await
+        .unwrap();
+    repository
+        .update(
+            created_order.id.clone(),
+            created_order.clone(),
+            TransactionSession::default(),
+        )
+        .await
+        .unwrap();
+
+    let result = repository
+        .update(
+            created_order.id.clone(),
+            created_order.clone(),
+            TransactionSession::default(),
+        )
+        .await;
+
+    assert!(is_version_conflict(&result.unwrap_err()));
+}
+
+async fn deleted_order_is_not_found(repository: &DynOrderRepository) {
+    let order = placed_order();
+    let created_order = repository
+        .create(order.id.clone(), order, TransactionSession::default())
+        .await
+        .unwrap();
+
+    repository
+        .delete(&created_order.id, TransactionSession::default())
+        .await
+        .unwrap();
+
+    assert!(is_not_found(
+        &repository.read(&created_order.id).await.unwrap_err()
+    ));
+    assert!(is_not_found(
+        &repository
+            .delete(&created_order.id, TransactionSession::default())
+            .await
+            .unwrap_err()
+    ));
+}
+
+async fn updated_before_pages_by_id(repository: &DynOrderRepository) {
+    let mut ids = Vec::new();
+    for _ in 0..3 {
+        let order = placed_order();
+        ids.push(order.id.clone());
+        repository
+            .create(order.id.clone(), order, TransactionSession::default())
+            .await
+            .unwrap();
+    }
+    ids.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+    let cutoff_utc = Utc::now() + TimeDelta::seconds(1);
+
+    let first_page = repository
+        .read_updated_before(cutoff_utc, None, 2)
+        .await
+        .unwrap();
+    let after_id = first_page.last().unwrap().id.clone();
+    let second_page = repository
+        .read_updated_before(cutoff_utc, Some(&after_id), 2)
+        .await
+        .unwrap();
+    let before_any = repository
+        .read_updated_before(Utc::now() - TimeDelta::days(1), None, 2)
+        .await
+        .unwrap();
+
+    let paged_ids: Vec<OrderId> = first_page
+        .into_iter()
+        .chain(second_page)
+        .map(|order| order.id)
+        .collect();
+    assert_eq!(paged_ids, ids);
+    assert!(before_any.is_empty());
+}
+
+async fn check_parity(repository: &DynOrderRepository) {
+    // Pages through every stored order, so it runs while there are no others
+    updated_before_pages_by_id(repository).await;
+    update_of_missing_order_is_not_found(repository).await;
+    update_bumps_the_version(repository).await;
+    update_of_stale_order_is_a_version_conflict(repository).await;
+    deleted_order_is_not_found(repository).await;
+}
+
+#[tokio::test]
+async fn in_memory_order_repository() {
+    check_parity(&InMemoryOrderRepository::new()).await;
+}
+
+#[tokio::test]
+async fn sqlite_order_repository() {
+    // Every connection to :memory: gets a database of its own
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+
+    check_parity(&SqliteOrderRepository::new(&pool).await.unwrap()).await;
+}
+
+#[tokio::test]
+#[ignore = "needs a MongoDB server at MONGODB_TEST_URI"]
+async fn mongodb_order_repository() {
+    let info = MongoDbInitializationInfo {
+        uri: env::var("MONGODB_TEST_URI").unwrap(),
+        database: String::from("order_repository_parity"),
+        collection: format!("orders_{}", uuid::Uuid::new_v4()),
+    };
+    let client = Client::with_uri_str(&info.uri).await.unwrap();
+    let repository = MongoDbOrderRepository::new(&info, &client).await;
+
+    check_parity(&repository).await;
+
+    client
+        .database(&info.database)
+        .collection::<Order>(&info.collection)
+        .drop()
+        .await
+        .unwrap();
+}